@@ -0,0 +1,55 @@
+//! Embeds the server in-process with a generator of its own, handing out the
+//! addresses of a documentation range in order rather than random ones, and
+//! requests a few of them.
+//!
+//! Run with `cargo run --example custom_generator`.
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use addrcore::Constraints;
+
+use client::Client;
+use server::{AddrGenerator, Server};
+
+/// Counts through 192.0.2.0/24 on port 80. Every connection gets a clone of
+/// its own, so each counts from the start.
+#[derive(Clone, Default)]
+struct Sequential {
+    next: u8,
+}
+
+impl AddrGenerator for Sequential {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        match *constraints {
+            Constraints { family: None, ports: None, cidr: None, .. } => Ok(()),
+            _ => Err("Only requests without constraints are served".to_string()),
+        }
+    }
+
+    fn next_addr(&mut self, _constraints: &Constraints) -> SocketAddr {
+        let ip = Ipv4Addr::new(192, 0, 2, self.next);
+        self.next = self.next.wrapping_add(1);
+        (ip, 80).into()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let server = Server::bind(([127, 0, 0, 1], 0).into())
+        .generator(Sequential::default())
+        .build()
+        .await
+        .expect("Could not bind");
+    let addr = server.local_addr().expect("Not listening on TCP");
+    tokio::spawn(server.run());
+
+    let client = Client::connect(addr).await.expect("Could not connect");
+    // Carries on counting where the first request left off.
+    for n in [3, 2].iter() {
+        match client.request_addrs(*n).await {
+            Ok(addrs) => println!("{} address(es): {:?}", n, addrs),
+            Err(e) => eprintln!("Request for {} address(es) failed: {}", n, e),
+        }
+    }
+    client.close().await.expect("Could not close connection");
+}
@@ -0,0 +1,31 @@
+//! Sends several requests at once over a single connection, printing the
+//! answers in the order the requests were made.
+//!
+//! Serves them from a server started in-process, so that it runs on its own:
+//! `cargo run --example pipelining`.
+
+use futures::future;
+
+use client::Client;
+use server::Server;
+
+#[tokio::main]
+async fn main() {
+    let server = Server::bind(([127, 0, 0, 1], 0).into()).build().await.expect("Could not bind");
+    let addr = server.local_addr().expect("Not listening on TCP");
+    tokio::spawn(server.run());
+
+    let client = Client::connect(addr).await.expect("Could not connect");
+
+    // Every request is sent as soon as it's made rather than once the
+    // previous one is answered.
+    let requests = (1..=5).map(|n| client.request_addrs(n));
+    for (n, result) in (1..).zip(future::join_all(requests).await) {
+        match result {
+            Ok(addrs) => println!("{} address(es): {:?}", n, addrs),
+            Err(e) => eprintln!("Request for {} address(es) failed: {}", n, e),
+        }
+    }
+
+    client.close().await.expect("Could not close connection");
+}
@@ -0,0 +1,89 @@
+//! Drives both ends of the protocol without any I/O. The codecs only turn
+//! messages into bytes and back, so they can be fed from any transport, or
+//! none at all as here, where bytes are handed over a few at a time to show
+//! that frames may arrive in pieces.
+//!
+//! The datagram variant works the same way: an answer is split into chunks
+//! and put back together however they arrive.
+//!
+//! Run with `cargo run --example sans_io`.
+
+use std::net::SocketAddr;
+
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use addrcore::datagram::{self, Reassembler};
+use addrcore::{
+    ClientMessage, ClientToServerCodec, Constraints, Request, Response, ServerMessage,
+    ServerToClientCodec,
+};
+
+/// Decodes what `wire` holds, moving it over `step` bytes at a time as a
+/// socket might.
+fn deliver<D: Decoder>(decoder: &mut D, wire: &mut BytesMut, step: usize) -> Vec<D::Item>
+where
+    D::Error: std::fmt::Debug,
+{
+    let mut received = BytesMut::new();
+    let mut items = Vec::new();
+    while !wire.is_empty() {
+        let n = step.min(wire.len());
+        received.extend_from_slice(&wire.split_to(n));
+        while let Some(item) = decoder.decode(&mut received).expect("Invalid frame") {
+            items.push(item);
+        }
+    }
+    items
+}
+
+/// Answers a request as a server would, with made up addresses.
+fn answer(msg: ClientMessage) -> ServerMessage {
+    let n = match msg {
+        ClientMessage::Request(req) => req.num_addrs,
+        other => panic!("Unexpected {:?}", other),
+    };
+    let addrs = (0..n as u16).map(|port| SocketAddr::from(([192, 0, 2, 1], port))).collect();
+    ServerMessage::Response(Response { index: 0, addrs, ttls: None, families: None, seed: None })
+}
+
+fn main() {
+    let mut client = ClientToServerCodec::new();
+    let mut server = ServerToClientCodec::new();
+
+    // The client's bytes, as they'd be written to a socket.
+    let mut to_server = BytesMut::new();
+    for n in 1..=3 {
+        let req = Request { num_addrs: n, constraints: Constraints::default() };
+        client.encode(ClientMessage::Request(req), &mut to_server).expect("Could not encode");
+    }
+    println!("Client sends {} bytes", to_server.len());
+
+    let mut to_client = BytesMut::new();
+    for msg in deliver(&mut server, &mut to_server, 5) {
+        println!("Server received {:?}", msg);
+        server.encode(answer(msg), &mut to_client).expect("Could not encode");
+    }
+    for msg in deliver(&mut client, &mut to_client, 7) {
+        println!("Client received {:?}", msg);
+    }
+
+    // An answer too large for one datagram arrives in chunks, here last
+    // first.
+    let large = answer(ClientMessage::Request(Request {
+        num_addrs: 500,
+        constraints: Constraints::default(),
+    }));
+    let chunks = datagram::split(42, &[large]).expect("Could not split");
+    println!("Answer to datagram 42 split into {} chunks", chunks.len());
+    let mut reassembler = Reassembler::new();
+    for chunk in chunks.into_iter().rev() {
+        if let Some((id, msgs)) = reassembler.push(chunk).expect("Invalid chunk") {
+            for msg in msgs {
+                if let ServerMessage::Response(resp) = msg {
+                    println!("Answer to datagram {}: {} addresses", id, resp.addrs.len());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Subscribes to fresh addresses pushed by the server every half a second,
+//! printing the first few updates before unsubscribing.
+//!
+//! Subscribes to a server started in-process, so that it runs on its own:
+//! `cargo run --example subscription`.
+
+use futures::StreamExt;
+
+use client::Client;
+use server::Server;
+
+#[tokio::main]
+async fn main() {
+    let server = Server::bind(([127, 0, 0, 1], 0).into()).build().await.expect("Could not bind");
+    let addr = server.local_addr().expect("Not listening on TCP");
+    tokio::spawn(server.run());
+
+    let client = Client::connect(addr).await.expect("Could not connect");
+
+    let mut updates = client.subscribe(3, 500);
+    // The stream ends early if the connection drops.
+    for _ in 0..5 {
+        match updates.next().await {
+            Some(update) => println!("Update #{}: {:?}", update.index, update.addrs),
+            None => break,
+        }
+    }
+    client.unsubscribe();
+
+    client.close().await.expect("Could not close connection");
+}
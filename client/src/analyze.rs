@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use tracing::{error, warn};
+
+use addrcore::{Constraints, Request};
+
+use client::Client;
+
+use crate::report::{report, ErrorFormat, Failure};
+use crate::stats::Stats;
+
+/// Histogram rows, each summing as many bins.
+const ROWS: usize = 16;
+
+/// Width of the longest histogram bar.
+const WIDTH: usize = 40;
+
+/// Sampling options.
+#[derive(clap::Args)]
+pub struct AnalyzeArgs {
+    /// Addresses to sample.
+    #[arg(long, value_name = "N", default_value_t = 100_000)]
+    count: u32,
+    /// Addresses to request at a time.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 10_000,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    chunk: u32,
+}
+
+/// Requests a sample of addresses a chunk at a time and prints how they're
+/// distributed. Fails on the first error.
+pub async fn run(
+    client: Client,
+    args: AnalyzeArgs,
+    errors: ErrorFormat,
+    stats: &mut Stats,
+) -> ExitCode {
+    let mut addrs = Vec::with_capacity(args.count as usize);
+    let start = Instant::now();
+    let mut status = ExitCode::SUCCESS;
+    while addrs.len() < args.count as usize {
+        let num_addrs = args.chunk.min(args.count - addrs.len() as u32);
+        let req = Request { num_addrs, constraints: Constraints::default() };
+        let sent = Instant::now();
+        stats.sent();
+        match client.request(req).await {
+            // The sample would never fill up.
+            Ok(resp) if resp.addrs.is_empty() => {
+                stats.answered(sent.elapsed());
+                warn!("Empty response, stopping at {} addresses", addrs.len());
+                break;
+            }
+            Ok(resp) => {
+                stats.answered(sent.elapsed());
+                addrs.extend(resp.addrs);
+            }
+            Err(e) => {
+                stats.failed();
+                status = report(errors, Failure::of(&e), &format!("Request failed: {}", e));
+                break;
+            }
+        }
+    }
+    if let Err(e) = client.close().await {
+        error!("Could not close connection: {}", e);
+    }
+    if status == ExitCode::SUCCESS {
+        print!("{}", analysis(&addrs, start.elapsed()));
+    }
+    status
+}
+
+/// Histograms of every IPv4 octet and of the ports, each with how far it is
+/// from uniform.
+fn analysis(addrs: &[SocketAddr], elapsed: Duration) -> String {
+    let mut octets = [[0u64; 256]; 4];
+    // Ports are binned by their high byte.
+    let mut ports = [0u64; 256];
+    let mut v6 = 0;
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                for (bins, octet) in octets.iter_mut().zip(ip.octets()) {
+                    bins[octet as usize] += 1;
+                }
+            }
+            IpAddr::V6(_) => v6 += 1,
+        }
+        ports[(addr.port() >> 8) as usize] += 1;
+    }
+    let unique: HashSet<_> = addrs.iter().collect();
+    let mut text = format!(
+        "Sampled {} address(es) in {:.1}s: {} IPv4, {} IPv6, {} repeated\n",
+        addrs.len(),
+        elapsed.as_secs_f64(),
+        addrs.len() - v6,
+        v6,
+        addrs.len() - unique.len()
+    );
+    for (i, bins) in octets.iter().enumerate() {
+        text += &section(&format!("Octet {} of IPv4 addresses", i + 1), bins, 1);
+    }
+    text += &section("Ports", &ports, 256);
+    text
+}
+
+/// A histogram of 256 bins as `ROWS` bars, each covering `width` values per
+/// bin, under a line with the chi-squared statistic.
+fn section(title: &str, bins: &[u64; 256], width: usize) -> String {
+    let mut text = format!("\n{}: {}\n", title, uniformity(bins));
+    let rows: Vec<u64> = bins.chunks(bins.len() / ROWS).map(|row| row.iter().sum()).collect();
+    let max = rows.iter().copied().max().unwrap_or(0).max(1);
+    let span = bins.len() / ROWS * width;
+    for (i, &count) in rows.iter().enumerate() {
+        let label = format!("{}-{}", i * span, (i + 1) * span - 1);
+        let bar = "#".repeat((count * WIDTH as u64 / max) as usize);
+        text += &format!("  {:>11} {:<width$} {}\n", label, bar, count, width = WIDTH);
+    }
+    text
+}
+
+/// The chi-squared statistic of `bins` against a uniform distribution and
+/// what it suggests. It's turned into a z-score with the Wilson-Hilferty
+/// approximation, so anything beyond 3 standard deviations is suspect: too
+/// uneven for a random generator, or too even.
+fn uniformity(bins: &[u64]) -> String {
+    let total: u64 = bins.iter().sum();
+    let expected = total as f64 / bins.len() as f64;
+    // The approximation needs at least 5 samples per bin.
+    if expected < 5.0 {
+        return format!("too few samples, {} needed", bins.len() * 5);
+    }
+    let chi2: f64 = bins.iter().map(|&n| (n as f64 - expected).powi(2) / expected).sum();
+    let df = (bins.len() - 1) as f64;
+    let variance = 2.0 / (9.0 * df);
+    let z = ((chi2 / df).cbrt() - (1.0 - variance)) / variance.sqrt();
+    let verdict = if z > 3.0 {
+        "biased"
+    } else if z < -3.0 {
+        "suspiciously even"
+    } else {
+        "looks uniform"
+    };
+    format!("chi-squared {:.1} with {} degrees of freedom, z {:.2}, {}", chi2, df, z, verdict)
+}
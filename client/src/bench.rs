@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info};
+
+use tokio::time;
+
+use futures::future::{self, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+
+use client::Error;
+
+use crate::report::{report, ErrorFormat, Failure};
+use crate::stats::Stats;
+use crate::{Dialer, Target};
+
+/// Load test options.
+#[derive(clap::Args)]
+pub struct BenchArgs {
+    /// Number of connections to spread requests across.
+    #[arg(
+        long,
+        value_name = "C",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    connections: u32,
+    /// Requests to send per second across all connections, regardless of
+    /// how fast they're answered. Without it every connection sends a new
+    /// request as soon as the previous one is answered.
+    #[arg(long, value_name = "R", value_parser = clap::value_parser!(u32).range(1..))]
+    rps: Option<u32>,
+    /// How long to send requests for, e.g. 30s, 500ms or 2m.
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+    duration: Duration,
+    /// Addresses to request with every request.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    count: u32,
+}
+
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (n, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n = n.parse::<u64>().map_err(|_| format!("Invalid duration {}", s))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        _ => Err(format!("Invalid duration unit {}, expected ms, s or m", unit)),
+    }
+}
+
+/// Sends requests over several connections for the given duration, then
+/// waits for the outstanding ones and reports latencies, errors and the
+/// throughput achieved. Fails with the most frequent error, if any.
+pub async fn run(
+    dialer: &Dialer,
+    target: &Target,
+    args: BenchArgs,
+    error_format: ErrorFormat,
+) -> ExitCode {
+    let connects = (0..args.connections).map(|_| dialer.connect(target));
+    let clients = match future::join_all(connects).await.into_iter().collect::<Result<Vec<_>, _>>()
+    {
+        Ok(clients) => clients,
+        Err(e) => {
+            let msg = format!("Could not connect to {}: {}", target, e);
+            return report(error_format, Failure::of(&e), &msg);
+        }
+    };
+    info!("Benchmarking {} with {} connection(s)", target, clients.len());
+
+    let count = args.count;
+    let send = |i: usize| {
+        let start = Instant::now();
+        clients[i].request_addrs(count).map(move |result| (i, result, start.elapsed()))
+    };
+    let mut stats = Stats::new(dialer.metrics.clone());
+    // Kind and number of failures by error.
+    let mut errors = BTreeMap::new();
+    let mut answers = FuturesUnordered::new();
+    let mut ticks = time::interval(Duration::from_secs_f64(1.0 / args.rps.unwrap_or(1) as f64));
+    if args.rps.is_none() {
+        for i in 0..clients.len() {
+            answers.push(send(i));
+            stats.sent();
+        }
+    }
+    let mut next = 0;
+    let end = time::sleep(args.duration);
+    tokio::pin!(end);
+    loop {
+        tokio::select! {
+            _ = &mut end => break,
+            _ = ticks.tick(), if args.rps.is_some() => {
+                answers.push(send(next % clients.len()));
+                next += 1;
+                stats.sent();
+            }
+            Some((i, result, latency)) = answers.next(), if !answers.is_empty() => {
+                // A lost connection would fail every request sent on it
+                // right away, so it's left alone.
+                let usable = !matches!(result, Err(Error::Io(_)) | Err(Error::Closed));
+                record(&mut stats, &mut errors, result.map(drop), latency);
+                if args.rps.is_none() && usable {
+                    answers.push(send(i));
+                    stats.sent();
+                }
+            }
+        }
+    }
+    // Requests sent before the end still count.
+    while let Some((_, result, latency)) = answers.next().await {
+        record(&mut stats, &mut errors, result.map(drop), latency);
+    }
+    drop(answers);
+
+    match args.rps {
+        Some(rps) => println!("{} connection(s), target {} requests/s", clients.len(), rps),
+        None => println!("{} connection(s), one request at a time each", clients.len()),
+    }
+    print!("{}", stats);
+    for (error, (_, n)) in errors.iter() {
+        println!("{} x {}", n, error);
+    }
+    for client in clients {
+        if let Err(e) = client.close().await {
+            error!("Could not close connection: {}", e);
+        }
+    }
+    match errors.values().max_by_key(|(_, n)| *n) {
+        Some((failure, _)) => (*failure).into(),
+        None => ExitCode::SUCCESS,
+    }
+}
+
+fn record(
+    stats: &mut Stats,
+    errors: &mut BTreeMap<String, (Failure, u64)>,
+    result: Result<(), Error>,
+    latency: Duration,
+) {
+    match result {
+        Ok(()) => stats.answered(latency),
+        Err(e) => {
+            debug!("Request failed: {}", e);
+            stats.failed();
+            errors.entry(e.to_string()).or_insert((Failure::of(&e), 0)).1 += 1;
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Defaults for command line options, which override them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Servers to fail over between, as `<host>:<port>`.
+    pub servers: Vec<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+    /// Name of an output format.
+    pub output: Option<String>,
+    pub tls: Option<bool>,
+    pub ca: Option<String>,
+    pub domain: Option<String>,
+}
+
+/// Written by `config init`, with every option commented out.
+const TEMPLATE: &str = r#"# Defaults for the client, which command line options override.
+
+# Servers to fail over between, tried in turn until one accepts, instead of
+# host and port.
+# servers = ["127.0.0.1:6000", "127.0.0.1:6001"]
+
+# Name or IP, and port, of the server.
+# host = "127.0.0.1"
+# port = 6000
+
+# Fail requests that aren't answered within this many milliseconds, and
+# resend them up to this many times.
+# timeout_ms = 2000
+# retries = 0
+
+# How to print responses: table, plain, json, ndjson or csv.
+# output = "plain"
+
+# Connect over TLS, verifying the server with the CA certificates in a PEM
+# file, against its host or else the given name.
+# tls = true
+# ca = "/etc/ssl/certs/ca.pem"
+# domain = "localhost"
+"#;
+
+/// Where the config file is read from unless given, under
+/// `$XDG_CONFIG_HOME` or else `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("addrclient").join("config.toml"))
+}
+
+/// Reads the config file at `path`, or returns None if there's none.
+pub fn load(path: &Path) -> Result<Option<ConfigFile>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+    toml::from_str(&text).map(Some).map_err(|e| format!("Invalid {}: {}", path.display(), e))
+}
+
+/// Writes the commented template to `path`, refusing to overwrite an
+/// existing file unless `force` is set.
+pub fn init(path: &Path, force: bool) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(force)
+        .create_new(!force)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(TEMPLATE.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("addrclient-{}-{}", std::process::id(), name)).join("c.toml")
+    }
+
+    #[test]
+    fn template_sets_every_option() {
+        let commented: ConfigFile = toml::from_str(TEMPLATE).unwrap();
+        assert_eq!(commented.servers, Vec::<String>::new());
+        assert_eq!(commented.port, None);
+        // Every option is described, and valid once uncommented.
+        let uncommented: String = TEMPLATE
+            .lines()
+            .filter_map(|line| line.strip_prefix("# ").filter(|line| line.contains(" = ")))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let config: ConfigFile = toml::from_str(&uncommented).unwrap();
+        assert_eq!(config.servers, ["127.0.0.1:6000", "127.0.0.1:6001"]);
+        assert_eq!((config.host.as_deref(), config.port), (Some("127.0.0.1"), Some(6000)));
+        assert_eq!((config.timeout_ms, config.retries), (Some(2000), Some(0)));
+        assert_eq!(config.output.as_deref(), Some("plain"));
+        assert_eq!(config.tls, Some(true));
+        assert_eq!(config.ca.as_deref(), Some("/etc/ssl/certs/ca.pem"));
+        assert_eq!(config.domain.as_deref(), Some("localhost"));
+    }
+
+    #[test]
+    fn writes_the_template_once() {
+        let path = temp_path("init");
+        assert!(load(&path).unwrap().is_none());
+        init(&path, false).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), TEMPLATE);
+        fs::write(&path, "port = 7000\n").unwrap();
+        assert_eq!(init(&path, false).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(load(&path).unwrap().unwrap().port, Some(7000));
+        init(&path, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), TEMPLATE);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        let path = temp_path("invalid");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "prot = 7000\n").unwrap();
+        assert!(load(&path).unwrap_err().starts_with("Invalid"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
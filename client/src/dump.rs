@@ -0,0 +1,73 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use tracing::error;
+
+use addrcore::{ClientMessage, ServerMessage, WireTap};
+
+/// Bytes of a frame dumped at most, so huge responses don't flood the file.
+const MAX_DUMP: usize = 4096;
+
+/// Longest summary of a decoded message, in characters.
+const MAX_SUMMARY: usize = 200;
+
+/// Writes every frame to a file: a line with the time, the direction (`>>`
+/// sent, `<<` received), the decoded message and the size of the frame,
+/// then a hexdump of its bytes.
+pub struct WireDump {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl WireDump {
+    pub fn new(file: File) -> Self {
+        WireDump { file: Mutex::new(BufWriter::new(file)) }
+    }
+
+    fn dump(&self, arrow: &str, frame: &[u8], msg: &dyn Debug) {
+        let mut summary = format!("{:?}", msg);
+        if let Some((i, _)) = summary.char_indices().nth(MAX_SUMMARY) {
+            summary.truncate(i);
+            summary.push_str("...");
+        }
+        let now = humantime::format_rfc3339_millis(SystemTime::now());
+        let mut text = format!("{} {} {} ({} bytes)\n", now, arrow, summary, frame.len());
+        text += &hexdump(&frame[..frame.len().min(MAX_DUMP)]);
+        if frame.len() > MAX_DUMP {
+            text += &format!("... {} more bytes\n", frame.len() - MAX_DUMP);
+        }
+        text.push('\n');
+        let mut file = self.file.lock().unwrap();
+        // Flushed every frame so the dump is complete if the client dies.
+        if let Err(e) = file.write_all(text.as_bytes()).and_then(|_| file.flush()) {
+            error!("Could not dump frame: {}", e);
+        }
+    }
+}
+
+impl WireTap for WireDump {
+    fn sent(&self, frame: &[u8], msg: &ClientMessage) {
+        self.dump(">>", frame, msg);
+    }
+
+    fn received(&self, frame: &[u8], msg: &ServerMessage) {
+        self.dump("<<", frame, msg);
+    }
+}
+
+/// 16 bytes a line: the offset, the bytes in hex and as ASCII, with dots
+/// for unprintable ones.
+fn hexdump(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for (n, line) in bytes.chunks(16).enumerate() {
+        let hex: Vec<_> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        text += &format!("  {:08x}  {:<47}  |{}|\n", n * 16, hex.join(" "), ascii);
+    }
+    text
+}
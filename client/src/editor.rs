@@ -0,0 +1,153 @@
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::sync::mpsc as std_mpsc;
+use std::thread::{self, JoinHandle};
+
+use tracing::warn;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+
+use tokio::sync::mpsc;
+
+pub enum Input {
+    Line(String),
+    /// Ctrl-C.
+    Interrupted,
+    /// Ctrl-D, or the end of piped input.
+    Eof,
+    Failed(io::Error),
+}
+
+/// Lines read with history and line editing by a dedicated thread, or just
+/// read as they are if stdin isn't a terminal, e.g. when input is piped.
+///
+/// The line editor blocks its thread until a line is entered, so the thread
+/// only prompts when asked for a line. Shutting down while it isn't waiting
+/// for input lets the thread exit right away, saving the history.
+pub struct LineEditor {
+    wanted: std_mpsc::Sender<()>,
+    lines: mpsc::Receiver<Input>,
+    /// Whether a line was asked for but not yet received.
+    prompting: bool,
+    interactive: bool,
+    thread: JoinHandle<()>,
+}
+
+/// Where the thread reads lines from.
+enum Source {
+    /// Boxed as it's far larger than `Piped`.
+    Editor { editor: Box<DefaultEditor>, history: Option<PathBuf> },
+    /// Neither prompts nor keeps history.
+    Piped,
+}
+
+impl Source {
+    fn read(&mut self) -> Input {
+        let editor = match self {
+            Source::Editor { editor, .. } => editor,
+            Source::Piped => {
+                let mut line = String::new();
+                return match io::stdin().read_line(&mut line) {
+                    Ok(0) => Input::Eof,
+                    Ok(_) => Input::Line(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+                    Err(e) => Input::Failed(e),
+                };
+            }
+        };
+        match editor.readline("> ") {
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                Input::Line(line)
+            }
+            Err(ReadlineError::Interrupted) => Input::Interrupted,
+            Err(ReadlineError::Eof) => Input::Eof,
+            Err(e) => Input::Failed(io::Error::other(e.to_string())),
+        }
+    }
+
+    fn save_history(self) {
+        if let Source::Editor { mut editor, history: Some(path) } = self {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            if let Err(e) = editor.save_history(&path) {
+                warn!("Could not save history to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl LineEditor {
+    pub fn spawn() -> io::Result<LineEditor> {
+        let interactive = io::stdin().is_terminal();
+        let mut source = match interactive {
+            true => {
+                let mut editor = DefaultEditor::new().map_err(|e| io::Error::other(e.to_string()))?;
+                let history = history_path();
+                if let Some(ref path) = history {
+                    // There is none on the first run.
+                    let _ = editor.load_history(path);
+                }
+                Source::Editor { editor: Box::new(editor), history }
+            }
+            false => Source::Piped,
+        };
+        let (wanted, wanted_rx) = std_mpsc::channel();
+        let (tx, lines) = mpsc::channel(1);
+        let thread = thread::spawn(move || {
+            while wanted_rx.recv().is_ok() {
+                if tx.blocking_send(source.read()).is_err() {
+                    break;
+                }
+            }
+            source.save_history();
+        });
+        Ok(LineEditor { wanted, lines, prompting: false, interactive, thread })
+    }
+
+    /// Whether lines are typed at a terminal rather than piped in.
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    /// Prompts for the next line, unless a prompt is still pending from a
+    /// cancelled call, and waits for it.
+    pub async fn next_line(&mut self) -> Input {
+        if !self.prompting {
+            let _ = self.wanted.send(());
+            self.prompting = true;
+        }
+        let input = self.lines.recv().await.unwrap_or(Input::Eof);
+        self.prompting = false;
+        input
+    }
+
+    /// Stops prompting and waits for the thread to exit, which takes a line
+    /// of input if it's still prompting. A thread reading piped input has
+    /// nothing to save so it's left to die with the process instead.
+    pub fn shutdown(self) {
+        let LineEditor { wanted, lines, prompting, interactive, thread } = self;
+        if !interactive {
+            return;
+        }
+        if prompting {
+            println!("Press Enter to exit");
+        }
+        drop(wanted);
+        drop(lines);
+        let _ = thread.join();
+    }
+}
+
+/// Where history is kept across runs, under `$XDG_CONFIG_HOME` or else
+/// `~/.config`.
+fn history_path() -> Option<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("maidsafe-test-client").join("history"))
+}
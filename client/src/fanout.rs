@@ -0,0 +1,220 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::process::ExitCode;
+use std::time::Instant;
+
+use tracing::{error, info};
+
+use futures::future;
+
+use addrcore::{Constraints, Request, Response};
+
+use crate::report::{report, Failure};
+use crate::stats::Stats;
+use crate::{Dialer, Output, Target};
+
+/// Fan-out options.
+#[derive(clap::Args)]
+pub struct FanoutArgs {
+    /// Addresses to request from every server.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    count: u32,
+}
+
+/// Connects to every server of `target` at once and sends each the same
+/// request, then prints the addresses of all the responses without
+/// duplicates, in the order of the servers, or merged in order if every
+/// response came sorted, as with --sort. How long each server took to
+/// answer, or why it didn't, goes to stderr. Fails with the first server's
+/// failure, if any.
+pub async fn run(
+    dialer: &Dialer,
+    target: &Target,
+    args: FanoutArgs,
+    output: &Output,
+    stats: &mut Stats,
+) -> ExitCode {
+    let targets = match *target {
+        Target::Failover { ref servers, .. } => servers
+            .iter()
+            .map(|(host, port)| Target::Tcp { host: host.clone(), port: *port })
+            .collect(),
+        ref target => vec![target.clone()],
+    };
+    let req = Request { num_addrs: args.count, constraints: Constraints::default() };
+    let asks = targets.iter().map(|target| async move {
+        let client = dialer.connect(target).await?;
+        let start = Instant::now();
+        let result = client.request(req).await;
+        let latency = start.elapsed();
+        if let Err(e) = client.close().await {
+            error!("Could not close connection to {}: {}", target, e);
+        }
+        result.map(|resp| (resp, latency))
+    });
+    let start = Instant::now();
+    let answers = future::join_all(asks).await;
+    let elapsed = start.elapsed();
+    info!("Fanned out to {} server(s) in {:?}", targets.len(), elapsed);
+
+    // Every server is counted as sent to, including those never reached.
+    for _ in 0..targets.len() {
+        stats.sent();
+    }
+    let mut status = None;
+    let mut responses = Vec::new();
+    let mut total = 0;
+    for (target, answer) in targets.iter().zip(answers) {
+        let (resp, latency) = match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                stats.failed();
+                let msg = format!("{} failed: {}", target, e);
+                status.get_or_insert(report(output.errors, Failure::of(&e), &msg));
+                continue;
+            }
+        };
+        stats.answered(latency);
+        let ms = latency.as_secs_f64() * 1000.0;
+        eprintln!("{}: {} address(es) in {:.3} ms", target, resp.addrs.len(), ms);
+        total += resp.addrs.len();
+        responses.push(resp);
+    }
+    let sorted = responses.iter().all(|resp| resp.addrs.windows(2).all(|w| w[0] <= w[1]));
+    let merged = match sorted {
+        true => merge_sorted(&responses),
+        false => merge(&responses),
+    };
+    eprintln!(
+        "{} unique of {} address(es) from {}/{} server(s)",
+        merged.addrs.len(),
+        total,
+        responses.len(),
+        targets.len()
+    );
+    if !responses.is_empty() {
+        *dialer.active.lock().unwrap() = target.to_string();
+        output.begin(true);
+        output.print(&merged, &output.meta(false, None, Some(elapsed))).await;
+    }
+    status.unwrap_or(ExitCode::SUCCESS)
+}
+
+/// Merges responses in turn, keeping the first of every address.
+fn merge(responses: &[Response]) -> Response {
+    let mut seen = HashSet::new();
+    let mut merged = Merged::new();
+    for resp in responses {
+        for (i, addr) in resp.addrs.iter().enumerate() {
+            if seen.insert(*addr) {
+                merged.push(resp, i);
+            }
+        }
+    }
+    merged.0
+}
+
+/// Merges sorted responses into one sorted response with a k-way merge,
+/// which drops duplicates by comparing with the last address taken
+/// rather than remembering every one.
+fn merge_sorted(responses: &[Response]) -> Response {
+    let mut heads: BinaryHeap<_> = responses
+        .iter()
+        .enumerate()
+        .filter_map(|(n, resp)| resp.addrs.first().map(|addr| Reverse((*addr, n, 0))))
+        .collect();
+    let mut merged = Merged::new();
+    while let Some(Reverse((addr, n, i))) = heads.pop() {
+        let resp = &responses[n];
+        if merged.0.addrs.last() != Some(&addr) {
+            merged.push(resp, i);
+        }
+        if let Some(next) = resp.addrs.get(i + 1) {
+            heads.push(Reverse((*next, n, i + 1)));
+        }
+    }
+    merged.0
+}
+
+/// A response being merged from others.
+struct Merged(Response);
+
+impl Merged {
+    fn new() -> Self {
+        Merged(Response {
+            index: 0,
+            addrs: Vec::new(),
+            ttls: Some(Vec::new()),
+            families: None,
+            seed: None,
+        })
+    }
+
+    /// Takes the `i`th address of `resp`.
+    fn push(&mut self, resp: &Response, i: usize) {
+        self.0.addrs.push(resp.addrs[i]);
+        // TTLs are only kept if every address came with one.
+        self.0.ttls = match (self.0.ttls.take(), &resp.ttls) {
+            (Some(mut ttls), Some(theirs)) => {
+                ttls.push(theirs[i]);
+                Some(ttls)
+            }
+            _ => None,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(addrs: &[&str], ttls: Option<Vec<u32>>) -> Response {
+        Response {
+            index: 0,
+            addrs: addrs.iter().map(|addr| addr.parse().unwrap()).collect(),
+            ttls,
+            families: None,
+            seed: None,
+        }
+    }
+
+    fn addrs(resp: &Response) -> Vec<String> {
+        resp.addrs.iter().map(|addr| addr.to_string()).collect()
+    }
+
+    #[test]
+    fn merges_in_the_order_of_the_servers() {
+        let responses = [
+            response(&["10.0.0.2:1", "10.0.0.1:1"], Some(vec![2, 1])),
+            response(&["10.0.0.1:1", "10.0.0.3:1"], Some(vec![5, 3])),
+        ];
+        let merged = merge(&responses);
+        assert_eq!(addrs(&merged), ["10.0.0.2:1", "10.0.0.1:1", "10.0.0.3:1"]);
+        assert_eq!(merged.ttls, Some(vec![2, 1, 3]));
+    }
+
+    #[test]
+    fn merges_sorted_responses_in_order() {
+        let responses = [
+            response(&["10.0.0.1:1", "10.0.0.4:1"], Some(vec![1, 4])),
+            response(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.5:1"], Some(vec![9, 2, 5])),
+            response(&[], None),
+            response(&["10.0.0.3:1", "10.0.0.4:1"], Some(vec![3, 9])),
+        ];
+        let merged = merge_sorted(&responses);
+        let want = ["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1", "10.0.0.4:1", "10.0.0.5:1"];
+        assert_eq!(addrs(&merged), want);
+        // The first server's copy of a duplicate is kept.
+        assert_eq!(merged.ttls, Some(vec![1, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn drops_ttls_unless_every_address_has_one() {
+        let responses = [
+            response(&["10.0.0.1:1"], Some(vec![1])),
+            response(&["10.0.0.2:1"], None),
+        ];
+        assert_eq!(merge(&responses).ttls, None);
+        assert_eq!(merge_sorted(&responses).ttls, None);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use addrcore::{FamilyCounts, Response};
+
+use crate::Hook;
+
+/// Hook tidying up the addresses of every response: filters them, then
+/// sorts them, then cuts them short, keeping any TTLs with their address and
+/// any family counts up to date.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Drops repeated addresses, keeping the first of each.
+    pub unique: bool,
+    /// Sorts addresses, IPv4 before IPv6, then by IP and port.
+    pub sort: bool,
+    /// Drops addresses that aren't globally routable: private, loopback,
+    /// link-local and unspecified ones.
+    pub exclude_private: bool,
+    /// Keeps at most this many addresses of every response.
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    /// Whether the filter leaves responses as they are.
+    pub fn is_noop(&self) -> bool {
+        !self.unique && !self.sort && !self.exclude_private && self.limit.is_none()
+    }
+}
+
+impl Hook for Filter {
+    fn on_response(&self, resp: &mut Response) {
+        let mut keep: Vec<usize> = (0..resp.addrs.len()).collect();
+        if self.exclude_private {
+            keep.retain(|&i| !is_private(resp.addrs[i].ip()));
+        }
+        if self.unique {
+            let mut seen = HashSet::with_capacity(keep.len());
+            keep.retain(|&i| seen.insert(resp.addrs[i]));
+        }
+        if self.sort {
+            keep.sort_by_key(|&i| resp.addrs[i]);
+        }
+        if let Some(limit) = self.limit {
+            keep.truncate(limit);
+        }
+        resp.ttls = resp.ttls.take().map(|ttls| keep.iter().map(|&i| ttls[i]).collect());
+        resp.addrs = keep.iter().map(|&i| resp.addrs[i]).collect();
+        resp.families = resp.families.map(|_| FamilyCounts::of(&resp.addrs));
+    }
+}
+
+/// Whether `ip` isn't globally routable: private, loopback, link-local or
+/// unspecified.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+            let local = first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80;
+            local || ip.is_loopback() || ip.is_unspecified()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn response(addrs: &[&str]) -> Response {
+        let addrs: Vec<SocketAddr> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+        let ttls = Some((0..addrs.len() as u32).collect());
+        Response { index: 0, addrs, ttls, families: None, seed: None }
+    }
+
+    #[test]
+    fn filters_sorts_and_limits() {
+        let filter = Filter { unique: true, sort: true, exclude_private: true, limit: Some(2) };
+        let mut resp = response(&[
+            "8.8.8.8:53",
+            "10.0.0.1:80",
+            "[2001:db8::1]:80",
+            "1.1.1.1:53",
+            "8.8.8.8:53",
+        ]);
+        filter.on_response(&mut resp);
+        assert_eq!(resp.addrs, response(&["1.1.1.1:53", "8.8.8.8:53"]).addrs);
+        // TTLs stay with their addresses.
+        assert_eq!(resp.ttls, Some(vec![3, 0]));
+    }
+
+    #[test]
+    fn noop() {
+        let filter = Filter::default();
+        assert!(filter.is_noop());
+        let mut resp = response(&["10.0.0.2:80", "10.0.0.1:80", "10.0.0.2:80"]);
+        let before = resp.clone();
+        filter.on_response(&mut resp);
+        assert_eq!(resp, before);
+    }
+
+    #[test]
+    fn private_addresses() {
+        let private = [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+        ];
+        for ip in private.iter() {
+            assert!(is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["8.8.8.8", "172.32.0.1", "2001:4860::8888", "fec0::1"].iter() {
+            assert!(!is_private(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use addrcore::Response;
+
+use client::is_private;
+
+/// How responses are printed.
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// One address per line, under the index of batched responses.
+    Plain,
+    /// A JSON object per response, including the request's metadata.
+    Json,
+    /// A JSON object per address, one per line.
+    Ndjson,
+    /// A row per address.
+    Csv,
+    /// An aligned table of addresses with their family and whether they're
+    /// private, followed by a summary. The default on a terminal.
+    Table,
+}
+
+pub const CSV_HEADER: &str = "server,update,index,count,latency_ms,ip,port,ttl\n";
+
+/// What the machine-readable formats print alongside a response's addresses.
+pub struct Meta {
+    /// Address or socket path of the server.
+    pub server: String,
+    /// Whether the response is a subscription update.
+    pub update: bool,
+    /// Index of the response within its batch or transaction, or of the
+    /// update within its subscription.
+    pub index: Option<u32>,
+    /// Time from sending the request to receiving the whole response, which
+    /// updates don't have.
+    pub latency: Option<Duration>,
+}
+
+impl Meta {
+    fn latency_ms(&self) -> Option<f64> {
+        self.latency.map(|latency| latency.as_secs_f64() * 1000.0)
+    }
+}
+
+impl Format {
+    /// Extension of the files holding output in this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Plain | Format::Table => "txt",
+            Format::Json => "json",
+            Format::Ndjson => "ndjson",
+            Format::Csv => "csv",
+        }
+    }
+
+    /// Header of a run saved to a file, telling when it started and which
+    /// server answered it.
+    pub fn run_header(self, server: &str, started: SystemTime) -> String {
+        let started = humantime::format_rfc3339_seconds(started).to_string();
+        match self {
+            Format::Plain | Format::Table => format!("# {} {}\n", started, server),
+            Format::Json => format!("{:#}\n", run(server, &started)),
+            Format::Ndjson => format!("{}\n", run(server, &started)),
+            Format::Csv => format!("# {} {}\n{}", started, server, CSV_HEADER),
+        }
+    }
+
+    pub fn format(self, resp: &Response, meta: &Meta) -> String {
+        self.render(resp, meta, false)
+    }
+
+    /// Formats a response, with ANSI colors if `color` is set and the format
+    /// has any.
+    pub fn render(self, resp: &Response, meta: &Meta, color: bool) -> String {
+        match self {
+            Format::Plain => plain(resp, meta),
+            Format::Table => table(resp, meta, color),
+            Format::Json => {
+                let addrs = (0..resp.addrs.len()).map(|i| addr(resp, i)).collect::<Vec<_>>();
+                let mut obj = header(resp, meta);
+                obj["addrs"] = Value::Array(addrs);
+                format!("{:#}\n", obj)
+            }
+            Format::Ndjson => {
+                let mut text = String::new();
+                for i in 0..resp.addrs.len() {
+                    let mut obj = header(resp, meta);
+                    obj["addr"] = addr(resp, i);
+                    text += &format!("{}\n", obj);
+                }
+                text
+            }
+            Format::Csv => {
+                let latency = meta.latency_ms().map(|ms| ms.to_string()).unwrap_or_default();
+                let index = meta.index.map(|i| i.to_string()).unwrap_or_default();
+                let mut text = String::new();
+                for (i, addr) in resp.addrs.iter().enumerate() {
+                    let ttl = ttl(resp, i).map(|ttl| ttl.to_string()).unwrap_or_default();
+                    text += &format!(
+                        "{},{},{},{},{},{},{},{}\n",
+                        meta.server,
+                        meta.update,
+                        index,
+                        resp.addrs.len(),
+                        latency,
+                        addr.ip(),
+                        addr.port(),
+                        ttl
+                    );
+                }
+                text
+            }
+        }
+    }
+}
+
+fn plain(resp: &Response, meta: &Meta) -> String {
+    let mut text = match (meta.update, meta.index) {
+        (true, Some(index)) => format!("update #{}\n", index),
+        (false, Some(index)) => format!("#{}\n", index),
+        _ => String::new(),
+    };
+    for (i, addr) in resp.addrs.iter().enumerate() {
+        match ttl(resp, i) {
+            Some(ttl) => text += &format!("{} (ttl {}s)\n", addr, ttl),
+            None => text += &format!("{}\n", addr),
+        }
+    }
+    text
+}
+
+fn table(resp: &Response, meta: &Meta, color: bool) -> String {
+    let paint = |code: &str, text: String| match color {
+        true => format!("\x1b[{}m{}\x1b[0m", code, text),
+        false => text,
+    };
+    let mut text = match (meta.update, meta.index) {
+        (true, Some(index)) => paint("1", format!("update #{}", index)) + "\n",
+        (false, Some(index)) => paint("1", format!("#{}", index)) + "\n",
+        _ => String::new(),
+    };
+    let ips: Vec<_> = resp.addrs.iter().map(|addr| addr.ip().to_string()).collect();
+    let width = ips.iter().map(String::len).max().unwrap_or(0).max("IP".len());
+    let mut header =
+        format!("{:<w$}  {:>5}  {:<6}  {:<7}", "IP", "PORT", "FAMILY", "SCOPE", w = width);
+    if resp.ttls.is_some() {
+        header += "  TTL";
+    }
+    text += &paint("1;4", header);
+    text += "\n";
+    let (mut v6, mut private) = (0, 0);
+    for (i, addr) in resp.addrs.iter().enumerate() {
+        let (family, family_color) = match addr.is_ipv6() {
+            true => ("IPv6", "35"),
+            false => ("IPv4", "36"),
+        };
+        let (scope, scope_color) = match is_private(addr.ip()) {
+            true => ("private", "33"),
+            false => ("public", "32"),
+        };
+        v6 += addr.is_ipv6() as usize;
+        private += is_private(addr.ip()) as usize;
+        text += &format!(
+            "{}  {:>5}  {}  {}",
+            paint(family_color, format!("{:<w$}", ips[i], w = width)),
+            addr.port(),
+            paint(family_color, format!("{:<6}", family)),
+            paint(scope_color, format!("{:<7}", scope)),
+        );
+        if let Some(ttl) = ttl(resp, i) {
+            text += &format!("  {}s", ttl);
+        }
+        text += "\n";
+    }
+    let n = resp.addrs.len();
+    let mut summary = format!(
+        "{} address{}: {} IPv4, {} IPv6, {} private",
+        n,
+        if n == 1 { "" } else { "es" },
+        n - v6,
+        v6,
+        private
+    );
+    if let Some(ms) = meta.latency_ms() {
+        summary += &format!(", in {:.3} ms", ms);
+    }
+    if let Some(seed) = resp.seed {
+        summary += &format!(", seed {}", seed);
+    }
+    text += &paint("2", summary);
+    text += "\n";
+    text
+}
+
+fn run(server: &str, started: &str) -> Value {
+    json!({ "run": { "started": started, "server": server } })
+}
+
+fn header(resp: &Response, meta: &Meta) -> Value {
+    json!({
+        "server": meta.server,
+        "update": meta.update,
+        "index": meta.index,
+        "count": resp.addrs.len(),
+        "families": resp.families.map(|families| json!({ "v4": families.v4, "v6": families.v6 })),
+        "seed": resp.seed,
+        "latency_ms": meta.latency_ms(),
+    })
+}
+
+fn addr(resp: &Response, i: usize) -> Value {
+    json!({
+        "ip": resp.addrs[i].ip().to_string(),
+        "port": resp.addrs[i].port(),
+        "ttl": ttl(resp, i),
+    })
+}
+
+fn ttl(resp: &Response, i: usize) -> Option<u32> {
+    resp.ttls.as_ref().map(|ttls| ttls[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use addrcore::FamilyCounts;
+
+    fn response() -> Response {
+        let addrs = vec!["93.184.216.34:443".parse().unwrap(), "[fd00::1]:80".parse().unwrap()];
+        let families = Some(FamilyCounts { v4: 1, v6: 1 });
+        Response { index: 0, addrs, ttls: Some(vec![60, 30]), families, seed: Some(7) }
+    }
+
+    fn meta(index: Option<u32>) -> Meta {
+        let latency = Some(Duration::from_micros(1500));
+        Meta { server: "127.0.0.1:6000".to_string(), update: false, index, latency }
+    }
+
+    #[test]
+    fn prints_plain_lines() {
+        let text = Format::Plain.format(&response(), &meta(Some(2)));
+        assert_eq!(text, "#2\n93.184.216.34:443 (ttl 60s)\n[fd00::1]:80 (ttl 30s)\n");
+        let update = Meta { update: true, ..meta(Some(3)) };
+        assert!(Format::Plain.format(&response(), &update).starts_with("update #3\n"));
+    }
+
+    #[test]
+    fn prints_json_with_metadata() {
+        let text = Format::Json.format(&response(), &meta(None));
+        let obj: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(obj["server"], "127.0.0.1:6000");
+        assert_eq!(obj["count"], 2);
+        assert_eq!(obj["latency_ms"], 1.5);
+        assert_eq!(obj["families"], json!({ "v4": 1, "v6": 1 }));
+        assert_eq!(obj["seed"], 7);
+        assert_eq!(obj["addrs"][1], json!({ "ip": "fd00::1", "port": 80, "ttl": 30 }));
+
+        let text = Format::Ndjson.format(&response(), &meta(None));
+        let lines: Vec<Value> =
+            text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["addr"]["ip"], "93.184.216.34");
+        assert_eq!(lines[1]["count"], 2);
+    }
+
+    #[test]
+    fn prints_a_csv_row_per_address() {
+        let text = Format::Csv.format(&response(), &meta(Some(1)));
+        let want = "127.0.0.1:6000,false,1,2,1.5,93.184.216.34,443,60\n\
+                    127.0.0.1:6000,false,1,2,1.5,fd00::1,80,30\n";
+        assert_eq!(text, want);
+        assert_eq!(CSV_HEADER.split(',').count(), 8);
+    }
+
+    #[test]
+    fn summarizes_tables() {
+        let text = Format::Table.format(&response(), &meta(None));
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], "IP              PORT  FAMILY  SCOPE    TTL");
+        assert_eq!(lines[1], "93.184.216.34    443  IPv4    public   60s");
+        assert_eq!(lines[2], "fd00::1           80  IPv6    private  30s");
+        let summary = "2 addresses: 1 IPv4, 1 IPv6, 1 private, in 1.500 ms, seed 7";
+        assert_eq!(lines[3], summary);
+        let colored = Format::Table.render(&response(), &meta(None), true);
+        assert!(colored.contains("\x1b[32mpublic \x1b[0m"));
+    }
+
+    #[test]
+    fn heads_saved_runs() {
+        let started = SystemTime::UNIX_EPOCH;
+        let header = Format::Csv.run_header("s", started);
+        assert_eq!(header, format!("# 1970-01-01T00:00:00Z s\n{}", CSV_HEADER));
+        let run: Value = serde_json::from_str(&Format::Ndjson.run_header("s", started)).unwrap();
+        assert_eq!(run["run"]["server"], "s");
+        assert_eq!(Format::Table.extension(), "txt");
+    }
+}
@@ -0,0 +1,1213 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_util::codec::{Decoder, Framed};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::BoxFuture;
+use futures::{stream, FutureExt, Sink, SinkExt, Stream, StreamExt};
+
+use rand::Rng;
+
+mod filter;
+mod limit;
+mod pool;
+mod schema;
+mod sockopt;
+mod udp;
+
+use crate::limit::TokenBucket;
+use crate::schema::Schema;
+
+pub use crate::filter::{is_private, Filter};
+pub use crate::pool::{ClientPool, PoolConfig};
+pub use crate::sockopt::SocketOptions;
+pub use crate::udp::UdpClient;
+
+use addrcore::{
+    ClientMessage, ClientToServerCodec, Constraints, DecodePolicy, ErrorCode, ErrorResponse,
+    QuotaWarning, Request, Response, ServerInfo, ServerMessage, WireFormat, WireTap,
+    SUBSCRIPTION_INDEX,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The connection failed.
+    Io(io::Error),
+    /// The server couldn't serve the request.
+    Server(ErrorResponse),
+    /// The connection was closed before the request was answered.
+    Closed,
+    /// No response arrived in time, after any retries.
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Server(ErrorResponse {
+                code: ErrorCode::RateLimited { retry_after_ms },
+                message,
+                ..
+            }) => write!(f, "Server error: {}, retry in {} ms", message, retry_after_ms),
+            Error::Server(e) => write!(f, "Server error: {}", e.message),
+            Error::Closed => write!(f, "Connection closed"),
+            Error::Timeout => write!(f, "Request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Post-processing of every response and subscription update before it's
+/// handed to the caller, e.g. to enrich or tag addresses. Responses split up
+/// by flow control are only seen once reassembled.
+pub trait Hook: Send + Sync {
+    fn on_response(&self, resp: &mut Response);
+
+    /// Called with every piece of a response as it arrives, before it's
+    /// reassembled, e.g. to show progress: each chunk flow control splits it
+    /// into, or the whole response if it isn't split. Updates aren't
+    /// included.
+    fn on_progress(&self, _piece: &Response) {}
+
+    /// Called with every response as the server sent it, once reassembled
+    /// but before any hook changes it, along with how many addresses were
+    /// requested if that's known, e.g. to check it. Updates aren't included.
+    fn on_received(&self, _requested: Option<u32>, _resp: &Response) {}
+
+    /// Called whenever the server warns that most of a rate limit is used
+    /// up, e.g. to slow down before requests are rejected.
+    fn on_quota_warning(&self, _warning: &QuotaWarning) {}
+}
+
+impl<F> Hook for F
+where
+    F: Fn(&mut Response) + Send + Sync,
+{
+    fn on_response(&self, resp: &mut Response) {
+        self(resp)
+    }
+}
+
+/// Connection options.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Pre-shared key to authenticate frames with, which the server must
+    /// share.
+    pub hmac_key: Option<Vec<u8>>,
+    /// Token to authenticate with right after connecting, which servers
+    /// requiring authentication serve nothing without.
+    pub token: Option<String>,
+    /// Enables flow control with this many credits, which are granted back
+    /// as responses are consumed.
+    pub credits: Option<u32>,
+    /// What to do with frames from the server that can't be decoded.
+    pub policy: DecodePolicy,
+    /// How frames are encoded over stream connections, which the server must
+    /// match. JSON frames are checked against `schema/server-message.json`.
+    pub wire_format: WireFormat,
+    /// How long to wait for the answer to a request, batch or transaction
+    /// before failing it with `Error::Timeout`. Waits forever if unset.
+    pub timeout: Option<Duration>,
+    /// How many times to resend a request that timed out. A late answer to
+    /// an earlier attempt is discarded.
+    pub retries: u32,
+    /// Reconnects with this backoff if the connection drops, sending every
+    /// unanswered request and any subscription again. Only clients that know
+    /// how to connect, rather than being handed a stream, can reconnect.
+    pub reconnect: Option<Backoff>,
+    /// Requests, batches and transactions to send per second at most,
+    /// overriding the limit the server advertises. Requests over the limit
+    /// are held back rather than failed.
+    pub rate_limit: Option<u32>,
+    /// Run on every response in order.
+    pub hooks: Vec<Arc<dyn Hook>>,
+    /// Sees every frame sent and received over a stream connection.
+    pub wire_tap: Option<Arc<dyn WireTap>>,
+    /// Set on the socket of TCP connections made by `connect_with`, and on
+    /// every reconnect.
+    pub socket: SocketOptions,
+}
+
+/// Delays between attempts to reconnect.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Delay before the first attempt, doubled after every failed one.
+    pub initial: Duration,
+    /// Upper bound of the delay.
+    pub max: Duration,
+    /// Attempts before giving up, or unlimited if unset.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the attempt following `failures` failed ones, up to
+    /// half of which is random so that clients dropped at the same time
+    /// don't all reconnect at the same time.
+    fn delay(&self, failures: u32) -> Duration {
+        let delay = self.initial.saturating_mul(2u32.saturating_pow(failures)).min(self.max);
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0, 0.5))
+    }
+}
+
+/// Connections made by this process, numbering the span of each.
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Makes a new transport to the server.
+type Connector<S> = Box<dyn Fn() -> BoxFuture<'static, io::Result<S>> + Send + Sync>;
+
+/// Where to deliver the response(s) to a sent message. The server answers
+/// messages in order, so these are queued in the order they're sent.
+enum Pending {
+    One(oneshot::Sender<Result<Response, Error>>),
+    Batch {
+        responses: Vec<Response>,
+        remaining: usize,
+        tx: oneshot::Sender<Result<Vec<Response>, Error>>,
+        /// Whether an error answers the whole batch, as for transactions,
+        /// rather than just one of its counts.
+        atomic: bool,
+    },
+    /// Responses to skip, left over from a failed batch.
+    Discard(usize),
+}
+
+impl Pending {
+    /// Whether nobody waits for the answer anymore, e.g. after a timeout.
+    fn is_abandoned(&self) -> bool {
+        match self {
+            Pending::One(tx) => tx.is_canceled(),
+            Pending::Batch { tx, .. } => tx.is_canceled(),
+            Pending::Discard(_) => true,
+        }
+    }
+}
+
+struct Command {
+    msg: ClientMessage,
+    pending: Option<Pending>,
+}
+
+/// A sent message waiting for its answer, kept to be sent again after
+/// reconnecting.
+struct InFlight {
+    msg: ClientMessage,
+    pending: Pending,
+}
+
+/// How many addresses the response with `index` to `msg` should hold.
+fn requested(msg: &ClientMessage, index: u32) -> Option<u32> {
+    match msg {
+        ClientMessage::Request(req) => Some(req.num_addrs),
+        ClientMessage::Batch(counts) => counts.get(index as usize).copied(),
+        ClientMessage::Transaction(reqs) => reqs.get(index as usize).map(|req| req.num_addrs),
+        _ => None,
+    }
+}
+
+/// Hands a response or error to whoever is waiting for it.
+fn complete(in_flight: &mut VecDeque<InFlight>, result: Result<Response, Error>) {
+    let InFlight { msg, pending } = match in_flight.pop_front() {
+        Some(next) => next,
+        None => {
+            warn!("Unexpected response: {:?}", result);
+            return;
+        }
+    };
+    match pending {
+        Pending::One(tx) => {
+            let _ = tx.send(result);
+        }
+        Pending::Batch { mut responses, remaining, tx, atomic } => match result {
+            Ok(resp) => {
+                responses.push(resp);
+                if remaining > 1 {
+                    let remaining = remaining - 1;
+                    let pending = Pending::Batch { responses, remaining, tx, atomic };
+                    in_flight.push_front(InFlight { msg, pending });
+                } else {
+                    let _ = tx.send(Ok(responses));
+                }
+            }
+            Err(e) => {
+                // A throttled batch is answered with one error in all.
+                let whole = atomic || is_rate_limited(&e);
+                let _ = tx.send(Err(e));
+                if !whole && remaining > 1 {
+                    let pending = Pending::Discard(remaining - 1);
+                    in_flight.push_front(InFlight { msg, pending });
+                }
+            }
+        },
+        Pending::Discard(remaining) => {
+            if remaining > 1 {
+                let pending = Pending::Discard(remaining - 1);
+                in_flight.push_front(InFlight { msg, pending });
+            }
+        }
+    }
+}
+
+fn is_rate_limited(e: &Error) -> bool {
+    matches!(e, Error::Server(ErrorResponse { code: ErrorCode::RateLimited { .. }, .. }))
+}
+
+/// Drops what nobody waits for anymore and resets partly answered batches,
+/// returning the messages to send again on a new connection.
+fn replay(in_flight: &mut VecDeque<InFlight>) -> Vec<ClientMessage> {
+    in_flight.retain(|entry| !entry.pending.is_abandoned());
+    in_flight
+        .iter_mut()
+        .map(|entry| {
+            if let Pending::Batch { ref mut responses, ref mut remaining, .. } = entry.pending {
+                *remaining += responses.len();
+                responses.clear();
+            }
+            entry.msg.clone()
+        })
+        .collect()
+}
+
+/// A connection to the address server.
+///
+/// The connection is driven by tasks spawned on the Tokio runtime it was
+/// created in. Dropping the client says goodbye to the server and closes the
+/// connection in the background, while `close` waits for it to be closed.
+pub struct Client {
+    commands: mpsc::UnboundedSender<Command>,
+    updates: Arc<Mutex<Option<mpsc::UnboundedSender<Response>>>>,
+    info: ServerInfo,
+    closed: Option<oneshot::Receiver<()>>,
+    timeout: Option<Duration>,
+    retries: u32,
+    /// Requests, batches and transactions made, numbering the span of each.
+    calls: AtomicU64,
+}
+
+impl Client {
+    /// Connects to the server at `addr` over plain TCP.
+    pub async fn connect(addr: SocketAddr) -> Result<Client, Error> {
+        Client::connect_with(addr, Config::default()).await
+    }
+
+    pub async fn connect_with(addr: SocketAddr, config: Config) -> Result<Client, Error> {
+        let options = config.socket.clone();
+        let connect = move || {
+            let options = options.clone();
+            async move {
+                let stream = TcpStream::connect(addr).await?;
+                options.apply(&stream)?;
+                Ok(stream)
+            }
+        };
+        // The span of the connection is opened within this one.
+        Client::with_connector(connect, config)
+            .instrument(info_span!("client", server = %addr))
+            .await
+    }
+
+    /// Starts a session over the transport made by `connect`, e.g. a TLS
+    /// stream, which is called again to reconnect if enabled. Must be called
+    /// within a Tokio runtime. Returns once the server has advertised its
+    /// capabilities.
+    pub async fn with_connector<C, F, S>(connect: C, config: Config) -> Result<Client, Error>
+    where
+        C: Fn() -> F + Send + Sync + 'static,
+        F: Future<Output = io::Result<S>> + Send + 'static,
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (conn, info) = handshake(connect().await?, &config).await?;
+        let connect: Connector<S> = Box::new(move || connect().boxed());
+        Ok(Client::start(conn, info, Some(connect), config))
+    }
+
+    /// Starts a session over an already established transport, which can't
+    /// be reconnected. Must be called within a Tokio runtime. Returns once
+    /// the server has advertised its capabilities.
+    pub async fn from_stream<S>(stream: S, config: Config) -> Result<Client, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (conn, info) = handshake(stream, &config).await?;
+        Ok(Client::start(conn, info, None, config))
+    }
+
+    fn start<S>(
+        conn: Framed<S, ClientToServerCodec>,
+        info: ServerInfo,
+        connect: Option<Connector<S>>,
+        config: Config,
+    ) -> Client
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (commands, command_port) = mpsc::unbounded();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let updates = Arc::new(Mutex::new(None));
+
+        let grants = config.credits.map(|_| commands.clone());
+        let (timeout, retries) = (config.timeout, config.retries);
+        let session_updates = updates.clone();
+        let rate_limit = config.rate_limit.or(info.max_requests_per_sec);
+        // Logs of the connection, across reconnects, are told apart from
+        // those of other connections by its span.
+        let span = info_span!("conn", id = CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1);
+        let task = async move {
+            session(conn, rate_limit, connect, config, command_port, grants, session_updates)
+                .await;
+            let _ = closed_tx.send(());
+        };
+        tokio::spawn(task.instrument(span));
+
+        let calls = AtomicU64::new(0);
+        Client { commands, updates, info, closed: Some(closed_rx), timeout, retries, calls }
+    }
+
+    fn send(&self, msg: ClientMessage, pending: Option<Pending>) {
+        // If the connection is gone the pending sender is dropped, failing
+        // the request with `Error::Closed`.
+        let _ = self.commands.unbounded_send(Command { msg, pending });
+    }
+
+    /// Sends `msg` right away, returning a future of its answer which
+    /// resends it if it times out. Every attempt is answered in turn, so
+    /// `pending` makes a fresh entry for each.
+    fn call<'a, T: 'a>(
+        &'a self,
+        msg: ClientMessage,
+        pending: impl Fn(oneshot::Sender<Result<T, Error>>) -> Pending + 'static,
+    ) -> impl Future<Output = Result<T, Error>> + 'a {
+        let (tx, rx) = oneshot::channel();
+        self.send(msg.clone(), Some(pending(tx)));
+        let span = info_span!("request", id = self.calls.fetch_add(1, Ordering::Relaxed) + 1);
+        let answer = async move {
+            let mut rx = rx;
+            let mut attempts = 0;
+            loop {
+                let timeout = match self.timeout {
+                    Some(timeout) => timeout,
+                    None => return rx.await.map_err(|_| Error::Closed)?,
+                };
+                match time::timeout(timeout, rx).await {
+                    Ok(result) => return result.map_err(|_| Error::Closed)?,
+                    Err(_) if attempts < self.retries => {
+                        attempts += 1;
+                        warn!("Request timed out, retrying ({}/{})", attempts, self.retries);
+                        let (tx, next) = oneshot::channel();
+                        self.send(msg.clone(), Some(pending(tx)));
+                        rx = next;
+                    }
+                    Err(_) => return Err(Error::Timeout),
+                }
+            }
+        };
+        answer.instrument(span)
+    }
+
+    /// Whether the connection is gone for good, failing every request.
+    pub fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+
+    /// The capabilities the server advertised on connect.
+    pub fn server_info(&self) -> &ServerInfo {
+        &self.info
+    }
+
+    /// Requests `n` random addresses.
+    pub fn request_addrs(
+        &self,
+        n: u32,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>, Error>> + '_ {
+        let req = Request { num_addrs: n, constraints: Constraints::default() };
+        let answer = self.request(req);
+        async move { Ok(answer.await?.addrs) }
+    }
+
+    /// Sends a request, returning a future of its response.
+    ///
+    /// Like batches and transactions, the request is sent before the future
+    /// is first polled, so several may be outstanding on the connection at
+    /// once. The server answers them in the order they were sent.
+    pub fn request(&self, req: Request) -> impl Future<Output = Result<Response, Error>> + '_ {
+        self.call(ClientMessage::Request(req), Pending::One)
+    }
+
+    /// Requests several counts in one frame, returning one response per
+    /// count in the same order.
+    pub fn batch(
+        &self,
+        counts: Vec<u32>,
+    ) -> impl Future<Output = Result<Vec<Response>, Error>> + '_ {
+        let n = counts.len();
+        let pending = move |tx| Pending::Batch {
+            responses: Vec::with_capacity(n),
+            remaining: n,
+            tx,
+            atomic: false,
+        };
+        let answer = if n > 0 {
+            Some(self.call(ClientMessage::Batch(counts), pending))
+        } else {
+            None
+        };
+        async move {
+            match answer {
+                Some(answer) => answer.await,
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    /// Sends several requests to be served all or nothing, returning one
+    /// response per request in the same order, with no address appearing
+    /// twice across them, or the error for the first request that couldn't
+    /// be served.
+    pub fn transaction(
+        &self,
+        reqs: Vec<Request>,
+    ) -> impl Future<Output = Result<Vec<Response>, Error>> + '_ {
+        let n = reqs.len();
+        let pending = move |tx| Pending::Batch {
+            responses: Vec::with_capacity(n),
+            remaining: n,
+            tx,
+            atomic: true,
+        };
+        let answer = if n > 0 {
+            Some(self.call(ClientMessage::Transaction(reqs), pending))
+        } else {
+            None
+        };
+        async move {
+            match answer {
+                Some(answer) => answer.await,
+                None => Ok(Vec::new()),
+            }
+        }
+    }
+
+    /// Changes the server's log level for this connection, if `token` is the
+    /// server's debug token.
+    pub fn set_log_level(&self, token: &str, level: log::LevelFilter) {
+        self.send(ClientMessage::Debug { token: token.to_string(), level }, None);
+    }
+
+    /// Subscribes to `count` fresh addresses every `interval_ms`
+    /// milliseconds, replacing any previous subscription. The returned
+    /// stream ends when unsubscribed, disconnected or rejected by the
+    /// server, but carries on across reconnects.
+    pub fn subscribe(&self, count: u32, interval_ms: u32) -> mpsc::UnboundedReceiver<Response> {
+        let (tx, rx) = mpsc::unbounded();
+        *self.updates.lock().unwrap() = Some(tx);
+        self.send(ClientMessage::Subscribe { count, interval_ms }, None);
+        rx
+    }
+
+    pub fn unsubscribe(&self) {
+        self.updates.lock().unwrap().take();
+        self.send(ClientMessage::Unsubscribe, None);
+    }
+
+    /// Says goodbye to the server and shuts down the connection's write
+    /// half, returning once the server has closed its end as well so that
+    /// every response sent before is handled.
+    pub async fn close(mut self) -> Result<(), Error> {
+        let closed = self.closed.take().expect("closed is only taken here");
+        // Goodbye is sent on drop.
+        drop(self);
+        closed.await.map_err(|_| Error::Closed)
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.send(ClientMessage::Goodbye, None);
+    }
+}
+
+/// Frames `stream`, waits for the server to advertise its capabilities and
+/// authenticates with the token, if any.
+async fn handshake<S>(
+    stream: S,
+    config: &Config,
+) -> Result<(Framed<S, ClientToServerCodec>, ServerInfo), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let codec = match config.hmac_key {
+        Some(ref key) => ClientToServerCodec::with_key(key),
+        None => ClientToServerCodec::new(),
+    };
+    let codec = codec.with_policy(config.policy).with_format(config.wire_format);
+    // JSON frames are checked against the bundled schema, so that what's
+    // wrong with them is reported in full.
+    let codec = match config.wire_format {
+        WireFormat::Json => codec.with_json_check(Schema::server_messages()),
+        WireFormat::Binary => codec,
+    };
+    let codec = match config.wire_tap {
+        Some(ref tap) => codec.with_tap(tap.clone()),
+        None => codec,
+    };
+    let mut conn = codec.framed(stream);
+    let info = match conn.next().await {
+        Some(Ok(ServerMessage::Info(info))) => info,
+        Some(Ok(msg)) => {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected server info, got {:?}", msg),
+            )))
+        }
+        Some(Err(e)) => return Err(Error::Io(e)),
+        None => return Err(Error::Closed),
+    };
+    info!("Server info: {:?}", info);
+    if let Some(ref token) = config.token {
+        conn.send(ClientMessage::Auth { token: token.clone() }).await?;
+    }
+    Ok((conn, info))
+}
+
+/// Drives the connection until goodbye. If it drops and the client can
+/// reconnect, every unanswered message and the active subscription, if any,
+/// are sent again on the new connection.
+///
+/// Requests are held back so as not to exceed `rate_limit` per second,
+/// which follows what the server advertises unless set in the config.
+async fn session<S>(
+    mut conn: Framed<S, ClientToServerCodec>,
+    rate_limit: Option<u32>,
+    connect: Option<Connector<S>>,
+    config: Config,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    grants: Option<mpsc::UnboundedSender<Command>>,
+    updates: Arc<Mutex<Option<mpsc::UnboundedSender<Response>>>>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let in_flight = Mutex::new(VecDeque::new());
+    let mut subscription = None;
+    let mut limiter = rate_limit.map(TokenBucket::new);
+    // Sent ahead of new commands after reconnecting.
+    let mut resend = Vec::new();
+    loop {
+        let (writer, reader) = conn.split();
+        let mut goodbye = false;
+        {
+            let commands = stream::iter(resend.drain(..)).chain(&mut commands);
+            let write = write(
+                writer,
+                commands,
+                config.credits,
+                &in_flight,
+                &mut subscription,
+                &mut goodbye,
+                limiter.as_mut(),
+            );
+            let read = read(reader, grants.clone(), &config.hooks, &in_flight, updates.clone());
+            tokio::pin!(write, read);
+            tokio::select! {
+                result = &mut read => {
+                    if let Err(e) = result {
+                        error!("Read error: {}", e);
+                    }
+                }
+                result = &mut write => match result {
+                    // The server closes its end once it has answered
+                    // everything sent before goodbye.
+                    Ok(()) => {
+                        if let Err(e) = read.await {
+                            error!("Read error: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Write error: {}", e),
+                },
+            }
+        }
+        if goodbye {
+            break;
+        }
+        let (connect, backoff) = match (connect.as_ref(), config.reconnect.as_ref()) {
+            (Some(connect), Some(backoff)) => (connect, backoff),
+            _ => break,
+        };
+        warn!("Connection lost");
+        let mut queued = Vec::new();
+        conn = match reconnect(connect, backoff, &config, &mut commands, &mut queued).await {
+            Some((conn, info)) => {
+                if config.rate_limit.is_none() {
+                    // The server may have been restarted with another limit.
+                    limiter = info.max_requests_per_sec.map(TokenBucket::new);
+                }
+                conn
+            }
+            None => break,
+        };
+        let mut msgs = replay(&mut in_flight.lock().unwrap());
+        msgs.extend(subscription.clone());
+        info!("Sending {} message(s) again", msgs.len());
+        resend = msgs
+            .into_iter()
+            .map(|msg| Command { msg, pending: None })
+            .chain(queued)
+            .collect();
+    }
+    // Whoever is still waiting gets `Error::Closed`.
+    in_flight.lock().unwrap().clear();
+}
+
+/// Connects again after waiting out the backoff, holding on to commands
+/// issued meanwhile. Gives up once out of attempts or on goodbye.
+async fn reconnect<S>(
+    connect: &Connector<S>,
+    backoff: &Backoff,
+    config: &Config,
+    commands: &mut mpsc::UnboundedReceiver<Command>,
+    queued: &mut Vec<Command>,
+) -> Option<(Framed<S, ClientToServerCodec>, ServerInfo)>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut failures = 0;
+    loop {
+        if backoff.max_attempts.is_some_and(|max| failures >= max) {
+            error!("Giving up reconnecting after {} attempts", failures);
+            return None;
+        }
+        let delay = backoff.delay(failures);
+        info!("Reconnecting in {:?}", delay);
+        let attempt = async {
+            time::sleep(delay).await;
+            match connect().await {
+                Ok(stream) => handshake(stream, config).await,
+                Err(e) => Err(Error::Io(e)),
+            }
+        };
+        tokio::pin!(attempt);
+        let result = loop {
+            tokio::select! {
+                result = &mut attempt => break result,
+                cmd = commands.next() => match cmd {
+                    Some(Command { msg: ClientMessage::Goodbye, .. }) | None => {
+                        info!("Stopped reconnecting on goodbye");
+                        return None;
+                    }
+                    // Credits granted on the lost connection don't carry
+                    // over to the new one.
+                    Some(Command { msg: ClientMessage::Credits(_), .. }) => (),
+                    Some(cmd) => queued.push(cmd),
+                },
+            }
+        };
+        match result {
+            Ok((conn, info)) => {
+                info!("Reconnected after {} failed attempts", failures);
+                return Some((conn, info));
+            }
+            Err(e) => {
+                failures += 1;
+                warn!("Could not reconnect: {}", e);
+            }
+        }
+    }
+}
+
+/// Sends commands until Goodbye, even though the reader may still hold a
+/// sender for granting credits. The write half is then shut down so that
+/// the server sees a clean EOF.
+///
+/// Requests, batches and transactions wait for the limiter, if any, after
+/// being tracked as in flight so that they're replayed if the connection
+/// drops while they wait.
+async fn write<W, C>(
+    mut writer: W,
+    mut commands: C,
+    credits: Option<u32>,
+    in_flight: &Mutex<VecDeque<InFlight>>,
+    subscription: &mut Option<ClientMessage>,
+    goodbye: &mut bool,
+    mut limiter: Option<&mut TokenBucket>,
+) -> io::Result<()>
+where
+    W: Sink<ClientMessage, Error = io::Error> + Unpin,
+    C: Stream<Item = Command> + Unpin,
+{
+    if let Some(n) = credits {
+        writer.send(ClientMessage::Credits(n)).await?;
+    }
+    while let Some(cmd) = commands.next().await {
+        match cmd.msg {
+            ClientMessage::Goodbye => break,
+            ClientMessage::Subscribe { .. } => *subscription = Some(cmd.msg.clone()),
+            ClientMessage::Unsubscribe => *subscription = None,
+            _ => (),
+        }
+        if let Some(pending) = cmd.pending {
+            let msg = cmd.msg.clone();
+            in_flight.lock().unwrap().push_back(InFlight { msg, pending });
+        }
+        if let Some(ref mut limiter) = limiter {
+            match cmd.msg {
+                ClientMessage::Request(_)
+                | ClientMessage::Batch(_)
+                | ClientMessage::Transaction(_) => limiter.acquire().await,
+                _ => (),
+            }
+        }
+        debug!("Sending {:?}", cmd.msg);
+        writer.send(cmd.msg).await?;
+    }
+    *goodbye = true;
+    writer.send(ClientMessage::Goodbye).await?;
+    writer.close().await
+}
+
+/// Hands every response to whoever is waiting for it and every update to
+/// the subscriber once run through the hooks, granting credits back if flow
+/// control is enabled.
+async fn read<R>(
+    mut reader: R,
+    grants: Option<mpsc::UnboundedSender<Command>>,
+    hooks: &[Arc<dyn Hook>],
+    in_flight: &Mutex<VecDeque<InFlight>>,
+    updates: Arc<Mutex<Option<mpsc::UnboundedSender<Response>>>>,
+) -> io::Result<()>
+where
+    R: Stream<Item = io::Result<ServerMessage>> + Unpin,
+{
+    // Leading chunks of a response split up by flow control.
+    let mut partial: Option<Response> = None;
+    while let Some(msg) = reader.next().await {
+        let msg = msg?;
+        debug!("Received {:?}", msg);
+        if let Some(ref grants) = grants {
+            let consumed = match msg {
+                ServerMessage::Response(ref resp)
+                | ServerMessage::Update(ref resp)
+                | ServerMessage::Partial(ref resp) => resp.addrs.len() as u32,
+                ServerMessage::Error(_)
+                | ServerMessage::Info(_)
+                | ServerMessage::Notice(_)
+                | ServerMessage::QuotaWarning(_) => 0,
+            };
+            if consumed > 0 {
+                let _ = grants.unbounded_send(Command {
+                    msg: ClientMessage::Credits(consumed),
+                    pending: None,
+                });
+            }
+        }
+        if let ServerMessage::Partial(ref piece) | ServerMessage::Response(ref piece) = msg {
+            for hook in hooks.iter() {
+                hook.on_progress(piece);
+            }
+        }
+        let result = match msg {
+            ServerMessage::Partial(resp) => {
+                match partial {
+                    Some(ref mut head) => head.append(resp),
+                    None => partial = Some(resp),
+                }
+                continue;
+            }
+            ServerMessage::Response(resp) => {
+                let mut resp = match partial.take() {
+                    Some(mut head) => {
+                        head.append(resp);
+                        head
+                    }
+                    None => resp,
+                };
+                let requested = {
+                    let in_flight = in_flight.lock().unwrap();
+                    in_flight.front().and_then(|next| requested(&next.msg, resp.index))
+                };
+                for hook in hooks.iter() {
+                    hook.on_received(requested, &resp);
+                }
+                for hook in hooks.iter() {
+                    hook.on_response(&mut resp);
+                }
+                Ok(resp)
+            }
+            // Ends the stream of updates that won't come, leaving the
+            // requests in flight be.
+            ServerMessage::Error(err) if err.index == SUBSCRIPTION_INDEX => {
+                warn!("Subscription rejected: {}", err.message);
+                updates.lock().unwrap().take();
+                continue;
+            }
+            ServerMessage::Error(err) => Err(Error::Server(err)),
+            ServerMessage::Update(mut update) => {
+                for hook in hooks.iter() {
+                    hook.on_response(&mut update);
+                }
+                if let Some(ref tx) = *updates.lock().unwrap() {
+                    let _ = tx.unbounded_send(update);
+                }
+                continue;
+            }
+            ServerMessage::Info(info) => {
+                info!("Server info changed: {:?}", info);
+                continue;
+            }
+            ServerMessage::Notice(text) => {
+                warn!("Server notice: {}", text);
+                continue;
+            }
+            ServerMessage::QuotaWarning(warning) => {
+                debug!("{} request(s) left of the rate limit", warning.remaining);
+                for hook in hooks.iter() {
+                    hook.on_quota_warning(&warning);
+                }
+                continue;
+            }
+        };
+        complete(&mut in_flight.lock().unwrap(), result);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::DuplexStream;
+
+    use futures::future;
+
+    use addrcore::ServerToClientCodec;
+
+    /// The server end of a connection, played by the test.
+    type Server = Framed<DuplexStream, ServerToClientCodec>;
+
+    async fn accept(stream: DuplexStream) -> Server {
+        let mut server = ServerToClientCodec::new().framed(stream);
+        server.send(ServerMessage::Info(ServerInfo::default())).await.unwrap();
+        server
+    }
+
+    async fn connect(config: Config) -> (Client, Server) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (client, server) = tokio::join!(Client::from_stream(client, config), accept(server));
+        (client.unwrap(), server)
+    }
+
+    async fn recv(server: &mut Server) -> ClientMessage {
+        server.next().await.unwrap().unwrap()
+    }
+
+    fn request(num_addrs: u32) -> ClientMessage {
+        ClientMessage::Request(Request { num_addrs, constraints: Constraints::default() })
+    }
+
+    fn response(index: u32, ports: &[u16]) -> ServerMessage {
+        let addrs = ports.iter().map(|&port| ([10, 0, 0, 1], port).into()).collect();
+        ServerMessage::Response(Response { index, addrs, ttls: None, families: None, seed: None })
+    }
+
+    fn error(index: u32) -> ServerMessage {
+        let code = ErrorCode::Unsatisfiable;
+        ServerMessage::Error(ErrorResponse { index, code, message: String::new() })
+    }
+
+    fn ports(resp: &Response) -> Vec<u16> {
+        resp.addrs.iter().map(SocketAddr::port).collect()
+    }
+
+    #[tokio::test]
+    async fn pipelines_requests() {
+        let (client, mut server) = connect(Config::default()).await;
+        // Both are sent before either is awaited.
+        let first = client.request_addrs(1);
+        let second = client.request_addrs(2);
+        assert_eq!(recv(&mut server).await, request(1));
+        assert_eq!(recv(&mut server).await, request(2));
+        server.send(response(0, &[1])).await.unwrap();
+        server.send(response(0, &[2, 3])).await.unwrap();
+        let (first, second) = tokio::join!(first, second);
+        assert_eq!(first.unwrap(), vec![([10, 0, 0, 1], 1).into()]);
+        assert_eq!(second.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_batch_skips_the_rest() {
+        let (client, mut server) = connect(Config::default()).await;
+        let batch = client.batch(vec![1, 1, 1]);
+        let after = client.request(Request { num_addrs: 2, constraints: Constraints::default() });
+        assert_eq!(recv(&mut server).await, ClientMessage::Batch(vec![1, 1, 1]));
+        assert_eq!(recv(&mut server).await, request(2));
+        for msg in [response(0, &[1]), error(1), response(2, &[3]), response(0, &[4, 5])] {
+            server.send(msg).await.unwrap();
+        }
+        match batch.await {
+            Err(Error::Server(err)) => assert_eq!(err.index, 1),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(ports(&after.await.unwrap()), [4, 5]);
+    }
+
+    #[tokio::test]
+    async fn throttled_batch_is_answered_once() {
+        let (client, mut server) = connect(Config::default()).await;
+        let batch = client.batch(vec![1, 1, 1]);
+        let after = client.request_addrs(1);
+        assert_eq!(recv(&mut server).await, ClientMessage::Batch(vec![1, 1, 1]));
+        assert_eq!(recv(&mut server).await, request(1));
+        let code = ErrorCode::RateLimited { retry_after_ms: 100 };
+        let error = ErrorResponse { index: 0, code, message: String::new() };
+        server.send(ServerMessage::Error(error)).await.unwrap();
+        server.send(response(0, &[1])).await.unwrap();
+        match batch.await {
+            Err(Error::Server(err)) => assert_eq!(err.code, code),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(after.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_batch_leaves_the_next_request_answered() {
+        let server = server::Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(10);
+        let server = server.build().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let config = Config { timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let client = Client::connect_with(addr, config).await.unwrap();
+        // Under the limit one by one, but not in all.
+        let batch = client.batch(vec![6, 6]);
+        let after = client.request_addrs(1);
+        match batch.await {
+            Err(Error::Server(err)) => assert_eq!(err.code, ErrorCode::TooManyAddrs { max: 10 }),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(after.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_subscription_leaves_requests_answered() {
+        let server = server::Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(10);
+        let server = server.build().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let config = Config { timeout: Some(Duration::from_secs(5)), ..Default::default() };
+        let client = Client::connect_with(addr, config).await.unwrap();
+        let mut updates = client.subscribe(11, 10);
+        let answer = client.request_addrs(1);
+        assert_eq!(answer.await.unwrap().len(), 1);
+        assert!(updates.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn quota_warnings_reach_the_hooks() {
+        struct Warnings(Mutex<Vec<QuotaWarning>>);
+        impl Hook for Warnings {
+            fn on_response(&self, _resp: &mut Response) {}
+
+            fn on_quota_warning(&self, warning: &QuotaWarning) {
+                self.0.lock().unwrap().push(*warning);
+            }
+        }
+
+        let server = server::Server::bind(([127, 0, 0, 1], 0).into());
+        let server = server.rate_limit(1, 4).quota_warning(50).build().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let warnings = Arc::new(Warnings(Mutex::new(Vec::new())));
+        // Not held back to the limit the server advertises.
+        let config = Config {
+            timeout: Some(Duration::from_secs(5)),
+            rate_limit: Some(100),
+            hooks: vec![warnings.clone()],
+            ..Default::default()
+        };
+        let client = Client::connect_with(addr, config).await.unwrap();
+        client.request_addrs(1).await.unwrap();
+        assert!(warnings.0.lock().unwrap().is_empty());
+        client.request_addrs(1).await.unwrap();
+        let warnings = warnings.0.lock().unwrap();
+        assert_eq!(warnings.iter().map(|warning| warning.remaining).collect::<Vec<_>>(), [2]);
+    }
+
+    #[tokio::test]
+    async fn authenticates_frames_within_the_session() {
+        let server = server::Server::bind(([127, 0, 0, 1], 0).into()).hmac_key(&b"secret"[..]);
+        let server = server.build().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let config = Config {
+            hmac_key: Some(b"secret".to_vec()),
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let client = Client::connect_with(addr, config).await.unwrap();
+        assert!(client.server_info().session.is_some());
+        assert_eq!(client.request_addrs(2).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_transaction_is_answered_once() {
+        let (client, mut server) = connect(Config::default()).await;
+        let reqs = vec![Request { num_addrs: 1, constraints: Constraints::default() }; 2];
+        let transaction = client.transaction(reqs.clone());
+        let after = client.request_addrs(1);
+        assert_eq!(recv(&mut server).await, ClientMessage::Transaction(reqs));
+        assert_eq!(recv(&mut server).await, request(1));
+        server.send(error(1)).await.unwrap();
+        server.send(response(0, &[1])).await.unwrap();
+        assert!(matches!(transaction.await, Err(Error::Server(_))));
+        assert_eq!(after.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reassembles_with_credits() {
+        let config = Config { credits: Some(2), ..Default::default() };
+        let (client, mut server) = connect(config).await;
+        assert_eq!(recv(&mut server).await, ClientMessage::Credits(2));
+        let answer = client.request_addrs(3);
+        assert_eq!(recv(&mut server).await, request(3));
+        let partial = match response(0, &[1, 2]) {
+            ServerMessage::Response(resp) => ServerMessage::Partial(resp),
+            _ => unreachable!(),
+        };
+        server.send(partial).await.unwrap();
+        // Consumed credits are granted back.
+        assert_eq!(recv(&mut server).await, ClientMessage::Credits(2));
+        server.send(response(0, &[3])).await.unwrap();
+        assert_eq!(recv(&mut server).await, ClientMessage::Credits(1));
+        let ports: Vec<_> = answer.await.unwrap().iter().map(SocketAddr::port).collect();
+        assert_eq!(ports, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn close_says_goodbye() {
+        let (client, mut server) = connect(Config::default()).await;
+        let server = async move {
+            assert_eq!(recv(&mut server).await, ClientMessage::Goodbye);
+            assert!(server.next().await.is_none());
+        };
+        let (closed, ()) = tokio::join!(client.close(), server);
+        closed.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closing_fails_unanswered() {
+        let (client, mut server) = connect(Config::default()).await;
+        let answer = client.request_addrs(1);
+        assert_eq!(recv(&mut server).await, request(1));
+        drop(server);
+        assert!(matches!(answer.await, Err(Error::Closed)));
+        assert!(client.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_after_timeout() {
+        let config =
+            Config { timeout: Some(Duration::from_secs(1)), retries: 1, ..Default::default() };
+        let (client, mut server) = connect(config).await;
+        let answer = client.request_addrs(1);
+        let server = async move {
+            assert_eq!(recv(&mut server).await, request(1));
+            // Sent again once the first attempt times out.
+            assert_eq!(recv(&mut server).await, request(1));
+            // The late answer to the first attempt is discarded.
+            server.send(response(0, &[1])).await.unwrap();
+            server.send(response(0, &[2])).await.unwrap();
+            server
+        };
+        let (answer, mut server) = tokio::join!(answer, server);
+        assert_eq!(answer.unwrap(), vec![([10, 0, 0, 1], 2).into()]);
+
+        let (answer, ()) = tokio::join!(client.request_addrs(1), async {
+            assert_eq!(recv(&mut server).await, request(1));
+            assert_eq!(recv(&mut server).await, request(1));
+        });
+        assert!(matches!(answer, Err(Error::Timeout)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_after_reconnecting() {
+        // Every connection's server end is handed to the test.
+        let (accepted, mut servers) = mpsc::unbounded();
+        let connect = move || {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let _ = accepted.unbounded_send(server);
+            future::ready(Ok(client))
+        };
+        let backoff = Backoff { max_attempts: Some(1), ..Default::default() };
+        let config = Config { reconnect: Some(backoff), ..Default::default() };
+        let client = Client::with_connector(connect, config);
+        let (client, mut server) =
+            tokio::join!(client, async { accept(servers.next().await.unwrap()).await });
+        let client = client.unwrap();
+
+        let subscribe = ClientMessage::Subscribe { count: 1, interval_ms: 1000 };
+        let mut updates = client.subscribe(1, 1000);
+        let answer = client.request_addrs(2);
+        assert_eq!(recv(&mut server).await, subscribe);
+        assert_eq!(recv(&mut server).await, request(2));
+        drop(server);
+
+        let mut server = accept(servers.next().await.unwrap()).await;
+        assert_eq!(recv(&mut server).await, request(2));
+        assert_eq!(recv(&mut server).await, subscribe);
+        server.send(response(0, &[1, 2])).await.unwrap();
+        assert_eq!(answer.await.unwrap().len(), 2);
+        let update = match response(0, &[3]) {
+            ServerMessage::Response(resp) => ServerMessage::Update(resp),
+            _ => unreachable!(),
+        };
+        server.send(update).await.unwrap();
+        assert_eq!(ports(&updates.next().await.unwrap()), [3]);
+
+        // Out of attempts, as nothing accepts the next connection.
+        drop((server, servers));
+        assert!(matches!(client.request_addrs(1).await, Err(Error::Closed)));
+    }
+
+    #[tokio::test]
+    async fn checks_json_frames_against_the_schema() {
+        use tokio::io::AsyncWriteExt;
+
+        let config = Config { wire_format: WireFormat::Json, ..Default::default() };
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let info = br#"{"type":"info","body":{"version":"1.0","features":[]}}"#;
+        server.write_u32(info.len() as u32).await.unwrap();
+        server.write_all(info).await.unwrap();
+        match Client::from_stream(client, config).await {
+            Err(Error::Io(e)) => {
+                assert!(e.to_string().contains("/body: \"max_frame_len\" is a required"), "{}", e)
+            }
+            other => panic!("Unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+use tokio::time::{self, Instant};
+
+/// Spaces out requests to stay within a rate, allowing bursts of up to a
+/// second's worth after idling.
+pub struct TokenBucket {
+    per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_sec: u32) -> Self {
+        let per_sec = per_sec.max(1) as f64;
+        TokenBucket { per_sec, tokens: per_sec, last_refill: Instant::now() }
+    }
+
+    /// Waits until a request may be sent and accounts for it.
+    pub async fn acquire(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            time::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.per_sec)).await;
+            self.refill();
+        }
+        // May go slightly negative due to rounding, which the next wait makes
+        // up for.
+        self.tokens -= 1.0;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.per_sec);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bursts_then_spaces_out() {
+        let mut bucket = TokenBucket::new(10);
+        let start = Instant::now();
+        for _ in 0..10 {
+            bucket.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        bucket.acquire().await;
+        bucket.acquire().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+}
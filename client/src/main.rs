@@ -1,106 +1,1453 @@
-use std::io;
-use std::thread;
-use std::fs::File;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufReader, IsTerminal, Write};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{ExitCode, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use log::*;
-use simplelog::*;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
 
-use tokio::prelude::*;
-use tokio::net::TcpStream;
-use tokio::codec::Decoder;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
 
-use futures::sync::mpsc;
+use futures::future::LocalBoxFuture;
+use futures::stream::FuturesOrdered;
+use futures::{FutureExt, StreamExt, TryFutureExt};
 
-use core::{Request, Response, ClientToServerCodec};
+use rand::seq::SliceRandom;
 
-fn main() {
-    let mut args = std::env::args();
-    let program = args.next().unwrap();
-    let (host, port) = match (args.next(), args.next()) {
-        (Some(host), Some(port)) => (host, port),
-        _ => return println!("Usage: {} <host> <port>", program),
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName};
+
+use addrcore::{ClientMessage, Constraints, DecodePolicy, Family, Request, Response, WireFormat};
+
+use client::{Backoff, Client, Error, Filter, SocketOptions, UdpClient};
+
+mod analyze;
+mod bench;
+mod config_file;
+mod dump;
+mod editor;
+mod fanout;
+mod format;
+mod meta;
+mod metrics;
+mod progress;
+mod report;
+mod resolve;
+mod script;
+mod socks;
+mod stats;
+mod validate;
+mod watch;
+
+use crate::analyze::AnalyzeArgs;
+use crate::bench::BenchArgs;
+use crate::config_file::ConfigFile;
+use crate::dump::WireDump;
+use crate::editor::{Input, LineEditor};
+use crate::fanout::FanoutArgs;
+use crate::format::{Format, Meta, CSV_HEADER};
+use crate::meta::{MetaCommand, HELP};
+use crate::metrics::{Metrics, Tracker};
+use crate::progress::Progress;
+use crate::report::{report, ErrorFormat, Failure};
+use crate::resolve::{connect_any, resolve};
+use crate::stats::Stats;
+use crate::validate::Validator;
+use crate::watch::WatchArgs;
+
+/// Makes a connector verifying servers with the CA certificates at
+/// `ca_path`, presenting the certificate and key at `cert`, if given.
+fn tls_connector(ca_path: &str, cert: Option<(&str, &str)>) -> Result<TlsConnector, String> {
+    let file = File::open(ca_path).map_err(|e| format!("Could not open {}: {}", ca_path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid CA file {}: {}", ca_path, e))?;
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(&certs);
+    let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+    let config = match cert {
+        Some((cert_path, key_path)) => config
+            .with_client_auth_cert(load_certs(cert_path)?, load_key(key_path)?)
+            .map_err(|e| format!("Invalid client certificate or key: {}", e))?,
+        None => config.with_no_client_auth(),
     };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid certificate file {}: {}", path, e))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    let open = || File::open(path).map_err(|e| format!("Could not open {}: {}", path, e));
+    let invalid = |e| format!("Invalid key file {}: {}", path, e);
+    // Try PKCS8 first and fall back to RSA keys.
+    let mut keys =
+        rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(open()?)).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = rustls_pemfile::rsa_private_keys(&mut BufReader::new(open()?)).map_err(invalid)?;
+    }
+    let key = keys.pop().ok_or_else(|| format!("No private key found in {}", path))?;
+    Ok(PrivateKey(key))
+}
+
+/// Requests random socket addresses from the server, reading commands from
+/// stdin.
+///
+/// Exits with 1 if a request is refused, 2 on invalid arguments, 3 if the
+/// connection fails, 4 if the server sends something invalid, 5 if a request
+/// times out, 6 if a response fails --validate and 130 if interrupted.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Name or IP of the server. Every address a name resolves to is tried
+    /// until one accepts. IPv6 addresses may be bracketed, and link-local
+    /// ones need the zone of their interface, as in fe80::1%eth0.
+    #[arg(long, default_value = "127.0.0.1", value_parser = resolve::parse_host)]
+    host: String,
+    /// Port of the server, required unless connecting otherwise.
+    #[arg(long)]
+    port: Option<u16>,
+    /// Server to fail over to, as <host>:<port> with an IPv6 host in
+    /// brackets, e.g. [::1]:6000, instead of --host and --port. May be given
+    /// several times. Servers are tried in turn until one accepts, including
+    /// when reconnecting.
+    #[arg(
+        long = "server",
+        value_name = "HOST:PORT",
+        value_parser = resolve::parse_server,
+        conflicts_with_all = ["host", "port", "unix", "udp"]
+    )]
+    servers: Vec<(String, u16)>,
+    /// Order to try the --server targets in.
+    #[arg(long, value_name = "POLICY", value_enum, default_value = "sequential")]
+    failover_policy: FailoverPolicy,
+    /// Unix domain socket of the server, instead of its host and port.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["port", "tls", "proxy", "udp"])]
+    unix: Option<PathBuf>,
+    /// Connect over TLS, which requires --ca.
+    #[arg(long)]
+    tls: bool,
+    /// PEM file of the CA certificates to verify the server with.
+    #[arg(long, value_name = "FILE")]
+    ca: Option<String>,
+    /// Name to verify the server's certificate against, the host by default.
+    #[arg(long, value_name = "NAME")]
+    domain: Option<String>,
+    /// PEM certificate chain to present to servers requiring one, which
+    /// requires --tls and --key.
+    #[arg(long, value_name = "FILE", requires = "key")]
+    cert: Option<String>,
+    /// PEM private key of --cert, PKCS8 or RSA.
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    key: Option<String>,
+    /// Reach the server through a SOCKS5 proxy, such as an SSH dynamic
+    /// forward or Tor, given as socks5://<host>:<port>.
+    #[arg(long, value_name = "URL", value_parser = socks::parse_proxy)]
+    proxy: Option<SocketAddr>,
+    /// Send requests over UDP, one per datagram, which the server must
+    /// serve. Only works with --count, and unanswered requests are resent
+    /// after --timeout, 2 seconds by default, up to --retries times.
+    #[arg(
+        long,
+        requires = "count",
+        conflicts_with_all = [
+            "tls", "proxy", "credits", "hmac_key", "token", "lenient", "reconnect",
+            "wire_format"
+        ]
+    )]
+    udp: bool,
+    /// Enable flow control with this many credits.
+    #[arg(long, value_name = "N")]
+    credits: Option<u32>,
+    /// Pre-shared key authenticating every frame, which the server must
+    /// share.
+    #[arg(long, value_name = "SECRET")]
+    hmac_key: Option<String>,
+    /// Token to authenticate with, which servers run with --auth-file
+    /// require.
+    #[arg(long, value_name = "TOKEN")]
+    token: Option<String>,
+    /// Skip frames that can't be decoded instead of disconnecting, and
+    /// precede every frame with a sync marker to recover from corruption.
+    #[arg(long)]
+    lenient: bool,
+    /// Encoding of frames, which the server must use too: compact binary, or
+    /// a JSON object a frame, checked against the bundled schema of server
+    /// messages as it's received. Datagrams are always binary.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "binary")]
+    wire_format: WireFormatArg,
+    /// Fail requests that aren't answered within this many milliseconds.
+    #[arg(long, value_name = "MS")]
+    timeout: Option<u64>,
+    /// Resend a request that timed out up to this many times, which requires
+    /// --timeout except over UDP.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+    /// Reconnect with exponential backoff if the connection drops, sending
+    /// unanswered requests again.
+    #[arg(long)]
+    reconnect: bool,
+    /// Delay before the first reconnect attempt, doubled after each failed
+    /// one. 100 by default.
+    #[arg(long, value_name = "MS", requires = "reconnect")]
+    backoff_initial: Option<u64>,
+    /// Upper bound of the delay between reconnect attempts. 10000 by default.
+    #[arg(long, value_name = "MS", requires = "reconnect")]
+    backoff_max: Option<u64>,
+    /// Give up reconnecting after this many failed attempts.
+    #[arg(long, value_name = "N", requires = "reconnect")]
+    reconnect_attempts: Option<u32>,
+    /// Send at most this many requests per second, instead of the limit the
+    /// server advertises, if any.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+    /// Set TCP_NODELAY, sending small requests at once rather than
+    /// coalescing them.
+    #[arg(long)]
+    nodelay: bool,
+    /// Probe the connection with TCP keepalives once idle for this long.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    keepalive: Option<Duration>,
+    /// Time between keepalive probes, the system's default if not given.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        requires = "keepalive",
+        value_parser = humantime::parse_duration
+    )]
+    keepalive_interval: Option<Duration>,
+    /// Size of the socket's send buffer, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    send_buffer: Option<u64>,
+    /// Size of the socket's receive buffer, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    recv_buffer: Option<u64>,
+    /// Request this many addresses, print them and exit instead of reading
+    /// commands from stdin.
+    #[arg(long, value_name = "N")]
+    count: Option<u32>,
+    /// Show a progress bar on stderr while the response to a --count
+    /// request for at least N addresses arrives, if stderr is a terminal.
+    /// Only responses split into chunks by flow control make progress.
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    progress_threshold: u32,
+    /// Send this many requests with --count.
+    #[arg(long, value_name = "R", requires = "count", default_value_t = 1)]
+    repeat: u32,
+    /// Run the requests, batches and transactions in FILE, one per line as
+    /// typed at the prompt, and exit instead of reading commands from stdin.
+    #[arg(long, value_name = "FILE", conflicts_with = "count")]
+    script: Option<PathBuf>,
+    /// Run up to this many lines of the script at a time.
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "script",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    parallel: u32,
+    /// Write the answers to every line of the script to a file of its own
+    /// in DIR, named after the line, instead of printing them.
+    #[arg(long, value_name = "DIR", requires = "script")]
+    out_dir: Option<PathBuf>,
+    /// Also write every response to FILE in the output format, after a
+    /// header telling when the run started and which server answered it.
+    #[arg(long, value_name = "FILE")]
+    save: Option<PathBuf>,
+    /// Append to the --save file instead of overwriting it.
+    #[arg(long, requires = "save")]
+    append: bool,
+    /// How to print errors to stderr, each on a line of its own.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "text")]
+    errors: ErrorFormat,
+    /// Serve Prometheus metrics of the whole run at http://ADDR/metrics:
+    /// request counters and latencies, bytes sent and received, and
+    /// reconnects. Bytes aren't counted over UDP.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    /// Print request counters, latency percentiles and throughput to stderr
+    /// on exit with --count, --script, watch, analyze or piped input. The REPL
+    /// always prints them.
+    #[arg(long)]
+    stats: bool,
+    /// Log a hexdump of every frame sent and received to FILE, with its
+    /// direction, time and decoded message. Not supported over UDP.
+    #[arg(long, value_name = "FILE", conflicts_with = "udp")]
+    dump_wire: Option<PathBuf>,
+    /// Shell command to pipe every response through before output.
+    #[arg(long, value_name = "CMD")]
+    pipe_to: Option<String>,
+    /// How to print responses. Defaults to a colored table if stdout is a
+    /// terminal, and plain otherwise. Colors are left out if NO_COLOR is set.
+    #[arg(long, value_name = "FORMAT", value_enum)]
+    output: Option<Format>,
+    /// Drop repeated addresses from every response.
+    #[arg(long)]
+    unique: bool,
+    /// Sort the addresses of every response.
+    #[arg(long)]
+    sort: bool,
+    /// Drop private, loopback and link-local addresses from every response.
+    #[arg(long)]
+    exclude_private: bool,
+    /// Keep at most this many addresses of every response, once filtered.
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+    /// Check every response as the server sent it, reporting repeated
+    /// addresses, port 0, multicast and reserved addresses, and counts that
+    /// don't match the request, to test other server implementations.
+    #[arg(long)]
+    validate: bool,
+    /// File to write the log to, or stderr if it can't be created.
+    #[arg(long, value_name = "FILE", default_value = "/tmp/maidsafe-test-client.log")]
+    log_file: PathBuf,
+    /// Maximum level of log records, from off to trace. Defaults to RUST_LOG
+    /// if it's set to a level, and info otherwise.
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+    /// Write the log to stderr instead of --log-file.
+    #[arg(long, conflicts_with = "log_file")]
+    log_stderr: bool,
+    /// Format of log records: text, or JSON objects one a line with the
+    /// fields of their spans, such as the connection they're about.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// File to read defaults for these options from, instead of
+    /// ~/.config/addrclient/config.toml.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Load test the server, reporting latency percentiles, errors and the
+    /// throughput achieved.
+    Bench(BenchArgs),
+    /// Request addresses periodically until Ctrl-C, printing every response
+    /// or what changed since the previous one.
+    Watch(WatchArgs),
+    /// Send the same request to every --server at once, printing the
+    /// combined addresses without duplicates and each server's latency.
+    Fanout(FanoutArgs),
+    /// Request a large sample of addresses and print how they're
+    /// distributed: a histogram of every octet and of the ports, each with a
+    /// chi-squared test of how uniform it is.
+    Analyze(AnalyzeArgs),
+    /// Manage the config file.
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Write a commented template to the config file.
+    Init {
+        /// Overwrite the file if it exists.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Fills in the options not given on the command line from the config file.
+/// The file's server is only used if none is given on the command line.
+fn apply_config_file(args: &mut Args, matches: &ArgMatches, file: ConfigFile, path: &Path) {
+    let given = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let invalid = |msg: String| -> ! {
+        let msg = format!("{}: {}", path.display(), msg);
+        Args::command().error(ErrorKind::InvalidValue, msg).exit()
+    };
+    if !["host", "port", "unix", "servers"].iter().any(|&id| given(id)) {
+        for server in file.servers {
+            args.servers.push(resolve::parse_server(&server).unwrap_or_else(|e| invalid(e)));
+        }
+        if args.servers.is_empty() {
+            if let Some(host) = file.host {
+                args.host = resolve::parse_host(&host).unwrap_or_else(|e| invalid(e));
+            }
+            args.port = file.port;
+        }
+    }
+    if !given("timeout") {
+        args.timeout = file.timeout_ms;
+    }
+    if !given("retries") {
+        args.retries = file.retries.unwrap_or(args.retries);
+    }
+    if let (false, Some(output)) = (given("output"), file.output) {
+        let format = Format::from_str(&output, true);
+        let format = format.unwrap_or_else(|_| invalid(format!("Unknown format {}", output)));
+        args.output = Some(format);
+    }
+    // TLS doesn't apply to Unix domain sockets or UDP.
+    if args.unix.is_none() && !args.udp {
+        if !given("tls") {
+            args.tls = file.tls.unwrap_or(false);
+        }
+        if !given("ca") {
+            args.ca = file.ca;
+        }
+        if !given("domain") {
+            args.domain = file.domain;
+        }
+    }
+}
+
+/// Checks the requirements between options that the config file may meet.
+fn check_args(args: &Args) {
+    let missing = if args.port.is_none() && args.unix.is_none() && args.servers.is_empty() {
+        Some("--port, --unix or --server is required")
+    } else if args.tls && args.ca.is_none() {
+        Some("--tls requires --ca")
+    } else if args.cert.is_some() && !args.tls {
+        Some("--cert requires --tls")
+    } else if args.retries > 0 && args.timeout.is_none() && !args.udp {
+        Some("--retries requires --timeout")
+    } else {
+        None
+    };
+    if let Some(msg) = missing {
+        Args::command().error(ErrorKind::MissingRequiredArgument, msg).exit();
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum WireFormatArg {
+    Binary,
+    Json,
+}
+
+/// Logs to --log-file, or to stderr if asked to or if the file can't be
+/// created, as text or JSON. Records of dependencies logging with `log` are
+/// included.
+fn init_logging(args: &Args) {
+    let level = args.log_level.or_else(env_log_level).unwrap_or(LevelFilter::INFO);
+    let (file, error) = match args.log_stderr {
+        true => (None, None),
+        false => match File::create(&args.log_file) {
+            Ok(file) => (Some(Mutex::new(file)), None),
+            Err(e) => (None, Some(e)),
+        },
+    };
+    let layer = tracing_subscriber::fmt::layer();
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match (file, args.log_format) {
+        (Some(file), LogFormat::Json) => layer.json().with_writer(file).boxed(),
+        (Some(file), LogFormat::Text) => layer.with_ansi(false).with_writer(file).boxed(),
+        (None, LogFormat::Json) => layer.json().with_writer(io::stderr).boxed(),
+        (None, LogFormat::Text) => {
+            let ansi = io::stderr().is_terminal();
+            layer.with_ansi(ansi).with_writer(io::stderr).boxed()
+        }
+    };
+    tracing_subscriber::registry().with(layer.with_filter(level)).init();
+    if let Some(e) = error {
+        let path = args.log_file.display();
+        warn!("Could not create log file {}, logging to stderr: {}", path, e);
+    }
+}
+
+/// The level RUST_LOG is set to. Per module directives aren't supported.
+fn env_log_level() -> Option<LevelFilter> {
+    let value = std::env::var("RUST_LOG").ok()?;
+    match value.parse() {
+        Ok(level) => Some(level),
+        Err(_) => {
+            eprintln!("Ignoring RUST_LOG={}, which isn't a log level", value);
+            None
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    let config_path = args.config.clone().or_else(config_file::default_path);
+    if let Some(Mode::Config(ConfigCommand::Init { force })) = args.mode {
+        let path = match config_path {
+            Some(path) => path,
+            None => Args::command()
+                .error(ErrorKind::MissingRequiredArgument, "No home directory, give --config")
+                .exit(),
+        };
+        return match config_file::init(&path, force) {
+            Ok(()) => {
+                println!("Wrote {}", path.display());
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("Could not write {}: {}", path.display(), e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if let Some(path) = config_path {
+        match config_file::load(&path) {
+            Ok(Some(file)) => apply_config_file(&mut args, &matches, file, &path),
+            // Only a config file given explicitly has to exist.
+            Ok(None) if args.config.is_some() => Args::command()
+                .error(ErrorKind::Io, format!("{} not found", path.display()))
+                .exit(),
+            Ok(None) => (),
+            Err(e) => Args::command().error(ErrorKind::Io, e).exit(),
+        }
+    }
+    check_args(&args);
+
+    init_logging(&args);
+
+    let tls = match (args.tls, args.ca) {
+        (true, Some(ca)) => {
+            let cert = args.cert.as_deref().zip(args.key.as_deref());
+            let connector = tls_connector(&ca, cert)
+                .unwrap_or_else(|e| Args::command().error(ErrorKind::Io, e).exit());
+            Some(connector)
+        }
+        // check_args ensures --ca is given with --tls.
+        _ => None,
+    };
+    let domain = args.domain.map(|domain| match ServerName::try_from(domain.as_str()) {
+        Ok(domain) => domain,
+        Err(_) => Args::command()
+            .error(ErrorKind::InvalidValue, format!("Invalid domain name {}", domain))
+            .exit(),
+    });
 
-    WriteLogger::new(
-        LevelFilter::Info,
-        Config::default(),
-        File::create(format!("/tmp/maidsafe-test-client.log")).unwrap(),
+    // A broken script is reported before connecting.
+    let jobs = args.script.map(|path| {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => Args::command()
+                .error(ErrorKind::Io, format!("Could not read {}: {}", path.display(), e))
+                .exit(),
+        };
+        match script::parse(&text) {
+            Ok(jobs) => jobs,
+            Err(e) => Args::command()
+                .error(ErrorKind::InvalidValue, format!("Invalid script {}: {}", path.display(), e))
+                .exit(),
+        }
+    });
+    if let Some(ref out_dir) = args.out_dir {
+        if let Err(e) = fs::create_dir_all(out_dir) {
+            Args::command()
+                .error(ErrorKind::Io, format!("Could not create {}: {}", out_dir.display(), e))
+                .exit();
+        }
+    }
+    let append = args.append;
+    let save = args.save.as_ref().map(|path| {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path);
+        match file {
+            Ok(file) => Arc::new(Mutex::new(file)),
+            Err(e) => Args::command()
+                .error(ErrorKind::Io, format!("Could not open {}: {}", path.display(), e))
+                .exit(),
+        }
+    });
+
+    if args.keepalive == Some(Duration::ZERO) || args.keepalive_interval == Some(Duration::ZERO) {
+        let msg = "--keepalive and --keepalive-interval must be longer than zero";
+        Args::command().error(ErrorKind::InvalidValue, msg).exit();
+    }
+    let mut config = client::Config {
+        hmac_key: args.hmac_key.map(String::into_bytes),
+        token: args.token,
+        credits: args.credits,
+        policy: if args.lenient { DecodePolicy::Lenient } else { DecodePolicy::Strict },
+        wire_format: match args.wire_format {
+            WireFormatArg::Binary => WireFormat::Binary,
+            WireFormatArg::Json => WireFormat::Json,
+        },
+        timeout: args.timeout.map(Duration::from_millis),
+        retries: args.retries,
+        reconnect: if args.reconnect {
+            let mut backoff = Backoff::default();
+            if let Some(ms) = args.backoff_initial {
+                backoff.initial = Duration::from_millis(ms);
+            }
+            if let Some(ms) = args.backoff_max {
+                backoff.max = Duration::from_millis(ms);
+            }
+            backoff.max_attempts = args.reconnect_attempts;
+            Some(backoff)
+        } else {
+            None
+        },
+        rate_limit: args.rate_limit,
+        hooks: Vec::new(),
+        wire_tap: None,
+        socket: SocketOptions {
+            nodelay: args.nodelay,
+            keepalive: args.keepalive,
+            keepalive_interval: args.keepalive_interval,
+            send_buffer: args.send_buffer.map(|n| n as usize),
+            recv_buffer: args.recv_buffer.map(|n| n as usize),
+        },
+    };
+    let filter = Filter {
+        unique: args.unique,
+        sort: args.sort,
+        exclude_private: args.exclude_private,
+        limit: args.limit,
+    };
+    if !filter.is_noop() {
+        config.hooks.push(Arc::new(filter));
+    }
+    let validator = match args.validate {
+        true => {
+            let validator = Arc::new(Validator::new(args.errors));
+            config.hooks.push(validator.clone());
+            Some(validator)
+        }
+        false => None,
+    };
+    let verdict = |status| match validator {
+        Some(ref validator) => validator.verdict(status),
+        None => status,
+    };
+    if let Some(ref path) = args.dump_wire {
+        match File::create(path) {
+            Ok(file) => config.wire_tap = Some(Arc::new(WireDump::new(file))),
+            Err(e) => Args::command()
+                .error(ErrorKind::Io, format!("Could not create {}: {}", path.display(), e))
+                .exit(),
+        }
+    }
+    let metrics = match args.metrics_addr {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                let metrics = Arc::new(Metrics::default());
+                tokio::spawn(metrics::serve(listener, metrics.clone()));
+                Some(metrics)
+            }
+            Err(e) => Args::command()
+                .error(ErrorKind::Io, format!("Could not listen on {}: {}", addr, e))
+                .exit(),
+        },
+        None => None,
+    };
+    let active = Arc::new(Mutex::new(String::new()));
+    let progress = match args.count {
+        Some(count) if count >= args.progress_threshold && io::stderr().is_terminal() => {
+            let progress = Arc::new(Progress::default());
+            config.hooks.push(progress.clone());
+            Some(progress)
+        }
+        _ => None,
+    };
+    let dialer = Dialer { tls, domain, proxy: args.proxy, config, active, metrics };
+    let pipe_to = args.pipe_to;
+    // check_args ensures --port, --unix or --server is given.
+    let target = match (args.unix, args.port) {
+        (Some(path), _) => Target::Unix(path),
+        (None, _) if !args.servers.is_empty() => {
+            Target::Failover { servers: args.servers, policy: args.failover_policy }
+        }
+        (None, port) => Target::Tcp { host: args.host, port: port.unwrap_or_default() },
+    };
+    let server = dialer.active.clone();
+    let terminal = io::stdout().is_terminal() && pipe_to.is_none();
+    let format = match args.output {
+        Some(format) => format,
+        None if terminal => Format::Table,
+        None => Format::Plain,
+    };
+    let color = terminal && std::env::var_os("NO_COLOR").is_none();
+    let output = Output { pipe_to, format, server, save, errors: args.errors, color };
+    match args.mode {
+        Some(Mode::Bench(bench)) => {
+            return verdict(bench::run(&dialer, &target, bench, args.errors).await)
+        }
+        Some(Mode::Fanout(fanout)) => {
+            let mut stats = Stats::new(dialer.metrics.clone());
+            let status = fanout::run(&dialer, &target, fanout, &output, &mut stats).await;
+            if args.stats {
+                eprint!("{}", stats);
+            }
+            return verdict(status);
+        }
+        _ => (),
+    }
+    let client = match target {
+        // clap ensures --udp isn't given with --unix. Datagrams go to the
+        // first address as there's no telling whether a server is listening.
+        Target::Tcp { ref host, port } if args.udp => match resolve(host, port).await {
+            Ok(addrs) => {
+                *dialer.active.lock().unwrap() = target.to_string();
+                let client = UdpClient::connect(addrs[0], dialer.config.clone()).await;
+                client.map(Transport::Udp)
+            }
+            Err(e) => Err(Error::Io(e)),
+        },
+        _ => dialer.connect(&target).await.map(Transport::Stream),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            let msg = format!("Could not connect to {}: {}", target, e);
+            return report(args.errors, Failure::of(&e), &msg);
+        }
+    };
+    output.begin(args.out_dir.is_none());
+
+    if let Some(count) = args.count {
+        let mut stats = Stats::new(dialer.metrics.clone());
+        let status = match client {
+            Transport::Stream(client) => {
+                let request = |req: Request| {
+                    if let Some(ref progress) = progress {
+                        progress.start(req.num_addrs);
+                    }
+                    client.request(req).inspect(|_| {
+                        if let Some(ref progress) = progress {
+                            progress.finish();
+                        }
+                    })
+                };
+                let run = one_shot(request, &output, count, args.repeat, &mut stats);
+                let status = abortable(run, args.errors).await;
+                if let Err(e) = client.close().await {
+                    error!("Could not close connection: {}", e);
+                }
+                status
+            }
+            Transport::Udp(client) => {
+                let request = |req| client.request(req);
+                let run = one_shot(request, &output, count, args.repeat, &mut stats);
+                abortable(run, args.errors).await
+            }
+        };
+        if args.stats {
+            eprint!("{}", stats);
+        }
+        return verdict(status);
+    }
+    let client = match client {
+        Transport::Stream(client) => client,
+        // clap ensures --count is given with --udp.
+        Transport::Udp(_) => unreachable!(),
+    };
+    match args.mode {
+        Some(Mode::Watch(watch)) => {
+            let mut stats = Stats::new(dialer.metrics.clone());
+            let status = watch::run(client, watch, &output, &mut stats).await;
+            if args.stats {
+                eprint!("{}", stats);
+            }
+            return verdict(status);
+        }
+        Some(Mode::Analyze(analyze)) => {
+            let mut stats = Stats::new(dialer.metrics.clone());
+            let run = analyze::run(client, analyze, args.errors, &mut stats);
+            let status = abortable(run, args.errors).await;
+            if args.stats {
+                eprint!("{}", stats);
+            }
+            return verdict(status);
+        }
+        _ => (),
+    }
+    if let Some(jobs) = jobs {
+        let mut stats = Stats::new(dialer.metrics.clone());
+        let parallel = args.parallel as usize;
+        let run = script::run(client, jobs, parallel, output, args.out_dir, &mut stats);
+        let status = abortable(run, args.errors).await;
+        if args.stats {
+            eprint!("{}", stats);
+        }
+        return verdict(status);
+    }
+    repl(client, dialer, output, args.stats).await;
+    verdict(ExitCode::SUCCESS)
+}
+
+/// Runs `run` until it's done or the user hits Ctrl-C.
+async fn abortable(run: impl Future<Output = ExitCode>, errors: ErrorFormat) -> ExitCode {
+    tokio::select! {
+        status = run => status,
+        _ = tokio::signal::ctrl_c() => report(errors, Failure::Aborted, "Interrupted"),
+    }
+}
+
+enum Transport {
+    Stream(Client),
+    Udp(UdpClient),
+}
+
+fn greet(client: &Client) {
+    let info = client.server_info();
+    println!(
+        "Connected to server v{} (features: {})",
+        info.version,
+        info.features.join(", ")
     );
+}
+
+/// Sends `repeat` requests for `count` addresses each with `request`,
+/// printing every response. Fails on the first error.
+async fn one_shot<F, R>(
+    request: F,
+    output: &Output,
+    count: u32,
+    repeat: u32,
+    stats: &mut Stats,
+) -> ExitCode
+where
+    F: Fn(Request) -> R,
+    R: Future<Output = Result<Response, Error>>,
+{
+    let mut status = ExitCode::SUCCESS;
+    for _ in 0..repeat {
+        let req = Request { num_addrs: count, constraints: Constraints::default() };
+        let start = Instant::now();
+        stats.sent();
+        match request(req).await {
+            Ok(resp) => {
+                let latency = start.elapsed();
+                stats.answered(latency);
+                output.print(&resp, &output.meta(false, None, Some(latency))).await;
+            }
+            Err(e) => {
+                stats.failed();
+                let msg = format!("Request failed: {}", e);
+                status = report(output.errors, Failure::of(&e), &msg);
+                break;
+            }
+        }
+    }
+    status
+}
 
-    let (stdin_chan, stdin_port) = mpsc::unbounded();
-    let (stdout_chan, stdout_port) = std::sync::mpsc::channel();
+/// Where a server listens.
+#[derive(Clone, Debug)]
+enum Target {
+    /// A name or IP, and a port.
+    Tcp { host: String, port: u16 },
+    /// Names or IPs, and ports, of servers to fail over between.
+    Failover { servers: Vec<(String, u16)>, policy: FailoverPolicy },
+    Unix(PathBuf),
+}
 
-    thread::spawn(move || ui_thread(stdin_chan, stdout_port));
+/// Order in which to try the servers to fail over between.
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum FailoverPolicy {
+    /// As given, starting over from the first on every connect.
+    Sequential,
+    /// Shuffled anew on every connect, to spread clients across servers.
+    Random,
+}
 
-    let addr = format!("{}:{}", host, port).parse().unwrap();
-    let connect = TcpStream::connect(&addr);
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // IPv6 addresses are bracketed to set the port apart.
+            Target::Tcp { host, port } if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            Target::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            Target::Failover { servers, .. } => {
+                for (i, (host, port)) in servers.iter().enumerate() {
+                    let target = Target::Tcp { host: host.clone(), port: *port };
+                    write!(f, "{}{}", if i > 0 { ", " } else { "" }, target)?;
+                }
+                Ok(())
+            }
+            Target::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
 
-    let session = connect.and_then(move |stream| {
-        info!("Starting session");
-        let (writer, reader) = ClientToServerCodec.framed(stream).split();
+/// How to connect to a server, which the REPL can switch between.
+struct Dialer {
+    tls: Option<TlsConnector>,
+    /// Name to verify the server's certificate against instead of its IP.
+    domain: Option<ServerName>,
+    /// SOCKS5 proxy to reach servers through.
+    proxy: Option<SocketAddr>,
+    config: client::Config,
+    /// The server last connected to, which changes on failover.
+    active: Arc<Mutex<String>>,
+    /// Counts the bytes of every connection and reconnects, if served.
+    metrics: Option<Arc<Metrics>>,
+}
 
-        let write = stdin_port
-            .map_err(|()| unreachable!("stdin_port can't fail"))
-            .fold(writer, |writer, req| {
-                info!("Sending request: {:?}", req);
-                if req.num_addrs == 0 {
-                    // TODO: gracefully shutdown Tokio runtime.
-                    std::process::exit(0);
-                } else {
-                    writer.send(req)
+impl Dialer {
+    /// Connects over TLS if a connector is given, or plain TCP otherwise,
+    /// through the proxy if any. Unix domain sockets are connected to as
+    /// is.
+    ///
+    /// Host names are resolved again on every reconnect, unless connecting
+    /// through a proxy, which resolves them itself. Servers to fail over
+    /// between are tried in turn on every connect and reconnect.
+    async fn connect(&self, target: &Target) -> Result<Client, Error> {
+        let config = self.config.clone();
+        let tracker = Tracker::new(self.metrics.clone());
+        let (servers, policy) = match *target {
+            Target::Tcp { ref host, port } => {
+                (vec![(host.clone(), port)], FailoverPolicy::Sequential)
+            }
+            Target::Failover { ref servers, policy } => (servers.clone(), policy),
+            Target::Unix(ref path) => {
+                *self.active.lock().unwrap() = target.to_string();
+                let path = path.clone();
+                let connect = move || {
+                    let tracker = tracker.clone();
+                    UnixStream::connect(path.clone()).map_ok(move |stream| tracker.track(stream))
+                };
+                return Client::with_connector(connect, config).await;
+            }
+        };
+        // The certificate is verified against the host unless an explicit
+        // domain is given.
+        let mut domains = Vec::with_capacity(servers.len());
+        for (host, _) in servers.iter() {
+            let domain = match self.domain {
+                Some(ref domain) => domain.clone(),
+                None => ServerName::try_from(host.as_str()).map_err(|_| {
+                    let msg = format!("Invalid domain name {}", host);
+                    Error::Io(io::Error::new(io::ErrorKind::InvalidInput, msg))
+                })?,
+            };
+            domains.push(domain);
+        }
+        let (proxy, active) = (self.proxy, self.active.clone());
+        let options = config.socket.clone();
+        // Connects to the first server that accepts, returning its index.
+        let tcp = move || {
+            let mut order: Vec<usize> = (0..servers.len()).collect();
+            if policy == FailoverPolicy::Random {
+                order.shuffle(&mut rand::thread_rng());
+            }
+            let (servers, active, tracker) = (servers.clone(), active.clone(), tracker.clone());
+            let options = options.clone();
+            async move {
+                let mut errors = Vec::new();
+                for i in order {
+                    let (ref host, port) = servers[i];
+                    let target = Target::Tcp { host: host.clone(), port };
+                    let stream = match proxy {
+                        Some(proxy) => socks::connect(proxy, host, port).await,
+                        None => match resolve(host, port).await {
+                            Ok(addrs) => connect_any(&addrs, TcpStream::connect).await,
+                            Err(e) => Err(e),
+                        },
+                    };
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = options.apply(&stream) {
+                                warn!("Could not set the socket options of {}: {}", target, e);
+                            }
+                            *active.lock().unwrap() = target.to_string();
+                            return Ok((tracker.track(stream), i));
+                        }
+                        Err(e) => {
+                            warn!("Could not connect to {}: {}", target, e);
+                            errors.push((target, e));
+                        }
+                    }
+                }
+                if errors.len() == 1 {
+                    return Err(errors.remove(0).1);
                 }
-            })
-            .map(|_| ());
+                let errors: Vec<_> =
+                    errors.iter().map(|(target, e)| format!("{}: {}", target, e)).collect();
+                let msg = format!("Could not connect to any server ({})", errors.join("; "));
+                Err(io::Error::other(msg))
+            }
+        };
+        match self.tls {
+            Some(ref connector) => {
+                let connector = connector.clone();
+                let connect = move || {
+                    let (connector, domains, tcp) = (connector.clone(), domains.clone(), tcp());
+                    async move {
+                        let (stream, i) = tcp.await?;
+                        connector.connect(domains[i].clone(), stream).await
+                    }
+                };
+                Client::with_connector(connect, config).await
+            }
+            None => {
+                let connect = move || {
+                    let tcp = tcp();
+                    async move { tcp.await.map(|(stream, _)| stream) }
+                };
+                Client::with_connector(connect, config).await
+            }
+        }
+    }
+}
+
+/// Where responses are written: stdout, or the stdin of a shell command run
+/// once per response, e.g. to enrich addresses with GeoIP data, and the file
+/// they're saved to if any.
+#[derive(Clone)]
+struct Output {
+    pipe_to: Option<String>,
+    format: Format,
+    /// The server connected to, shared with the dialer.
+    server: Arc<Mutex<String>>,
+    save: Option<Arc<Mutex<File>>>,
+    errors: ErrorFormat,
+    /// Whether responses are printed with colors, which are never saved.
+    color: bool,
+}
 
-        let read = reader.for_each(move |resp| {
-            info!("Got response: {:?}", resp);
-            stdout_chan.send(resp).unwrap();
-            Ok(())
-        });
+impl Output {
+    fn meta(&self, update: bool, index: Option<u32>, latency: Option<Duration>) -> Meta {
+        Meta { server: self.server(), update, index, latency }
+    }
 
-        read.select(write).map(|_| ()).map_err(|(err, _)| err)
-    });
+    /// Prints a response as soon as it's received, so that line based
+    /// formats stream.
+    /// Starts the output of a run with the header of the saved file, and
+    /// that of CSV on stdout if responses are printed there.
+    fn begin(&self, stdout: bool) {
+        self.start_run();
+        if self.format == Format::Csv && self.pipe_to.is_none() && stdout {
+            print!("{}", CSV_HEADER);
+        }
+    }
+
+    /// Saves the header of a new run, e.g. once connected to another server
+    /// or switched to another format.
+    fn start_run(&self) {
+        self.save(&self.format.run_header(&self.server(), SystemTime::now()));
+    }
 
-    tokio::run(session.map_err(|_e| ()));
+    fn server(&self) -> String {
+        self.server.lock().unwrap().clone()
+    }
+
+    /// Appends `text` to the save file, if any.
+    fn save(&self, text: &str) {
+        if let Some(ref file) = self.save {
+            if let Err(e) = file.lock().unwrap().write_all(text.as_bytes()) {
+                error!("Could not save response: {}", e);
+                eprintln!("Could not save response: {}", e);
+            }
+        }
+    }
+
+    async fn print(&self, resp: &Response, meta: &Meta) {
+        let mut text = self.format.format(resp, meta);
+        self.save(&text);
+        if self.color {
+            text = self.format.render(resp, meta, true);
+        }
+        // Every run of the command gets a document of its own.
+        if self.format == Format::Csv && self.pipe_to.is_some() {
+            text.insert_str(0, CSV_HEADER);
+        }
+        self.emit(&text).await;
+    }
+
+    async fn emit(&self, text: &str) {
+        let cmd = match self.pipe_to {
+            Some(ref cmd) => cmd,
+            None => {
+                print!("{}", text);
+                return;
+            }
+        };
+        match pipe(cmd, text).await {
+            Ok(status) if !status.success() => warn!("`{}` exited with {}", cmd, status),
+            Ok(_) => (),
+            Err(e) => {
+                error!("Could not run `{}`: {}", cmd, e);
+                println!("Could not run `{}`: {}", cmd, e);
+            }
+        }
+    }
+}
+
+/// Runs `cmd` in a shell with `text` as its stdin.
+async fn pipe(cmd: &str, text: &str) -> io::Result<ExitStatus> {
+    let mut child = Command::new("sh").arg("-c").arg(cmd).stdin(Stdio::piped()).spawn()?;
+    // The command's stdin is closed once written so that it can finish.
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(text.as_bytes()).await?;
+    drop(stdin);
+    child.wait().await
+}
+
+/// Parses the constraints following the count of a single request, e.g.
+/// `in 10.0.0.0/8 ports 1024-65535 v4`, or `seed 42` to get the addresses
+/// of a response with that seed again.
+fn parse_constraints<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<Constraints> {
+    let mut constraints = Constraints::default();
+    while let Some(word) = words.next() {
+        match word {
+            "in" => constraints.cidr = Some(words.next()?.parse().ok()?),
+            "ports" => {
+                let mut range = words.next()?.splitn(2, '-');
+                let lo = range.next()?.parse().ok()?;
+                let hi = range.next()?.parse().ok()?;
+                constraints.ports = Some((lo, hi));
+            }
+            "v4" => constraints.family = Some(Family::V4),
+            "v6" => constraints.family = Some(Family::V6),
+            "seed" => constraints.seed = Some(words.next()?.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some(constraints)
+}
+
+/// Parses a line of user input, which is either a single count optionally
+/// followed by constraints (e.g. `10 in 10.0.0.0/8 ports 1024-65535`), a
+/// comma separated list of counts (e.g. `5, 10, 100`) sent as a batch, or
+/// `debug <token> <level>` to change the server's log level for this
+/// connection, `sub <count> <interval_ms>` and `unsub` to manage a
+/// subscription, or `tx` followed by semicolon separated requests (e.g.
+/// `tx 5; 10 in 10.0.0.0/8`) to send a transaction.
+fn parse_input(input: &str) -> Option<ClientMessage> {
+    let mut words = input.split_whitespace();
+    match words.next() {
+        Some("debug") => {
+            let token = words.next()?.to_string();
+            let level = words.next()?.parse().ok()?;
+            return Some(ClientMessage::Debug { token, level });
+        }
+        Some("sub") => {
+            let count = words.next()?.parse().ok()?;
+            let interval_ms = words.next()?.parse().ok()?;
+            return Some(ClientMessage::Subscribe { count, interval_ms });
+        }
+        Some("unsub") => return Some(ClientMessage::Unsubscribe),
+        Some("tx") => {
+            let rest = words.collect::<Vec<_>>().join(" ");
+            let reqs = rest
+                .split(';')
+                .map(|req| {
+                    let mut words = req.split_whitespace();
+                    let num_addrs = words.next()?.parse().ok()?;
+                    let constraints = parse_constraints(words)?;
+                    Some(Request { num_addrs, constraints })
+                })
+                .collect::<Option<Vec<_>>>()?;
+            return Some(ClientMessage::Transaction(reqs));
+        }
+        Some(word) => {
+            if let Ok(num_addrs) = word.parse() {
+                let constraints = parse_constraints(words)?;
+                return Some(ClientMessage::Request(Request { num_addrs, constraints }));
+            }
+        }
+        None => (),
+    }
+    let counts = input
+        .split(',')
+        .map(|n| n.trim().parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .ok()?;
+    if counts.len() == 1 {
+        Some(ClientMessage::Request(Request {
+            num_addrs: counts[0],
+            constraints: Constraints::default(),
+        }))
+    } else {
+        Some(ClientMessage::Batch(counts))
+    }
 }
 
-fn ui_thread(
-    mut stdin_chan: mpsc::UnboundedSender<Request>,
-    stdout_port: std::sync::mpsc::Receiver<Response>,
-) {
-    info!("Starting stdio thread");
+/// The responses to a command and whether they're indexed within a batch,
+/// along with how long they took to arrive.
+type Answer = LocalBoxFuture<'static, (Result<(Vec<Response>, bool), Error>, Duration)>;
+
+/// The state of the REPL, which meta-commands change.
+struct Session {
+    /// None while disconnected.
+    client: Option<Rc<Client>>,
+    output: Output,
+    /// Prints the updates of the active subscription, if any.
+    printer: Option<JoinHandle<()>>,
+    /// Answers to the requests sent so far and how long each took, in the
+    /// order they were sent.
+    answers: FuturesOrdered<Answer>,
+    stats: Stats,
+}
+
+impl Session {
+    /// Prints the responses to a command, which are indexed if they answer a
+    /// batch or transaction, returning whether the connection is still
+    /// usable.
+    async fn show(
+        &mut self,
+        result: Result<(Vec<Response>, bool), Error>,
+        latency: Duration,
+    ) -> bool {
+        match result {
+            Ok((resps, indexed)) => {
+                self.stats.answered(latency);
+                for resp in resps {
+                    let index = if indexed { Some(resp.index) } else { None };
+                    let meta = self.output.meta(false, index, Some(latency));
+                    self.output.print(&resp, &meta).await;
+                }
+                true
+            }
+            Err(Error::Server(err)) => {
+                self.stats.failed();
+                println!("Error: {}", err.message);
+                true
+            }
+            // A late answer is dropped.
+            Err(Error::Timeout) => {
+                self.stats.failed();
+                println!("Error: {}", Error::Timeout);
+                true
+            }
+            Err(e) => {
+                self.stats.failed();
+                error!("Connection error: {}", e);
+                println!("Error: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Closes the connection, if any, once everything sent on it is answered
+    /// if `drain` is set, or right away otherwise, dropping the answers.
+    async fn disconnect(&mut self, drain: bool) {
+        if drain {
+            while let Some((result, latency)) = self.answers.next().await {
+                if !self.show(result, latency).await {
+                    break;
+                }
+            }
+        }
+        self.answers = FuturesOrdered::new();
+        // Nothing else holds on to the client once its answers are gone.
+        if let Some(Ok(client)) = self.client.take().map(Rc::try_unwrap) {
+            if let Err(e) = client.close().await {
+                error!("Could not close connection: {}", e);
+            }
+        }
+        // The updates stream ends with the connection, after which the last
+        // updates are printed.
+        if let Some(printer) = self.printer.take() {
+            let _ = printer.await;
+        }
+    }
+
+    /// Runs a meta-command, returning whether to quit.
+    async fn run(&mut self, cmd: MetaCommand, dialer: &Dialer) -> bool {
+        match cmd {
+            MetaCommand::Connect(host, port) => {
+                self.disconnect(true).await;
+                let target = Target::Tcp { host, port };
+                match dialer.connect(&target).await {
+                    Ok(client) => {
+                        info!("Connected to {}", target);
+                        greet(&client);
+                        self.client = Some(Rc::new(client));
+                        self.output.start_run();
+                    }
+                    Err(e) => {
+                        error!("Could not connect to {}: {}", target, e);
+                        println!("Could not connect to {}: {}", target, e);
+                    }
+                }
+            }
+            MetaCommand::Disconnect => {
+                if self.client.is_none() {
+                    println!("Not connected");
+                } else {
+                    self.disconnect(true).await;
+                    println!("Disconnected");
+                }
+            }
+            MetaCommand::Stats => {
+                match self.client {
+                    Some(_) => println!("Server: {}", self.output.server()),
+                    None => println!("Not connected"),
+                }
+                print!("{}", self.stats);
+            }
+            MetaCommand::Format(format) => {
+                if format == Format::Csv && self.output.pipe_to.is_none() {
+                    print!("{}", CSV_HEADER);
+                }
+                self.output.format = format;
+                self.output.start_run();
+            }
+            MetaCommand::Help => print!("{}", HELP),
+            MetaCommand::Quit => return true,
+        }
+        false
+    }
+}
+
+/// Reads commands until Ctrl-D, `:quit` or a request for 0 addresses.
+/// Requests are sent as soon as they're entered rather than after the
+/// previous one is answered, and their answers are printed in order as they
+/// arrive. Ctrl-C cancels the requests still waiting for an answer.
+/// Piped input is read until it runs out, with no prompt, greeting or stats
+/// unless `stats` is set, so that only the answers go to stdout.
+async fn repl(client: Client, dialer: Dialer, output: Output, stats: bool) {
+    info!("Starting REPL");
+    let mut editor = match LineEditor::spawn() {
+        Ok(editor) => editor,
+        Err(e) => {
+            error!("Could not start line editor: {}", e);
+            println!("Could not start line editor: {}", e);
+            let _ = client.close().await;
+            return;
+        }
+    };
+    // Piped input gets nothing but the answers on stdout.
+    let interactive = editor.is_interactive();
+    if interactive {
+        greet(&client);
+    }
+    let mut session = Session {
+        client: Some(Rc::new(client)),
+        output,
+        printer: None,
+        answers: FuturesOrdered::new(),
+        stats: Stats::new(dialer.metrics.clone()),
+    };
     loop {
-        let mut buf = String::new();
-        print!("> ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut buf).unwrap();
-        let num_addrs = match buf.trim().parse() {
-            Ok(n) => n,
-            Err(_) => {
-                println!("Input must be an integer");
+        let input = tokio::select! {
+            Some((result, latency)) = session.answers.next(), if !session.answers.is_empty() => {
+                if !session.show(result, latency).await {
+                    session.disconnect(false).await;
+                    println!("Disconnected, use :connect <host>:<port> to connect again");
+                }
                 continue;
-            },
+            }
+            input = editor.next_line() => input,
         };
-        let req = Request { num_addrs };
-        stdin_chan = match stdin_chan.send(req).wait() {
-            Ok(tx) => tx,
-            Err(e) => {
-                error!("Stdin error: {}", e);
+        let line = match input {
+            Input::Line(line) => line,
+            Input::Interrupted => {
+                // Late answers are dropped.
+                if !session.answers.is_empty() {
+                    println!("Cancelled {} request(s)", session.answers.len());
+                    session.answers = FuturesOrdered::new();
+                }
+                continue;
+            }
+            Input::Eof => break,
+            Input::Failed(e) => {
+                error!("Input error: {}", e);
                 break;
             }
         };
-        match stdout_port.recv() {
-            Ok(resp) => {
-                for addr in resp.addrs {
-                    println!("{}", addr);
+        if let Some(cmd) = line.trim().strip_prefix(':') {
+            match MetaCommand::parse(cmd) {
+                Ok(cmd) => {
+                    if session.run(cmd, &dialer).await {
+                        break;
+                    }
                 }
-            },
-            Err(_) => (), // TODO
-        }
-        if num_addrs == 0 {
-            info!("Exiting program");
-            break;
+                Err(e) => println!("{}", e),
+            }
+            continue;
         }
+        let msg = match parse_input(&line) {
+            Some(ClientMessage::Request(Request { num_addrs: 0, .. })) => break,
+            Some(msg) => msg,
+            None => {
+                println!("Input must be an integer optionally followed by \
+                          `[in <cidr>] [ports <lo>-<hi>] [v4|v6] [seed <n>]`, a comma separated \
+                          list of integers, `debug <token> <level>`, `sub <count> <interval_ms>`, \
+                          `unsub`, `tx <request>; <request>...` or a command, see :help");
+                continue;
+            }
+        };
+        let client = match session.client {
+            Some(ref client) => client.clone(),
+            None => {
+                println!("Not connected, use :connect <host>:<port>");
+                continue;
+            }
+        };
+        let start = Instant::now();
+        // The responses and whether they're indexed within a batch.
+        let answer = match msg {
+            ClientMessage::Request(req) => {
+                async move { client.request(req).await.map(|resp| (vec![resp], false)) }
+                    .boxed_local()
+            }
+            ClientMessage::Batch(counts) => {
+                async move { client.batch(counts).await.map(|resps| (resps, true)) }
+                    .boxed_local()
+            }
+            ClientMessage::Transaction(reqs) => {
+                async move { client.transaction(reqs).await.map(|resps| (resps, true)) }
+                    .boxed_local()
+            }
+            ClientMessage::Debug { token, level } => {
+                client.set_log_level(&token, level);
+                continue;
+            }
+            ClientMessage::Subscribe { count, interval_ms } => {
+                // Updates arrive unprompted so they're printed as they come
+                // rather than waited for.
+                let mut updates = client.subscribe(count, interval_ms);
+                let output = session.output.clone();
+                session.printer = Some(tokio::spawn(async move {
+                    while let Some(update) = updates.next().await {
+                        let meta = output.meta(true, Some(update.index), None);
+                        output.print(&update, &meta).await;
+                    }
+                }));
+                continue;
+            }
+            ClientMessage::Unsubscribe => {
+                client.unsubscribe();
+                continue;
+            }
+            ClientMessage::Goodbye | ClientMessage::Credits(_) | ClientMessage::Auth { .. } => {
+                continue
+            }
+        };
+        session.stats.sent();
+        session
+            .answers
+            .push_back(answer.map(move |result| (result, start.elapsed())).boxed_local());
     }
+    // Whatever was sent before exiting is still answered.
+    session.disconnect(true).await;
+    if interactive && !session.stats.is_empty() {
+        print!("{}", session.stats);
+    } else if !interactive && stats {
+        eprint!("{}", session.stats);
+    }
+    info!("Exiting program");
+    editor.shutdown();
 }
-
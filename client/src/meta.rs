@@ -0,0 +1,68 @@
+use clap::ValueEnum;
+
+use crate::format::Format;
+use crate::resolve::parse_host_port;
+
+pub const HELP: &str = "\
+Requests:
+  <count> [in <cidr>] [ports <lo>-<hi>] [v4|v6]   request addresses
+  <count>, <count>...                           request a batch
+  tx <request>; <request>...                    request all or nothing
+  sub <count> <interval_ms>                     subscribe to updates
+  unsub                                         cancel the subscription
+  debug <token> <level>                         change the server's log level
+  0                                             quit
+Commands:
+  :connect <host>:<port>   connect to another server
+  :disconnect              close the connection once everything is answered
+  :stats                   show the server, request counters, latencies and throughput
+  :format <format>         print responses as table, plain, json, ndjson or csv
+  :help                    show this help
+  :quit                    quit once everything is answered
+";
+
+/// A line starting with a colon, which acts on the REPL rather than being
+/// sent to the server.
+pub enum MetaCommand {
+    /// A host name or IP, and a port.
+    Connect(String, u16),
+    Disconnect,
+    Stats,
+    Format(Format),
+    Help,
+    Quit,
+}
+
+impl MetaCommand {
+    /// Parses `line` without its leading colon, returning what's wrong with
+    /// it otherwise.
+    pub fn parse(line: &str) -> Result<MetaCommand, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(format!("Too many arguments to :{}", name));
+        }
+        let cmd = match (name, arg) {
+            ("connect", Some(addr)) => match parse_host_port(addr) {
+                Some((host, port)) => MetaCommand::Connect(host, port),
+                None => return Err(format!("Invalid address {}", addr)),
+            },
+            ("connect", None) => return Err("Usage: :connect <host>:<port>".to_string()),
+            ("format", Some(format)) => match Format::from_str(format, true) {
+                Ok(format) => MetaCommand::Format(format),
+                Err(_) => return Err(format!("Unknown format {}", format)),
+            },
+            ("format", None) => return Err("Usage: :format <format>".to_string()),
+            ("disconnect", None) => MetaCommand::Disconnect,
+            ("stats", None) => MetaCommand::Stats,
+            ("help", None) => MetaCommand::Help,
+            ("quit", None) => MetaCommand::Quit,
+            (_, Some(_)) if ["disconnect", "stats", "help", "quit"].contains(&name) => {
+                return Err(format!(":{} takes no arguments", name))
+            }
+            _ => return Err(format!("Unknown command :{}, see :help", name)),
+        };
+        Ok(cmd)
+    }
+}
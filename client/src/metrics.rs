@@ -0,0 +1,190 @@
+use std::fmt::Write as _;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Upper bounds of the latency histogram's buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counters of a client's whole run, served to Prometheus so that long soak
+/// tests can be watched.
+#[derive(Default)]
+pub struct Metrics {
+    sent: AtomicU64,
+    answered: AtomicU64,
+    failed: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    reconnects: AtomicU64,
+    /// Answers within each bucket's bound, but not the previous one's.
+    latency_buckets: [AtomicU64; BUCKETS.len()],
+    latency_sum_us: AtomicU64,
+}
+
+impl Metrics {
+    pub fn sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn answered(&self, latency: Duration) {
+        self.answered.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_us.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        let secs = latency.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.latency_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics in the Prometheus text format.
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let counters = [
+            ("requests_sent", "Requests, batches and transactions sent.", &self.sent),
+            ("responses_received", "Requests, batches and transactions answered.", &self.answered),
+            ("requests_failed", "Requests, batches and transactions failed.", &self.failed),
+            ("bytes_received", "Bytes read from servers.", &self.bytes_in),
+            ("bytes_sent", "Bytes written to servers.", &self.bytes_out),
+            ("reconnects", "Connections made again after dropping.", &self.reconnects),
+        ];
+        for (name, help, counter) in counters.iter() {
+            let _ = writeln!(text, "# HELP client_{}_total {}", name, help);
+            let _ = writeln!(text, "# TYPE client_{}_total counter", name);
+            let _ = writeln!(text, "client_{}_total {}", name, counter.load(Ordering::Relaxed));
+        }
+        let name = "client_request_latency_seconds";
+        let _ = writeln!(text, "# HELP {} Time from sending a request to its answer.", name);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        let mut count = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(self.latency_buckets.iter()) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let answered = self.answered.load(Ordering::Relaxed);
+        let sum = self.latency_sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, answered);
+        let _ = writeln!(text, "{}_sum {}", name, sum);
+        let _ = writeln!(text, "{}_count {}", name, answered);
+        text
+    }
+}
+
+/// Wraps every connection a connector makes to count its bytes, counting all
+/// but the first as reconnects.
+#[derive(Clone)]
+pub struct Tracker {
+    metrics: Option<Arc<Metrics>>,
+    connected: Arc<AtomicBool>,
+}
+
+impl Tracker {
+    pub fn new(metrics: Option<Arc<Metrics>>) -> Self {
+        Tracker { metrics, connected: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn track<S>(&self, stream: S) -> Counted<S> {
+        let reconnect = self.connected.swap(true, Ordering::Relaxed);
+        if let (Some(metrics), true) = (&self.metrics, reconnect) {
+            metrics.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+        Counted { inner: stream, metrics: self.metrics.clone() }
+    }
+}
+
+/// A connection to a server counting the bytes that go through it.
+pub struct Counted<S> {
+    inner: S,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some(ref metrics) = this.metrics {
+            let read = (buf.filled().len() - before) as u64;
+            metrics.bytes_in.fetch_add(read, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Some(metrics), Poll::Ready(Ok(written))) = (&this.metrics, &poll) {
+            metrics.bytes_out.fetch_add(*written as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Answers every HTTP request for /metrics on `listener` with the metrics.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("Could not accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                debug!("Could not serve metrics to {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn respond(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    // The headers are of no interest but are read so that the peer doesn't
+    // see its request cut off.
+    let mut header = String::new();
+    while stream.read_line(&mut header).await? > 2 {
+        header.clear();
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
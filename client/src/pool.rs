@@ -0,0 +1,278 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use tokio::time;
+
+use futures::future::BoxFuture;
+use futures::{stream, FutureExt, StreamExt};
+
+use addrcore::{Request, Response};
+
+use crate::{Client, Config, Error};
+
+/// Pool options.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Number of connections to maintain.
+    pub size: usize,
+    /// How often every connection is checked, dead ones being replaced.
+    pub health_check: Duration,
+    /// How long a connection may take to answer a health check before it's
+    /// considered dead.
+    pub probe_timeout: Duration,
+    /// Most connections made at once while filling the pool, or all of
+    /// them if unset.
+    pub warm_up_parallelism: Option<usize>,
+    /// Fewest connections that must be made for the pool to start.
+    pub min_open: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 4,
+            health_check: Duration::from_secs(5),
+            probe_timeout: Duration::from_secs(2),
+            warm_up_parallelism: None,
+            min_open: 1,
+        }
+    }
+}
+
+type Connect = Box<dyn Fn() -> BoxFuture<'static, Result<Client, Error>> + Send + Sync>;
+
+struct Inner {
+    connect: Connect,
+    /// Empty while a connection is being replaced.
+    slots: Vec<Mutex<Option<Arc<Client>>>>,
+    /// Where the round robin continues.
+    next: AtomicUsize,
+}
+
+/// Several connections to the same server, with requests spread across them
+/// round robin.
+///
+/// A background task checks every connection periodically by requesting no
+/// addresses, and replaces those that are closed or don't answer in time.
+/// Requests already sent on a connection that dies fail rather than being
+/// moved to another one.
+pub struct ClientPool {
+    inner: Arc<Inner>,
+}
+
+impl ClientPool {
+    /// Connects to the server at `addr` over plain TCP.
+    pub async fn connect(
+        addr: SocketAddr,
+        config: Config,
+        pool: PoolConfig,
+    ) -> Result<ClientPool, Error> {
+        ClientPool::with_connector(move || Client::connect_with(addr, config.clone()), pool).await
+    }
+
+    /// Makes and handshakes every connection with `connect` before
+    /// returning, at most `warm_up_parallelism` at a time, so that no
+    /// request waits for one. Fails if fewer than `min_open` can be made,
+    /// the others being retried on the next health check. Must be called
+    /// within a Tokio runtime.
+    pub async fn with_connector<C, F>(connect: C, config: PoolConfig) -> Result<ClientPool, Error>
+    where
+        C: Fn() -> F + Send + Sync + 'static,
+        F: Future<Output = Result<Client, Error>> + Send + 'static,
+    {
+        assert!(config.size > 0, "Pool must have at least one connection");
+        assert!(config.min_open <= config.size, "Pool can't open more connections than its size");
+        let connect: Connect = Box::new(move || connect().boxed());
+        let parallelism = config.warm_up_parallelism.unwrap_or(config.size).max(1);
+        let results: Vec<_> =
+            stream::iter((0..config.size).map(|_| connect())).buffered(parallelism).collect().await;
+        let mut last_error = None;
+        let slots = results
+            .into_iter()
+            .map(|result| match result {
+                Ok(client) => Mutex::new(Some(Arc::new(client))),
+                Err(e) => {
+                    warn!("Could not connect: {}", e);
+                    last_error = Some(e);
+                    Mutex::new(None)
+                }
+            })
+            .collect::<Vec<_>>();
+        let open = slots.iter().filter(|slot| slot.lock().unwrap().is_some()).count();
+        if open == 0 || open < config.min_open {
+            warn!("Opened {} of the {} connection(s) needed", open, config.min_open.max(1));
+            return Err(last_error.unwrap_or(Error::Closed));
+        }
+        let inner = Arc::new(Inner { connect, slots, next: AtomicUsize::new(0) });
+        tokio::spawn(maintain(Arc::downgrade(&inner), config));
+        Ok(ClientPool { inner })
+    }
+
+    /// The next open connection in turn, if any.
+    pub fn get(&self) -> Option<Arc<Client>> {
+        let slots = &self.inner.slots;
+        let start = self.inner.next.fetch_add(1, Ordering::Relaxed);
+        (0..slots.len()).find_map(|i| {
+            let slot = slots[(start + i) % slots.len()].lock().unwrap();
+            slot.as_ref().filter(|client| !client.is_closed()).cloned()
+        })
+    }
+
+    /// The number of open connections.
+    pub fn open(&self) -> usize {
+        self.inner
+            .slots
+            .iter()
+            .filter(|slot| match *slot.lock().unwrap() {
+                Some(ref client) => !client.is_closed(),
+                None => false,
+            })
+            .count()
+    }
+
+    /// Requests `n` random addresses on the next open connection.
+    pub async fn request_addrs(&self, n: u32) -> Result<Vec<SocketAddr>, Error> {
+        self.get().ok_or(Error::Closed)?.request_addrs(n).await
+    }
+
+    pub async fn request(&self, req: Request) -> Result<Response, Error> {
+        self.get().ok_or(Error::Closed)?.request(req).await
+    }
+
+    pub async fn batch(&self, counts: Vec<u32>) -> Result<Vec<Response>, Error> {
+        self.get().ok_or(Error::Closed)?.batch(counts).await
+    }
+
+    pub async fn transaction(&self, reqs: Vec<Request>) -> Result<Vec<Response>, Error> {
+        self.get().ok_or(Error::Closed)?.transaction(reqs).await
+    }
+}
+
+/// Checks every connection in turn each interval, replacing dead ones, until
+/// the pool is dropped.
+async fn maintain(inner: Weak<Inner>, config: PoolConfig) {
+    let mut interval = time::interval(config.health_check);
+    // The first tick completes immediately.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        for (i, slot) in inner.slots.iter().enumerate() {
+            let client = slot.lock().unwrap().clone();
+            let healthy = match client {
+                Some(client) => is_healthy(&client, config.probe_timeout).await,
+                None => false,
+            };
+            if healthy {
+                continue;
+            }
+            // Requests still holding on to the old connection finish or fail
+            // on their own.
+            slot.lock().unwrap().take();
+            match (inner.connect)().await {
+                Ok(client) => {
+                    info!("Replaced connection {} of the pool", i);
+                    *slot.lock().unwrap() = Some(Arc::new(client));
+                }
+                Err(e) => warn!("Could not replace connection {} of the pool: {}", i, e),
+            }
+        }
+    }
+}
+
+async fn is_healthy(client: &Client, timeout: Duration) -> bool {
+    if client.is_closed() {
+        return false;
+    }
+    match time::timeout(timeout, client.request_addrs(0)).await {
+        // The server answering at all is enough.
+        Ok(Ok(_)) | Ok(Err(Error::Server(_))) => true,
+        Ok(Err(e)) => {
+            warn!("Health check failed: {}", e);
+            false
+        }
+        Err(_) => {
+            warn!("Health check timed out");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    use tokio_util::codec::Decoder;
+
+    use futures::SinkExt;
+
+    use addrcore::{ServerInfo, ServerMessage, ServerToClientCodec};
+
+    /// Connects to a server that greets every client and then reads until
+    /// it leaves, failing every connection from the `fail_from`th on and
+    /// counting those in flight at once in `peak`.
+    fn connector(
+        fail_from: usize,
+        peak: Arc<AtomicUsize>,
+    ) -> impl Fn() -> BoxFuture<'static, Result<Client, Error>> + Send + Sync {
+        let made = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        move || {
+            let (made, in_flight, peak) = (made.clone(), in_flight.clone(), peak.clone());
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                if made.fetch_add(1, Ordering::SeqCst) >= fail_from {
+                    return Err(Error::Io(io::ErrorKind::ConnectionRefused.into()));
+                }
+                let (client, server) = tokio::io::duplex(1024);
+                tokio::spawn(async move {
+                    let mut server = ServerToClientCodec::new().framed(server);
+                    server.send(ServerMessage::Info(ServerInfo::default())).await.unwrap();
+                    while let Some(Ok(_)) = server.next().await {}
+                });
+                Client::from_stream(client, Config::default()).await
+            }
+            .boxed()
+        }
+    }
+
+    fn config(size: usize, warm_up_parallelism: Option<usize>, min_open: usize) -> PoolConfig {
+        PoolConfig { size, warm_up_parallelism, min_open, ..PoolConfig::default() }
+    }
+
+    #[tokio::test]
+    async fn warms_up_a_few_connections_at_a_time() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let pool =
+            ClientPool::with_connector(connector(usize::MAX, peak.clone()), config(6, Some(2), 6))
+                .await
+                .unwrap();
+        assert_eq!(pool.open(), 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_below_the_connections_needed() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let pool = ClientPool::with_connector(connector(2, peak.clone()), config(4, None, 2))
+            .await
+            .unwrap();
+        assert_eq!(pool.open(), 2);
+        assert_eq!(peak.load(Ordering::SeqCst), 4);
+        let result = ClientPool::with_connector(connector(2, peak), config(4, None, 3)).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use addrcore::Response;
+
+use client::Hook;
+
+/// How often the bar is redrawn at most.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+const WIDTH: usize = 30;
+
+/// A progress bar on stderr for the response to a large request, advanced
+/// as its chunks arrive. Only one response is tracked at a time.
+#[derive(Default)]
+pub struct Progress {
+    bar: Mutex<Option<Bar>>,
+}
+
+struct Bar {
+    total: u64,
+    received: u64,
+    started: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    /// Starts tracking the response to a request for `total` addresses.
+    pub fn start(&self, total: u32) {
+        let bar = Bar { total: total as u64, received: 0, started: Instant::now(), drawn: None };
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    /// Stops tracking the response, clearing the bar if it was drawn.
+    pub fn finish(&self) {
+        if let Some(Bar { drawn: Some(_), .. }) = self.bar.lock().unwrap().take() {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+impl Hook for Progress {
+    fn on_response(&self, _resp: &mut Response) {}
+
+    fn on_progress(&self, piece: &Response) {
+        let mut bar = self.bar.lock().unwrap();
+        let bar = match *bar {
+            Some(ref mut bar) => bar,
+            None => return,
+        };
+        bar.received = (bar.received + piece.addrs.len() as u64).min(bar.total);
+        // Drawing isn't worth it for responses that arrive all at once.
+        let due = match bar.drawn {
+            Some(drawn) => drawn.elapsed() >= REDRAW_INTERVAL,
+            None => bar.started.elapsed() >= REDRAW_INTERVAL,
+        };
+        if !due {
+            return;
+        }
+        bar.drawn = Some(Instant::now());
+        let fraction = bar.received as f64 / bar.total.max(1) as f64;
+        let filled = (fraction * WIDTH as f64) as usize;
+        let elapsed = bar.started.elapsed().as_secs_f64();
+        let rate = bar.received as f64 / elapsed.max(0.001);
+        let eta = (bar.total - bar.received) as f64 / rate.max(1.0);
+        eprint!(
+            "\r[{}{}] {:>3}% {}/{} addrs, {:.0}/s, ETA {:.0}s\x1b[K",
+            "#".repeat(filled),
+            ".".repeat(WIDTH - filled),
+            (fraction * 100.0) as u32,
+            bar.received,
+            bar.total,
+            rate,
+            eta
+        );
+        let _ = io::stderr().flush();
+    }
+}
@@ -0,0 +1,116 @@
+use std::io;
+use std::process::ExitCode;
+
+use tracing::error;
+
+use clap::ValueEnum;
+
+use serde_json::json;
+
+use client::Error;
+
+/// Why the client failed, which sets its exit code. Usage errors exit with
+/// 2, as for any clap program.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Failure {
+    /// A request was refused, or its answer couldn't be written.
+    Request = 1,
+    /// The server couldn't be reached, or the connection dropped.
+    Connection = 3,
+    /// The server sent something that couldn't be decoded.
+    Protocol = 4,
+    /// A request wasn't answered in time.
+    Timeout = 5,
+    /// A response failed --validate.
+    Invalid = 6,
+    /// The user interrupted the client with Ctrl-C.
+    Aborted = 130,
+}
+
+impl Failure {
+    pub fn of(e: &Error) -> Failure {
+        match e {
+            Error::Io(e) if e.kind() == io::ErrorKind::InvalidData => Failure::Protocol,
+            Error::Io(_) | Error::Closed => Failure::Connection,
+            Error::Timeout => Failure::Timeout,
+            Error::Server(_) => Failure::Request,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Failure::Request => "request",
+            Failure::Connection => "connection",
+            Failure::Protocol => "protocol",
+            Failure::Timeout => "timeout",
+            Failure::Invalid => "invalid",
+            Failure::Aborted => "aborted",
+        }
+    }
+}
+
+impl From<Failure> for ExitCode {
+    fn from(failure: Failure) -> ExitCode {
+        ExitCode::from(failure as u8)
+    }
+}
+
+/// How errors are printed to stderr.
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    /// `error[<kind>]: <message>`
+    Text,
+    /// A JSON object with the kind and message.
+    Json,
+}
+
+/// Logs an error and prints it to stderr as one line, returning the exit
+/// code for it.
+pub fn report(format: ErrorFormat, failure: Failure, msg: &str) -> ExitCode {
+    error!("{}", msg);
+    match format {
+        ErrorFormat::Text => eprintln!("error[{}]: {}", failure.name(), msg),
+        ErrorFormat::Json => {
+            eprintln!("{}", json!({ "error": failure.name(), "message": msg }))
+        }
+    }
+    failure.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use addrcore::{ErrorCode, ErrorResponse};
+
+    #[test]
+    fn tells_failures_apart() {
+        let invalid = Error::Io(io::ErrorKind::InvalidData.into());
+        assert_eq!(Failure::of(&invalid), Failure::Protocol);
+        let reset = Error::Io(io::ErrorKind::ConnectionReset.into());
+        assert_eq!(Failure::of(&reset), Failure::Connection);
+        assert_eq!(Failure::of(&Error::Closed), Failure::Connection);
+        assert_eq!(Failure::of(&Error::Timeout), Failure::Timeout);
+        let code = ErrorCode::Unsatisfiable;
+        let refused = ErrorResponse { index: 0, code, message: String::new() };
+        assert_eq!(Failure::of(&Error::Server(refused)), Failure::Request);
+    }
+
+    #[test]
+    fn exits_with_a_code_per_failure() {
+        let codes = [
+            (Failure::Request, 1),
+            (Failure::Connection, 3),
+            (Failure::Protocol, 4),
+            (Failure::Timeout, 5),
+            (Failure::Invalid, 6),
+            (Failure::Aborted, 130),
+        ];
+        for (failure, code) in codes {
+            assert_eq!(ExitCode::from(failure), ExitCode::from(code));
+            for format in [ErrorFormat::Text, ErrorFormat::Json] {
+                assert_eq!(report(format, failure, "failed"), ExitCode::from(code));
+            }
+        }
+    }
+}
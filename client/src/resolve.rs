@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tracing::debug;
+
+use tokio::net;
+use tokio::time;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+
+/// How long an attempt to connect gets before the next address is tried as
+/// well, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Splits `<host>:<port>`, where an IPv6 host is in brackets, as in
+/// `[::1]:6000` or `[fe80::1%eth0]:6000`.
+pub fn parse_host_port(s: &str) -> Option<(String, u16)> {
+    let (host, port) = s.rsplit_once(':')?;
+    // An unbracketed IPv6 address would be split within.
+    if host.contains(':') && !host.starts_with('[') {
+        return None;
+    }
+    let host = unbracket(host);
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// Parses a host command line argument: a name, an IPv4 address or an IPv6
+/// one, optionally in brackets and with a zone, as in `fe80::1%eth0`.
+pub fn parse_host(s: &str) -> Result<String, String> {
+    match unbracket(s) {
+        "" => Err("expected a name or IP".to_string()),
+        host => Ok(host.to_string()),
+    }
+}
+
+fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
+
+/// Parses a `<host>:<port>` command line argument.
+pub fn parse_server(s: &str) -> Result<(String, u16), String> {
+    parse_host_port(s).ok_or_else(|| "expected <host>:<port>".to_string())
+}
+
+/// Resolves `host`, which may also be an IP, returning the addresses to try
+/// in order. The zone of a scoped IPv6 address, an interface name or index,
+/// is left to the system resolver.
+pub async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let addrs = net::lookup_host((host, port))
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not resolve {}: {}", host, e)))?
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        let msg = format!("No addresses found for {}", host);
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+    debug!("Resolved {} to {:?}", host, addrs);
+    Ok(interleave(addrs))
+}
+
+/// Alternates between IPv6 and IPv4 addresses, starting with the family of
+/// the first one, so that a broken family doesn't hold up the other.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs[0].is_ipv6();
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|addr| addr.is_ipv6() == first_v6);
+    let mut addrs = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        addrs.extend(first.pop_front());
+        addrs.extend(second.pop_front());
+    }
+    addrs
+}
+
+/// Connects to the first of `addrs` that accepts, starting the next attempt
+/// as soon as one fails or once the latest hasn't succeeded in a while,
+/// without giving up on the earlier ones (happy eyeballs, RFC 8305). Fails
+/// with every attempt's error if none succeeds.
+pub async fn connect_any<F, C, T>(addrs: &[SocketAddr], connect: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> C,
+    C: Future<Output = io::Result<T>>,
+{
+    let attempt = |addr| connect(addr).map(move |result| (addr, result));
+    let mut addrs = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    attempts.extend(addrs.next().map(attempt));
+    let mut errors = Vec::new();
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    debug!("Could not connect to {}: {}", addr, e);
+                    errors.push(format!("{}: {}", addr, e));
+                    attempts.extend(addrs.next().map(attempt));
+                }
+            },
+            _ = time::sleep(ATTEMPT_DELAY) => attempts.extend(addrs.next().map(attempt)),
+        }
+    }
+    let msg = match errors.len() {
+        0 => "No addresses to connect to".to_string(),
+        1 => errors.remove(0),
+        _ => format!("Could not connect to any address ({})", errors.join("; ")),
+    };
+    Err(io::Error::other(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::future;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_hosts_and_ports() {
+        let parsed = parse_host_port;
+        assert_eq!(parsed("localhost:6000"), Some(("localhost".to_string(), 6000)));
+        assert_eq!(parsed("10.0.0.1:1"), Some(("10.0.0.1".to_string(), 1)));
+        assert_eq!(parsed("[::1]:6000"), Some(("::1".to_string(), 6000)));
+        assert_eq!(parsed("[fe80::1%eth0]:6000"), Some(("fe80::1%eth0".to_string(), 6000)));
+        for invalid in ["localhost", "::1:6000", ":6000", "[]:6000", "host:port", "host:70000"] {
+            assert_eq!(parsed(invalid), None, "{}", invalid);
+        }
+        assert_eq!(parse_host("[::1]").unwrap(), "::1");
+        assert_eq!(parse_host("fe80::1%eth0").unwrap(), "fe80::1%eth0");
+        assert!(parse_host("[]").is_err());
+        assert!(parse_server("localhost").is_err());
+    }
+
+    #[test]
+    fn alternates_families() {
+        let mixed = addrs(&["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1", "10.0.0.2:1"]);
+        let want = addrs(&["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "[::3]:1"]);
+        assert_eq!(interleave(mixed), want);
+        let v4 = addrs(&["10.0.0.1:1", "[::1]:1"]);
+        assert_eq!(interleave(v4.clone()), v4);
+    }
+
+    #[tokio::test]
+    async fn resolves_ips() {
+        assert_eq!(resolve("127.0.0.1", 6000).await.unwrap(), addrs(&["127.0.0.1:6000"]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moves_on_from_slow_addresses() {
+        let candidates = addrs(&["10.0.0.1:1", "10.0.0.2:1", "10.0.0.3:1"]);
+        let start = time::Instant::now();
+        // The first never answers and the second is refused, so the third
+        // is tried as soon as the second fails.
+        let connected = connect_any(&candidates, |addr| async move {
+            match addr.ip().to_string().as_str() {
+                "10.0.0.1" => future::pending().await,
+                "10.0.0.2" => Err(io::ErrorKind::ConnectionRefused.into()),
+                _ => Ok(addr),
+            }
+        })
+        .await;
+        assert_eq!(connected.unwrap(), candidates[2]);
+        assert_eq!(start.elapsed(), ATTEMPT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn fails_with_every_error() {
+        let refused = |_| async { Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused)) };
+        let e = connect_any(&addrs(&["10.0.0.1:1", "10.0.0.2:1"]), refused).await.unwrap_err();
+        let msg = e.to_string();
+        assert!(msg.starts_with("Could not connect to any address (10.0.0.1:1: "), "{}", msg);
+        assert!(msg.contains("; 10.0.0.2:1: "), "{}", msg);
+        let e = connect_any(&[], refused).await.unwrap_err();
+        assert_eq!(e.to_string(), "No addresses to connect to");
+    }
+}
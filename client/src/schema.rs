@@ -0,0 +1,123 @@
+use std::sync::{Arc, OnceLock};
+
+use jsonschema::{Draft, JSONSchema};
+
+use serde_json::Value;
+
+use addrcore::JsonCheck;
+
+/// Schema of the JSON frames servers send, which hand-written servers and
+/// clients in other languages can check their messages against too.
+const SERVER_MESSAGE: &str = include_str!("../schema/server-message.json");
+
+/// Checks JSON frames from the server against the bundled schema, reporting
+/// every mismatch with the path to the offending value.
+pub struct Schema {
+    schema: JSONSchema,
+}
+
+impl Schema {
+    /// The bundled schema of server messages, compiled once.
+    pub fn server_messages() -> Arc<Schema> {
+        static SCHEMA: OnceLock<Arc<Schema>> = OnceLock::new();
+        SCHEMA
+            .get_or_init(|| {
+                let schema = serde_json::from_str(SERVER_MESSAGE).expect("Invalid bundled schema");
+                let schema = JSONSchema::options()
+                    .with_draft(Draft::Draft7)
+                    .compile(&schema)
+                    .expect("Invalid bundled schema");
+                Arc::new(Schema { schema })
+            })
+            .clone()
+    }
+}
+
+impl JsonCheck for Schema {
+    fn check(&self, msg: &Value) -> Result<(), String> {
+        self.schema.validate(msg).map_err(|errors| {
+            let errors: Vec<_> = errors
+                .map(|e| match e.instance_path.to_string() {
+                    path if path.is_empty() => e.to_string(),
+                    path => format!("{}: {}", path, e),
+                })
+                .collect();
+            format!("Invalid server message: {}", errors.join("; "))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use addrcore::{
+        ErrorCode, ErrorResponse, FamilyCounts, QuotaWarning, Response, ServerInfo, ServerMessage,
+    };
+
+    #[test]
+    fn accepts_every_message() {
+        let addrs = vec!["10.0.0.1:80".parse().unwrap(), "[::1]:443".parse().unwrap()];
+        let resp = Response {
+            index: 1,
+            addrs: addrs.clone(),
+            ttls: Some(vec![30, 60]),
+            families: Some(FamilyCounts::of(&addrs)),
+            seed: Some(u64::MAX),
+        };
+        let msgs = vec![
+            ServerMessage::Response(resp.clone()),
+            ServerMessage::Update(resp.clone()),
+            ServerMessage::Partial(Response { ttls: None, families: None, seed: None, ..resp }),
+            ServerMessage::Error(ErrorResponse {
+                index: 0,
+                code: ErrorCode::RateLimited { retry_after_ms: 10 },
+                message: "Slow down".to_string(),
+            }),
+            ServerMessage::Error(ErrorResponse {
+                index: 0,
+                code: ErrorCode::Unsatisfiable,
+                message: String::new(),
+            }),
+            ServerMessage::Info(ServerInfo {
+                version: "1.0".to_string(),
+                features: vec!["tls".to_string()],
+                max_frame_len: 1024,
+                max_addrs: Some(10),
+                max_requests_per_sec: None,
+                session: Some(7),
+            }),
+            ServerMessage::Notice("Bye".to_string()),
+            ServerMessage::QuotaWarning(QuotaWarning { remaining: 1, burst: 2, per_sec: 3 }),
+        ];
+        let schema = Schema::server_messages();
+        for msg in msgs {
+            let value = serde_json::to_value(&msg).unwrap();
+            assert_eq!(schema.check(&value), Ok(()), "{}", value);
+        }
+    }
+
+    #[test]
+    fn reports_the_path_of_every_mismatch() {
+        let schema = Schema::server_messages();
+        let check = |text: &str| schema.check(&serde_json::from_str(text).unwrap()).unwrap_err();
+
+        let err = check(r#"{"type":"response","body":{"index":-1,"addrs":["10.0.0.1:80",5]}}"#);
+        assert!(err.contains("/body/index: -1 is less than the minimum of 0"), "{}", err);
+        assert!(err.contains(r#"/body/addrs/1: 5 is not of type "string""#), "{}", err);
+
+        let err = check(r#"{"type":"response","body":{"index":0,"addrs":["10.0.0.1"]}}"#);
+        assert!(err.starts_with("Invalid server message: /body/addrs/0: "), "{}", err);
+
+        let err = check(
+            r#"{"type":"error","body":{"index":0,"code":{"kind":"too_many_addrs"},"message":""}}"#,
+        );
+        assert!(err.contains(r#"/body/code: "max" is a required property"#), "{}", err);
+
+        let err = check(r#"{"type":"hello"}"#);
+        assert!(err.contains("/type: "), "{}", err);
+
+        let err = check(r#"{"body":"hi"}"#);
+        assert_eq!(err, r#"Invalid server message: "type" is a required property"#);
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use tracing::error;
+
+use futures::stream::{self, StreamExt};
+
+use addrcore::ClientMessage;
+
+use client::Client;
+
+use crate::format::{Format, CSV_HEADER};
+use crate::report::{report, Failure};
+use crate::stats::Stats;
+use crate::{parse_input, Output};
+
+/// A request, batch or transaction of a script and the line it's on.
+pub struct Job {
+    line: usize,
+    msg: ClientMessage,
+}
+
+/// Parses a script of one request, batch or transaction per line, written
+/// as in the REPL, skipping blank lines and `#` comments.
+pub fn parse(text: &str) -> Result<Vec<Job>, String> {
+    let mut jobs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_input(line) {
+            Some(
+                msg @ (ClientMessage::Request(_)
+                | ClientMessage::Batch(_)
+                | ClientMessage::Transaction(_)),
+            ) => jobs.push(Job { line: i + 1, msg }),
+            _ => return Err(format!("Line {} is not a request, batch or transaction", i + 1)),
+        }
+    }
+    Ok(jobs)
+}
+
+/// Runs the jobs up to `parallel` at a time, printing their answers in the
+/// order of the script or writing each job's to a file of its own in
+/// `out_dir`, named after its line. Every job is run even if some fail, then
+/// the connection is closed.
+pub async fn run(
+    client: Client,
+    jobs: Vec<Job>,
+    parallel: usize,
+    output: Output,
+    out_dir: Option<PathBuf>,
+    stats: &mut Stats,
+) -> ExitCode {
+    // Set by the first failure.
+    let mut status = None;
+    let mut answers = stream::iter(jobs)
+        .map(|job| {
+            let client = &client;
+            async move {
+                let start = Instant::now();
+                // The responses and whether they're indexed within a batch.
+                let result = match job.msg {
+                    ClientMessage::Request(req) => {
+                        client.request(req).await.map(|resp| (vec![resp], false))
+                    }
+                    ClientMessage::Batch(counts) => {
+                        client.batch(counts).await.map(|resps| (resps, true))
+                    }
+                    ClientMessage::Transaction(reqs) => {
+                        client.transaction(reqs).await.map(|resps| (resps, true))
+                    }
+                    _ => unreachable!("Scripts only hold requests"),
+                };
+                (job.line, result, start.elapsed())
+            }
+        })
+        .buffered(parallel)
+        .boxed_local();
+    while let Some((line, result, latency)) = answers.next().await {
+        stats.sent();
+        let (resps, indexed) = match result {
+            Ok(answer) => {
+                stats.answered(latency);
+                answer
+            }
+            Err(e) => {
+                stats.failed();
+                let msg = format!("Line {} failed: {}", line, e);
+                status.get_or_insert(report(output.errors, Failure::of(&e), &msg));
+                continue;
+            }
+        };
+        let out_dir = match out_dir {
+            Some(ref out_dir) => out_dir,
+            None => {
+                for resp in resps.iter() {
+                    let index = if indexed { Some(resp.index) } else { None };
+                    output.print(resp, &output.meta(false, index, Some(latency))).await;
+                }
+                continue;
+            }
+        };
+        let mut text = String::new();
+        if output.format == Format::Csv {
+            text += CSV_HEADER;
+        }
+        for resp in resps.iter() {
+            let index = if indexed { Some(resp.index) } else { None };
+            let formatted = output.format.format(resp, &output.meta(false, index, Some(latency)));
+            output.save(&formatted);
+            text += &formatted;
+        }
+        let path = out_dir.join(format!("{}.{}", line, output.format.extension()));
+        if let Err(e) = fs::write(&path, text) {
+            let msg = format!("Could not write {}: {}", path.display(), e);
+            status.get_or_insert(report(output.errors, Failure::Request, &msg));
+        }
+    }
+    // Done borrowing the client.
+    drop(answers);
+    if let Err(e) = client.close().await {
+        error!("Could not close connection: {}", e);
+    }
+    status.unwrap_or(ExitCode::SUCCESS)
+}
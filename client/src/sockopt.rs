@@ -0,0 +1,48 @@
+use std::io;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+use tokio::net::TcpStream;
+
+/// Options of the socket connected to the server, set once it's connected.
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// Sets TCP_NODELAY, sending small requests at once rather than
+    /// coalescing them.
+    pub nodelay: bool,
+    /// Probes the connection with TCP keepalives once idle for this long, if
+    /// set.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes, the system's default if unset.
+    pub keepalive_interval: Option<Duration>,
+    /// SO_SNDBUF, in bytes, the system's default if unset.
+    pub send_buffer: Option<usize>,
+    /// SO_RCVBUF, in bytes, the system's default if unset.
+    pub recv_buffer: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Sets the options of a connected stream.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            let keepalive = match self.keepalive_interval {
+                Some(interval) => keepalive.with_interval(interval),
+                None => keepalive,
+            };
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use bytes::BytesMut;
+
+use addrcore::wire::bytes::Writer;
+
+fn error(msg: &str) -> io::Error {
+    io::Error::other(msg)
+}
+
+/// Parses `socks5://<host>:<port>`.
+pub fn parse_proxy(url: &str) -> Result<SocketAddr, String> {
+    let addr = url
+        .strip_prefix("socks5://")
+        .ok_or_else(|| format!("Unsupported proxy {}, expected socks5://<host>:<port>", url))?;
+    addr.parse().map_err(|_| format!("Invalid proxy address {}", addr))
+}
+
+/// Connects to port `port` of `host` through the SOCKS5 proxy at `proxy`
+/// without authentication, as offered by SSH dynamic forwards and Tor
+/// (RFC 1928). Host names are resolved by the proxy. The returned stream is
+/// then relayed to the host as is.
+pub async fn connect(proxy: SocketAddr, host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+
+    // <8:version><8:nmethods><methods>, offering only "no authentication".
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match choice {
+        [5, 0] => (),
+        [5, _] => return Err(error("Proxy requires authentication")),
+        _ => return Err(error("Not a SOCKS5 proxy")),
+    }
+
+    // <8:version><8:command><8:reserved><8:address type><address><16:port>,
+    // where command 1 is CONNECT and the address is 4 or 16 bytes for type 1
+    // (IPv4) or 4 (IPv6), and <8:len><name> for type 3.
+    let mut req = BytesMut::new();
+    let mut writer = Writer::new(&mut req);
+    writer.slice(&[5, 1, 0]);
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            writer.u8(1);
+            writer.slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            writer.u8(4);
+            writer.slice(&ip.octets());
+        }
+        Err(_) => {
+            writer.u8(3);
+            writer.short_str(host).map_err(|_| error("Host name too long"))?;
+        }
+    }
+    writer.u16(port);
+    stream.write_all(&req).await?;
+
+    // Same layout, with the reply code in place of the command.
+    let mut head = [0; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 5 {
+        return Err(error("Not a SOCKS5 proxy"));
+    }
+    if head[1] != 0 {
+        return Err(error(reply_message(head[1])));
+    }
+    // The address the proxy connected from isn't of use.
+    let addr_len = match head[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(error("Invalid address type in proxy reply")),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "Proxy failure",
+        2 => "Connection not allowed by proxy",
+        3 => "Network unreachable from proxy",
+        4 => "Host unreachable from proxy",
+        5 => "Connection refused by server",
+        6 => "TTL expired at proxy",
+        7 => "Command not supported by proxy",
+        8 => "Address type not supported by proxy",
+        _ => "Unknown proxy error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    /// Runs a proxy for one client, which chooses `method` and replies to
+    /// the request with `code`, then returns the request it got.
+    async fn proxy(method: u8, code: u8) -> (SocketAddr, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, method]).await.unwrap();
+            if method != 0 {
+                return Vec::new();
+            }
+            let mut req = vec![0; 64];
+            let n = stream.read(&mut req).await.unwrap();
+            req.truncate(n);
+            // Bound to a name, which is read past too.
+            stream.write_all(&[5, code, 0, 3, 4, b'p', b'r', b'o', b'x', 0, 1]).await.unwrap();
+            stream.write_all(b"relayed").await.unwrap();
+            req
+        });
+        (addr, proxy)
+    }
+
+    #[tokio::test]
+    async fn connects_through_the_proxy() {
+        let hosts: [(&str, &[u8]); 3] = [
+            ("10.0.0.1", &[1, 10, 0, 0, 1]),
+            ("::1", &[4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+            ("example.com", b"\x03\x0bexample.com"),
+        ];
+        for (host, addr) in hosts {
+            let (proxy_addr, proxy) = proxy(0, 0).await;
+            let mut stream = connect(proxy_addr, host, 6000).await.unwrap();
+            let mut relayed = [0; 7];
+            stream.read_exact(&mut relayed).await.unwrap();
+            assert_eq!(&relayed, b"relayed");
+            let want = [&[5, 1, 0][..], addr, &6000u16.to_be_bytes()].concat();
+            assert_eq!(proxy.await.unwrap(), want, "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_proxy_failures() {
+        let (addr, _proxy) = proxy(2, 0).await;
+        let e = connect(addr, "10.0.0.1", 6000).await.unwrap_err();
+        assert_eq!(e.to_string(), "Proxy requires authentication");
+        let (addr, _proxy) = proxy(0, 5).await;
+        let e = connect(addr, "10.0.0.1", 6000).await.unwrap_err();
+        assert_eq!(e.to_string(), "Connection refused by server");
+    }
+
+    #[test]
+    fn parses_proxy_urls() {
+        assert_eq!(parse_proxy("socks5://127.0.0.1:1080"), Ok(([127, 0, 0, 1], 1080).into()));
+        assert!(parse_proxy("http://127.0.0.1:1080").unwrap_err().starts_with("Unsupported"));
+        assert!(parse_proxy("socks5://localhost").unwrap_err().starts_with("Invalid"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hdrhistogram::Histogram;
+
+use crate::metrics::Metrics;
+
+/// Counters and latencies of the requests, batches and transactions sent
+/// since the stats were created, across connections.
+pub struct Stats {
+    sent: u64,
+    answered: u64,
+    failed: u64,
+    /// Latencies of the answered ones in microseconds, up to a minute with
+    /// 3 significant digits so that memory use doesn't grow with the run.
+    latencies: Histogram<u64>,
+    started: Instant,
+    /// Also updated if metrics are served.
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Stats {
+    pub fn new(metrics: Option<Arc<Metrics>>) -> Self {
+        Stats {
+            sent: 0,
+            answered: 0,
+            failed: 0,
+            latencies: Histogram::new_with_bounds(1, 60_000_000, 3).unwrap(),
+            started: Instant::now(),
+            metrics,
+        }
+    }
+
+    pub fn sent(&mut self) {
+        self.sent += 1;
+        if let Some(ref metrics) = self.metrics {
+            metrics.sent();
+        }
+    }
+
+    pub fn answered(&mut self, latency: Duration) {
+        self.answered += 1;
+        // Longer latencies are recorded as the longest tracked.
+        self.latencies.saturating_record(latency.as_micros().max(1) as u64);
+        if let Some(ref metrics) = self.metrics {
+            metrics.answered(latency);
+        }
+    }
+
+    pub fn failed(&mut self) {
+        self.failed += 1;
+        if let Some(ref metrics) = self.metrics {
+            metrics.failed();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sent == 0
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Whatever isn't accounted for was cancelled or is still waiting.
+        let unanswered = self.sent - self.answered - self.failed;
+        writeln!(
+            f,
+            "sent: {}, answered: {}, failed: {}, unanswered: {}",
+            self.sent, self.answered, self.failed, unanswered
+        )?;
+        if self.latencies.is_empty() {
+            writeln!(f, "latency: n/a")?;
+        } else {
+            let ms = |us: u64| us as f64 / 1000.0;
+            let at = |quantile| ms(self.latencies.value_at_quantile(quantile));
+            writeln!(
+                f,
+                "latency (ms): min {:.3}, mean {:.3}, p50 {:.3}, p95 {:.3}, p99 {:.3}, max {:.3}",
+                ms(self.latencies.min()),
+                self.latencies.mean() / 1000.0,
+                at(0.5),
+                at(0.95),
+                at(0.99),
+                ms(self.latencies.max())
+            )?;
+        }
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = self.answered as f64 / elapsed;
+        writeln!(f, "throughput: {:.1} answers/s over {:.1}s", throughput, elapsed)
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, info_span, warn, Instrument};
+
+use tokio::net::UdpSocket;
+use tokio::time;
+use tokio_util::udp::UdpFramed;
+
+use futures::channel::{mpsc, oneshot};
+use futures::{SinkExt, StreamExt};
+
+use addrcore::datagram::{ClientDatagramCodec, Reassembler};
+use addrcore::{ClientMessage, Constraints, Request, Response, ServerMessage};
+
+use crate::{requested, Config, Error, Hook};
+
+/// How long to wait for an answer unless configured otherwise, as datagrams
+/// may be lost.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to hold on to the chunks of an incomplete answer.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Answer = Result<Vec<ServerMessage>, Error>;
+
+struct Outgoing {
+    id: u32,
+    msg: ClientMessage,
+    /// Only set on the first attempt.
+    tx: Option<oneshot::Sender<Answer>>,
+}
+
+/// Client sending requests, batches and transactions to a server over UDP,
+/// one per datagram.
+///
+/// There's no connection, so no handshake, subscriptions or flow control.
+/// Requests unanswered within the configured timeout, 2 seconds by default,
+/// are resent as many times as configured, and responses are run through
+/// the hooks. Every other option of the config is ignored.
+pub struct UdpClient {
+    commands: mpsc::UnboundedSender<Outgoing>,
+    next_id: AtomicU32,
+    timeout: Duration,
+    retries: u32,
+    hooks: Vec<Arc<dyn Hook>>,
+}
+
+impl UdpClient {
+    /// Binds a socket to an ephemeral port to talk to the server at `addr`.
+    /// Must be called within a Tokio runtime.
+    pub async fn connect(addr: SocketAddr, config: Config) -> Result<UdpClient, Error> {
+        let local: IpAddr = match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((local, 0)).await?;
+        let (commands, command_port) = mpsc::unbounded();
+        let run = run(UdpFramed::new(socket, ClientDatagramCodec), addr, command_port);
+        tokio::spawn(run.instrument(info_span!("udp", server = %addr)));
+        Ok(UdpClient {
+            commands,
+            next_id: AtomicU32::new(0),
+            timeout: config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            retries: config.retries,
+            hooks: config.hooks,
+        })
+    }
+
+    async fn call(&self, msg: ClientMessage) -> Answer {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, mut rx) = oneshot::channel();
+        let mut tx = Some(tx);
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                warn!("Request timed out, retrying ({}/{})", attempt, self.retries);
+            }
+            // Attempts share the ID, so whichever is answered first completes
+            // the request.
+            let outgoing = Outgoing { id, msg: msg.clone(), tx: tx.take() };
+            self.commands.unbounded_send(outgoing).map_err(|_| Error::Closed)?;
+            if let Ok(result) = time::timeout(self.timeout, &mut rx).await {
+                return result.map_err(|_| Error::Closed)?;
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Requests `n` random addresses.
+    pub async fn request_addrs(&self, n: u32) -> Result<Vec<SocketAddr>, Error> {
+        let req = Request { num_addrs: n, constraints: Constraints::default() };
+        Ok(self.request(req).await?.addrs)
+    }
+
+    pub async fn request(&self, req: Request) -> Result<Response, Error> {
+        let msg = ClientMessage::Request(req);
+        let resps = self.responses(&msg, self.call(msg.clone()).await?)?;
+        resps.into_iter().next().ok_or_else(|| {
+            Error::Io(io::Error::new(io::ErrorKind::InvalidData, "Empty answer"))
+        })
+    }
+
+    /// Requests several counts in one datagram, returning one response per
+    /// count in the same order.
+    pub async fn batch(&self, counts: Vec<u32>) -> Result<Vec<Response>, Error> {
+        if counts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let msg = ClientMessage::Batch(counts);
+        self.responses(&msg, self.call(msg.clone()).await?)
+    }
+
+    /// Sends several requests to be served all or nothing, as with
+    /// `Client::transaction`.
+    pub async fn transaction(&self, reqs: Vec<Request>) -> Result<Vec<Response>, Error> {
+        if reqs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let msg = ClientMessage::Transaction(reqs);
+        self.responses(&msg, self.call(msg.clone()).await?)
+    }
+
+    /// The responses of an answer to `sent` run through the hooks, or the
+    /// error for the first request that couldn't be served.
+    fn responses(
+        &self,
+        sent: &ClientMessage,
+        msgs: Vec<ServerMessage>,
+    ) -> Result<Vec<Response>, Error> {
+        let mut resps = Vec::with_capacity(msgs.len());
+        for msg in msgs {
+            match msg {
+                ServerMessage::Response(mut resp) => {
+                    let requested = requested(sent, resp.index);
+                    for hook in self.hooks.iter() {
+                        hook.on_received(requested, &resp);
+                    }
+                    for hook in self.hooks.iter() {
+                        hook.on_response(&mut resp);
+                    }
+                    resps.push(resp);
+                }
+                ServerMessage::Error(err) => return Err(Error::Server(err)),
+                msg => warn!("Unexpected {:?} over UDP", msg),
+            }
+        }
+        Ok(resps)
+    }
+}
+
+/// Sends messages to the server and hands each answer to whoever is
+/// waiting for it, until the client is dropped.
+async fn run(
+    framed: UdpFramed<ClientDatagramCodec>,
+    server: SocketAddr,
+    mut commands: mpsc::UnboundedReceiver<Outgoing>,
+) {
+    let (mut writer, mut reader) = framed.split();
+    let mut pending: HashMap<u32, oneshot::Sender<Answer>> = HashMap::new();
+    let mut reassembler = Reassembler::new();
+    loop {
+        tokio::select! {
+            cmd = commands.next() => {
+                let cmd = match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                };
+                // Nobody waits for requests given up on anymore.
+                pending.retain(|_, tx| !tx.is_canceled());
+                if let Some(tx) = cmd.tx {
+                    pending.insert(cmd.id, tx);
+                }
+                debug!("Sending {:?}", cmd.msg);
+                if let Err(e) = writer.send(((cmd.id, cmd.msg), server)).await {
+                    if let Some(tx) = pending.remove(&cmd.id) {
+                        let _ = tx.send(Err(Error::Io(e)));
+                    }
+                }
+            }
+            chunk = reader.next() => {
+                let chunk = match chunk {
+                    Some(Ok((chunk, addr))) if addr == server => chunk,
+                    Some(Ok((_, addr))) => {
+                        warn!("Ignoring datagram from {}", addr);
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        warn!("Invalid datagram: {}", e);
+                        continue;
+                    }
+                    None => break,
+                };
+                reassembler.expire(REASSEMBLY_TIMEOUT);
+                match reassembler.push(chunk) {
+                    Ok(Some((id, msgs))) => {
+                        // Late answers to requests already answered or given
+                        // up on are dropped.
+                        if let Some(tx) = pending.remove(&id) {
+                            let _ = tx.send(Ok(msgs));
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => warn!("Invalid answer: {}", e),
+                }
+            }
+        }
+    }
+}
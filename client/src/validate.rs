@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use addrcore::{FamilyCounts, Response};
+
+use client::Hook;
+
+use crate::report::{report, ErrorFormat, Failure};
+
+/// Hook checking every response as the server sent it, reporting each
+/// violation to stderr as it's found. Meant for testing other servers
+/// against this client.
+pub struct Validator {
+    errors: ErrorFormat,
+    violations: AtomicU64,
+}
+
+impl Validator {
+    pub fn new(errors: ErrorFormat) -> Self {
+        Validator { errors, violations: AtomicU64::new(0) }
+    }
+
+    /// Turns a successful run into a failed one if any response was
+    /// invalid, telling how many violations there were.
+    pub fn verdict(&self, status: ExitCode) -> ExitCode {
+        let violations = self.violations.load(Ordering::Relaxed);
+        if violations == 0 || status != ExitCode::SUCCESS {
+            return status;
+        }
+        report(self.errors, Failure::Invalid, &format!("{} violation(s) found", violations))
+    }
+}
+
+impl Hook for Validator {
+    fn on_response(&self, _resp: &mut Response) {}
+
+    fn on_received(&self, requested: Option<u32>, resp: &Response) {
+        for violation in check(requested, resp) {
+            self.violations.fetch_add(1, Ordering::Relaxed);
+            let msg = format!("Response {} is invalid: {}", resp.index, violation);
+            report(self.errors, Failure::Invalid, &msg);
+        }
+    }
+}
+
+/// What's wrong with a response to a request for `requested` addresses:
+/// a different number of addresses or TTLs, family counts that don't match
+/// the addresses, repeated addresses, port 0 and addresses no host can have.
+fn check(requested: Option<u32>, resp: &Response) -> Vec<String> {
+    let mut violations = Vec::new();
+    match requested {
+        Some(n) if n as usize != resp.addrs.len() => {
+            violations.push(format!("{} address(es) for a request of {}", resp.addrs.len(), n))
+        }
+        _ => (),
+    }
+    match resp.ttls {
+        Some(ref ttls) if ttls.len() != resp.addrs.len() => violations.push(format!(
+            "{} TTL(s) for {} address(es)",
+            ttls.len(),
+            resp.addrs.len()
+        )),
+        _ => (),
+    }
+    match resp.families {
+        Some(families) if families != FamilyCounts::of(&resp.addrs) => {
+            let actual = FamilyCounts::of(&resp.addrs);
+            violations.push(format!(
+                "{} IPv4 and {} IPv6 address(es) counted as {} and {}",
+                actual.v4, actual.v6, families.v4, families.v6
+            ))
+        }
+        _ => (),
+    }
+    let mut seen = HashSet::with_capacity(resp.addrs.len());
+    for addr in resp.addrs.iter() {
+        if !seen.insert(addr) {
+            violations.push(format!("{} is repeated", addr));
+        }
+        if addr.port() == 0 {
+            violations.push(format!("{} has port 0", addr));
+        }
+        if let Some(range) = reserved(addr.ip()) {
+            violations.push(format!("{} is {}", addr, range));
+        }
+    }
+    violations
+}
+
+/// The kind of range `ip` is in if it can't be a host's unicast address.
+fn reserved(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) if ip.is_unspecified() => Some("unspecified"),
+        IpAddr::V4(ip) if ip.is_broadcast() => Some("broadcast"),
+        IpAddr::V4(ip) if ip.is_multicast() => Some("multicast"),
+        // "This network" (0.0.0.0/8) and the future use range (240.0.0.0/4).
+        IpAddr::V4(ip) if ip.octets()[0] == 0 || ip.octets()[0] >= 240 => Some("reserved"),
+        IpAddr::V6(ip) if ip.is_unspecified() => Some("unspecified"),
+        IpAddr::V6(ip) if ip.is_multicast() => Some("multicast"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(addrs: &[&str]) -> Response {
+        let addrs: Vec<_> = addrs.iter().map(|addr| addr.parse().unwrap()).collect();
+        let families = Some(FamilyCounts::of(&addrs));
+        Response { index: 0, ttls: Some(vec![60; addrs.len()]), addrs, families, seed: None }
+    }
+
+    #[test]
+    fn passes_valid_responses() {
+        let resp = response(&["93.184.216.34:443", "[2001:db8::1]:80"]);
+        assert!(check(Some(2), &resp).is_empty());
+        // Subscription updates answer no request of a set size.
+        assert!(check(None, &resp).is_empty());
+    }
+
+    #[test]
+    fn finds_every_violation() {
+        let mut resp = response(&["10.0.0.1:1", "10.0.0.1:1", "10.0.0.2:0"]);
+        resp.ttls = Some(vec![60]);
+        resp.families = Some(FamilyCounts { v4: 2, v6: 1 });
+        let violations = check(Some(2), &resp);
+        assert_eq!(
+            violations,
+            [
+                "3 address(es) for a request of 2",
+                "1 TTL(s) for 3 address(es)",
+                "3 IPv4 and 0 IPv6 address(es) counted as 2 and 1",
+                "10.0.0.1:1 is repeated",
+                "10.0.0.2:0 has port 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_addresses_no_host_can_have() {
+        let ranges = [
+            ("0.0.0.0:1", "unspecified"),
+            ("255.255.255.255:1", "broadcast"),
+            ("224.0.0.1:1", "multicast"),
+            ("0.1.2.3:1", "reserved"),
+            ("240.0.0.1:1", "reserved"),
+            ("[::]:1", "unspecified"),
+            ("[ff02::1]:1", "multicast"),
+        ];
+        for (addr, range) in ranges {
+            assert_eq!(check(Some(1), &response(&[addr])), [format!("{} is {}", addr, range)]);
+        }
+    }
+
+    #[test]
+    fn fails_runs_with_violations() {
+        let validator = Validator::new(ErrorFormat::Text);
+        validator.on_received(Some(1), &response(&["10.0.0.1:1"]));
+        assert_eq!(validator.verdict(ExitCode::SUCCESS), ExitCode::SUCCESS);
+        validator.on_received(Some(2), &response(&["10.0.0.1:1"]));
+        assert_eq!(validator.verdict(ExitCode::SUCCESS), ExitCode::from(Failure::Invalid));
+        // Failing for another reason first is what's reported.
+        let failed = ExitCode::from(Failure::Timeout);
+        assert_eq!(validator.verdict(failed), failed);
+    }
+}
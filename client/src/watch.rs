@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{error, info};
+
+use tokio::time::{self, MissedTickBehavior};
+
+use addrcore::{Constraints, Request};
+
+use client::Client;
+
+use crate::bench::parse_duration;
+use crate::report::{report, Failure};
+use crate::stats::Stats;
+use crate::Output;
+
+/// Polling options.
+#[derive(clap::Args)]
+pub struct WatchArgs {
+    /// Addresses to request every time.
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    count: u32,
+    /// How long to wait between requests, e.g. 5s, 500ms or 1m.
+    #[arg(long, value_name = "DURATION", default_value = "5s", value_parser = parse_duration)]
+    interval: Duration,
+    /// Print only the addresses that appeared (+) or disappeared (-) since
+    /// the previous response.
+    #[arg(long)]
+    diff: bool,
+}
+
+/// Requests addresses every interval until Ctrl-C, printing every response
+/// or what changed since the previous one. Failed requests are reported and
+/// retried on the next tick, unless the connection is lost.
+pub async fn run(client: Client, args: WatchArgs, output: &Output, stats: &mut Stats) -> ExitCode {
+    let mut status = ExitCode::SUCCESS;
+    let mut ticks = time::interval(args.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut previous = None;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => (),
+            _ = &mut ctrl_c => break,
+        }
+        let req = Request { num_addrs: args.count, constraints: Constraints::default() };
+        let start = Instant::now();
+        stats.sent();
+        let result = tokio::select! {
+            result = client.request(req) => result,
+            _ = &mut ctrl_c => break,
+        };
+        match result {
+            Ok(resp) => {
+                let latency = start.elapsed();
+                stats.answered(latency);
+                if args.diff {
+                    let text = diff(previous.as_deref(), &resp.addrs);
+                    output.save(&text);
+                    output.emit(&text).await;
+                    previous = Some(resp.addrs);
+                } else {
+                    output.print(&resp, &output.meta(false, None, Some(latency))).await;
+                }
+            }
+            Err(e) => {
+                stats.failed();
+                let failure = Failure::of(&e);
+                let code = report(output.errors, failure, &format!("Request failed: {}", e));
+                if let Failure::Connection | Failure::Protocol = failure {
+                    status = code;
+                    break;
+                }
+            }
+        }
+    }
+    info!("Stopped watching");
+    if let Err(e) = client.close().await {
+        error!("Could not close connection: {}", e);
+    }
+    status
+}
+
+/// What changed between two responses, under a line with the time and the
+/// number of changes. Every address is new in the first response.
+fn diff(previous: Option<&[SocketAddr]>, current: &[SocketAddr]) -> String {
+    let previous = previous.unwrap_or_default();
+    let before: HashSet<_> = previous.iter().collect();
+    let after: HashSet<_> = current.iter().collect();
+    let added: Vec<_> = current.iter().filter(|addr| !before.contains(addr)).collect();
+    let removed: Vec<_> = previous.iter().filter(|addr| !after.contains(addr)).collect();
+    let now = humantime::format_rfc3339_seconds(SystemTime::now());
+    let mut text = format!("# {}: +{} -{}\n", now, added.len(), removed.len());
+    for addr in added {
+        text += &format!("+ {}\n", addr);
+    }
+    for addr in removed {
+        text += &format!("- {}\n", addr);
+    }
+    text
+}
@@ -0,0 +1,200 @@
+use std::io;
+
+use bytes::{BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::wire::bytes::{Reader, Writer};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 8;
+const MAC_LEN: usize = 32;
+
+/// Size added to each frame's payload by authentication.
+pub const OVERHEAD: usize = NONCE_LEN + MAC_LEN;
+
+/// Which way a frame travels. Part of the MAC so that frames can't be
+/// reflected back at their sender.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Direction {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Direction::ClientToServer => b"client-to-server",
+            Direction::ServerToClient => b"server-to-client",
+        }
+    }
+}
+
+/// Authenticates frames with an HMAC-SHA256 keyed by a pre-shared secret.
+///
+/// An authenticated payload is laid out as follows:
+///
+/// <64:nonce><payload><256:mac>
+///
+/// where the MAC covers the direction, session, nonce and payload. Nonces
+/// start at 0 and increase by one with every frame sent, and a frame whose
+/// nonce isn't greater than the last one received is rejected as a replay.
+///
+/// The session is a random value the server picks for every connection and
+/// announces in its info, so that frames sealed on one connection are
+/// rejected on any other. That info, the first frame the server sends, is
+/// the only one not bound to the session, which the client doesn't know yet.
+#[derive(Clone)]
+pub struct FrameAuth {
+    key: Vec<u8>,
+    send_dir: Direction,
+    recv_dir: Direction,
+    /// Unknown to the client until it receives the server's info.
+    session: Option<u64>,
+    next_nonce: u64,
+    last_nonce: Option<u64>,
+}
+
+impl FrameAuth {
+    /// Creates the authenticator for the endpoint sending frames in
+    /// `send_dir`.
+    pub fn new(key: &[u8], send_dir: Direction) -> Self {
+        let recv_dir = match send_dir {
+            Direction::ClientToServer => Direction::ServerToClient,
+            Direction::ServerToClient => Direction::ClientToServer,
+        };
+        FrameAuth {
+            key: key.to_vec(),
+            send_dir,
+            recv_dir,
+            session: None,
+            next_nonce: 0,
+            last_nonce: None,
+        }
+    }
+
+    /// The session frames are bound to, if known.
+    pub fn session(&self) -> Option<u64> {
+        self.session
+    }
+
+    /// Binds every frame from now on to `session`.
+    pub fn set_session(&mut self, session: u64) {
+        self.session = Some(session);
+    }
+
+    fn mac(&self, dir: Direction, nonce: &[u8], payload: &[u8]) -> HmacSha256 {
+        // HMAC accepts keys of any length.
+        let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+        mac.update(dir.label());
+        let announces_session = dir == Direction::ServerToClient && nonce == [0; NONCE_LEN];
+        if let Some(session) = self.session.filter(|_| !announces_session) {
+            let mut bytes = BytesMut::with_capacity(8);
+            Writer::new(&mut bytes).u64(session);
+            mac.update(&bytes);
+        }
+        mac.update(nonce);
+        mac.update(payload);
+        mac
+    }
+
+    /// Wraps an outgoing payload with the next nonce and its MAC.
+    pub fn seal(&mut self, payload: &[u8]) -> BytesMut {
+        let mut sealed = BytesMut::with_capacity(OVERHEAD + payload.len());
+        Writer::new(&mut sealed).u64(self.next_nonce);
+        self.next_nonce += 1;
+        let code = self.mac(self.send_dir, &sealed, payload).finalize().into_bytes();
+
+        sealed.put_slice(payload);
+        sealed.put_slice(&code);
+        sealed
+    }
+
+    /// Verifies an incoming authenticated payload and returns the payload
+    /// within, rejecting forged or replayed frames.
+    pub fn open(&mut self, mut sealed: BytesMut) -> io::Result<BytesMut> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if sealed.len() < OVERHEAD {
+            return Err(invalid("Frame too short to be authenticated"));
+        }
+        let code = sealed.split_off(sealed.len() - MAC_LEN);
+        let payload = sealed.split_off(NONCE_LEN);
+        let nonce = sealed;
+        self.mac(self.recv_dir, &nonce, &payload)
+            .verify_slice(&code)
+            .map_err(|_| invalid("Invalid frame MAC"))?;
+        let nonce = Reader::new(&nonce).u64()?;
+        if let Some(last) = self.last_nonce {
+            if nonce <= last {
+                return Err(invalid("Replayed frame"));
+            }
+        }
+        self.last_nonce = Some(nonce);
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open() {
+        let mut client = FrameAuth::new(b"secret", Direction::ClientToServer);
+        let mut server = FrameAuth::new(b"secret", Direction::ServerToClient);
+        for payload in [&b"hello"[..], &b""[..], &b"world"[..]].iter() {
+            let sealed = client.seal(payload);
+            assert_eq!(&server.open(sealed).unwrap()[..], *payload);
+        }
+    }
+
+    #[test]
+    fn reject_tampered() {
+        let mut client = FrameAuth::new(b"secret", Direction::ClientToServer);
+        let mut server = FrameAuth::new(b"secret", Direction::ServerToClient);
+        let mut sealed = client.seal(b"hello");
+        sealed[NONCE_LEN] ^= 1;
+        assert!(server.open(sealed).is_err());
+    }
+
+    #[test]
+    fn reject_wrong_key() {
+        let mut client = FrameAuth::new(b"secret", Direction::ClientToServer);
+        let mut server = FrameAuth::new(b"other", Direction::ServerToClient);
+        assert!(server.open(client.seal(b"hello")).is_err());
+    }
+
+    #[test]
+    fn reject_replay() {
+        let mut client = FrameAuth::new(b"secret", Direction::ClientToServer);
+        let mut server = FrameAuth::new(b"secret", Direction::ServerToClient);
+        let sealed = client.seal(b"hello");
+        assert!(server.open(sealed.clone()).is_ok());
+        assert!(server.open(sealed).is_err());
+    }
+
+    #[test]
+    fn reject_other_session() {
+        let mut server = FrameAuth::new(b"secret", Direction::ServerToClient);
+        server.set_session(1);
+        let mut client = FrameAuth::new(b"secret", Direction::ClientToServer);
+        // The first frame from the server is opened before the session is
+        // known.
+        assert!(client.open(server.seal(b"info")).is_ok());
+        client.set_session(1);
+        assert!(client.open(server.seal(b"hello")).is_ok());
+        assert!(server.open(client.seal(b"hello")).is_ok());
+
+        let mut other = FrameAuth::new(b"secret", Direction::ServerToClient);
+        other.set_session(2);
+        assert!(other.open(client.seal(b"hello")).is_err());
+    }
+
+    #[test]
+    fn reject_reflection() {
+        let mut client = FrameAuth::new(b"secret", Direction::ClientToServer);
+        let sealed = client.seal(b"hello");
+        assert!(client.open(sealed).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! A datagram variant of the protocol, for serving requests over UDP.
+//!
+//! A client sends one message per datagram, encoded as
+//!
+//! <32:id><payload>
+//!
+//! where the ID is chosen by the client to match answers to requests and the
+//! payload is encoded as in a frame. The server answers with the messages it
+//! would send over a stream, each encoded as `<32:len><payload>`, which are
+//! concatenated and split into chunks that each fit a datagram:
+//!
+//! <32:id><16:seq><16:total><chunk>
+//!
+//! Chunks may arrive out of order, so the client puts the answer back
+//! together once all `total` have arrived. Nothing is resent by the server,
+//! leaving it to the client to retry requests whose answer is incomplete.
+//! Datagrams aren't authenticated.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::wire::bytes::{Reader, Writer};
+use crate::{ClientMessage, ServerMessage};
+
+/// Maximum length of a datagram, small enough not to be fragmented on
+/// virtually any path.
+pub const MAX_DATAGRAM_LEN: usize = 1200;
+
+const CHUNK_HEADER_LEN: usize = 8;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A piece of the answer to the request with the given ID.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub id: u32,
+    pub seq: u16,
+    pub total: u16,
+    pub data: Bytes,
+}
+
+/// Splits the messages answering the request with the given ID into chunks
+/// that each fit a datagram.
+pub fn split(id: u32, msgs: &[ServerMessage]) -> io::Result<Vec<Chunk>> {
+    let mut payload = BytesMut::new();
+    for msg in msgs {
+        let mut body = BytesMut::new();
+        crate::encode_server_message(msg, &mut body)?;
+        let mut writer = Writer::new(&mut payload);
+        writer.u32(body.len() as u32);
+        writer.slice(&body);
+    }
+    let payload = payload.freeze();
+    let chunk_len = MAX_DATAGRAM_LEN - CHUNK_HEADER_LEN;
+    let total = payload.len().div_ceil(chunk_len).max(1);
+    if total > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Answer too large"));
+    }
+    let chunks = (0..total)
+        .map(|seq| {
+            let end = payload.len().min((seq + 1) * chunk_len);
+            Chunk {
+                id,
+                seq: seq as u16,
+                total: total as u16,
+                data: payload.slice(seq * chunk_len..end),
+            }
+        })
+        .collect();
+    Ok(chunks)
+}
+
+struct Partial {
+    chunks: Vec<Option<Bytes>>,
+    missing: usize,
+    started: Instant,
+}
+
+/// Puts answers back together from their chunks.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<u32, Partial>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Reassembler::default()
+    }
+
+    /// Adds a chunk, returning the ID and messages of its answer once every
+    /// chunk of it has arrived. Duplicate chunks are ignored.
+    pub fn push(&mut self, chunk: Chunk) -> io::Result<Option<(u32, Vec<ServerMessage>)>> {
+        let total = chunk.total as usize;
+        if chunk.seq >= chunk.total {
+            return Err(invalid("Chunk sequence number out of range"));
+        }
+        let partial = self.partial.entry(chunk.id).or_insert_with(|| Partial {
+            chunks: vec![None; total],
+            missing: total,
+            started: Instant::now(),
+        });
+        if partial.chunks.len() != total {
+            self.partial.remove(&chunk.id);
+            return Err(invalid("Inconsistent number of chunks"));
+        }
+        let slot = &mut partial.chunks[chunk.seq as usize];
+        if slot.is_none() {
+            *slot = Some(chunk.data);
+            partial.missing -= 1;
+        }
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&chunk.id).unwrap();
+        let mut payload = BytesMut::new();
+        for data in partial.chunks.into_iter().flatten() {
+            payload.extend_from_slice(&data);
+        }
+        let mut reader = Reader::new(&payload);
+        let mut msgs = Vec::new();
+        while !reader.is_empty() {
+            let len = reader.u32()? as usize;
+            msgs.push(crate::decode_server_message(reader.take(len)?)?);
+        }
+        Ok(Some((chunk.id, msgs)))
+    }
+
+    /// Drops answers still incomplete after `max_age`, whose missing chunks
+    /// were most likely lost.
+    pub fn expire(&mut self, max_age: Duration) {
+        self.partial.retain(|_, partial| partial.started.elapsed() < max_age);
+    }
+}
+
+/// Client side datagram codec for use with `UdpFramed`: encodes messages
+/// tagged with an ID and decodes chunks of their answers.
+#[derive(Default)]
+pub struct ClientDatagramCodec;
+
+impl Encoder<(u32, ClientMessage)> for ClientDatagramCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, (id, msg): (u32, ClientMessage), buf: &mut BytesMut) -> io::Result<()> {
+        let start = buf.len();
+        Writer::new(buf).u32(id);
+        crate::encode_client_message(&msg, buf);
+        if buf.len() - start > MAX_DATAGRAM_LEN {
+            buf.truncate(start);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Message too large"));
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for ClientDatagramCodec {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Chunk>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        // Every datagram is consumed whole, even if invalid.
+        let datagram = buf.split().freeze();
+        let mut reader = Reader::new(&datagram);
+        let id = reader.u32()?;
+        let seq = reader.u16()?;
+        let total = reader.u16()?;
+        let data = datagram.slice(CHUNK_HEADER_LEN..);
+        Ok(Some(Chunk { id, seq, total, data }))
+    }
+}
+
+/// Server side datagram codec for use with `UdpFramed`: decodes messages
+/// tagged with an ID and encodes chunks of their answers.
+#[derive(Default)]
+pub struct ServerDatagramCodec;
+
+impl Encoder<Chunk> for ServerDatagramCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, chunk: Chunk, buf: &mut BytesMut) -> io::Result<()> {
+        let mut writer = Writer::new(buf);
+        writer.u32(chunk.id);
+        writer.u16(chunk.seq);
+        writer.u16(chunk.total);
+        writer.slice(&chunk.data);
+        Ok(())
+    }
+}
+
+impl Decoder for ServerDatagramCodec {
+    type Item = (u32, ClientMessage);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<(u32, ClientMessage)>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        // Every datagram is consumed whole, even if invalid.
+        let datagram = buf.split();
+        let mut reader = Reader::new(&datagram);
+        let id = reader.u32()?;
+        let msg = crate::decode_client_message(reader.rest())?;
+        Ok(Some((id, msg)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Constraints, Request, Response};
+
+    fn response(index: u32, n: u32) -> ServerMessage {
+        let addrs = (0..n).map(|i| ([10, 0, (i >> 8) as u8, i as u8], 80).into()).collect();
+        ServerMessage::Response(Response { index, addrs, ttls: None, families: None, seed: None })
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        // 300 addresses of 6 bytes each take two chunks.
+        let msgs = vec![response(0, 300), response(1, 3)];
+        let chunks = split(7, &msgs).unwrap();
+        assert_eq!(chunks.len(), 2);
+
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(chunks[1].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(chunks[1].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(chunks[0].clone()).unwrap(), Some((7, msgs)));
+    }
+
+    #[test]
+    fn chunks_fit_datagrams() {
+        let mut codec = ServerDatagramCodec;
+        for chunk in split(1, &[response(0, 1000)]).unwrap() {
+            let mut buf = BytesMut::new();
+            codec.encode(chunk.clone(), &mut buf).unwrap();
+            assert!(buf.len() <= MAX_DATAGRAM_LEN);
+            assert_eq!(ClientDatagramCodec.decode(&mut buf).unwrap(), Some(chunk));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn request_roundtrip() {
+        let req = Request { num_addrs: 5, constraints: Constraints::default() };
+        let mut buf = BytesMut::new();
+        ClientDatagramCodec.encode((42, ClientMessage::Request(req)), &mut buf).unwrap();
+        let decoded = ServerDatagramCodec.decode(&mut buf).unwrap();
+        assert_eq!(decoded, Some((42, ClientMessage::Request(req))));
+        assert_eq!(ServerDatagramCodec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn oversized_request() {
+        let mut buf = BytesMut::new();
+        let msg = ClientMessage::Batch(vec![1; MAX_DATAGRAM_LEN / 4]);
+        assert!(ClientDatagramCodec.encode((1, msg), &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+}
@@ -1,253 +1,2257 @@
+use std::fmt;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 
 use log::*;
-use simplelog::*;
 
-use tokio::codec::{Decoder, Encoder};
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
 
-/// Client request containign the number of random IPv4 addresses it wishes to
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+mod auth;
+pub mod datagram;
+pub mod wire;
+
+use crate::auth::{Direction, FrameAuth};
+use crate::wire::bytes::{Reader, Writer};
+
+/// Client request containign the number of random addresses it wishes to
 /// receive from server.
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Request {
     pub num_addrs: u32,
+    #[serde(default)]
+    pub constraints: Constraints,
+}
+
+/// Address family of the requested addresses.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    V4,
+    V6,
 }
 
-/// Server response containing random IPv4 addresses.
-#[derive(Clone, Debug, PartialEq)]
+/// A block of IP addresses in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    fn max_prefix_len(&self) -> u8 {
+        match self.addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    pub fn family(&self) -> Family {
+        match self.addr {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    /// Returns whether `ip` lies within this block.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .parse()
+            .map_err(|_| format!("Invalid CIDR address in {}", s))?;
+        let prefix_len = parts
+            .next()
+            .ok_or_else(|| format!("Missing prefix length in {}", s))?
+            .parse()
+            .map_err(|_| format!("Invalid prefix length in {}", s))?;
+        let cidr = Cidr { addr, prefix_len };
+        if prefix_len > cidr.max_prefix_len() {
+            return Err(format!("Prefix length too long in {}", s));
+        }
+        Ok(cidr)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Optional constraints on the addresses generated for a request. The
+/// default places no constraints.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Constraints {
+    /// Only addresses of this family, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family: Option<Family>,
+    /// Only ports in this inclusive range, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ports: Option<(u16, u16)>,
+    /// Only addresses within this block, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cidr: Option<Cidr>,
+    /// Generate the addresses from this seed, as echoed in an earlier
+    /// response, so as to get the same addresses again. In a transaction,
+    /// the seed of the first request applies to all of them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// Messages a client may send to the server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// A single request, answered with a response with index 0.
+    Request(Request),
+    /// Several counts in one frame, without constraints. The server replies
+    /// with one response per count, tagged with the count's index in the
+    /// batch, or with a single error if the batch is over the rate limit.
+    Batch(Vec<u32>),
+    /// Changes the server's log level for the sending connection only. Only
+    /// honored if the token matches the server's debug token. No response is
+    /// sent.
+    Debug { token: String, level: LevelFilter },
+    /// Asks the server to push `count` fresh addresses every `interval_ms`
+    /// milliseconds until unsubscribed. Replaces any active subscription.
+    /// Rejected with an error indexed `SUBSCRIPTION_INDEX`.
+    Subscribe { count: u32, interval_ms: u32 },
+    /// Cancels the active subscription, if any.
+    Unsubscribe,
+    /// Announces that the client is about to close the connection. Nothing is
+    /// read after this.
+    Goodbye,
+    /// Grants the server permission to send this many more address entries.
+    /// Flow control is off for a connection until its first grant, after
+    /// which the server splits and holds back responses so as to never exceed
+    /// the granted credits.
+    Credits(u32),
+    /// Several requests served all or nothing: either every request is
+    /// answered with a response tagged with its index in the transaction, or
+    /// a single error is sent for the first request that couldn't be served.
+    /// No address appears twice across the responses.
+    Transaction(Vec<Request>),
+    /// Authenticates the connection with a token the server knows. Servers
+    /// that require authentication reject everything else until this is
+    /// sent. No response is sent for a valid token.
+    Auth { token: String },
+}
+
+/// Server response containing random addresses.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Response {
+    /// Index of the count this response answers within its batch, or 0 for
+    /// single requests.
+    pub index: u32,
     pub addrs: Vec<SocketAddr>,
+    /// How long each address should be considered valid for, in seconds, if
+    /// the server set a TTL. Has one entry per address.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttls: Option<Vec<u32>>,
+    /// How many of the addresses are of each family, as the server counted
+    /// them, if it did. Servers count them for responses holding IPv6
+    /// addresses, so that clients can check the mix they were served.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub families: Option<FamilyCounts>,
+    /// Seed the addresses were generated from, if the server is seeded or
+    /// the request asked for one. Requesting as many addresses with the same
+    /// constraints and this seed regenerates them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+/// Counts of the addresses of a response of each family.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FamilyCounts {
+    pub v4: u32,
+    pub v6: u32,
+}
+
+impl FamilyCounts {
+    pub fn of(addrs: &[SocketAddr]) -> Self {
+        let v6 = addrs.iter().filter(|addr| addr.is_ipv6()).count() as u32;
+        FamilyCounts { v4: addrs.len() as u32 - v6, v6 }
+    }
+}
+
+impl Response {
+    /// Appends the addresses of `other`, e.g. when reassembling a response
+    /// from partial chunks. TTLs and family counts are kept only if both
+    /// carry them, and the seed if either does.
+    pub fn append(&mut self, other: Response) {
+        self.addrs.extend(other.addrs);
+        self.ttls = match (self.ttls.take(), other.ttls) {
+            (Some(mut ttls), Some(other)) => {
+                ttls.extend(other);
+                Some(ttls)
+            }
+            _ => None,
+        };
+        self.families = match (self.families, other.families) {
+            (Some(ours), Some(theirs)) => {
+                Some(FamilyCounts { v4: ours.v4 + theirs.v4, v6: ours.v6 + theirs.v6 })
+            }
+            _ => None,
+        };
+        self.seed = self.seed.or(other.seed);
+    }
+
+    /// Returns when each address expires, given the time the response was
+    /// received, or `None` if the response carries no TTLs.
+    pub fn expiries(&self, received: Instant) -> Option<Vec<Instant>> {
+        self.ttls.as_ref().map(|ttls| {
+            ttls.iter()
+                .map(|ttl| received + Duration::from_secs(*ttl as u64))
+                .collect()
+        })
+    }
+
+    /// Removes the addresses that have expired by `now`, given the time the
+    /// response was received. Addresses without a TTL never expire.
+    pub fn remove_expired(&mut self, received: Instant, now: Instant) {
+        let expiries = match self.expiries(received) {
+            Some(expiries) => expiries,
+            None => return,
+        };
+        let ttls = self.ttls.take().unwrap_or_default();
+        let (addrs, ttls): (Vec<SocketAddr>, Vec<u32>) = self.addrs
+            .drain(..)
+            .zip(ttls)
+            .zip(expiries)
+            .filter(|(_, expiry)| *expiry > now)
+            .map(|(entry, _)| entry)
+            .unzip();
+        self.addrs = addrs;
+        self.ttls = Some(ttls);
+        // Counts the addresses left, if the server counted them.
+        self.families = self.families.map(|_| FamilyCounts::of(&self.addrs));
+    }
 }
 
-pub struct ClientToServerCodec;
+/// Messages the server may send to a client.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "body", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Reply to a request or to one count of a batch.
+    Response(Response),
+    /// Addresses pushed by an active subscription. The index is the sequence
+    /// number of the update within the subscription.
+    Update(Response),
+    /// A leading chunk of a response that had to be split due to flow
+    /// control. The chunks' addresses are followed by those of the eventual
+    /// `Response` with the same index.
+    Partial(Response),
+    /// The request with the given index couldn't be served.
+    Error(ErrorResponse),
+    /// The server's capabilities, sent as the first message on every
+    /// connection.
+    Info(ServerInfo),
+    /// Text from the server's operator, such as a shutdown notice, sent
+    /// unsolicited to every client.
+    Notice(String),
+    /// The client has used up most of its rate limit and should slow down
+    /// before its requests are rejected.
+    QuotaWarning(QuotaWarning),
+}
 
-/// Encoded client request format is as follows:
+/// How much of its rate limit a client has left.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuotaWarning {
+    /// Requests the client may send right away.
+    pub remaining: u32,
+    /// Requests the client may send in a burst after idling.
+    pub burst: u32,
+    /// Requests the client may send a second once its burst is used up.
+    pub per_sec: u32,
+}
+
+/// Advertisement of what the server supports, so clients can adapt before
+/// sending requests.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub version: String,
+    /// Names of the optional features the server supports, e.g. `"tls"`.
+    pub features: Vec<String>,
+    /// Maximum frame length the server accepts.
+    pub max_frame_len: u32,
+    /// Maximum number of addresses per request, if limited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_addrs: Option<u32>,
+    /// Maximum number of requests per second per client, if limited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_sec: Option<u32>,
+    /// The session frames of the connection are bound to, if they're
+    /// authenticated. Set by the server's codec as it encodes the info.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<u64>,
+}
+
+impl ServerInfo {
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// Reason a request was rejected.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The request's constraints can't be satisfied.
+    Unsatisfiable,
+    /// The client sent too many requests and may try again after this long.
+    RateLimited { retry_after_ms: u32 },
+    /// More addresses were requested than the server serves at once.
+    TooManyAddrs { max: u32 },
+    /// The connection isn't authenticated, or the token isn't valid.
+    AuthFailed,
+}
+
+impl ErrorCode {
+    fn write(self, writer: &mut Writer) {
+        match self {
+            ErrorCode::Unsatisfiable => writer.u8(1),
+            ErrorCode::RateLimited { retry_after_ms } => {
+                writer.u8(2);
+                writer.u32(retry_after_ms);
+            }
+            ErrorCode::TooManyAddrs { max } => {
+                writer.u8(3);
+                writer.u32(max);
+            }
+            ErrorCode::AuthFailed => writer.u8(4),
+        }
+    }
+
+    fn read(reader: &mut Reader) -> io::Result<ErrorCode> {
+        match reader.u8()? {
+            1 => Ok(ErrorCode::Unsatisfiable),
+            2 => Ok(ErrorCode::RateLimited { retry_after_ms: reader.u32()? }),
+            3 => Ok(ErrorCode::TooManyAddrs { max: reader.u32()? }),
+            4 => Ok(ErrorCode::AuthFailed),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown error code")),
+        }
+    }
+}
+
+/// Error reply in place of the response to the request with the same index,
+/// or to a subscription if the index is `SUBSCRIPTION_INDEX`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub index: u32,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+const TAG_REQUEST: u8 = 0;
+const TAG_BATCH: u8 = 1;
+const TAG_DEBUG: u8 = 2;
+const TAG_SUBSCRIBE: u8 = 3;
+const TAG_UNSUBSCRIBE: u8 = 4;
+const TAG_GOODBYE: u8 = 5;
+const TAG_CREDITS: u8 = 6;
+const TAG_TRANSACTION: u8 = 7;
+const TAG_AUTH: u8 = 8;
+
+const TAG_RESPONSE: u8 = 0;
+const TAG_UPDATE: u8 = 1;
+const TAG_PARTIAL: u8 = 2;
+const TAG_ERROR: u8 = 3;
+const TAG_INFO: u8 = 4;
+const TAG_NOTICE: u8 = 5;
+const TAG_QUOTA_WARNING: u8 = 6;
+
+/// Set in a request's constraint flags for each constraint present.
+const CONSTRAINT_FAMILY: u8 = 1;
+const CONSTRAINT_PORTS: u8 = 2;
+const CONSTRAINT_CIDR: u8 = 4;
+const CONSTRAINT_SEED: u8 = 8;
+
+/// Set in a response's flags if every entry is followed by its TTL.
+const FLAG_TTL: u8 = 1;
+/// Set in a response's flags if every entry starts with its IP version, as
+/// some are IPv6. Responses of IPv4 addresses alone are encoded without it.
+const FLAG_MIXED: u8 = 2;
+/// Set in a response's flags if they're followed by its family counts.
+const FLAG_FAMILIES: u8 = 4;
+/// Set in a response's flags if they're followed by its seed, after any
+/// family counts.
+const FLAG_SEED: u8 = 8;
+
+/// Maximum length of a frame's payload. Anything longer is rejected by the
+/// decoders before being buffered in full.
+pub const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// Index of an error rejecting a subscription, which answers no request, so
+/// that it isn't taken for the answer to one in flight.
+pub const SUBSCRIPTION_INDEX: u32 = u32::MAX;
+
+/// Marks a point in the stream where a frame starts. Its length field is
+/// larger than any valid frame's, so it can't be mistaken for one. Every
+/// decoder skips markers found between frames, while lenient decoders also
+/// scan forward to the next marker to recover from corrupt framing.
+const SYNC_MARKER: [u8; 8] = [0xff, 0xff, 0xff, 0xff, b'S', b'Y', b'N', b'C'];
+
+/// What a decoder does with input it can't decode.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum DecodePolicy {
+    /// Fail, tearing down the connection. The default.
+    #[default]
+    Strict,
+    /// Skip frames that can't be decoded or authenticated, and after corrupt
+    /// framing, discard input up to the next sync marker. Encoders precede
+    /// every frame with a sync marker so that the peer can do the same.
+    Lenient,
+}
+
+/// How the payload of every frame is encoded. Both ends of a connection must
+/// use the same format, as nothing on the wire tells them apart.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum WireFormat {
+    /// The compact format described at `encode_client_message` and
+    /// `encode_server_message`. The default.
+    #[default]
+    Binary,
+    /// A JSON object per message, as described at `encode_json`, e.g. for
+    /// clients written in languages without an implementation of the binary
+    /// format.
+    Json,
+}
+
+/// Length of the length field preceding every frame.
+const LENGTH_FIELD_LEN: usize = 4;
+
+/// Length delimited framing shared by both codecs, optionally authenticating
+/// every frame with a pre-shared key.
+#[derive(Clone)]
+struct Framing {
+    frames: LengthDelimitedCodec,
+    auth: Option<FrameAuth>,
+    policy: DecodePolicy,
+    /// Whether input is being discarded up to the next sync marker.
+    resyncing: bool,
+    /// Whether part of a frame has been decoded, in which case what follows
+    /// can't be a sync marker.
+    mid_frame: bool,
+    /// What was consumed since the last frame was decoded, if recorded for
+    /// a tap.
+    consumed: Option<BytesMut>,
+}
+
+impl Framing {
+    fn new(auth: Option<FrameAuth>) -> Self {
+        let frames = LengthDelimitedCodec::builder()
+            .length_field_length(LENGTH_FIELD_LEN)
+            .max_frame_length(MAX_FRAME_LEN + auth::OVERHEAD)
+            .new_codec();
+        Framing {
+            frames,
+            auth,
+            policy: DecodePolicy::Strict,
+            resyncing: false,
+            mid_frame: false,
+            consumed: None,
+        }
+    }
+
+    fn encode(&mut self, payload: BytesMut, buf: &mut BytesMut) -> io::Result<()> {
+        let payload = match self.auth {
+            Some(ref mut auth) => auth.seal(&payload),
+            None => payload,
+        };
+        if self.policy == DecodePolicy::Lenient {
+            buf.reserve(SYNC_MARKER.len());
+            buf.put_slice(&SYNC_MARKER);
+        }
+        self.frames.encode(payload.freeze(), buf)
+    }
+
+    /// Decodes the next frame's payload with `decode_payload`, applying the
+    /// decode policy to errors.
+    fn decode<T>(
+        &mut self,
+        buf: &mut BytesMut,
+        decode_payload: impl Fn(&[u8]) -> io::Result<T>,
+    ) -> io::Result<Option<T>> {
+        loop {
+            if self.resyncing {
+                match find_sync_marker(buf) {
+                    Some(pos) => {
+                        self.advance(buf, pos);
+                        self.resyncing = false;
+                    }
+                    None => {
+                        // Keep what may be the start of a marker.
+                        let keep = buf.len().min(SYNC_MARKER.len() - 1);
+                        self.advance(buf, buf.len() - keep);
+                        return Ok(None);
+                    }
+                }
+            }
+            if !self.mid_frame {
+                if buf.starts_with(&SYNC_MARKER) {
+                    self.advance(buf, SYNC_MARKER.len());
+                    continue;
+                }
+                if buf.len() < SYNC_MARKER.len() && SYNC_MARKER.starts_with(&buf[..]) {
+                    // Can't tell a marker from a frame yet.
+                    return Ok(None);
+                }
+            }
+            let len = buf.len();
+            let mut head = [0; LENGTH_FIELD_LEN];
+            let n = len.min(LENGTH_FIELD_LEN);
+            head[..n].copy_from_slice(&buf[..n]);
+            let result = self.frames.decode(buf);
+            if let Some(ref mut consumed) = self.consumed {
+                // The length field is consumed as soon as it's there, and
+                // the payload only once all of it is.
+                let payload = match result {
+                    Ok(Some(ref payload)) => &payload[..],
+                    _ => &[],
+                };
+                consumed.extend_from_slice(&head[..len - buf.len() - payload.len()]);
+                consumed.extend_from_slice(payload);
+            }
+            self.mid_frame = matches!(result, Ok(None));
+            let payload = match result {
+                Ok(Some(payload)) => payload,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    if self.policy == DecodePolicy::Strict {
+                        return Err(e);
+                    }
+                    warn!("Invalid framing, resynchronizing: {}", e);
+                    // The corrupt length field was left in place, so skip at
+                    // least its first byte to make progress.
+                    self.advance(buf, 1);
+                    self.resyncing = true;
+                    continue;
+                }
+            };
+            let result = match self.auth {
+                Some(ref mut auth) => auth.open(payload),
+                None => Ok(payload),
+            };
+            match result.and_then(|payload| decode_payload(&payload)) {
+                Ok(item) => return Ok(Some(item)),
+                Err(e) => {
+                    if self.policy == DecodePolicy::Strict {
+                        return Err(e);
+                    }
+                    // The frame was consumed as a whole, so the next one
+                    // follows right after.
+                    warn!("Skipping invalid frame: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Discards the first `n` bytes of `buf`, recording them if tapped.
+    fn advance(&mut self, buf: &mut BytesMut, n: usize) {
+        if let Some(ref mut consumed) = self.consumed {
+            consumed.extend_from_slice(&buf[..n]);
+        }
+        buf.advance(n);
+    }
+}
+
+fn find_sync_marker(buf: &[u8]) -> Option<usize> {
+    buf.windows(SYNC_MARKER.len()).position(|window| window == SYNC_MARKER)
+}
+
+/// Encoded client message payload format is as follows:
+///
+/// <8:tag><32:n>[<8:flags>[<8:family>][<16:lo><16:hi>][<8:version><ip><8:prefix>][<64:seed>]]
+///
+/// for a single request, where n is a 32-bit integer denoting the number of
+/// random addresses, optionally followed by constraints. Flags say which
+/// constraints follow: the family (4 or 6), an inclusive port range, a CIDR
+/// block whose IP is 4 or 16 bytes depending on its version (4 or 6) and a
+/// seed, and
+///
+/// <8:tag><32:n><32:n>...<32:n>
+///
+/// for a batch of counts, and
+///
+/// <8:tag><8:level><token>
+///
+/// for a debug frame, where level is 0 (off) to 5 (trace) and token is the
+/// UTF-8 encoded remainder of the payload, and
 ///
-/// <32:n>
+/// <8:tag><32:count><32:interval_ms>
 ///
-/// Where n is a 32-bit integer denoting the number of random ipv4 addresses
-impl Encoder for ClientToServerCodec {
-    type Item = Request;
+/// to subscribe,
+///
+/// <8:tag><32:n>
+///
+/// to grant n credits,
+///
+/// <8:tag><<16:len><request>>...<<16:len><request>>
+///
+/// for a transaction, where each request is encoded as a single request
+/// without the tag, and
+///
+/// <8:tag><token>
+///
+/// to authenticate, where token is the UTF-8 encoded remainder of the
+/// payload, while unsubscribing and saying goodbye are just the tag.
+fn encode_client_message(msg: &ClientMessage, buf: &mut BytesMut) {
+    let mut writer = Writer::new(buf);
+    match msg {
+        ClientMessage::Request(req) => {
+            writer.u8(TAG_REQUEST);
+            encode_request(req, &mut writer);
+        }
+        ClientMessage::Batch(counts) => {
+            writer.u8(TAG_BATCH);
+            for n in counts.iter() {
+                writer.u32(*n);
+            }
+        }
+        ClientMessage::Debug { token, level } => {
+            writer.u8(TAG_DEBUG);
+            writer.u8(*level as u8);
+            writer.slice(token.as_bytes());
+        }
+        ClientMessage::Subscribe { count, interval_ms } => {
+            writer.u8(TAG_SUBSCRIBE);
+            writer.u32(*count);
+            writer.u32(*interval_ms);
+        }
+        ClientMessage::Unsubscribe => writer.u8(TAG_UNSUBSCRIBE),
+        ClientMessage::Goodbye => writer.u8(TAG_GOODBYE),
+        ClientMessage::Credits(n) => {
+            writer.u8(TAG_CREDITS);
+            writer.u32(*n);
+        }
+        ClientMessage::Transaction(reqs) => {
+            writer.u8(TAG_TRANSACTION);
+            for req in reqs.iter() {
+                let mut body = BytesMut::new();
+                encode_request(req, &mut Writer::new(&mut body));
+                writer.u16(body.len() as u16);
+                writer.slice(&body);
+            }
+        }
+        ClientMessage::Auth { token } => {
+            writer.u8(TAG_AUTH);
+            writer.slice(token.as_bytes());
+        }
+    }
+}
+
+fn encode_request(req: &Request, writer: &mut Writer) {
+    writer.u32(req.num_addrs);
+    encode_constraints(&req.constraints, writer);
+}
+
+fn decode_request(body: &[u8]) -> io::Result<Request> {
+    if body.len() < 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid request length"));
+    }
+    let mut reader = Reader::new(body);
+    let num_addrs = reader.u32()?;
+    let constraints = decode_constraints(reader.rest())?;
+    Ok(Request { num_addrs, constraints })
+}
+
+fn encode_constraints(constraints: &Constraints, writer: &mut Writer) {
+    if *constraints == Constraints::default() {
+        return;
+    }
+    let mut flags = 0;
+    if constraints.family.is_some() {
+        flags |= CONSTRAINT_FAMILY;
+    }
+    if constraints.ports.is_some() {
+        flags |= CONSTRAINT_PORTS;
+    }
+    if constraints.cidr.is_some() {
+        flags |= CONSTRAINT_CIDR;
+    }
+    if constraints.seed.is_some() {
+        flags |= CONSTRAINT_SEED;
+    }
+    writer.u8(flags);
+    if let Some(family) = constraints.family {
+        writer.u8(match family {
+            Family::V4 => 4,
+            Family::V6 => 6,
+        });
+    }
+    if let Some((lo, hi)) = constraints.ports {
+        writer.u16(lo);
+        writer.u16(hi);
+    }
+    if let Some(cidr) = constraints.cidr {
+        writer.ip_addr(cidr.addr);
+        writer.u8(cidr.prefix_len);
+    }
+    if let Some(seed) = constraints.seed {
+        writer.u64(seed);
+    }
+}
+
+fn decode_constraints(body: &[u8]) -> io::Result<Constraints> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut constraints = Constraints::default();
+    if body.is_empty() {
+        return Ok(constraints);
+    }
+    let mut reader = Reader::new(body);
+    let flags = reader.u8()?;
+    if flags & CONSTRAINT_FAMILY != 0 {
+        constraints.family = match reader.u8()? {
+            4 => Some(Family::V4),
+            6 => Some(Family::V6),
+            _ => return Err(invalid("Invalid address family")),
+        };
+    }
+    if flags & CONSTRAINT_PORTS != 0 {
+        let lo = reader.u16()?;
+        let hi = reader.u16()?;
+        constraints.ports = Some((lo, hi));
+    }
+    if flags & CONSTRAINT_CIDR != 0 {
+        let addr = reader.ip_addr()?;
+        let cidr = Cidr { addr, prefix_len: reader.u8()? };
+        if cidr.prefix_len > cidr.max_prefix_len() {
+            return Err(invalid("Invalid CIDR prefix length"));
+        }
+        constraints.cidr = Some(cidr);
+    }
+    if flags & CONSTRAINT_SEED != 0 {
+        constraints.seed = Some(reader.u64()?);
+    }
+    if !reader.is_empty() {
+        return Err(invalid("Invalid constraints length"));
+    }
+    Ok(constraints)
+}
+
+fn level_from_u8(n: u8) -> Option<LevelFilter> {
+    match n {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+fn decode_client_message(payload: &[u8]) -> io::Result<ClientMessage> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let (tag, body) = match payload.split_first() {
+        Some((tag, body)) => (*tag, body),
+        None => return Err(invalid("Empty message")),
+    };
+    let mut reader = Reader::new(body);
+    match tag {
+        TAG_REQUEST => decode_request(body).map(ClientMessage::Request),
+        TAG_BATCH => {
+            if body.is_empty() || !body.len().is_multiple_of(4) {
+                return Err(invalid("Invalid batch length"));
+            }
+            let mut counts = Vec::with_capacity(body.len() / 4);
+            while !reader.is_empty() {
+                counts.push(reader.u32()?);
+            }
+            Ok(ClientMessage::Batch(counts))
+        }
+        TAG_DEBUG => {
+            let (level, token) = match body.split_first() {
+                Some((level, token)) => (*level, token),
+                None => return Err(invalid("Invalid debug frame length")),
+            };
+            let level = level_from_u8(level).ok_or_else(|| invalid("Invalid log level"))?;
+            let token = String::from_utf8(token.to_vec())
+                .map_err(|_| invalid("Debug token must be UTF-8"))?;
+            Ok(ClientMessage::Debug { token, level })
+        }
+        TAG_SUBSCRIBE => {
+            if body.len() != 8 {
+                return Err(invalid("Invalid subscribe length"));
+            }
+            let count = reader.u32()?;
+            let interval_ms = reader.u32()?;
+            Ok(ClientMessage::Subscribe { count, interval_ms })
+        }
+        TAG_UNSUBSCRIBE => {
+            if !body.is_empty() {
+                return Err(invalid("Invalid unsubscribe length"));
+            }
+            Ok(ClientMessage::Unsubscribe)
+        }
+        TAG_GOODBYE => {
+            if !body.is_empty() {
+                return Err(invalid("Invalid goodbye length"));
+            }
+            Ok(ClientMessage::Goodbye)
+        }
+        TAG_CREDITS => {
+            if body.len() != 4 {
+                return Err(invalid("Invalid credits length"));
+            }
+            Ok(ClientMessage::Credits(reader.u32()?))
+        }
+        TAG_TRANSACTION => {
+            if body.is_empty() {
+                return Err(invalid("Empty transaction"));
+            }
+            let mut reqs = Vec::new();
+            while !reader.is_empty() {
+                let len = reader.u16()? as usize;
+                reqs.push(decode_request(reader.take(len)?)?);
+            }
+            Ok(ClientMessage::Transaction(reqs))
+        }
+        TAG_AUTH => {
+            let token = String::from_utf8(body.to_vec())
+                .map_err(|_| invalid("Auth token must be UTF-8"))?;
+            Ok(ClientMessage::Auth { token })
+        }
+        _ => Err(invalid("Unknown message tag")),
+    }
+}
+
+/// Encoded server message payload format is as follows:
+///
+/// <8:tag><32:index><8:flags><<32:ip><16:port>>...<<32:ip><16:port>>
+///
+/// for responses, partial responses and subscription updates alike. If the
+/// TTL flag is set, every entry is instead
+///
+/// <<32:ip><16:port><32:ttl>>
+///
+/// where ttl is in seconds. If any address is IPv6, the mixed flag is set
+/// and every IP is instead
+///
+/// <8:version><ip>
+///
+/// where the IP is 4 or 16 bytes depending on its version (4 or 6). If the
+/// families flag is set, the flags are followed by
+///
+/// <32:v4><32:v6>
+///
+/// the number of addresses of each family, and if the seed flag is set, by
+///
+/// <64:seed>
+///
+/// the seed they were generated from. The number of addresses is
+/// implied by the frame length. Errors are encoded as
+///
+/// <8:tag><32:index><8:code>[<32:arg>]<message>
+///
+/// where message is the UTF-8 encoded remainder of the payload. Only the
+/// rate limited code is followed by an argument, the retry delay in
+/// milliseconds, and the too many addresses code, by the limit. Server info
+/// is encoded as
+///
+/// <8:tag><8:len><version><8:n><<8:len><feature>>...<32:max_frame_len>
+/// <8:present><32:max_addrs><8:present><32:max_requests_per_sec>
+///
+/// where strings are UTF-8 prefixed by their length, and limits are only
+/// meaningful if the preceding present byte is 1. Notices are encoded as
+///
+/// <8:tag><message>
+///
+/// where message is the UTF-8 encoded remainder of the payload, and quota
+/// warnings as
+///
+/// <8:tag><32:remaining><32:burst><32:per_sec>
+fn encode_server_message(msg: &ServerMessage, buf: &mut BytesMut) -> io::Result<()> {
+    let (tag, resp) = match msg {
+        ServerMessage::Response(resp) => (TAG_RESPONSE, resp),
+        ServerMessage::Update(resp) => (TAG_UPDATE, resp),
+        ServerMessage::Partial(resp) => (TAG_PARTIAL, resp),
+        ServerMessage::Info(info) => {
+            encode_info(info, buf)?;
+            return Ok(());
+        }
+        ServerMessage::Notice(text) => {
+            let mut writer = Writer::new(buf);
+            writer.u8(TAG_NOTICE);
+            writer.slice(text.as_bytes());
+            return Ok(());
+        }
+        ServerMessage::QuotaWarning(warning) => {
+            let mut writer = Writer::new(buf);
+            writer.u8(TAG_QUOTA_WARNING);
+            writer.u32(warning.remaining);
+            writer.u32(warning.burst);
+            writer.u32(warning.per_sec);
+            return Ok(());
+        }
+        ServerMessage::Error(err) => {
+            let mut writer = Writer::new(buf);
+            writer.u8(TAG_ERROR);
+            writer.u32(err.index);
+            err.code.write(&mut writer);
+            writer.slice(err.message.as_bytes());
+            return Ok(());
+        }
+    };
+    let mut flags = match resp.ttls {
+        Some(ref ttls) => {
+            if ttls.len() != resp.addrs.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Number of TTLs doesn't match number of addresses"
+                ));
+            }
+            FLAG_TTL
+        }
+        None => 0,
+    };
+    let mixed = resp.addrs.iter().any(|addr| addr.is_ipv6());
+    if mixed {
+        flags |= FLAG_MIXED;
+    }
+    if resp.families.is_some() {
+        flags |= FLAG_FAMILIES;
+    }
+    if resp.seed.is_some() {
+        flags |= FLAG_SEED;
+    }
+    let mut writer = Writer::new(buf);
+    writer.u8(tag);
+    writer.u32(resp.index);
+    writer.u8(flags);
+    if let Some(families) = resp.families {
+        writer.u32(families.v4);
+        writer.u32(families.v6);
+    }
+    if let Some(seed) = resp.seed {
+        writer.u64(seed);
+    }
+    for (i, addr) in resp.addrs.iter().enumerate() {
+        if mixed {
+            writer.ip_addr(addr.ip());
+            writer.u16(addr.port());
+        } else {
+            writer.socket_addr_v4(addr)?;
+        }
+        if let Some(ref ttls) = resp.ttls {
+            writer.u32(ttls[i]);
+        }
+    }
+    Ok(())
+}
+
+fn encode_info(info: &ServerInfo, buf: &mut BytesMut) -> io::Result<()> {
+    if info.features.len() > u8::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many features"));
+    }
+    let mut writer = Writer::new(buf);
+    writer.u8(TAG_INFO);
+    writer.short_str(&info.version)?;
+    writer.u8(info.features.len() as u8);
+    for feature in info.features.iter() {
+        writer.short_str(feature)?;
+    }
+    writer.u32(info.max_frame_len);
+    writer.opt_u32(info.max_addrs);
+    writer.opt_u32(info.max_requests_per_sec);
+    // Left out unless frames are authenticated.
+    if let Some(session) = info.session {
+        writer.u64(session);
+    }
+    Ok(())
+}
+
+fn decode_info(body: &[u8]) -> io::Result<ServerInfo> {
+    let mut reader = Reader::new(body);
+    let version = reader.short_str()?;
+    let num_features = reader.u8()?;
+    let mut features = Vec::with_capacity(num_features as usize);
+    for _ in 0..num_features {
+        features.push(reader.short_str()?);
+    }
+    let mut info = ServerInfo {
+        version,
+        features,
+        max_frame_len: reader.u32()?,
+        max_addrs: reader.opt_u32()?,
+        max_requests_per_sec: reader.opt_u32()?,
+        session: None,
+    };
+    if !reader.is_empty() {
+        info.session = Some(reader.u64()?);
+    }
+    if !reader.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid server info length"));
+    }
+    Ok(info)
+}
+
+fn decode_server_message(payload: &[u8]) -> io::Result<ServerMessage> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if payload.first() == Some(&TAG_INFO) {
+        return decode_info(&payload[1..]).map(ServerMessage::Info);
+    }
+    if payload.first() == Some(&TAG_NOTICE) {
+        let text = String::from_utf8(payload[1..].to_vec())
+            .map_err(|_| invalid("Notice must be UTF-8"))?;
+        return Ok(ServerMessage::Notice(text));
+    }
+    if payload.first() == Some(&TAG_QUOTA_WARNING) {
+        let mut reader = Reader::new(&payload[1..]);
+        let warning = QuotaWarning {
+            remaining: reader.u32()?,
+            burst: reader.u32()?,
+            per_sec: reader.u32()?,
+        };
+        if !reader.is_empty() {
+            return Err(invalid("Invalid quota warning length"));
+        }
+        return Ok(ServerMessage::QuotaWarning(warning));
+    }
+    if payload.len() < 6 {
+        return Err(invalid("Invalid payload length"));
+    }
+    let mut reader = Reader::new(payload);
+    let tag = reader.u8()?;
+    let index = reader.u32()?;
+    if tag == TAG_ERROR {
+        let code = ErrorCode::read(&mut reader)?;
+        let message = String::from_utf8(reader.rest().to_vec())
+            .map_err(|_| invalid("Error message must be UTF-8"))?;
+        return Ok(ServerMessage::Error(ErrorResponse { index, code, message }));
+    }
+    if tag != TAG_RESPONSE && tag != TAG_UPDATE && tag != TAG_PARTIAL {
+        return Err(invalid("Unknown message tag"));
+    }
+    let flags = reader.u8()?;
+    let has_ttls = flags & FLAG_TTL != 0;
+    let mixed = flags & FLAG_MIXED != 0;
+    let families = match flags & FLAG_FAMILIES {
+        0 => None,
+        _ => Some(FamilyCounts { v4: reader.u32()?, v6: reader.u32()? }),
+    };
+    let seed = match flags & FLAG_SEED {
+        0 => None,
+        _ => Some(reader.u64()?),
+    };
+    let entry_len = if has_ttls { 10 } else { 6 };
+    // Entries of mixed responses vary in length, so any trailing bytes are
+    // only found once they fail to read.
+    if !mixed && !reader.rest().len().is_multiple_of(entry_len) {
+        return Err(invalid("Invalid payload length"));
+    }
+    // At most, as mixed entries are longer.
+    let num_addrs = reader.rest().len() / entry_len;
+    let mut addrs = Vec::with_capacity(num_addrs);
+    let mut ttls = Vec::with_capacity(if has_ttls { num_addrs } else { 0 });
+    while !reader.is_empty() {
+        if mixed {
+            let ip = reader.ip_addr()?;
+            addrs.push(SocketAddr::new(ip, reader.u16()?));
+        } else {
+            addrs.push(reader.socket_addr_v4()?);
+        }
+        if has_ttls {
+            ttls.push(reader.u32()?);
+        }
+    }
+    let ttls = if has_ttls { Some(ttls) } else { None };
+    let resp = Response { index, addrs, ttls, families, seed };
+    match tag {
+        TAG_UPDATE => Ok(ServerMessage::Update(resp)),
+        TAG_PARTIAL => Ok(ServerMessage::Partial(resp)),
+        _ => Ok(ServerMessage::Response(resp)),
+    }
+}
+
+/// JSON payloads are objects whose type is the message's variant in snake
+/// case, with any contents under body, e.g.
+///
+/// {"type":"request","body":{"num_addrs":5,"constraints":{"family":"v6"}}}
+/// {"type":"batch","body":[5,10]}
+/// {"type":"goodbye"}
+///
+/// Structs keep their field names and leave out fields that aren't set, so
+/// a response may be just `{"index":0,"addrs":["10.0.0.1:80"]}`. Addresses,
+/// CIDR blocks and log levels are strings, port ranges pairs of numbers and
+/// error codes objects of their kind, e.g. `{"kind":"too_many_addrs",
+/// "max":100}`.
+fn encode_json<T: Serialize>(msg: &T, buf: &mut BytesMut) -> io::Result<()> {
+    serde_json::to_writer(buf.writer(), msg).map_err(io::Error::other)
+}
+
+fn decode_json<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+    serde_json::from_slice(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode_client_payload(format: WireFormat, payload: &[u8]) -> io::Result<ClientMessage> {
+    match format {
+        WireFormat::Binary => decode_client_message(payload),
+        WireFormat::Json => decode_json(payload),
+    }
+}
+
+fn decode_server_payload(
+    format: WireFormat,
+    check: Option<&dyn JsonCheck>,
+    payload: &[u8],
+) -> io::Result<ServerMessage> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let msg = match (format, check) {
+        (WireFormat::Binary, _) => return decode_server_message(payload),
+        (WireFormat::Json, None) => decode_json(payload)?,
+        (WireFormat::Json, Some(check)) => {
+            let value: serde_json::Value = decode_json(payload)?;
+            check.check(&value).map_err(invalid)?;
+            serde_json::from_value(value).map_err(|e| invalid(e.to_string()))?
+        }
+    };
+    // Binary payloads can't hold anything but one TTL per address.
+    if let ServerMessage::Response(ref resp)
+    | ServerMessage::Update(ref resp)
+    | ServerMessage::Partial(ref resp) = msg
+    {
+        if resp.ttls.as_ref().is_some_and(|ttls| ttls.len() != resp.addrs.len()) {
+            return Err(invalid("Number of TTLs doesn't match number of addresses".into()));
+        }
+    }
+    Ok(msg)
+}
+
+/// Sees every frame a client codec sends or receives along with its message,
+/// e.g. to dump them for debugging. A frame includes its length prefix and
+/// any sync marker and tag.
+pub trait WireTap: Send + Sync {
+    fn sent(&self, frame: &[u8], msg: &ClientMessage);
+    fn received(&self, frame: &[u8], msg: &ServerMessage);
+}
+
+/// Checks the JSON of every frame a client codec receives before it's
+/// deserialized, e.g. against a schema, so that malformed messages are
+/// reported with more than the first field serde fails on.
+pub trait JsonCheck: Send + Sync {
+    /// Returns what's wrong with `msg`, if anything.
+    fn check(&self, msg: &serde_json::Value) -> Result<(), String>;
+}
+
+/// Client side codec: encodes requests and decodes responses. Every message
+/// is sent as a frame with a 4 byte big endian length prefix followed by the
+/// payload.
+pub struct ClientToServerCodec {
+    frames: Framing,
+    format: WireFormat,
+    check: Option<Arc<dyn JsonCheck>>,
+    tap: Option<Arc<dyn WireTap>>,
+}
+
+impl ClientToServerCodec {
+    pub fn new() -> Self {
+        ClientToServerCodec {
+            frames: Framing::new(None),
+            format: WireFormat::Binary,
+            check: None,
+            tap: None,
+        }
+    }
+
+    /// Creates a codec that signs every frame with an HMAC keyed by `key` and
+    /// rejects incoming frames that are forged or replayed. The server must
+    /// use the same key.
+    pub fn with_key(key: &[u8]) -> Self {
+        let auth = FrameAuth::new(key, Direction::ClientToServer);
+        ClientToServerCodec {
+            frames: Framing::new(Some(auth)),
+            format: WireFormat::Binary,
+            check: None,
+            tap: None,
+        }
+    }
+
+    /// Sets what to do with input that can't be decoded.
+    pub fn with_policy(mut self, policy: DecodePolicy) -> Self {
+        self.frames.policy = policy;
+        self
+    }
+
+    /// Sets how payloads are encoded. The server must use the same format.
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Has `check` look at every JSON frame received before it's
+    /// deserialized, failing the frames it finds fault with.
+    pub fn with_json_check(mut self, check: Arc<dyn JsonCheck>) -> Self {
+        self.check = Some(check);
+        self
+    }
+
+    /// Hands every frame to `tap` once encoded or decoded.
+    pub fn with_tap(mut self, tap: Arc<dyn WireTap>) -> Self {
+        self.tap = Some(tap);
+        self.frames.consumed = Some(BytesMut::new());
+        self
+    }
+}
+
+impl ClientToServerCodec {
+    /// Decodes the next frame, adopting the session announced by the
+    /// server's info.
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> io::Result<Option<ServerMessage>> {
+        let (format, check) = (self.format, self.check.as_deref());
+        let msg =
+            self.frames.decode(buf, |payload| decode_server_payload(format, check, payload))?;
+        if let (Some(ServerMessage::Info(info)), Some(auth)) = (&msg, &mut self.frames.auth) {
+            if let Some(session) = info.session {
+                auth.set_session(session);
+            }
+        }
+        Ok(msg)
+    }
+}
+
+impl Default for ClientToServerCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder<ClientMessage> for ClientToServerCodec {
     type Error = io::Error;
 
-    fn encode(&mut self, item: Request, buf: &mut BytesMut) -> io::Result<()> {
+    fn encode(&mut self, item: ClientMessage, buf: &mut BytesMut) -> io::Result<()> {
         info!("Encoding {:?}", item);
-        buf.put_u32_be(item.num_addrs);
+        let mut payload = BytesMut::new();
+        match self.format {
+            WireFormat::Binary => encode_client_message(&item, &mut payload),
+            WireFormat::Json => encode_json(&item, &mut payload)?,
+        }
+        let start = buf.len();
+        self.frames.encode(payload, buf)?;
+        if let Some(ref tap) = self.tap {
+            tap.sent(&buf[start..], &item);
+        }
         Ok(())
     }
-}
+}
+
+impl Decoder for ClientToServerCodec {
+    type Item = ServerMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ServerMessage>> {
+        let msg = self.decode_frame(buf)?;
+        // Frames may be consumed over several calls, e.g. their length
+        // before the rest of them arrived.
+        if let (Some(ref msg), Some(ref tap), Some(ref mut consumed)) =
+            (&msg, &self.tap, &mut self.frames.consumed)
+        {
+            tap.received(consumed, msg);
+            consumed.clear();
+        }
+        Ok(msg)
+    }
+}
+
+/// Server side codec: decodes requests and encodes responses, using the same
+/// framing as `ClientToServerCodec`. Clones share the session of
+/// authenticated frames, e.g. to encode and decode a connection apart.
+#[derive(Clone)]
+pub struct ServerToClientCodec {
+    frames: Framing,
+    format: WireFormat,
+}
+
+impl ServerToClientCodec {
+    pub fn new() -> Self {
+        ServerToClientCodec { frames: Framing::new(None), format: WireFormat::Binary }
+    }
+
+    /// Creates a codec that signs every frame with an HMAC keyed by `key` and
+    /// rejects incoming frames that are forged or replayed, or that were
+    /// sealed for another connection. Clients must use the same key.
+    pub fn with_key(key: &[u8]) -> Self {
+        let mut auth = FrameAuth::new(key, Direction::ServerToClient);
+        auth.set_session(rand::random());
+        ServerToClientCodec { frames: Framing::new(Some(auth)), format: WireFormat::Binary }
+    }
+
+    /// Sets what to do with input that can't be decoded.
+    pub fn with_policy(mut self, policy: DecodePolicy) -> Self {
+        self.frames.policy = policy;
+        self
+    }
+
+    /// Sets how payloads are encoded. Clients must use the same format.
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl Default for ServerToClientCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Encoder<ServerMessage> for ServerToClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, mut item: ServerMessage, buf: &mut BytesMut) -> io::Result<()> {
+        info!("Encoding {:?}", item);
+        if let (ServerMessage::Info(info), Some(auth)) = (&mut item, &self.frames.auth) {
+            info.session = auth.session();
+        }
+        let mut payload = BytesMut::new();
+        match self.format {
+            WireFormat::Binary => encode_server_message(&item, &mut payload)?,
+            WireFormat::Json => encode_json(&item, &mut payload)?,
+        }
+        self.frames.encode(payload, buf)?;
+        info!("Encoded: {:?}", buf);
+        Ok(())
+    }
+}
+
+impl Decoder for ServerToClientCodec {
+    type Item = ClientMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<ClientMessage>> {
+        let format = self.format;
+        self.frames.decode(buf, |payload| decode_client_payload(format, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn client_to_server_request() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let req = ClientMessage::Request(Request {
+            num_addrs: 5,
+            constraints: Constraints::default(),
+        });
+        ClientToServerCodec::new().encode(req, &mut buf).unwrap();
+
+        let mut expected_buf = BytesMut::with_capacity(1024);
+        expected_buf.put_u32(5);
+        expected_buf.put_u8(TAG_REQUEST);
+        expected_buf.put_u32(5);
+        assert_eq!(&buf[..], &expected_buf[..]);
+    }
+
+    #[test]
+    fn wire_tap() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Frames(Mutex<Vec<Vec<u8>>>);
+
+        impl WireTap for Frames {
+            fn sent(&self, frame: &[u8], _msg: &ClientMessage) {
+                self.0.lock().unwrap().push(frame.to_vec());
+            }
+
+            fn received(&self, frame: &[u8], _msg: &ServerMessage) {
+                self.0.lock().unwrap().push(frame.to_vec());
+            }
+        }
+
+        let frames = Arc::new(Frames::default());
+        let mut codec = ClientToServerCodec::new().with_tap(frames.clone());
+        let mut buf = BytesMut::new();
+        codec.encode(ClientMessage::Batch(vec![1, 2]), &mut buf).unwrap();
+        assert_eq!(frames.0.lock().unwrap()[0], &buf[..]);
+
+        let resp = ServerMessage::Response(Response {
+            index: 0,
+            addrs: vec![(Ipv4Addr::new(10, 0, 0, 1), 80).into()],
+            ttls: None,
+            families: None,
+            seed: None,
+        });
+        let mut input = BytesMut::new();
+        ServerToClientCodec::new().encode(resp.clone(), &mut input).unwrap();
+        let frame = input.to_vec();
+        // A frame arriving in two pieces is tapped whole.
+        let mut rest = input.split_off(6);
+        assert_eq!(codec.decode(&mut input).unwrap(), None);
+        // A trailing partial frame isn't part of the decoded one.
+        rest.put_u8(0);
+        input.unsplit(rest);
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(resp.clone()));
+        assert_eq!(&input[..], &[0]);
+        assert_eq!(frames.0.lock().unwrap()[1], frame);
+
+        // So is the sync marker before it.
+        let mut input = BytesMut::new();
+        let mut server = ServerToClientCodec::new().with_policy(DecodePolicy::Lenient);
+        server.encode(resp.clone(), &mut input).unwrap();
+        let frame = input.to_vec();
+        assert_eq!(codec.decode(&mut input).unwrap(), Some(resp));
+        assert_eq!(frames.0.lock().unwrap()[2], frame);
+    }
 
-/// Encoded server response format is as follows:
-///
-/// <32:n><<32:ip><16:port>><<32:ip><16:port>>...<<32:ip><16:port>>
-///
-/// Where n is a 32-bit integer denoting the number of 32-bit IPv4 addresses
-/// contained in the response.
-impl Decoder for ClientToServerCodec {
-    type Item = Response;
-    type Error = io::Error;
+    #[test]
+    fn client_to_server_batch() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let batch = ClientMessage::Batch(vec![5, 10, 100]);
+        ClientToServerCodec::new().encode(batch.clone(), &mut buf).unwrap();
+
+        let mut expected_buf = BytesMut::with_capacity(1024);
+        expected_buf.put_u32(1 + 3 * 4);
+        expected_buf.put_u8(TAG_BATCH);
+        expected_buf.put_u32(5);
+        expected_buf.put_u32(10);
+        expected_buf.put_u32(100);
+        assert_eq!(&buf[..], &expected_buf[..]);
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Response>> {
-        if buf.len() < 4 {
-            // Need at least four bytes for the length field.
-            return Ok(None);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, batch),
+            other => panic!("Unexpected {:?}", other),
         }
-        let payload_len = {
-            // Convert from network byte order to host byte order. TODO can't
-            // BytesMut take care of this?
-            let mut n: u32 = 0;
-            for i in 0..4 {
-                n <<= 8;
-                n |= buf[i] as u32;
-            }
-            n as usize
+    }
+
+    #[test]
+    fn client_to_server_debug() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let debug = ClientMessage::Debug {
+            token: "secret".to_string(),
+            level: LevelFilter::Trace,
         };
-        if payload_len % 6 != 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Invalid payload length"
-            ));
-        }
-        let num_addrs = payload_len / 6;
-        info!("#addrs: {}", num_addrs);
-        // Check if we have all addresses in the response which has a 4 byte
-        // length field and `num_addrs` times 6 bytes (an address containsa
-        // 4 byte IP and a 2 byte port).
-        let msg_len = 4 + payload_len;
-        if buf.len() < msg_len {
-            return Ok(None)
-        }
-        info!("msg len: {}", msg_len);
-        // Start offset into the buffer at 4 to skip initial length field.
-        let mut offset = 4;
-        let mut addrs = Vec::with_capacity(num_addrs);
-        for _ in 0..num_addrs {
-            let ip = IpAddr::V4(Ipv4Addr::new(
-                    buf[offset],
-                    buf[offset + 1],
-                    buf[offset + 2],
-                    buf[offset + 3],
-            ));
-            //TODO let ip = IpAddr::V4(Ipv4Addr::from(&buf[offset..offset+4]));
-            offset += 4;
-            let port = {
-                let mut n: u16 = 0;
-                for i in 0..2 {
-                    n <<= 8;
-                    n |= buf[offset + i] as u16;
-                }
-                n
-            };
-            offset += 2;
-            addrs.push(SocketAddr::new(ip, port));
+        ClientToServerCodec::new().encode(debug.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[4..6], &[TAG_DEBUG, 5]);
+        assert_eq!(&buf[6..], b"secret");
+
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, debug),
+            other => panic!("Unexpected {:?}", other),
         }
-        buf.split_to(msg_len);
-        Ok(Some(Response { addrs }))
     }
-}
 
-pub struct ServerToClientCodec;
-
-/// Encoded server response format is as follows:
-///
-/// <32:n><<32:ip><16:port>><<32:ip><16:port>>...<<32:ip><16:port>>
-///
-/// Where n is a 32-bit integer denoting the number of 32-bit IPv4 addresses
-/// contained in the response.
-impl Encoder for ServerToClientCodec {
-    type Item = Response;
-    type Error = io::Error;
+    #[test]
+    fn client_to_server_auth() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let auth = ClientMessage::Auth { token: "s3cret".to_string() };
+        ClientToServerCodec::new().encode(auth.clone(), &mut buf).unwrap();
+        assert_eq!(buf[4], TAG_AUTH);
+        assert_eq!(&buf[5..], b"s3cret");
 
-    fn encode(&mut self, item: Response, buf: &mut BytesMut) -> io::Result<()> {
-        info!("Encoding {:?}", item);
-        // TODO: test that item.len() <= 32?
-        buf.put_u32_be(item.addrs.len() as u32 * 6);
-        for addr in item.addrs {
-            let ip = match addr.ip() {
-                IpAddr::V4(ip) => ip,
-                _ => return Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "Only IPv4 supported"
-                )),
-            };
-            buf.extend_from_slice(&ip.octets());
-            buf.put_u16_be(addr.port());
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, auth),
+            other => panic!("Unexpected {:?}", other),
         }
-        info!("Encoded: {:?}", buf);
-        Ok(())
     }
-}
 
-/// Encoded client request format is as follows:
-///
-/// <32:n>
-///
-/// Where n is a 32-bit integer denoting the number of random ipv4 addresses
-impl Decoder for ServerToClientCodec {
-    type Item = Request;
-    type Error = io::Error;
+    #[test]
+    fn client_to_server_subscribe() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let sub = ClientMessage::Subscribe { count: 3, interval_ms: 500 };
+        ClientToServerCodec::new().encode(sub.clone(), &mut buf).unwrap();
+        ClientToServerCodec::new().encode(ClientMessage::Unsubscribe, &mut buf).unwrap();
 
-    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
-        if buf.len() < 4 {
-            // Not enough bytes yet.
-            return Ok(None);
+        let mut codec = ServerToClientCodec::new();
+        match codec.decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, sub),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match codec.decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Unsubscribe),
+            other => panic!("Unexpected {:?}", other),
         }
-        let num_addrs = {
-            // Convert from network byte order to host byte order. TODO can't
-            // BytesMut take care of this?
-            let mut n: u32 = 0;
-            for i in 0..4 {
-                n <<= 8;
-                n |= buf[i] as u32;
-            }
-            n
-        };
-        buf.split_to(4);
-        Ok(Some(Request { num_addrs }))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn client_to_server_goodbye() {
+        let mut buf = BytesMut::with_capacity(1024);
+        ClientToServerCodec::new().encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 1, TAG_GOODBYE]);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Goodbye),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
 
     #[test]
-    fn client_to_server_request() {
+    fn client_to_server_update() {
         let mut buf = BytesMut::with_capacity(1024);
-        let req = Request { num_addrs: 5 };
-        ClientToServerCodec.encode(req, &mut buf);
+        let update = ServerMessage::Update(Response {
+            index: 7,
+            addrs: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 80)],
+            ttls: None,
+            families: None,
+            seed: None,
+        });
+        ServerToClientCodec::new().encode(update.clone(), &mut buf).unwrap();
+        assert_eq!(buf[4], TAG_UPDATE);
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, update),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
 
-        let mut expected_buf = BytesMut::with_capacity(1024);
-        expected_buf.put_u32_be(5);
-        assert_eq!(&buf[..4], &expected_buf[..4]);
+    #[test]
+    fn client_to_server_credits() {
+        let mut buf = BytesMut::with_capacity(1024);
+        ClientToServerCodec::new().encode(ClientMessage::Credits(100), &mut buf).unwrap();
+        assert_eq!(&buf[..], &[0, 0, 0, 5, TAG_CREDITS, 0, 0, 0, 100]);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Credits(100)),
+            other => panic!("Unexpected {:?}", other),
+        }
     }
 
     #[test]
-    fn client_to_server_response() {
-        let msg_len = 4 + 2 * 6;
+    fn client_to_server_partial() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let partial = ServerMessage::Partial(Response {
+            index: 2,
+            addrs: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)), 80)],
+            ttls: None,
+            families: None,
+            seed: None,
+        });
+        ServerToClientCodec::new().encode(partial.clone(), &mut buf).unwrap();
+        assert_eq!(buf[4], TAG_PARTIAL);
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, partial),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
 
+    #[test]
+    fn client_to_server_response() {
         let mut buf = BytesMut::with_capacity(1024);
-        buf.put_u32_be(2 * 6);
+        buf.put_u32(1 + 4 + 1 + 2 * 6);
+        buf.put_u8(TAG_RESPONSE);
+        buf.put_u32(1);
+        buf.put_u8(0);
         buf.put_u8(0);
         buf.put_u8(1);
         buf.put_u8(2);
         buf.put_u8(3);
-        buf.put_u16_be(16222);
+        buf.put_u16(16222);
         buf.put_u8(255);
         buf.put_u8(1);
         buf.put_u8(5);
         buf.put_u8(22);
-        buf.put_u16_be(5888);
+        buf.put_u16(5888);
 
-        let expected_resp = Response {
+        let expected_resp = ServerMessage::Response(Response {
+            index: 1,
             addrs: vec![
                 SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 1, 2, 3)), 16222),
                 SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 1, 5, 22)), 5888),
             ],
-        };
-        match ClientToServerCodec.decode(&mut buf) {
+            ttls: None,
+            families: None,
+            seed: None,
+        });
+        match ClientToServerCodec::new().decode(&mut buf) {
             Ok(Some(resp)) => assert_eq!(resp, expected_resp),
-            _ => assert!(false),
+            other => panic!("Unexpected {:?}", other),
         };
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn client_to_server_partial_response() {
+        let mut codec = ClientToServerCodec::new();
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_u32(1 + 4 + 1 + 6);
+        buf.put_u8(TAG_RESPONSE);
+        buf.put_u32(0);
+        buf.put_u8(0);
+        buf.put_slice(&[10, 0, 0]);
+        match codec.decode(&mut buf) {
+            Ok(None) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        buf.put_slice(&[1, 0, 80]);
+        match codec.decode(&mut buf) {
+            Ok(Some(ServerMessage::Response(resp))) => assert_eq!(
+                resp.addrs,
+                vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 80)]
+            ),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_to_server_invalid_response_length() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_u32(1 + 4 + 1 + 5);
+        buf.put_u8(TAG_RESPONSE);
+        buf.put_u32(0);
+        buf.put_u8(0);
+        buf.put_slice(&[0, 0, 0, 0, 0]);
+        assert!(ClientToServerCodec::new().decode(&mut buf).is_err());
     }
 
     #[test]
     fn server_to_client_request() {
         let mut buf = BytesMut::with_capacity(1024);
-        buf.put_slice(&[0, 0, 0, 5]);
-        match ServerToClientCodec.decode(&mut buf) {
-            Ok(Some(req)) => assert_eq!(req, Request { num_addrs: 5 }),
-            _ => assert!(false),
+        buf.put_slice(&[0, 0, 0, 5, TAG_REQUEST, 0, 0, 0, 5]);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Request(Request {
+                num_addrs: 5,
+                constraints: Constraints::default(),
+            })),
+            other => panic!("Unexpected {:?}", other),
         }
     }
 
+    #[test]
+    fn server_to_client_unknown_tag() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(&[0, 0, 0, 5, 0xff, 0, 0, 0, 5]);
+        assert!(ServerToClientCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn server_to_client_oversized_frame() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_u32(MAX_FRAME_LEN as u32 + auth::OVERHEAD as u32 + 1);
+        assert!(ServerToClientCodec::new().decode(&mut buf).is_err());
+    }
+
     #[test]
     fn server_to_client_response() {
         let mut buf = BytesMut::with_capacity(1024);
-        let resp = Response {
+        let resp = ServerMessage::Response(Response {
+            index: 0,
             addrs: vec![
                 SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 1, 2, 3)), 16222),
                 SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 1, 5, 22)), 5888),
             ],
-        };
-        ServerToClientCodec.encode(resp, &mut buf);
+            ttls: None,
+            families: None,
+            seed: None,
+        });
+        ServerToClientCodec::new().encode(resp, &mut buf).unwrap();
 
-        let msg_len = 4 + 2 * 6;
+        let msg_len = 4 + 1 + 4 + 1 + 2 * 6;
 
         let mut expected_buf = BytesMut::with_capacity(1024);
-        expected_buf.put_u32_be(2 * 6);
+        expected_buf.put_u32(1 + 4 + 1 + 2 * 6);
+        expected_buf.put_u8(TAG_RESPONSE);
+        expected_buf.put_u32(0);
+        expected_buf.put_u8(0);
         expected_buf.put_u8(0);
         expected_buf.put_u8(1);
         expected_buf.put_u8(2);
         expected_buf.put_u8(3);
-        expected_buf.put_u16_be(16222);
+        expected_buf.put_u16(16222);
         expected_buf.put_u8(255);
         expected_buf.put_u8(1);
         expected_buf.put_u8(5);
         expected_buf.put_u8(22);
-        expected_buf.put_u16_be(5888);
+        expected_buf.put_u16(5888);
         assert_eq!(&buf[..msg_len], &expected_buf[..msg_len]);
     }
+
+    #[test]
+    fn response_with_ttls() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let resp = ServerMessage::Response(Response {
+            index: 0,
+            addrs: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 1, 2, 3)), 16222),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 1, 5, 22)), 5888),
+            ],
+            ttls: Some(vec![60, 3600]),
+            families: None,
+            seed: None,
+        });
+        ServerToClientCodec::new().encode(resp.clone(), &mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 1 + 2 * 10);
+        assert_eq!(buf[9], FLAG_TTL);
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, resp),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn response_with_ipv6() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let v6 = "2001:4860::8888".parse().unwrap();
+        let resp = ServerMessage::Response(Response {
+            index: 3,
+            addrs: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 1, 2, 3)), 16222),
+                SocketAddr::new(IpAddr::V6(v6), 443),
+            ],
+            ttls: Some(vec![60, 3600]),
+            families: None,
+            seed: None,
+        });
+        ServerToClientCodec::new().encode(resp.clone(), &mut buf).unwrap();
+        // Every entry is tagged with its version.
+        assert_eq!(buf.len(), 4 + 1 + 4 + 1 + (1 + 4 + 2 + 4) + (1 + 16 + 2 + 4));
+        assert_eq!(buf[9], FLAG_TTL | FLAG_MIXED);
+        assert_eq!(ClientToServerCodec::new().decode(&mut buf).unwrap(), Some(resp));
+    }
+
+    #[test]
+    fn response_with_families() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let addrs = vec!["10.0.0.1:80".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap()];
+        let families = Some(FamilyCounts::of(&addrs));
+        assert_eq!(families, Some(FamilyCounts { v4: 1, v6: 1 }));
+        let resp =
+            ServerMessage::Response(Response { index: 0, addrs, ttls: None, families, seed: None });
+        ServerToClientCodec::new().encode(resp.clone(), &mut buf).unwrap();
+        assert_eq!(buf[9], FLAG_MIXED | FLAG_FAMILIES);
+        assert_eq!(&buf[10..18], &[0, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(ClientToServerCodec::new().decode(&mut buf).unwrap(), Some(resp));
+    }
+
+    #[test]
+    fn response_with_seed() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let addrs = vec!["10.0.0.1:80".parse().unwrap()];
+        let resp = Response { index: 0, addrs, ttls: None, families: None, seed: Some(1 << 40) };
+        ServerToClientCodec::new().encode(ServerMessage::Response(resp.clone()), &mut buf).unwrap();
+        assert_eq!(buf[9], FLAG_SEED);
+        assert_eq!(&buf[10..18], &(1u64 << 40).to_be_bytes());
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(ServerMessage::Response(decoded))) => assert_eq!(decoded, resp),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn response_with_ipv6_truncated() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let resp = ServerMessage::Update(Response {
+            index: 0,
+            addrs: vec!["[2001:4860::8888]:443".parse().unwrap()],
+            ttls: None,
+            families: None,
+            seed: None,
+        });
+        ServerToClientCodec::new().encode(resp, &mut buf).unwrap();
+        // Drop the last byte of the port, shortening the frame to match.
+        buf.truncate(buf.len() - 1);
+        let len = buf.len() as u32 - 4;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        assert!(ClientToServerCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn response_remove_expired() {
+        let received = Instant::now();
+        let mut resp = Response {
+            index: 0,
+            addrs: vec![
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 1, 2, 3)), 16222),
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 1, 5, 22)), 5888),
+            ],
+            ttls: Some(vec![60, 3600]),
+            families: None,
+            seed: None,
+        };
+        resp.remove_expired(received, received + Duration::from_secs(120));
+        assert_eq!(
+            resp.addrs,
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(255, 1, 5, 22)), 5888)]
+        );
+        assert_eq!(resp.ttls, Some(vec![3600]));
+    }
+
+    #[test]
+    fn request_with_constraints() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let req = ClientMessage::Request(Request {
+            num_addrs: 10,
+            constraints: Constraints {
+                family: Some(Family::V4),
+                ports: Some((1024, 65535)),
+                cidr: Some("10.0.0.0/8".parse().unwrap()),
+                seed: Some(7),
+            },
+        });
+        ClientToServerCodec::new().encode(req.clone(), &mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 1 + 1 + 4 + 1 + 4 + 1 + 8);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, req),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rate_limited_error() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let err = ServerMessage::Error(ErrorResponse {
+            index: 0,
+            code: ErrorCode::RateLimited { retry_after_ms: 250 },
+            message: "Too many requests".to_string(),
+        });
+        ServerToClientCodec::new().encode(err.clone(), &mut buf).unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 4 + 1 + 4 + 17);
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, err),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_response() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let err = ServerMessage::Error(ErrorResponse {
+            index: 3,
+            code: ErrorCode::Unsatisfiable,
+            message: "Only IPv4 supported".to_string(),
+        });
+        ServerToClientCodec::new().encode(err.clone(), &mut buf).unwrap();
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, err),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn auth_failed_error() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let err = ServerMessage::Error(ErrorResponse {
+            index: 0,
+            code: ErrorCode::AuthFailed,
+            message: "Invalid token".to_string(),
+        });
+        ServerToClientCodec::new().encode(err.clone(), &mut buf).unwrap();
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, err),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cidr() {
+        let cidr: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains("192.168.5.1".parse().unwrap()));
+        assert!(!cidr.contains("192.169.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+        assert_eq!(cidr.to_string(), "192.168.0.0/16");
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("255.255.255.255".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0".parse::<Cidr>().is_err());
+        assert!("fe80::/10".parse::<Cidr>().is_ok());
+    }
+
+    #[test]
+    fn server_info() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let info = ServerMessage::Info(ServerInfo {
+            version: "0.1.0".to_string(),
+            features: vec!["tls".to_string(), "subscribe".to_string()],
+            max_frame_len: MAX_FRAME_LEN as u32,
+            max_addrs: Some(100_000),
+            max_requests_per_sec: None,
+            session: None,
+        });
+        ServerToClientCodec::new().encode(info.clone(), &mut buf).unwrap();
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, info),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn notice() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let notice = ServerMessage::Notice("Shutting down in 5 minutes".to_string());
+        ServerToClientCodec::new().encode(notice.clone(), &mut buf).unwrap();
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, notice),
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        buf.put_slice(&[0, 0, 0, 2, TAG_NOTICE, 0xff]);
+        assert!(ClientToServerCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn quota_warning() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let warning =
+            ServerMessage::QuotaWarning(QuotaWarning { remaining: 2, burst: 20, per_sec: 5 });
+        ServerToClientCodec::new().encode(warning.clone(), &mut buf).unwrap();
+        match ClientToServerCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, warning),
+            other => panic!("Unexpected {:?}", other),
+        }
+
+        buf.put_slice(&[0, 0, 0, 5, TAG_QUOTA_WARNING, 0, 0, 0, 2]);
+        assert!(ClientToServerCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn truncated_server_info() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(&[0, 0, 0, 3, TAG_INFO, 5, b'0']);
+        assert!(ClientToServerCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn authenticated_codecs() {
+        let mut client = ClientToServerCodec::with_key(b"secret");
+        let mut server = ServerToClientCodec::with_key(b"secret");
+        let mut buf = BytesMut::with_capacity(1024);
+        server.encode(ServerMessage::Info(ServerInfo::default()), &mut buf).unwrap();
+        match client.decode(&mut buf) {
+            Ok(Some(ServerMessage::Info(info))) => assert!(info.session.is_some()),
+            other => panic!("Unexpected {:?}", other),
+        }
+        client.encode(ClientMessage::Credits(10), &mut buf).unwrap();
+        client.encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        match server.decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Credits(10)),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match server.decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Goodbye),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_to_server_transaction() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let transaction = ClientMessage::Transaction(vec![
+            Request { num_addrs: 5, constraints: Constraints::default() },
+            Request {
+                num_addrs: 10,
+                constraints: Constraints {
+                    family: None,
+                    ports: Some((80, 80)),
+                    cidr: Some("10.0.0.0/8".parse().unwrap()),
+                    seed: None,
+                },
+            },
+        ]);
+        ClientToServerCodec::new().encode(transaction.clone(), &mut buf).unwrap();
+        assert_eq!(buf[4], TAG_TRANSACTION);
+        assert_eq!(&buf[5..11], &[0, 4, 0, 0, 0, 5]);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, transaction),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn server_to_client_invalid_transaction() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(&[0, 0, 0, 1, TAG_TRANSACTION]);
+        assert!(ServerToClientCodec::new().decode(&mut buf).is_err());
+
+        // Sub-request longer than the payload.
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(&[0, 0, 0, 7, TAG_TRANSACTION, 0, 5, 0, 0, 0, 5]);
+        assert!(ServerToClientCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn frames_of_other_connections_rejected() {
+        let mut client = ClientToServerCodec::with_key(b"secret");
+        let mut server = ServerToClientCodec::with_key(b"secret");
+        let mut buf = BytesMut::with_capacity(1024);
+        server.encode(ServerMessage::Info(ServerInfo::default()), &mut buf).unwrap();
+        client.decode(&mut buf).unwrap();
+        client.encode(ClientMessage::Credits(10), &mut buf).unwrap();
+        let sealed = buf.clone();
+        assert!(server.decode(&mut buf).unwrap().is_some());
+
+        // Replayed on another connection, whose nonces also start at 0.
+        let mut other = ServerToClientCodec::with_key(b"secret");
+        let mut replayed = sealed;
+        assert!(other.decode(&mut replayed).is_err());
+    }
+
+    #[test]
+    fn unauthenticated_frame_rejected() {
+        let mut buf = BytesMut::with_capacity(1024);
+        ClientToServerCodec::new().encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        assert!(ServerToClientCodec::with_key(b"secret").decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn strict_decoder_rejects_invalid_frame() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(&[0, 0, 0, 1, 0xff]);
+        ClientToServerCodec::new().encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        assert!(ServerToClientCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn lenient_decoder_skips_invalid_frame() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(&[0, 0, 0, 1, 0xff]);
+        ClientToServerCodec::new().encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        let mut codec = ServerToClientCodec::new().with_policy(DecodePolicy::Lenient);
+        match codec.decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Goodbye),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn lenient_decoder_resyncs_after_corrupt_framing() {
+        let mut encoder = ClientToServerCodec::new().with_policy(DecodePolicy::Lenient);
+        let mut buf = BytesMut::with_capacity(1024);
+        // A length field larger than any frame, followed by garbage.
+        buf.put_slice(&[0x7f, 0, 0, 0, 1, 2, 3]);
+        encoder.encode(ClientMessage::Goodbye, &mut buf).unwrap();
+
+        assert!(ServerToClientCodec::new().decode(&mut buf.clone()).is_err());
+
+        let mut codec = ServerToClientCodec::new().with_policy(DecodePolicy::Lenient);
+        // The marker arrives a byte at a time.
+        let mut input = BytesMut::with_capacity(1024);
+        let mut decoded = None;
+        for b in buf.iter() {
+            input.put_u8(*b);
+            if let Some(msg) = codec.decode(&mut input).unwrap() {
+                decoded = Some(msg);
+            }
+        }
+        assert_eq!(decoded, Some(ClientMessage::Goodbye));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn strict_decoder_skips_sync_markers() {
+        let mut encoder = ClientToServerCodec::new().with_policy(DecodePolicy::Lenient);
+        let mut buf = BytesMut::with_capacity(1024);
+        encoder.encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        assert_eq!(&buf[..SYNC_MARKER.len()], &SYNC_MARKER);
+        match ServerToClientCodec::new().decode(&mut buf) {
+            Ok(Some(msg)) => assert_eq!(msg, ClientMessage::Goodbye),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sync_marker_bytes_inside_frame() {
+        // A payload arriving after its length field must not be mistaken for
+        // the start of a marker.
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_u32(1);
+        let mut codec = ServerToClientCodec::new();
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        buf.put_u8(0xff);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn json_client_messages() {
+        let cidr = "10.0.0.0/8".parse().unwrap();
+        let msgs = vec![
+            ClientMessage::Request(Request {
+                num_addrs: 5,
+                constraints: Constraints {
+                    family: Some(Family::V4),
+                    ports: Some((80, 90)),
+                    cidr: Some(cidr),
+                    seed: Some(7),
+                },
+            }),
+            ClientMessage::Batch(vec![5, 10]),
+            ClientMessage::Debug { token: "secret".to_string(), level: LevelFilter::Trace },
+            ClientMessage::Subscribe { count: 3, interval_ms: 100 },
+            ClientMessage::Unsubscribe,
+            ClientMessage::Goodbye,
+            ClientMessage::Credits(10),
+            ClientMessage::Transaction(vec![Request {
+                num_addrs: 2,
+                constraints: Constraints::default(),
+            }]),
+            ClientMessage::Auth { token: "token".to_string() },
+        ];
+        let mut encoder = ClientToServerCodec::new().with_format(WireFormat::Json);
+        let mut decoder = ServerToClientCodec::new().with_format(WireFormat::Json);
+        for msg in msgs {
+            let mut buf = BytesMut::with_capacity(1024);
+            encoder.encode(msg.clone(), &mut buf).unwrap();
+            assert_eq!(decoder.decode(&mut buf).unwrap(), Some(msg));
+        }
+
+        let mut buf = BytesMut::with_capacity(1024);
+        let req = ClientMessage::Request(Request {
+            num_addrs: 5,
+            constraints: Constraints { cidr: Some(cidr), ..Constraints::default() },
+        });
+        encoder.encode(req, &mut buf).unwrap();
+        assert_eq!(
+            &buf[4..],
+            br#"{"type":"request","body":{"num_addrs":5,"constraints":{"cidr":"10.0.0.0/8"}}}"#
+        );
+    }
+
+    #[test]
+    fn json_server_messages() {
+        let addrs = vec![
+            (Ipv4Addr::new(10, 0, 0, 1), 80).into(),
+            "[::1]:443".parse().unwrap(),
+        ];
+        let resp = Response {
+            index: 1,
+            addrs: addrs.clone(),
+            ttls: Some(vec![30, 60]),
+            families: Some(FamilyCounts::of(&addrs)),
+            seed: Some(42),
+        };
+        let msgs = vec![
+            ServerMessage::Response(resp.clone()),
+            ServerMessage::Update(resp.clone()),
+            ServerMessage::Partial(resp),
+            ServerMessage::Error(ErrorResponse {
+                index: 2,
+                code: ErrorCode::RateLimited { retry_after_ms: 500 },
+                message: "Slow down".to_string(),
+            }),
+            ServerMessage::Info(ServerInfo {
+                version: "1.0".to_string(),
+                features: vec!["tls".to_string()],
+                max_frame_len: 1024,
+                max_addrs: Some(100),
+                max_requests_per_sec: None,
+                session: None,
+            }),
+            ServerMessage::Notice("Shutting down".to_string()),
+            ServerMessage::QuotaWarning(QuotaWarning { remaining: 1, burst: 10, per_sec: 5 }),
+        ];
+        let mut encoder = ServerToClientCodec::new().with_format(WireFormat::Json);
+        let mut decoder = ClientToServerCodec::new().with_format(WireFormat::Json);
+        for msg in msgs {
+            let mut buf = BytesMut::with_capacity(1024);
+            encoder.encode(msg.clone(), &mut buf).unwrap();
+            assert_eq!(decoder.decode(&mut buf).unwrap(), Some(msg));
+        }
+
+        let mut buf = BytesMut::with_capacity(1024);
+        let err = ServerMessage::Error(ErrorResponse {
+            index: 0,
+            code: ErrorCode::TooManyAddrs { max: 100 },
+            message: "Too many".to_string(),
+        });
+        encoder.encode(err, &mut buf).unwrap();
+        let expected = concat!(
+            r#"{"type":"error","body":{"index":0,"#,
+            r#""code":{"kind":"too_many_addrs","max":100},"message":"Too many"}}"#,
+        );
+        assert_eq!(&buf[4..], expected.as_bytes());
+    }
+
+    #[test]
+    fn json_invalid_messages() {
+        let mut decoder = ClientToServerCodec::new().with_format(WireFormat::Json);
+        let payloads: [&[u8]; 4] = [
+            b"not json",
+            br#"{"type":"nonsense"}"#,
+            br#"{"type":"response","body":{"index":0,"addrs":["10.0.0.1"]}}"#,
+            br#"{"type":"response","body":{"index":0,"addrs":["10.0.0.1:80"],"ttls":[]}}"#,
+        ];
+        for payload in payloads.iter() {
+            let mut buf = BytesMut::with_capacity(1024);
+            buf.put_u32(payload.len() as u32);
+            buf.put_slice(payload);
+            assert!(decoder.decode(&mut buf).is_err(), "{:?}", payload);
+        }
+
+        // Nor do the formats mix.
+        let mut buf = BytesMut::with_capacity(1024);
+        ClientToServerCodec::new().encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        let mut decoder = ServerToClientCodec::new().with_format(WireFormat::Json);
+        assert!(decoder.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn authenticated_json_codecs() {
+        let mut server = ServerToClientCodec::with_key(b"key").with_format(WireFormat::Json);
+        let mut client = ClientToServerCodec::with_key(b"key").with_format(WireFormat::Json);
+        let mut buf = BytesMut::with_capacity(1024);
+        server.encode(ServerMessage::Info(ServerInfo::default()), &mut buf).unwrap();
+        match client.decode(&mut buf) {
+            Ok(Some(ServerMessage::Info(info))) => assert!(info.session.is_some()),
+            other => panic!("Unexpected {:?}", other),
+        }
+        client.encode(ClientMessage::Goodbye, &mut buf).unwrap();
+        assert_eq!(server.decode(&mut buf).unwrap(), Some(ClientMessage::Goodbye));
+    }
+
+    #[test]
+    fn json_check() {
+        struct NoNotices;
+
+        impl JsonCheck for NoNotices {
+            fn check(&self, msg: &serde_json::Value) -> Result<(), String> {
+                match msg["type"].as_str() {
+                    Some("notice") => Err("No notices".to_string()),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let mut encoder = ServerToClientCodec::new().with_format(WireFormat::Json);
+        let mut decoder = ClientToServerCodec::new()
+            .with_format(WireFormat::Json)
+            .with_json_check(Arc::new(NoNotices));
+        let mut buf = BytesMut::with_capacity(1024);
+        encoder.encode(ServerMessage::Notice("Hi".to_string()), &mut buf).unwrap();
+        let err = decoder.decode(&mut buf).unwrap_err();
+        assert_eq!(err.to_string(), "No notices");
+
+        let info = ServerMessage::Info(ServerInfo::default());
+        encoder.encode(info.clone(), &mut buf).unwrap();
+        assert_eq!(decoder.decode(&mut buf).unwrap(), Some(info));
+    }
 }
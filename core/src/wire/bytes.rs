@@ -0,0 +1,211 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use ::bytes::{BufMut, BytesMut};
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Appends big endian fields to a payload.
+pub struct Writer<'a> {
+    buf: &'a mut BytesMut,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut BytesMut) -> Self {
+        Writer { buf }
+    }
+
+    pub fn u8(&mut self, n: u8) {
+        self.buf.put_u8(n);
+    }
+
+    pub fn u16(&mut self, n: u16) {
+        self.buf.put_u16(n);
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.buf.put_u32(n);
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.buf.put_u64(n);
+    }
+
+    pub fn slice(&mut self, bytes: &[u8]) {
+        self.buf.put_slice(bytes);
+    }
+
+    /// Writes `<8:version><ip>`, where version is 4 or 6 and the IP is 4 or
+    /// 16 bytes accordingly.
+    pub fn ip_addr(&mut self, ip: IpAddr) {
+        match ip {
+            IpAddr::V4(ip) => {
+                self.buf.put_u8(4);
+                self.buf.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                self.buf.put_u8(6);
+                self.buf.put_slice(&ip.octets());
+            }
+        }
+    }
+
+    /// Writes `<32:ip><16:port>`, failing for IPv6 addresses.
+    pub fn socket_addr_v4(&mut self, addr: &SocketAddr) -> io::Result<()> {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Only IPv4 supported")),
+        };
+        self.buf.put_slice(&ip.octets());
+        self.buf.put_u16(addr.port());
+        Ok(())
+    }
+
+    /// Writes `<8:len><s>`, failing for strings longer than 255 bytes.
+    pub fn short_str(&mut self, s: &str) -> io::Result<()> {
+        if s.len() > u8::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "String too long"));
+        }
+        self.buf.put_u8(s.len() as u8);
+        self.buf.put_slice(s.as_bytes());
+        Ok(())
+    }
+
+    /// Writes `<8:present><32:n>`, where n is 0 if absent.
+    pub fn opt_u32(&mut self, n: Option<u32>) {
+        self.buf.put_u8(n.is_some() as u8);
+        self.buf.put_u32(n.unwrap_or(0));
+    }
+}
+
+/// Reads big endian fields from the front of a payload, failing on
+/// truncated input.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// What's left to read.
+    pub fn rest(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid("Truncated message"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Reads an IP written by `Writer::ip_addr`.
+    pub fn ip_addr(&mut self) -> io::Result<IpAddr> {
+        match self.u8()? {
+            4 => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(self.take(4)?);
+                Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            6 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(self.take(16)?);
+                Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            _ => Err(invalid("Invalid IP version")),
+        }
+    }
+
+    /// Reads `<32:ip><16:port>`.
+    pub fn socket_addr_v4(&mut self) -> io::Result<SocketAddr> {
+        let mut octets = [0; 4];
+        octets.copy_from_slice(self.take(4)?);
+        let port = self.u16()?;
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(octets)), port))
+    }
+
+    pub fn short_str(&mut self) -> io::Result<String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("String must be UTF-8"))
+    }
+
+    pub fn opt_u32(&mut self) -> io::Result<Option<u32>> {
+        let present = self.u8()? != 0;
+        let n = self.u32()?;
+        Ok(if present { Some(n) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_addrs() {
+        let v4: IpAddr = "10.1.2.3".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut buf = BytesMut::new();
+        let mut writer = Writer::new(&mut buf);
+        writer.ip_addr(v4);
+        writer.ip_addr(v6);
+        assert_eq!(&buf[..5], &[4, 10, 1, 2, 3]);
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.ip_addr().unwrap(), v4);
+        assert_eq!(reader.ip_addr().unwrap(), v6);
+        assert!(reader.is_empty());
+        assert!(Reader::new(&[5, 0, 0, 0, 0]).ip_addr().is_err());
+    }
+
+    #[test]
+    fn socket_addrs() {
+        let addr: SocketAddr = "10.1.2.3:258".parse().unwrap();
+        let mut buf = BytesMut::new();
+        Writer::new(&mut buf).socket_addr_v4(&addr).unwrap();
+        assert_eq!(&buf[..], &[10, 1, 2, 3, 1, 2]);
+        assert_eq!(Reader::new(&buf).socket_addr_v4().unwrap(), addr);
+
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        assert!(Writer::new(&mut buf).socket_addr_v4(&v6).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut reader = Reader::new(&[0, 1, 2]);
+        assert!(reader.u32().is_err());
+        assert_eq!(reader.u16().unwrap(), 1);
+        assert!(reader.short_str().is_err());
+    }
+}
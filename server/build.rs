@@ -0,0 +1,10 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Builds with the protoc shipped in protoc-bin-vendored, so a system one
+    // isn't needed. One set in PROTOC still wins.
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    // Only the service is served, the client is left to other languages.
+    tonic_build::configure().build_client(false).compile(&["proto/addrs.proto"], &["proto"])?;
+    Ok(())
+}
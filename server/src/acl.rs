@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use addrcore::Cidr;
+
+/// Which client IPs may connect, checked as connections are accepted. The
+/// most specific block containing an IP decides, denying on a tie, and IPs
+/// in none of them get the default.
+#[derive(Debug)]
+pub struct Acl {
+    allow_by_default: bool,
+    /// Blocks and whether they're allowed.
+    rules: Vec<(Cidr, bool)>,
+    /// Connections rejected since startup.
+    rejected: AtomicU64,
+}
+
+impl Acl {
+    /// Accepts everyone but the denied blocks.
+    pub fn allow_by_default() -> Self {
+        Acl { allow_by_default: true, rules: Vec::new(), rejected: AtomicU64::new(0) }
+    }
+
+    /// Rejects everyone but the allowed blocks.
+    pub fn deny_by_default() -> Self {
+        Acl { allow_by_default: false, ..Acl::allow_by_default() }
+    }
+
+    pub fn allow(mut self, cidr: Cidr) -> Self {
+        self.rules.push((cidr, true));
+        self
+    }
+
+    pub fn deny(mut self, cidr: Cidr) -> Self {
+        self.rules.push((cidr, false));
+        self
+    }
+
+    /// Whether `ip` may connect, counting it as rejected if not.
+    pub(crate) fn admits(&self, ip: IpAddr) -> bool {
+        // Dual-stack sockets see IPv4 clients as IPv4-mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let admitted = self
+            .rules
+            .iter()
+            .filter(|(cidr, _)| cidr.contains(ip))
+            // Of equally specific blocks, the denied one wins.
+            .max_by_key(|(cidr, allowed)| (cidr.prefix_len, !allowed))
+            .map_or(self.allow_by_default, |(_, allowed)| *allowed);
+        if !admitted {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    /// Connections rejected since startup.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make(default: bool, rules: &[(&str, bool)]) -> Acl {
+        let acl = if default { Acl::allow_by_default() } else { Acl::deny_by_default() };
+        rules.iter().fold(acl, |acl, &(cidr, allowed)| match allowed {
+            true => acl.allow(cidr.parse().unwrap()),
+            false => acl.deny(cidr.parse().unwrap()),
+        })
+    }
+
+    #[test]
+    fn most_specific_wins() {
+        let acl = make(false, &[("10.0.0.0/8", true), ("10.1.0.0/16", false)]);
+        assert!(acl.admits("10.2.0.1".parse().unwrap()));
+        assert!(!acl.admits("10.1.0.1".parse().unwrap()));
+        assert!(!acl.admits("192.168.0.1".parse().unwrap()));
+        assert_eq!(acl.rejected(), 2);
+
+        let acl = make(true, &[("10.0.0.0/8", false), ("10.1.0.0/16", true)]);
+        assert!(acl.admits("10.1.0.1".parse().unwrap()));
+        assert!(!acl.admits("10.2.0.1".parse().unwrap()));
+        assert!(acl.admits("192.168.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn deny_wins_ties() {
+        let acl = make(true, &[("10.0.0.0/8", true), ("10.0.0.0/8", false)]);
+        assert!(!acl.admits("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        let acl = make(false, &[("127.0.0.0/8", true)]);
+        assert!(acl.admits("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!acl.admits("::1".parse().unwrap()));
+    }
+}
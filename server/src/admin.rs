@@ -0,0 +1,237 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use tracing::{error, info, warn};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use addrcore::ServerMessage;
+
+use crate::{listen, supervise, Acl, Settings};
+
+const HELP: &str = "\
+stats                 counts of connections, rejections and panics, accepted and active
+                      connections per listener, connections queued at the limit, the
+                      changeable settings, and the memory (bytes), CPU (percent), file
+                      descriptors and tasks the process used when last sampled
+connections           the active connections: id, peer, connect time and requests
+udp                   the UDP clients: address, and datagrams received, duplicated,
+                      reordered and lost
+kick <peer|id>        close the connections of a peer, or with an id
+set max-addrs <n>     serve at most n addresses a request
+broadcast <text>      send a notice to every client
+drain                 stop accepting connections and exit once the last one closes
+help                  this
+";
+
+/// Serves operators connecting to `listener` until accepting fails. Every
+/// line they send is a command, answered with its output, if any, and then
+/// a line with `ok`, or with `error` followed by what went wrong.
+pub async fn serve(listener: UnixListener, settings: Arc<Settings>) {
+    if let Ok(addr) = listener.local_addr() {
+        if let Some(path) = addr.as_pathname() {
+            info!("Accepting admin commands on {}", path.display());
+        }
+    }
+    loop {
+        let stream = match listen::accept(|| listener.accept()).await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Admin socket error: {}", e);
+                return;
+            }
+        };
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(stream, &settings).await {
+                warn!("Admin session error: {}", e);
+            }
+        });
+    }
+}
+
+async fn session(stream: UnixStream, settings: &Settings) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        let reply = match execute(command, settings) {
+            Ok(output) => output + "ok\n",
+            Err(e) => {
+                warn!("Admin command {:?} failed: {}", command, e);
+                format!("error {}\n", e)
+            }
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Runs `command`, returning its output a line per item.
+fn execute(command: &str, settings: &Settings) -> Result<String, String> {
+    let words: Vec<_> = command.split_whitespace().collect();
+    let mut output = String::new();
+    match words.as_slice() {
+        ["stats"] => {
+            let _ = writeln!(output, "connections {}", settings.registry.connections().len());
+            let _ = writeln!(output, "accepted {}", supervise::accepted());
+            let _ = writeln!(output, "panics {}", supervise::panics());
+            let rejected = settings.acl.as_ref().map_or(0, Acl::rejected);
+            let _ = writeln!(output, "rejected {}", rejected);
+            for listener in settings.listeners.iter() {
+                let (accepted, active) = (listener.accepted(), listener.active());
+                let _ = writeln!(output, "listener {} {} {}", listener.name, accepted, active);
+            }
+            if let Some(ref connections) = settings.connections {
+                let _ = writeln!(output, "queued {}", connections.queued());
+            }
+            let _ = writeln!(output, "max-addrs {}", settings.max_addrs());
+            let _ = writeln!(output, "draining {}", settings.is_draining());
+            if let Some(usage) = settings.usage.last() {
+                let _ = writeln!(output, "rss {}", usage.rss);
+                let _ = writeln!(output, "cpu {:.1}", usage.cpu);
+                let _ = writeln!(output, "fds {}", usage.fds);
+                let _ = writeln!(output, "tasks {}", usage.tasks);
+            }
+        }
+        ["connections"] => {
+            for conn in settings.registry.connections() {
+                let connected = humantime::format_rfc3339_seconds(conn.connected);
+                let _ =
+                    writeln!(output, "{} {} {} {}", conn.id, conn.peer, connected, conn.requests);
+            }
+        }
+        ["udp"] => {
+            for (addr, counts) in settings.udp_peers.counts() {
+                let _ = writeln!(
+                    output,
+                    "{} {} {} {} {}",
+                    addr, counts.received, counts.duplicate, counts.reordered, counts.lost
+                );
+            }
+        }
+        ["kick", peer] => match settings.registry.kick(peer) {
+            0 => return Err(format!("no connection of {}", peer)),
+            n => info!("Kicking {} connection(s) of {}", n, peer),
+        },
+        ["set", "max-addrs", n] => {
+            let n: u32 = n.parse().map_err(|_| format!("invalid number {}", n))?;
+            if n == 0 {
+                return Err("max-addrs must be at least 1".to_string());
+            }
+            info!("Setting max-addrs to {}", n);
+            settings.max_addrs.store(n, Ordering::Relaxed);
+        }
+        ["broadcast", ..] if words.len() > 1 => {
+            // The text as it was typed, spacing included.
+            let text = command["broadcast".len()..].trim();
+            let n = settings.registry.broadcast(&ServerMessage::Notice(text.to_string()));
+            info!("Broadcast {:?} to {} connection(s)", text, n);
+            let _ = writeln!(output, "sent {}", n);
+        }
+        ["drain"] => {
+            info!("Draining by admin command");
+            settings.drain();
+        }
+        ["help"] => output.push_str(HELP),
+        _ => return Err(format!("unknown command {}, see help", command)),
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    use crate::Server;
+
+    async fn settings() -> Arc<Settings> {
+        Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(10).build().await.unwrap().settings
+    }
+
+    /// The value of `key` in the output of `stats`.
+    fn stat(settings: &Settings, key: &str) -> String {
+        let output = execute("stats", settings).unwrap();
+        let line = output.lines().find(|line| line.split(' ').next() == Some(key)).unwrap();
+        line[key.len() + 1..].to_string()
+    }
+
+    #[tokio::test]
+    async fn changes_settings() {
+        let settings = settings().await;
+        assert_eq!(stat(&settings, "max-addrs"), "10");
+        assert_eq!(execute("set max-addrs 3", &settings), Ok(String::new()));
+        assert_eq!(settings.max_addrs(), 3);
+        assert!(execute("set max-addrs 0", &settings).is_err());
+        assert!(execute("set max-addrs many", &settings).is_err());
+        assert_eq!(stat(&settings, "draining"), "false");
+        execute("drain", &settings).unwrap();
+        assert!(settings.is_draining());
+    }
+
+    #[tokio::test]
+    async fn answers_every_command() {
+        let settings = settings().await;
+        assert_eq!(stat(&settings, "connections"), "0");
+        assert_eq!(execute("connections", &settings), Ok(String::new()));
+        assert_eq!(execute("udp", &settings), Ok(String::new()));
+        assert_eq!(execute("broadcast  hello  there", &settings), Ok("sent 0\n".to_string()));
+        assert!(execute("broadcast", &settings).is_err());
+        assert!(execute("kick 10.0.0.1", &settings).is_err());
+        assert_eq!(execute("help", &settings), Ok(HELP.to_string()));
+        assert!(execute("reboot", &settings).unwrap_err().contains("see help"));
+    }
+
+    #[tokio::test]
+    async fn kicks_connections() {
+        let server = Server::bind(([127, 0, 0, 1], 0).into()).build().await.unwrap();
+        let (addr, settings) = (server.local_addr().unwrap(), server.settings.clone());
+        tokio::spawn(server.run());
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // Registered just after its info is sent.
+        let output = loop {
+            match execute("connections", &settings).unwrap() {
+                output if output.is_empty() => tokio::time::sleep(Duration::from_millis(1)).await,
+                output => break output,
+            }
+        };
+        let id = output.split(' ').next().unwrap();
+        assert_eq!(execute(&format!("kick {}", id), &settings), Ok(String::new()));
+        // Closed once kicked.
+        stream.read_to_end(&mut Vec::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_a_reply_per_line() {
+        let path = std::env::temp_dir().join(format!("admin-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(serve(listener, settings().await));
+        let (reader, mut writer) = UnixStream::connect(&path).await.unwrap().into_split();
+        writer.write_all(b"set max-addrs 5\n\nnope\nstats\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "ok");
+        let error = lines.next_line().await.unwrap().unwrap();
+        assert!(error.starts_with("error unknown command nope"), "{}", error);
+        let mut stats = Vec::new();
+        loop {
+            match lines.next_line().await.unwrap().unwrap() {
+                line if line == "ok" => break,
+                line => stats.push(line),
+            }
+        }
+        assert!(stats.contains(&"max-addrs 5".to_string()), "{:?}", stats);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::error::Elapsed;
+
+/// Limits the connections served at a time across listeners, and queues a
+/// few more beyond the limit for a while, if asked to, to smooth over short
+/// bursts of connections.
+pub struct Connections {
+    permits: Arc<Semaphore>,
+    max: usize,
+    queue: Option<Queue>,
+}
+
+struct Queue {
+    len: usize,
+    deadline: Duration,
+    depth: AtomicUsize,
+    /// Notified whenever a connection leaves the queue.
+    left: Notify,
+}
+
+/// Room for a connection about to be accepted, taken before accepting it.
+pub enum Ticket {
+    Unlimited,
+    Admitted(OwnedSemaphorePermit),
+    Queued(Queued),
+}
+
+/// A place in the queue, given up when dropped.
+pub struct Queued(Arc<Connections>);
+
+impl Connections {
+    /// Serves at most `max` connections, queueing up to `len` more for at
+    /// most `deadline` each if `queue` is set.
+    pub fn new(max: usize, queue: Option<(usize, Duration)>) -> Arc<Self> {
+        let queue = queue.map(|(len, deadline)| Queue {
+            len,
+            deadline,
+            depth: AtomicUsize::new(0),
+            left: Notify::new(),
+        });
+        Arc::new(Connections { permits: Arc::new(Semaphore::new(max)), max, queue })
+    }
+
+    /// Most connections served at a time.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Most connections queued beyond the limit and how long they wait, if
+    /// any are.
+    pub fn queue(&self) -> Option<(usize, Duration)> {
+        self.queue.as_ref().map(|queue| (queue.len, queue.deadline))
+    }
+
+    /// Connections waiting in the queue now.
+    pub fn queued(&self) -> usize {
+        self.queue.as_ref().map_or(0, |queue| queue.depth.load(Ordering::Relaxed))
+    }
+
+    /// Waits for room for another connection: a permit to serve it, or else
+    /// a place in the queue.
+    pub async fn ticket(self: &Arc<Self>) -> Ticket {
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            // The semaphore is never closed.
+            None => return Ticket::Admitted(self.acquire().await),
+        };
+        loop {
+            if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+                return Ticket::Admitted(permit);
+            }
+            // Taken before looking at the depth so as not to miss a
+            // connection leaving in between.
+            let left = queue.left.notified();
+            let entered = queue
+                .depth
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                    (depth < queue.len).then_some(depth + 1)
+                })
+                .is_ok();
+            if entered {
+                return Ticket::Queued(Queued(self.clone()));
+            }
+            tokio::select! {
+                permit = self.acquire() => return Ticket::Admitted(permit),
+                _ = left => (),
+            }
+        }
+    }
+
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.permits.clone().acquire_owned().await.expect("connection permits closed")
+    }
+}
+
+impl Ticket {
+    /// Waits for the connection to be served, holding the returned permit
+    /// meanwhile if connections are limited, or fails if it waited in the
+    /// queue past the deadline.
+    pub async fn admit(self) -> Result<Option<OwnedSemaphorePermit>, Elapsed> {
+        match self {
+            Ticket::Unlimited => Ok(None),
+            Ticket::Admitted(permit) => Ok(Some(permit)),
+            Ticket::Queued(queued) => {
+                let connections = &queued.0;
+                // Queued connections exist only with a queue.
+                let deadline = connections.queue.as_ref().map_or(Duration::ZERO, |q| q.deadline);
+                tokio::time::timeout(deadline, connections.acquire()).await.map(Some)
+            }
+        }
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        if let Some(ref queue) = self.0.queue {
+            queue.depth.fetch_sub(1, Ordering::Relaxed);
+            queue.left.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn queues_beyond_the_limit() {
+        let connections = Connections::new(1, Some((1, Duration::from_secs(5))));
+        let served = connections.ticket().await.admit().await.unwrap();
+        let queued = connections.ticket().await;
+        assert!(matches!(queued, Ticket::Queued(_)));
+        assert_eq!(connections.queued(), 1);
+        // The queue is full, so the next one waits for room.
+        let next = tokio::spawn({
+            let connections = connections.clone();
+            async move { connections.ticket().await.admit().await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        assert!(!next.is_finished());
+        let waiting = tokio::spawn(queued.admit());
+        drop(served);
+        assert!(waiting.await.unwrap().is_ok());
+        assert!(next.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_connections_queued_past_the_deadline() {
+        let connections = Connections::new(1, Some((1, Duration::from_millis(100))));
+        let _served = connections.ticket().await.admit().await.unwrap();
+        let queued = connections.ticket().await;
+        assert!(queued.admit().await.is_err());
+        assert_eq!(connections.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_permit_without_a_queue() {
+        let connections = Connections::new(1, None);
+        let served = connections.ticket().await;
+        assert!(matches!(served, Ticket::Admitted(_)));
+        let next = tokio::spawn({
+            let connections = connections.clone();
+            async move { connections.ticket().await.admit().await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        assert!(!next.is_finished());
+        drop(served);
+        assert!(next.await.unwrap());
+        assert_eq!(connections.queued(), 0);
+    }
+}
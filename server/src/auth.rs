@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use tracing::{info, warn};
+
+use tokio::time;
+
+use crate::ratelimit::RateLimiter;
+
+/// How often the tokens file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The valid tokens, swapped out whole when the file changes.
+type Tokens = RwLock<Arc<HashMap<String, Arc<Token>>>>;
+
+/// Valid client tokens read from a TOML file, which is reloaded whenever it
+/// changes, keeping the previous tokens if it's invalid. Connections using a
+/// token that's been removed fail their next request, so tokens can be
+/// rotated without a restart.
+///
+/// ```toml
+/// [[tokens]]
+/// name = "ci"
+/// token = "d41d8cd98f00b204"
+/// max_addrs = 100
+/// requests_per_sec = 10
+/// ```
+#[derive(Clone)]
+pub(crate) struct Auth {
+    tokens: Arc<Tokens>,
+}
+
+/// A client identity with the limits it's served under, on top of the
+/// server's own.
+pub(crate) struct Token {
+    /// Logged in place of the token.
+    pub name: String,
+    pub max_addrs: Option<u32>,
+    /// Shared by all connections using the token, if it's limited. Reset
+    /// whenever the file is reloaded.
+    pub rate_limit: Option<RateLimiter<()>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokensFile {
+    tokens: Vec<TokenEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenEntry {
+    name: String,
+    token: String,
+    max_addrs: Option<u32>,
+    requests_per_sec: Option<u32>,
+}
+
+impl Auth {
+    /// Reads the tokens at `path` and watches it in the background, until
+    /// all clones are dropped.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let modified = fs::metadata(&path)?.modified()?;
+        let tokens = read_tokens(&path)?;
+        info!("Accepting {} token(s) from {}", tokens.len(), path.display());
+        let tokens = Arc::new(RwLock::new(Arc::new(tokens)));
+        tokio::spawn(watch(path, modified, Arc::downgrade(&tokens)));
+        Ok(Auth { tokens })
+    }
+
+    /// The identity of `token`, if it's valid.
+    pub fn lookup(&self, token: &str) -> Option<Arc<Token>> {
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+}
+
+fn read_tokens(path: &Path) -> io::Result<HashMap<String, Arc<Token>>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let file: TokensFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| invalid(format!("Invalid {}: {}", path.display(), e)))?;
+    let mut tokens = HashMap::new();
+    for entry in file.tokens {
+        if entry.token.is_empty() {
+            return Err(invalid(format!("Token {} is empty", entry.name)));
+        }
+        if entry.max_addrs == Some(0) || entry.requests_per_sec == Some(0) {
+            return Err(invalid(format!("Token {} has a limit of zero", entry.name)));
+        }
+        let token = Token {
+            name: entry.name,
+            max_addrs: entry.max_addrs,
+            // Bursts of a second's worth, as for --rate-limit.
+            rate_limit: entry.requests_per_sec.map(|per_sec| RateLimiter::new(per_sec, per_sec)),
+        };
+        if let Some(dup) = tokens.insert(entry.token, Arc::new(token)) {
+            return Err(invalid(format!("Token {} is listed twice", dup.name)));
+        }
+    }
+    if tokens.is_empty() {
+        return Err(invalid(format!("No tokens in {}", path.display())));
+    }
+    Ok(tokens)
+}
+
+/// Reloads the tokens at `path` whenever it's modified, until they're
+/// dropped.
+async fn watch(path: PathBuf, mut modified: SystemTime, tokens: Weak<Tokens>) {
+    let mut interval = time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let tokens = match tokens.upgrade() {
+            Some(tokens) => tokens,
+            None => return,
+        };
+        match fs::metadata(&path).and_then(|meta| meta.modified()) {
+            Ok(time) if time != modified => modified = time,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Could not check tokens file {}: {}", path.display(), e);
+                continue;
+            }
+        }
+        match read_tokens(&path) {
+            Ok(new) => {
+                info!("Reloaded {} token(s) from {}", new.len(), path.display());
+                *tokens.write().unwrap() = Arc::new(new);
+            }
+            Err(e) => warn!("Could not reload {}, keeping the tokens: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::process;
+
+    use super::*;
+
+    const TOKENS: &str = r#"
+[[tokens]]
+name = "ci"
+token = "secret"
+max_addrs = 100
+requests_per_sec = 10
+
+[[tokens]]
+name = "dev"
+token = "hunter2"
+"#;
+
+    /// Writes `contents` to a tokens file named after the test.
+    fn tokens_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tokens-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn read_limits() {
+        let path = tokens_file("read", TOKENS);
+        let auth = Auth::open(&path).unwrap();
+        let ci = auth.lookup("secret").unwrap();
+        assert_eq!(ci.name, "ci");
+        assert_eq!(ci.max_addrs, Some(100));
+        let limiter = ci.rate_limit.as_ref().unwrap();
+        assert_eq!(limiter.per_sec(), 10);
+        let dev = auth.lookup("hunter2").unwrap();
+        assert_eq!(dev.name, "dev");
+        assert!(dev.max_addrs.is_none() && dev.rate_limit.is_none());
+        assert!(auth.lookup("ci").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_files() {
+        let entry = |fields: &str| format!("[[tokens]]\nname = \"ci\"\n{}\n", fields);
+        let files = [
+            entry("token = \"\""),
+            entry("token = \"secret\"\nmax_addrs = 0"),
+            entry("token = \"secret\"\nrequests_per_sec = 0"),
+            entry("token = \"secret\"\nmax_adrs = 10"),
+            entry("token = \"secret\"") + &entry("token = \"secret\""),
+            "tokens = []".to_string(),
+        ];
+        let path = tokens_file("invalid", "");
+        for contents in files.iter() {
+            fs::write(&path, contents).unwrap();
+            match read_tokens(&path) {
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", contents),
+                Ok(_) => panic!("Read {}", contents),
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rotates_tokens() {
+        let path = tokens_file("rotate", TOKENS);
+        let auth = Auth::open(&path).unwrap();
+        assert!(auth.lookup("secret").is_some());
+
+        let rewrite = |contents: &str| {
+            let modified = fs::metadata(&path).unwrap().modified().unwrap();
+            fs::write(&path, contents).unwrap();
+            let file = File::options().write(true).open(&path).unwrap();
+            file.set_modified(modified + Duration::from_secs(10)).unwrap();
+        };
+        rewrite("[[tokens]]\nname = \"ci\"\ntoken = \"rotated\"\n");
+        time::sleep(RELOAD_INTERVAL * 2).await;
+        assert!(auth.lookup("secret").is_none());
+        assert_eq!(auth.lookup("rotated").unwrap().name, "ci");
+
+        // An invalid file is ignored.
+        rewrite("tokens = []");
+        time::sleep(RELOAD_INTERVAL * 2).await;
+        assert!(auth.lookup("rotated").is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
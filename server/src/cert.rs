@@ -0,0 +1,99 @@
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+use x509_parser::prelude::*;
+
+/// The identity of the client of `stream`, from the certificate it
+/// presented, if any: the common name of its subject, or the whole subject
+/// if it has none.
+pub fn client_identity(stream: &TlsStream<TcpStream>) -> Option<String> {
+    let (_, conn) = stream.get_ref();
+    let der = &conn.peer_certificates()?.first()?.0;
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let subject = cert.subject();
+    let identity = match subject.iter_common_name().next().and_then(|cn| cn.as_str().ok()) {
+        Some(cn) => Some(cn.to_string()),
+        None if subject.iter().next().is_some() => Some(subject.to_string()),
+        None => None,
+    };
+    identity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::Arc;
+
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+    use tokio_rustls::rustls::{
+        Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig, ServerName,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    fn testdata(name: &str) -> BufReader<File> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/");
+        BufReader::new(File::open(format!("{}{}", path, name)).unwrap())
+    }
+
+    fn certs(name: &str) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut testdata(name)).unwrap().into_iter().map(Certificate).collect()
+    }
+
+    fn key(name: &str) -> PrivateKey {
+        PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut testdata(name)).unwrap().remove(0))
+    }
+
+    fn roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(&certs("ca.pem")[0]).unwrap();
+        roots
+    }
+
+    /// The identity the server tells from a handshake with a client
+    /// presenting the certificate of `client`, if any.
+    async fn handshake(client: Option<&str>) -> Option<String> {
+        let config = ServerConfig::builder().with_safe_defaults();
+        let config = match client {
+            Some(_) => config.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(roots()).boxed(),
+            ),
+            None => config.with_no_client_auth(),
+        };
+        let config = config.with_single_cert(certs("server.pem"), key("server.key")).unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots());
+        let config = match client {
+            Some(name) => {
+                let (cert, key) = (certs(&format!("{}.pem", name)), key(&format!("{}.key", name)));
+                config.with_client_auth_cert(cert, key).unwrap()
+            }
+            None => config.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let domain = ServerName::try_from("localhost").unwrap();
+            // Kept open until the server is done with the handshake.
+            connector.connect(domain, stream).await.unwrap()
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        let identity = client_identity(&stream);
+        connect.await.unwrap();
+        identity
+    }
+
+    #[tokio::test]
+    async fn identifies_clients_by_their_certificate() {
+        assert_eq!(handshake(Some("client")).await.as_deref(), Some("ci-client"));
+        assert_eq!(handshake(None).await, None);
+    }
+}
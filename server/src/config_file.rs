@@ -0,0 +1,580 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use tokio::sync::watch;
+use tokio::time;
+
+use tracing::level_filters::LevelFilter;
+
+use addrcore::Cidr;
+
+use server::{CidrGenerator, Endpoint, MixGenerator, PoolGenerator, RandomGenerator};
+
+use crate::LogFormat;
+
+/// Settings read from a file, for those too involved for command line
+/// options and to keep the rest in one place. Options given on the command
+/// line take precedence.
+///
+/// ```toml
+/// listen = ["127.0.0.1:7000", "/run/addrserver.sock"]
+///
+/// [limits]
+/// max_connections = 1000
+/// max_addrs = 100
+/// rate_limit = 10
+/// rate_burst = 20
+/// idle_timeout = "60s"
+///
+/// [generator]
+/// seed = 42
+/// no_repeat = 1000000
+///
+/// [log]
+/// level = "debug"
+/// file = "/var/log/addrserver.log"
+/// format = "json"
+///
+/// [tls]
+/// cert = "cert.pem"
+/// key = "key.pem"
+/// client_ca = "ca.pem"
+///
+/// [[schedules]]
+/// days = ["mon", "tue", "wed", "thu", "fri"]
+/// start = "09:00"
+/// end = "17:00"
+/// timezone = "Europe/Berlin"
+/// max_addrs = 10
+/// rate_limit = 5
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// As for --listen, in addition to those given on the command line.
+    #[serde(deserialize_with = "endpoints")]
+    pub listen: Vec<Endpoint>,
+    pub limits: Limits,
+    pub generator: Generator,
+    pub log: Log,
+    pub tls: Option<Tls>,
+    /// Sources of addresses, each serving a share of them in proportion to
+    /// its weight, instead of random ones.
+    pub pools: Vec<Pool>,
+    /// Limits that apply instead of `limits` during time windows.
+    pub schedules: Vec<Schedule>,
+}
+
+/// As for the options of the same names.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_connections: Option<u64>,
+    pub max_addrs: Option<u32>,
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// As for the options of the same names.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Generator {
+    pub seed: Option<u64>,
+    pub no_repeat: Option<u64>,
+}
+
+/// As for --log-level, --log-file and --log-format.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    #[serde(deserialize_with = "level")]
+    pub level: Option<LevelFilter>,
+    pub file: Option<PathBuf>,
+    pub format: Option<LogFormat>,
+}
+
+/// Serves TLS with a certificate and key, as for --tls, requiring clients
+/// to present a certificate signed by `client_ca` if set, as for
+/// --require-client-cert.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+}
+
+/// A source of addresses: a pool file, CIDR blocks or else random addresses.
+///
+/// ```toml
+/// [[pools]]
+/// weight = 80
+/// file = "peers.txt"
+///
+/// [[pools]]
+/// weight = 20
+/// cidrs = ["10.0.0.0/8"]
+/// ```
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pool {
+    pub weight: u32,
+    /// As for --pool-file.
+    pub file: Option<PathBuf>,
+    /// As for --without-replacement.
+    #[serde(default)]
+    pub without_replacement: bool,
+    /// As for --generate-from.
+    #[serde(default)]
+    pub cidrs: Vec<String>,
+}
+
+/// Limits that apply instead of those of `[limits]` from `start` to `end`
+/// on the given days, e.g. lower caps during business hours. The first
+/// schedule whose window the time falls in applies, and the limits it leaves
+/// unset are those of `[limits]`. A window ending at or before its start
+/// ends the next day.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    /// Days the window starts on, such as "mon", or every day if empty.
+    #[serde(default, deserialize_with = "weekdays")]
+    pub days: Vec<Weekday>,
+    /// Start of the window as HH:MM, included.
+    #[serde(deserialize_with = "time_of_day")]
+    pub start: NaiveTime,
+    /// End of the window as HH:MM, excluded.
+    #[serde(deserialize_with = "time_of_day")]
+    pub end: NaiveTime,
+    /// Time zone of the start and end, such as "Europe/Berlin", following
+    /// its daylight saving time. UTC by default.
+    #[serde(default, deserialize_with = "time_zone")]
+    pub timezone: Option<Tz>,
+    pub max_addrs: Option<u32>,
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+}
+
+impl Schedule {
+    /// Whether `now` falls within the window.
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let now = now.with_timezone(&self.timezone.unwrap_or(Tz::UTC));
+        let (date, time) = (now.date_naive(), now.time());
+        let starts_on =
+            |date: NaiveDate| self.days.is_empty() || self.days.contains(&date.weekday());
+        if self.start < self.end {
+            return starts_on(date) && self.start <= time && time < self.end;
+        }
+        // Past midnight, the window started the day before.
+        let yesterday = date.pred_opt().is_some_and(starts_on);
+        (starts_on(date) && self.start <= time) || (yesterday && time < self.end)
+    }
+}
+
+/// The limits in force at `now`: those of the first schedule whose window
+/// contains it, and of `[limits]` for those it leaves unset. A schedule
+/// setting a rate limit but no burst allows bursts of the rate.
+pub fn limits_at(config: &ConfigFile, now: DateTime<Utc>) -> Limits {
+    let mut limits = config.limits.clone();
+    if let Some(schedule) = config.schedules.iter().find(|schedule| schedule.contains(now)) {
+        limits.max_addrs = schedule.max_addrs.or(limits.max_addrs);
+        if schedule.rate_limit.is_some() {
+            limits.rate_limit = schedule.rate_limit;
+            limits.rate_burst = schedule.rate_burst;
+        }
+    }
+    limits
+}
+
+/// Applies the limits in force as windows of the schedules of the latest of
+/// `configs` open and close, and as soon as a new config is sent, reading
+/// the time off `clock`. Windows open and close on the minute, so the limits
+/// are looked at again on every minute.
+pub async fn follow_schedules(
+    mut configs: watch::Receiver<ConfigFile>,
+    clock: impl Fn() -> DateTime<Utc>,
+    apply: impl Fn(&Limits),
+) {
+    loop {
+        let now = clock();
+        apply(&limits_at(&configs.borrow_and_update(), now));
+        // Nanoseconds past a billion stand for a leap second.
+        let into_minute = Duration::new(now.second() as u64, now.nanosecond().min(999_999_999));
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(60) - into_minute) => {}
+            changed = configs.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Reads the config file at `path`.
+pub fn load(path: &Path) -> Result<ConfigFile, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let config: ConfigFile =
+        toml::from_str(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    let limits = &config.limits;
+    let zero = limits.max_connections == Some(0)
+        || [limits.max_addrs, limits.rate_limit, limits.rate_burst].contains(&Some(0))
+        || limits.idle_timeout == Some(Duration::ZERO);
+    if zero {
+        return Err(format!("Invalid {}: limits must be more than zero", path.display()));
+    }
+    if limits.rate_burst.is_some() && limits.rate_limit.is_none() {
+        return Err(format!("Invalid {}: rate_burst requires rate_limit", path.display()));
+    }
+    for (i, schedule) in config.schedules.iter().enumerate() {
+        let invalid = |e: &str| format!("Invalid {}: schedule {}: {}", path.display(), i + 1, e);
+        if [schedule.max_addrs, schedule.rate_limit, schedule.rate_burst].contains(&Some(0)) {
+            return Err(invalid("limits must be more than zero"));
+        }
+        if schedule.rate_burst.is_some() && schedule.rate_limit.is_none() {
+            return Err(invalid("rate_burst requires rate_limit"));
+        }
+        if schedule.start == schedule.end {
+            return Err(invalid("start and end must differ"));
+        }
+    }
+    Ok(config)
+}
+
+/// What changed from `old` to `new` that's only applied on restart.
+pub fn restart_needed(old: &ConfigFile, new: &ConfigFile) -> Vec<&'static str> {
+    let changes = [
+        ("listen", old.listen != new.listen),
+        ("limits.max_connections", old.limits.max_connections != new.limits.max_connections),
+        ("generator", old.generator != new.generator),
+        ("log.file", old.log.file != new.log.file),
+        ("log.format", old.log.format != new.log.format),
+        ("tls", old.tls.is_some() != new.tls.is_some()),
+        ("pools", old.pools != new.pools),
+    ];
+    changes.iter().filter(|(_, changed)| *changed).map(|(what, _)| *what).collect()
+}
+
+fn endpoints<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Endpoint>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| server::parse_endpoint(s).map_err(de::Error::custom))
+        .collect()
+}
+
+/// A duration such as 60s, as --idle-timeout takes.
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    humantime::parse_duration(&s).map(Some).map_err(de::Error::custom)
+}
+
+fn weekdays<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(|_| de::Error::custom(format!("invalid day {}", s))))
+        .collect()
+}
+
+/// A time of day such as 09:30.
+fn time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&s, "%H:%M").map_err(de::Error::custom)
+}
+
+fn time_zone<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Tz>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+fn level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<LevelFilter>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// Mixes the generators of `pools` by weight. Pool files are watched in the
+/// background, so this must be called within the runtime.
+pub fn mix(pools: &[Pool]) -> Result<MixGenerator, String> {
+    let mut mix = MixGenerator::new();
+    for (i, pool) in pools.iter().enumerate() {
+        let invalid = |e: &str| format!("Pool {}: {}", i + 1, e);
+        mix = match (&pool.file, pool.cidrs.as_slice()) {
+            (Some(_), [_, ..]) => return Err(invalid("only one of file and cidrs may be set")),
+            (Some(path), []) => {
+                let gen = PoolGenerator::open(path, !pool.without_replacement)
+                    .map_err(|e| invalid(&format!("could not load {}: {}", path.display(), e)))?;
+                mix.add(pool.weight, gen)
+            }
+            (None, []) => mix.add(pool.weight, RandomGenerator::default()),
+            (None, cidrs) => {
+                let cidrs = cidrs
+                    .iter()
+                    .map(|cidr| cidr.parse::<Cidr>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| invalid(&e))?;
+                mix.add(pool.weight, CidrGenerator::new(&cidrs).map_err(|e| invalid(&e))?)
+            }
+        };
+    }
+    if pools.iter().all(|pool| pool.weight == 0) {
+        return Err("No pool has a weight".to_string());
+    }
+    Ok(mix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    use chrono::TimeZone;
+
+    use tokio::time::Instant;
+
+    /// Loads `text` as a config file of its own.
+    fn load_str(name: &str, text: &str) -> Result<ConfigFile, String> {
+        let name = format!("config-{}-{}.toml", std::process::id(), name);
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, text).unwrap();
+        let config = load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn loads_every_section() {
+        let text = r#"
+            listen = ["127.0.0.1:7000", "/run/addrserver.sock"]
+
+            [limits]
+            max_connections = 1000
+            rate_limit = 10
+            idle_timeout = "60s"
+
+            [generator]
+            seed = 42
+
+            [log]
+            level = "debug"
+            format = "json"
+
+            [[pools]]
+            weight = 20
+            cidrs = ["10.0.0.0/8"]
+        "#;
+        let config = load_str("every", text).unwrap();
+        let listen = [
+            Endpoint::Tcp(([127, 0, 0, 1], 7000).into()),
+            Endpoint::Unix("/run/addrserver.sock".into()),
+        ];
+        assert_eq!(config.listen, listen);
+        assert_eq!(config.limits.max_connections, Some(1000));
+        assert_eq!(config.limits.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(config.generator.seed, Some(42));
+        assert_eq!(config.log.level, Some(LevelFilter::DEBUG));
+        assert_eq!(config.log.format, Some(LogFormat::Json));
+        assert_eq!(config.tls, None);
+        assert_eq!(config.pools[0].cidrs, ["10.0.0.0/8"]);
+        assert_eq!(load_str("empty", "").unwrap(), ConfigFile::default());
+    }
+
+    #[test]
+    fn rejects_bad_limits() {
+        for text in [
+            "[limits]\nmax_addrs = 0",
+            "[limits]\nidle_timeout = \"0s\"",
+            "[limits]\nrate_burst = 5",
+            "[limits]\nunknown = 1",
+            "listen = [\"nowhere\"]",
+        ] {
+            assert!(load_str("bad", text).is_err(), "{}", text);
+        }
+        assert!(load(Path::new("/nonexistent/config.toml")).is_err());
+    }
+
+    #[test]
+    fn tells_what_needs_a_restart() {
+        let old = load_str("old", "[limits]\nmax_addrs = 10\nmax_connections = 5").unwrap();
+        let new = load_str("new", "[limits]\nmax_addrs = 20\nmax_connections = 5").unwrap();
+        // Limits other than max_connections are reloaded.
+        assert!(restart_needed(&old, &new).is_empty());
+        let text = "[limits]\nmax_connections = 6\n[generator]\nseed = 1";
+        let new = load_str("restart", text).unwrap();
+        assert_eq!(restart_needed(&old, &new), ["limits.max_connections", "generator"]);
+    }
+
+    #[test]
+    fn checks_pools() {
+        let pool = |weight, cidrs: &[&str]| Pool {
+            weight,
+            file: None,
+            without_replacement: false,
+            cidrs: cidrs.iter().map(|cidr| cidr.to_string()).collect(),
+        };
+        assert!(mix(&[pool(1, &["10.0.0.0/8"]), pool(1, &[])]).is_ok());
+        assert!(mix(&[pool(0, &[])]).is_err());
+        assert!(mix(&[pool(1, &["::/0"])]).is_err());
+        assert!(mix(&[pool(1, &["not a cidr"])]).is_err());
+    }
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, sec).unwrap()
+    }
+
+    fn schedule(text: &str) -> Schedule {
+        let config = load_str("schedule", &format!("[[schedules]]\n{}", text)).unwrap();
+        config.schedules.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn loads_schedules() {
+        let text = r#"
+            days = ["mon", "Friday"]
+            start = "09:00"
+            end = "17:30"
+            timezone = "Europe/Berlin"
+            max_addrs = 10
+            rate_limit = 5
+            rate_burst = 8
+        "#;
+        let schedule = schedule(text);
+        assert_eq!(schedule.days, [Weekday::Mon, Weekday::Fri]);
+        assert_eq!(schedule.start, NaiveTime::from_hms_opt(9, 0, 0).unwrap());
+        assert_eq!(schedule.end, NaiveTime::from_hms_opt(17, 30, 0).unwrap());
+        assert_eq!(schedule.timezone, Some(chrono_tz::Europe::Berlin));
+        assert_eq!(schedule.max_addrs, Some(10));
+        assert_eq!((schedule.rate_limit, schedule.rate_burst), (Some(5), Some(8)));
+
+        let window = "start = \"09:00\"\nend = \"17:00\"\n";
+        for text in [
+            format!("{}max_addrs = 0", window),
+            format!("{}rate_burst = 5", window),
+            format!("{}days = [\"someday\"]", window),
+            format!("{}timezone = \"Mars/Olympus\"", window),
+            "start = \"9am\"\nend = \"17:00\"".to_string(),
+            "start = \"09:00\"\nend = \"09:00\"".to_string(),
+            "start = \"09:00\"".to_string(),
+        ] {
+            let text = format!("[[schedules]]\n{}", text);
+            assert!(load_str("bad-schedule", &text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn schedules_follow_their_time_zone() {
+        let schedule = schedule(concat!(
+            "days = [\"mon\"]\nstart = \"09:00\"\nend = \"17:00\"\n",
+            "timezone = \"Europe/Berlin\"",
+        ));
+        // CET in winter, a Monday.
+        assert!(!schedule.contains(utc(2024, 1, 8, 7, 59, 59)));
+        assert!(schedule.contains(utc(2024, 1, 8, 8, 0, 0)));
+        assert!(schedule.contains(utc(2024, 1, 8, 15, 59, 59)));
+        assert!(!schedule.contains(utc(2024, 1, 8, 16, 0, 0)));
+        // CEST in summer, also a Monday.
+        assert!(schedule.contains(utc(2024, 7, 8, 7, 0, 0)));
+        assert!(!schedule.contains(utc(2024, 7, 8, 15, 0, 0)));
+        // The next day.
+        assert!(!schedule.contains(utc(2024, 7, 9, 8, 0, 0)));
+
+        // UTC by default.
+        let schedule = self::schedule("start = \"09:00\"\nend = \"17:00\"");
+        assert!(schedule.contains(utc(2024, 7, 9, 9, 0, 0)));
+        assert!(!schedule.contains(utc(2024, 7, 9, 8, 0, 0)));
+    }
+
+    #[test]
+    fn windows_past_midnight_end_the_next_day() {
+        let schedule = schedule("days = [\"fri\"]\nstart = \"22:00\"\nend = \"06:00\"");
+        // Friday the 12th to Saturday the 13th.
+        assert!(!schedule.contains(utc(2024, 1, 12, 5, 0, 0)));
+        assert!(!schedule.contains(utc(2024, 1, 12, 21, 59, 59)));
+        assert!(schedule.contains(utc(2024, 1, 12, 22, 0, 0)));
+        assert!(schedule.contains(utc(2024, 1, 13, 5, 59, 59)));
+        assert!(!schedule.contains(utc(2024, 1, 13, 6, 0, 0)));
+        assert!(!schedule.contains(utc(2024, 1, 13, 23, 0, 0)));
+    }
+
+    #[test]
+    fn first_schedule_in_force_overrides_limits() {
+        let text = r#"
+            [limits]
+            max_addrs = 100
+            rate_limit = 10
+            rate_burst = 20
+            idle_timeout = "60s"
+
+            [[schedules]]
+            start = "09:00"
+            end = "17:00"
+            max_addrs = 10
+
+            [[schedules]]
+            start = "00:00"
+            end = "00:00"
+        "#;
+        assert!(load_str("limits-at", text).is_err());
+        let text = text.replace("end = \"00:00\"", "end = \"23:59\"\nrate_limit = 5");
+        let config = load_str("limits-at", &text).unwrap();
+
+        let limits = limits_at(&config, utc(2024, 1, 8, 12, 0, 0));
+        assert_eq!(limits.max_addrs, Some(10));
+        assert_eq!((limits.rate_limit, limits.rate_burst), (Some(10), Some(20)));
+        assert_eq!(limits.idle_timeout, Some(Duration::from_secs(60)));
+
+        let limits = limits_at(&config, utc(2024, 1, 8, 18, 0, 0));
+        assert_eq!(limits.max_addrs, Some(100));
+        assert_eq!((limits.rate_limit, limits.rate_burst), (Some(5), None));
+
+        let limits = limits_at(&config, utc(2024, 1, 8, 23, 59, 0));
+        assert_eq!(limits, config.limits);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_schedules_as_windows_open_and_close() {
+        let text = concat!(
+            "[limits]\nmax_addrs = 100\n",
+            "[[schedules]]\nstart = \"09:00\"\nend = \"09:02\"\nmax_addrs = 10",
+        );
+        let config = load_str("follow", text).unwrap();
+        // The wall clock goes along with the paused one.
+        let (start, started) = (utc(2024, 1, 8, 8, 58, 30), Instant::now());
+        let clock = move || start + (Instant::now() - started);
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let record = applied.clone();
+        let apply = move |limits: &Limits| {
+            let time = clock().format("%H:%M:%S").to_string();
+            record.lock().unwrap().push((time, limits.max_addrs.unwrap()));
+        };
+        let (configs, configs_rx) = watch::channel(config);
+        tokio::spawn(follow_schedules(configs_rx, clock, apply));
+
+        time::sleep(Duration::from_secs(4 * 60)).await;
+        let expected = [
+            ("08:58:30", 100),
+            ("08:59:00", 100),
+            ("09:00:00", 10),
+            ("09:01:00", 10),
+            ("09:02:00", 100),
+        ];
+        let expected: Vec<_> = expected.iter().map(|(time, n)| (time.to_string(), *n)).collect();
+        assert_eq!(*applied.lock().unwrap(), expected);
+
+        // A reloaded config applies right away.
+        let config = load_str("follow", "[limits]\nmax_addrs = 50").unwrap();
+        configs.send(config).unwrap();
+        tokio::task::yield_now().await;
+        let last = applied.lock().unwrap().last().cloned();
+        assert_eq!(last, Some(("09:02:30".to_string(), 50)));
+    }
+}
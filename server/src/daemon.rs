@@ -0,0 +1,263 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often `stop` checks whether the server has exited.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The daemon's end of the pipe its original process waits on, to tell the
+/// terminal whether it started.
+pub struct Detached {
+    ready: File,
+}
+
+/// Detaches the process from its terminal and session, by forking twice
+/// so that it can't acquire one again. The original process exits once the
+/// daemon calls `Detached::ready`, or with an error if the daemon exits
+/// first, so that failing to start is still noticed by whatever started it.
+///
+/// Forking only keeps the calling thread, so this must be called before
+/// any other thread is spawned, such as those of the runtime.
+pub fn daemonize(log_file: &Path) -> io::Result<Detached> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    if fork()? {
+        drop(writer);
+        // Nothing is read if the daemon exits before it's ready.
+        let mut ready = [0];
+        if reader.read(&mut ready).unwrap_or(0) == 0 {
+            eprintln!("The server failed to start, see {}", log_file.display());
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+    drop(reader);
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // No longer a session leader, so opening a terminal won't make it ours.
+    if fork()? {
+        process::exit(0);
+    }
+    Ok(Detached { ready: writer })
+}
+
+impl Detached {
+    /// Lets the original process exit, and detaches stdin, stdout and
+    /// stderr, which were kept until now to report failing to start.
+    pub fn ready(mut self) -> io::Result<()> {
+        let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+        for fd in 0..=2 {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.ready.write_all(&[1])
+    }
+}
+
+/// Forks, returning whether this is the parent.
+fn fork() -> io::Result<bool> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// A file holding the pid of the server, removed when it's dropped.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Writes the pid of this process to `path`, unless it holds that of a
+    /// server that's still running. A stale pid is overwritten.
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        if let Some(pid) = read_pid(&path)? {
+            if is_running(pid) {
+                let msg = format!("Already running as pid {}, see {}", pid, path.display());
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+            }
+        }
+        fs::write(&path, format!("{}\n", process::id()))?;
+        Ok(Pidfile { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Another server may have taken it over since.
+        if let Ok(Some(pid)) = read_pid(&self.path) {
+            if pid == process::id() as libc::pid_t {
+                let _ = fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+/// The pid in the pidfile at `path`, if there is one.
+fn read_pid(path: &Path) -> io::Result<Option<libc::pid_t>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match contents.trim().parse() {
+        Ok(pid) if pid > 0 => Ok(Some(pid)),
+        _ => {
+            let msg = format!("{} doesn't hold a pid", path.display());
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+/// Whether a process with `pid` exists, though it may be another user's.
+fn is_running(pid: libc::pid_t) -> bool {
+    match unsafe { libc::kill(pid, 0) } {
+        0 => true,
+        _ => io::Error::last_os_error().raw_os_error() == Some(libc::EPERM),
+    }
+}
+
+/// Drains the server of the pidfile at `path` with SIGTERM, waiting for it
+/// to exit, and kills it if it hasn't within `timeout`. Returns the exit
+/// code of `stop`.
+pub fn stop(path: &Path, timeout: Duration) -> i32 {
+    let pid = match read_pid(path) {
+        Ok(Some(pid)) if is_running(pid) => pid,
+        Ok(Some(pid)) => {
+            println!("Not running, removing the stale pid {} in {}", pid, path.display());
+            let _ = fs::remove_file(path);
+            return 0;
+        }
+        Ok(None) => {
+            println!("Not running");
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } == -1 {
+        eprintln!("Could not stop pid {}: {}", pid, io::Error::last_os_error());
+        return 1;
+    }
+    let start = Instant::now();
+    while is_running(pid) {
+        if start.elapsed() >= timeout {
+            let timeout = humantime::format_duration(timeout);
+            println!("Killing pid {}, still draining after {}", pid, timeout);
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+            // It can't remove the pidfile itself anymore.
+            let _ = fs::remove_file(path);
+            return 0;
+        }
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+    println!("Stopped pid {}", pid);
+    0
+}
+
+/// Prints whether the server of the pidfile at `path` is running, returning
+/// the exit code of `status` as for LSB init scripts: 0 if it is, 1 if the
+/// pidfile is stale, 3 if there's none and 4 if it can't be read.
+pub fn status(path: &Path) -> i32 {
+    match read_pid(path) {
+        Ok(Some(pid)) if is_running(pid) => {
+            println!("Running as pid {}", pid);
+            0
+        }
+        Ok(Some(pid)) => {
+            println!("Not running, but {} holds the stale pid {}", path.display(), pid);
+            1
+        }
+        Ok(None) => {
+            println!("Not running");
+            3
+        }
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            4
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process::Command;
+
+    fn pidfile(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("pidfile-{}-{}", process::id(), name))
+    }
+
+    /// The pid of a process that has exited.
+    fn exited_pid() -> u32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    #[test]
+    fn keeps_the_pid_while_running() {
+        let path = pidfile("running");
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(process::id() as libc::pid_t));
+        assert_eq!(status(&path), 0);
+        let again = Pidfile::create(&path).err().unwrap();
+        assert_eq!(again.kind(), io::ErrorKind::AlreadyExists);
+        drop(pidfile);
+        assert!(!path.exists());
+        assert_eq!(status(&path), 3);
+    }
+
+    #[test]
+    fn overwrites_stale_pids() {
+        let path = pidfile("stale");
+        fs::write(&path, format!("{}\n", exited_pid())).unwrap();
+        assert_eq!(status(&path), 1);
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(status(&path), 0);
+        drop(pidfile);
+
+        fs::write(&path, format!("{}\n", exited_pid())).unwrap();
+        assert_eq!(stop(&path, Duration::from_secs(1)), 0);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn leaves_pidfiles_taken_over() {
+        let path = pidfile("taken");
+        let pidfile = Pidfile::create(&path).unwrap();
+        fs::write(&path, "1\n").unwrap();
+        drop(pidfile);
+        assert_eq!(read_pid(&path).unwrap(), Some(1));
+        fs::write(&path, "not a pid\n").unwrap();
+        assert_eq!(status(&path), 4);
+        assert_eq!(stop(&path, Duration::from_secs(1)), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_the_server() {
+        let path = pidfile("stop");
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        fs::write(&path, format!("{}\n", child.id())).unwrap();
+        // Reaped as soon as it exits, so it's no longer seen running.
+        let reaper = thread::spawn(move || child.wait().unwrap());
+        assert_eq!(stop(&path, Duration::from_secs(5)), 0);
+        assert!(!reaper.join().unwrap().success());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::net::IpAddr;
+
+use bytes::BytesMut;
+
+use addrcore::wire::bytes::{Reader, Writer};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+const HEADER_LEN: usize = 12;
+const FLAG_QR: u16 = 0x8000;
+const MASK_OPCODE: u16 = 0x7800;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+/// The first byte of a label that points elsewhere in the message.
+const POINTER: u8 = 0xc0;
+/// Points at the name of the question, right after the header.
+const NAME_POINTER: u16 = 0xc000 | HEADER_LEN as u16;
+const MAX_NAME_LEN: usize = 255;
+
+/// Response codes of RFC 1035.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rcode {
+    NoError = 0,
+    FormErr = 1,
+    ServFail = 2,
+    NotImp = 4,
+    Refused = 5,
+}
+
+/// A query with a single question, of which only what it takes to answer it
+/// is kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    flags: u16,
+    /// The question as it was sent, echoed back in the response, or empty
+    /// if it couldn't be read.
+    question: Vec<u8>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+impl Query {
+    /// Reads a query, laid out as
+    ///
+    /// <16:id><16:flags><16:qdcount><16:ancount><16:nscount><16:arcount>
+    /// <<8:len><label>>...<8:0><16:qtype><16:qclass>
+    ///
+    /// Records after the question, such as EDNS options, are ignored.
+    /// Responses and queries without exactly one question are rejected.
+    pub fn decode(datagram: &[u8]) -> io::Result<Query> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut query = Query::header(datagram)?;
+        if query.flags & FLAG_QR != 0 {
+            return Err(invalid("Not a query"));
+        }
+        let mut reader = Reader::new(&datagram[4..HEADER_LEN]);
+        if reader.u16()? != 1 {
+            return Err(invalid("Queries must have exactly one question"));
+        }
+        let mut reader = Reader::new(&datagram[HEADER_LEN..]);
+        let mut name_len = 0;
+        loop {
+            let len = reader.u8()?;
+            if len == 0 {
+                break;
+            }
+            // Names of questions are never compressed as nothing precedes
+            // them.
+            if len & POINTER != 0 {
+                return Err(invalid("Invalid label"));
+            }
+            name_len += 1 + len as usize;
+            if name_len > MAX_NAME_LEN {
+                return Err(invalid("Name too long"));
+            }
+            reader.take(len as usize)?;
+        }
+        query.qtype = reader.u16()?;
+        query.qclass = reader.u16()?;
+        let question_len = datagram.len() - HEADER_LEN - reader.rest().len();
+        query.question = datagram[HEADER_LEN..HEADER_LEN + question_len].to_vec();
+        Ok(query)
+    }
+
+    /// Reads the header of a query alone, which is enough to reject it.
+    pub fn header(datagram: &[u8]) -> io::Result<Query> {
+        let mut reader = Reader::new(datagram);
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        reader.take(HEADER_LEN - 4)?;
+        Ok(Query { id, flags, question: Vec::new(), qtype: 0, qclass: 0 })
+    }
+
+    /// Whether it's a query rather than a response, which mustn't be
+    /// answered lest two servers answer each other forever.
+    pub fn is_query(&self) -> bool {
+        self.flags & FLAG_QR == 0
+    }
+
+    pub fn opcode(&self) -> u8 {
+        ((self.flags & MASK_OPCODE) >> 11) as u8
+    }
+
+    /// Writes the response with `rcode` and an answer for each of `ips`,
+    /// with `ttl` in seconds. Answers that would make the response longer
+    /// than `max_len` are left out and the response marked truncated.
+    pub fn respond(&self, rcode: Rcode, ips: &[IpAddr], ttl: u32, max_len: usize) -> BytesMut {
+        let mut len = HEADER_LEN + self.question.len();
+        let mut fits = 0;
+        for ip in ips {
+            let rdata_len = if ip.is_ipv4() { 4 } else { 16 };
+            // The name pointer, type, class, TTL and data length precede it.
+            len += 12 + rdata_len;
+            if len > max_len {
+                break;
+            }
+            fits += 1;
+        }
+        let mut flags = FLAG_QR | FLAG_AA | self.flags & (MASK_OPCODE | FLAG_RD) | rcode as u16;
+        if fits < ips.len() {
+            flags |= FLAG_TC;
+        }
+        let mut buf = BytesMut::with_capacity(len);
+        let mut writer = Writer::new(&mut buf);
+        writer.u16(self.id);
+        writer.u16(flags);
+        writer.u16(if self.question.is_empty() { 0 } else { 1 });
+        writer.u16(fits as u16);
+        writer.u16(0);
+        writer.u16(0);
+        writer.slice(&self.question);
+        for ip in &ips[..fits] {
+            writer.u16(NAME_POINTER);
+            match ip {
+                IpAddr::V4(ip) => {
+                    writer.u16(TYPE_A);
+                    writer.u16(CLASS_IN);
+                    writer.u32(ttl);
+                    writer.u16(4);
+                    writer.slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    writer.u16(TYPE_AAAA);
+                    writer.u16(CLASS_IN);
+                    writer.u32(ttl);
+                    writer.u16(16);
+                    writer.slice(&ip.octets());
+                }
+            }
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A query for the A records of example.com, with recursion desired.
+    const QUERY: [u8; 29] = [
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p', b'l',
+        b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1,
+    ];
+
+    #[test]
+    fn decode_query() {
+        let query = Query::decode(&QUERY).unwrap();
+        assert_eq!(query.id, 0x1234);
+        assert_eq!(query.opcode(), 0);
+        assert_eq!(query.qtype, TYPE_A);
+        assert_eq!(query.qclass, CLASS_IN);
+        assert_eq!(query.question, &QUERY[HEADER_LEN..]);
+    }
+
+    #[test]
+    fn invalid_queries() {
+        assert!(Query::decode(&QUERY[..QUERY.len() - 1]).is_err());
+        assert!(Query::decode(&QUERY[..HEADER_LEN - 1]).is_err());
+        assert!(Query::header(&QUERY[..HEADER_LEN]).is_ok());
+
+        let mut response = QUERY;
+        response[2] |= 0x80;
+        assert!(Query::decode(&response).is_err());
+
+        let mut two_questions = QUERY;
+        two_questions[5] = 2;
+        assert!(Query::decode(&two_questions).is_err());
+
+        let mut pointer = QUERY;
+        pointer[HEADER_LEN] = 0xc0;
+        assert!(Query::decode(&pointer).is_err());
+    }
+
+    #[test]
+    fn respond() {
+        let query = Query::decode(&QUERY).unwrap();
+        let ips: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        let resp = query.respond(Rcode::NoError, &ips, 60, 512);
+        let mut reader = Reader::new(&resp);
+        assert_eq!(reader.u16().unwrap(), 0x1234);
+        // A response, authoritative, with recursion desired echoed back.
+        assert_eq!(reader.u16().unwrap(), 0x8500);
+        assert_eq!(reader.u16().unwrap(), 1);
+        assert_eq!(reader.u16().unwrap(), 2);
+        reader.take(4).unwrap();
+        assert_eq!(reader.take(QUERY.len() - HEADER_LEN).unwrap(), &QUERY[HEADER_LEN..]);
+
+        assert_eq!(reader.u16().unwrap(), NAME_POINTER);
+        assert_eq!(reader.u16().unwrap(), TYPE_A);
+        assert_eq!(reader.u16().unwrap(), CLASS_IN);
+        assert_eq!(reader.u32().unwrap(), 60);
+        assert_eq!(reader.u16().unwrap(), 4);
+        assert_eq!(reader.take(4).unwrap(), &[10, 0, 0, 1]);
+
+        assert_eq!(reader.u16().unwrap(), NAME_POINTER);
+        assert_eq!(reader.u16().unwrap(), TYPE_AAAA);
+        reader.take(6).unwrap();
+        assert_eq!(reader.u16().unwrap(), 16);
+        reader.take(16).unwrap();
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn truncated_response() {
+        let query = Query::decode(&QUERY).unwrap();
+        let ips = vec!["10.0.0.1".parse().unwrap(); 100];
+        let resp = query.respond(Rcode::NoError, &ips, 0, 512);
+        assert!(resp.len() <= 512);
+        let mut reader = Reader::new(&resp);
+        reader.u16().unwrap();
+        assert_ne!(reader.u16().unwrap() & FLAG_TC, 0);
+        reader.u16().unwrap();
+        assert_eq!(reader.u16().unwrap() as usize, (512 - QUERY.len()) / 16);
+    }
+
+    #[test]
+    fn reject_with_header_only() {
+        let query = Query::header(&QUERY).unwrap();
+        let resp = query.respond(Rcode::FormErr, &[], 0, 512);
+        assert_eq!(&resp[..], &[0x12, 0x34, 0x85, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
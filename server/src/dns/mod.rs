@@ -0,0 +1,191 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use tracing::{debug, warn};
+
+use tokio::net::UdpSocket;
+
+use addrcore::{ClientMessage, Constraints, ErrorCode, Family, Request, ServerMessage};
+
+use crate::{answer_request, make_generator, record, throttle, AddrGenerator, Settings};
+
+mod message;
+
+use self::message::{Query, Rcode, CLASS_IN, TYPE_A, TYPE_AAAA};
+
+/// The generator stream of DNS, far from those of gRPC calls, which count up
+/// from the streams of the other transports.
+const DNS_STREAM: u64 = u64::MAX;
+
+/// Longest response sent over UDP to clients that don't say they take more,
+/// per RFC 1035. EDNS isn't supported.
+const MAX_UDP_LEN: usize = 512;
+
+/// Answers A and AAAA queries with `answers` random addresses each, and any
+/// other type of record with none. AAAA queries get none either if the
+/// generator doesn't serve IPv6 addresses, and likewise A queries and IPv4.
+/// Such empty answers mean that the name exists but has no records of the
+/// type (NODATA). Queries that can't be read are rejected with a format
+/// error, those of other opcodes or classes as not implemented, and those
+/// over the rate limit are refused. No more than the server's limit of
+/// addresses is served at once, even if it's lowered on reload.
+pub async fn serve(socket: UdpSocket, answers: u32, settings: Arc<Settings>) {
+    let mut gen = make_generator(&settings, None, DNS_STREAM);
+    let mut buf = vec![0; MAX_UDP_LEN];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buf).await {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("Could not receive DNS query: {}", e);
+                continue;
+            }
+        };
+        let (query, rcode, ips) = match Query::decode(&buf[..len]) {
+            Ok(query) => {
+                let (rcode, ips) = answer(&query, &mut *gen, answers, addr, &settings);
+                (query, rcode, ips)
+            }
+            Err(e) => match Query::header(&buf[..len]) {
+                Ok(query) if query.is_query() => {
+                    debug!("Invalid DNS query from {}: {}", addr, e);
+                    (query, Rcode::FormErr, Vec::new())
+                }
+                _ => {
+                    debug!("Ignoring datagram from {} that isn't a DNS query", addr);
+                    continue;
+                }
+            },
+        };
+        let ttl = settings.ttl.unwrap_or(0);
+        let resp = query.respond(rcode, &ips, ttl, MAX_UDP_LEN);
+        // A client that's gone away only fails its own answer.
+        if let Err(e) = socket.send_to(&resp, addr).await {
+            warn!("Could not answer {} over DNS: {}", addr, e);
+        }
+    }
+}
+
+fn answer(
+    query: &Query,
+    gen: &mut dyn AddrGenerator,
+    answers: u32,
+    addr: SocketAddr,
+    settings: &Settings,
+) -> (Rcode, Vec<IpAddr>) {
+    debug!("Received DNS query {:?} from {}", query, addr);
+    if query.opcode() != 0 || query.qclass != CLASS_IN {
+        return (Rcode::NotImp, Vec::new());
+    }
+    let family = match query.qtype {
+        TYPE_A => Family::V4,
+        TYPE_AAAA => Family::V6,
+        // The name exists, but has no records of this type.
+        _ => return (Rcode::NoError, Vec::new()),
+    };
+    let constraints = Constraints { family: Some(family), ..Default::default() };
+    let req = Request { num_addrs: answers.min(settings.max_addrs()), constraints };
+    if throttle(settings, Some(addr.ip()), &ClientMessage::Request(req)).is_some() {
+        warn!("{} is over the rate limit", addr);
+        return (Rcode::Refused, Vec::new());
+    }
+    match answer_request(gen, &req, settings.max_addrs(), settings) {
+        ServerMessage::Response(resp) => {
+            let ips = resp.addrs.iter().map(|addr| addr.ip()).collect();
+            record(settings, format_args!("dns:{}", addr), &ServerMessage::Response(resp));
+            (Rcode::NoError, ips)
+        }
+        ServerMessage::Error(err) => match err.code {
+            // Such as AAAA records from a generator of IPv4 addresses only.
+            ErrorCode::Unsatisfiable => (Rcode::NoError, Vec::new()),
+            ErrorCode::RateLimited { .. } | ErrorCode::AuthFailed => (Rcode::Refused, Vec::new()),
+            ErrorCode::TooManyAddrs { .. } => {
+                warn!("Could not answer {} over DNS: {}", addr, err.message);
+                (Rcode::ServFail, Vec::new())
+            }
+        },
+        reply => unreachable!("{:?} answering a request", reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::sync::atomic::Ordering;
+
+    use crate::{Families, RandomGenerator, Server};
+
+    /// A query for the records of example.com of type `qtype` and class
+    /// `qclass`.
+    fn query(qtype: u16, qclass: u16) -> Query {
+        let mut datagram = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        datagram.extend_from_slice(b"\x07example\x03com\x00");
+        datagram.extend_from_slice(&qtype.to_be_bytes());
+        datagram.extend_from_slice(&qclass.to_be_bytes());
+        Query::decode(&datagram).unwrap()
+    }
+
+    async fn settings(families: Families) -> Arc<Settings> {
+        let server = Server::bind(([127, 0, 0, 1], 0).into())
+            .generator(RandomGenerator::new(families))
+            .build()
+            .await
+            .unwrap();
+        server.settings
+    }
+
+    fn ask(settings: &Settings, query: &Query) -> (Rcode, Vec<IpAddr>) {
+        let mut gen = make_generator(settings, None, DNS_STREAM);
+        answer(query, &mut *gen, 4, ([127, 0, 0, 1], 5353).into(), settings)
+    }
+
+    #[tokio::test]
+    async fn answers_by_family() {
+        let settings = settings(Families::Both { v6_ratio: 0.5 }).await;
+        let (rcode, ips) = ask(&settings, &query(TYPE_A, CLASS_IN));
+        assert_eq!(rcode, Rcode::NoError);
+        assert_eq!(ips.len(), 4);
+        assert!(ips.iter().all(IpAddr::is_ipv4));
+
+        let (rcode, ips) = ask(&settings, &query(TYPE_AAAA, CLASS_IN));
+        assert_eq!(rcode, Rcode::NoError);
+        assert_eq!(ips.len(), 4);
+        assert!(ips.iter().all(IpAddr::is_ipv6));
+    }
+
+    #[tokio::test]
+    async fn no_data_for_families_not_served() {
+        let v4_only = settings(Families::V4).await;
+        assert_eq!(ask(&v4_only, &query(TYPE_AAAA, CLASS_IN)), (Rcode::NoError, Vec::new()));
+        let v6_only = settings(Families::V6).await;
+        assert_eq!(ask(&v6_only, &query(TYPE_A, CLASS_IN)), (Rcode::NoError, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn answers_no_more_than_the_limit() {
+        let settings = settings(Families::V4).await;
+        settings.max_addrs.store(2, Ordering::Relaxed);
+        let (rcode, ips) = ask(&settings, &query(TYPE_A, CLASS_IN));
+        assert_eq!((rcode, ips.len()), (Rcode::NoError, 2));
+    }
+
+    #[tokio::test]
+    async fn rejects_more_answers_than_the_limit() {
+        let server = Server::bind(([127, 0, 0, 1], 0).into())
+            .dns(([127, 0, 0, 1], 0).into())
+            .max_addrs(2)
+            .dns_answers(3);
+        let err = server.build().await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn other_types_and_classes() {
+        let settings = settings(Families::V4).await;
+        // TXT records, of which there are none.
+        assert_eq!(ask(&settings, &query(16, CLASS_IN)), (Rcode::NoError, Vec::new()));
+        // The CHAOS class.
+        assert_eq!(ask(&settings, &query(TYPE_A, 3)), (Rcode::NotImp, Vec::new()));
+    }
+}
@@ -0,0 +1,202 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use tracing::warn;
+
+use addrcore::{Cidr, Constraints};
+
+use crate::gen::AddrGenerator;
+
+/// How many times an excluded address is replaced before giving up, which
+/// only happens if nearly all addresses that can be generated are excluded.
+const MAX_RESAMPLES: u32 = 1000;
+
+/// A kind of special-purpose range whose addresses can be left out.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Reserved {
+    /// 127.0.0.0/8 and ::1.
+    Loopback,
+    /// 224.0.0.0/4 and ff00::/8.
+    Multicast,
+    /// 169.254.0.0/16 and fe80::/10.
+    LinkLocal,
+    /// 255.255.255.255.
+    Broadcast,
+    /// The RFC 1918 ranges and IPv6 unique local addresses, fc00::/7.
+    Private,
+}
+
+impl Reserved {
+    pub const ALL: [Reserved; 5] = [
+        Reserved::Loopback,
+        Reserved::Multicast,
+        Reserved::LinkLocal,
+        Reserved::Broadcast,
+        Reserved::Private,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Reserved::Loopback => "loopback",
+            Reserved::Multicast => "multicast",
+            Reserved::LinkLocal => "link-local",
+            Reserved::Broadcast => "broadcast",
+            Reserved::Private => "private",
+        }
+    }
+
+    fn blocks(self) -> Vec<Cidr> {
+        let v4 = |a, b, prefix_len| Cidr { addr: Ipv4Addr::new(a, b, 0, 0).into(), prefix_len };
+        let v6 = |segment, prefix_len| Cidr {
+            addr: Ipv6Addr::new(segment, 0, 0, 0, 0, 0, 0, 0).into(),
+            prefix_len,
+        };
+        match self {
+            Reserved::Loopback => {
+                vec![v4(127, 0, 8), Cidr { addr: Ipv6Addr::LOCALHOST.into(), prefix_len: 128 }]
+            }
+            Reserved::Multicast => vec![v4(224, 0, 4), v6(0xff00, 8)],
+            Reserved::LinkLocal => vec![v4(169, 254, 16), v6(0xfe80, 10)],
+            Reserved::Broadcast => vec![Cidr { addr: Ipv4Addr::BROADCAST.into(), prefix_len: 32 }],
+            Reserved::Private => {
+                vec![v4(10, 0, 8), v4(172, 16, 12), v4(192, 168, 16), v6(0xfc00, 7)]
+            }
+        }
+    }
+}
+
+impl FromStr for Reserved {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Reserved, String> {
+        Reserved::ALL.iter().copied().find(|kind| kind.name() == s).ok_or_else(|| {
+            let names: Vec<_> = Reserved::ALL.iter().map(|kind| kind.name()).collect();
+            format!("unknown range {}, expected one of {}", s, names.join(", "))
+        })
+    }
+}
+
+impl fmt::Display for Reserved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Wraps a generator, replacing the addresses it generates in excluded
+/// ranges with new ones until they're outside all of them.
+pub struct Exclude {
+    inner: Box<dyn AddrGenerator>,
+    blocks: Vec<Cidr>,
+}
+
+impl Exclude {
+    pub fn new(inner: Box<dyn AddrGenerator>, excluded: &[Reserved]) -> Self {
+        let blocks = excluded.iter().flat_map(|kind| kind.blocks()).collect();
+        Exclude { inner, blocks }
+    }
+
+    fn excludes(&self, ip: IpAddr) -> bool {
+        self.blocks.iter().any(|block| block.contains(ip))
+    }
+
+    /// Whether all of `cidr` lies within an excluded block.
+    fn covers(&self, cidr: Cidr) -> bool {
+        self.blocks
+            .iter()
+            .any(|block| block.prefix_len <= cidr.prefix_len && block.contains(cidr.addr))
+    }
+}
+
+impl AddrGenerator for Exclude {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        self.inner.check(constraints)?;
+        match constraints.cidr {
+            Some(cidr) if self.covers(cidr) => {
+                Err(format!("{} only holds excluded addresses", cidr))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr {
+        let mut addr = self.inner.next_addr(constraints);
+        for _ in 0..MAX_RESAMPLES {
+            if !self.excludes(addr.ip()) {
+                return addr;
+            }
+            addr = self.inner.next_addr(constraints);
+        }
+        warn!("Serving excluded address {} after {} resamples", addr, MAX_RESAMPLES);
+        addr
+    }
+
+    fn generate(&mut self, n: usize, constraints: &Constraints) -> Vec<SocketAddr> {
+        // Generated in one go as the inner generator may do it faster.
+        let mut addrs = self.inner.generate(n, constraints);
+        for addr in addrs.iter_mut() {
+            if self.excludes(addr.ip()) {
+                *addr = self.next_addr(constraints);
+            }
+        }
+        addrs
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.inner.reseed(seed);
+    }
+
+    fn next_seed(&mut self) -> Option<u64> {
+        self.inner.next_seed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gen::CidrGenerator;
+
+    /// Generates from `cidr`, leaving out private ranges.
+    fn exclude(cidr: &str) -> Exclude {
+        let inner = CidrGenerator::new(&[cidr.parse().unwrap()]).unwrap();
+        Exclude::new(Box::new(inner), &[Reserved::Private])
+    }
+
+    #[test]
+    fn parse_ranges() {
+        for kind in Reserved::ALL.iter() {
+            assert_eq!(kind.to_string().parse::<Reserved>(), Ok(*kind));
+        }
+        assert!("public".parse::<Reserved>().is_err());
+    }
+
+    #[test]
+    fn leaves_out_excluded() {
+        // Half of 10.0.0.0/7 is 10.0.0.0/8, which is private.
+        let mut gen = exclude("10.0.0.0/7");
+        let constraints = Constraints::default();
+        let addrs = gen.generate(1000, &constraints);
+        assert_eq!(addrs.len(), 1000);
+        assert!(addrs.iter().all(|addr| !gen.excludes(addr.ip())), "{:?}", addrs);
+        let addr = gen.next_addr(&constraints);
+        assert!(!gen.excludes(addr.ip()), "{}", addr);
+    }
+
+    #[test]
+    fn rejects_excluded_blocks() {
+        let gen = exclude("10.0.0.0/7");
+        let within =
+            |cidr: &str| Constraints { cidr: Some(cidr.parse().unwrap()), ..Default::default() };
+        assert!(gen.check(&within("10.1.0.0/16")).is_err());
+        assert!(gen.check(&within("10.0.0.0/8")).is_err());
+        // Only partly excluded.
+        assert!(gen.check(&within("10.0.0.0/7")).is_ok());
+        assert!(gen.check(&within("11.0.0.0/8")).is_ok());
+    }
+
+    #[test]
+    fn gives_up_when_all_excluded() {
+        let mut gen = exclude("192.168.0.0/24");
+        assert_eq!(gen.generate(2, &Constraints::default()).len(), 2);
+    }
+}
@@ -0,0 +1,435 @@
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::ready;
+use futures::stream::{Stream, StreamExt};
+
+use addrcore::{FamilyCounts, Response, ServerMessage};
+
+/// Credit based flow control for the messages written to a client.
+///
+/// Until the client first grants credits, messages pass through untouched.
+/// From then on no more address entries are let through than the client has
+/// granted: a response that doesn't fit is split into `Partial` chunks
+/// followed by the final `Response`, and updates are split into several
+/// updates with the same sequence number. Once credits run out, messages are
+/// held back until the next grant, or the stream ends if the client can't
+/// grant any more.
+///
+/// Responses and updates may also be streamed in chunks whatever the
+/// credits, sized by `AdaptiveChunks` to how fast the client reads them.
+pub struct FlowControl<S> {
+    messages: S,
+    grants: mpsc::UnboundedReceiver<u32>,
+    credits: Option<u64>,
+    pending: Option<ServerMessage>,
+    chunks: Option<AdaptiveChunks>,
+}
+
+impl<S> FlowControl<S>
+where
+    S: Stream<Item = ServerMessage> + Unpin,
+{
+    pub fn new(messages: S, grants: mpsc::UnboundedReceiver<u32>) -> Self {
+        FlowControl { messages, grants, credits: None, pending: None, chunks: None }
+    }
+
+    /// Splits responses and updates into chunks sized by `chunks`, which
+    /// must be told how long each took to write.
+    pub fn with_chunks(mut self, chunks: AdaptiveChunks) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Accounts for a message with `entries` addresses having taken
+    /// `elapsed` to write, if streaming in chunks.
+    pub fn wrote(&mut self, entries: usize, elapsed: Duration) {
+        if let Some(ref mut chunks) = self.chunks {
+            chunks.record(entries, elapsed);
+        }
+    }
+}
+
+/// Smallest chunk streamed, however slowly the client reads.
+const MIN_CHUNK: usize = 16;
+
+/// How long writing a chunk should take. Chunks are made as large as the
+/// client reads in this long, so that slow clients get small ones that
+/// interleave with other messages while fast ones aren't held up by many
+/// small writes.
+const CHUNK_WRITE_TIME: Duration = Duration::from_millis(20);
+
+/// Weight of the latest write in the estimated time the client takes to
+/// read an address.
+const SMOOTHING: f64 = 0.25;
+
+/// Writes taking less than this are taken to have taken this long, as they
+/// only went as far as the socket buffer.
+const MIN_WRITE_TIME: Duration = Duration::from_micros(10);
+
+/// Sizes the chunks a connection's responses are streamed in, from the rate
+/// the client reads them at as seen by how long writing chunks takes.
+///
+/// Starts at the smallest size and at most doubles with every write, so that
+/// quick writes into an empty socket buffer don't make for a huge chunk
+/// right away, but shrinks as soon as writes slow down. The time per address
+/// is averaged rather than the rate, so that a slow write weighs as much as
+/// it delays the client.
+#[derive(Clone, Debug)]
+pub struct AdaptiveChunks {
+    size: usize,
+    min: usize,
+    max: usize,
+    /// Estimated seconds it takes to write an address, once anything was
+    /// written.
+    secs_per_addr: Option<f64>,
+}
+
+impl AdaptiveChunks {
+    /// Chunks of `MIN_CHUNK` to `max` addresses, or of `max` alone if it's
+    /// smaller.
+    pub fn new(max: usize) -> Self {
+        let min = MIN_CHUNK.min(max).max(1);
+        AdaptiveChunks { size: min, min, max: max.max(min), secs_per_addr: None }
+    }
+
+    /// Addresses in the next chunk.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Adapts the size to `entries` addresses having taken `elapsed` to
+    /// write.
+    pub fn record(&mut self, entries: usize, elapsed: Duration) {
+        if entries == 0 {
+            return;
+        }
+        let sample = elapsed.max(MIN_WRITE_TIME).as_secs_f64() / entries as f64;
+        let secs_per_addr = match self.secs_per_addr {
+            Some(secs) => secs + SMOOTHING * (sample - secs),
+            None => sample,
+        };
+        self.secs_per_addr = Some(secs_per_addr);
+        let target = (CHUNK_WRITE_TIME.as_secs_f64() / secs_per_addr) as usize;
+        self.size = target.min(self.size.saturating_mul(2)).clamp(self.min, self.max);
+    }
+}
+
+impl<S> Stream for FlowControl<S>
+where
+    S: Stream<Item = ServerMessage> + Unpin,
+{
+    type Item = ServerMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ServerMessage>> {
+        // Drain all grants, which also registers interest in future ones in
+        // case we run out of credits below.
+        let mut grants_closed = false;
+        loop {
+            match self.grants.poll_next_unpin(cx) {
+                Poll::Ready(Some(n)) => *self.credits.get_or_insert(0) += n as u64,
+                Poll::Ready(None) => {
+                    grants_closed = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        let msg = match self.pending.take() {
+            Some(msg) => msg,
+            None => match ready!(self.messages.poll_next_unpin(cx)) {
+                Some(msg) => msg,
+                None => return Poll::Ready(None),
+            },
+        };
+        let chunk_size = self.chunks.as_ref().map(|chunks| chunks.size() as u64);
+        // The most entries that may be let through at once.
+        let limit = match (self.credits, chunk_size) {
+            (None, None) => return Poll::Ready(Some(msg)),
+            (Some(credits), None) => credits,
+            (None, Some(size)) => size,
+            (Some(credits), Some(size)) => credits.min(size),
+        };
+
+        // Partials are never queued but made here. Messages other than
+        // responses and updates carry no entries so they need no credits.
+        let (resp, is_update) = match msg {
+            ServerMessage::Response(resp) | ServerMessage::Partial(resp) => (resp, false),
+            ServerMessage::Update(resp) => (resp, true),
+            ServerMessage::Error(_)
+            | ServerMessage::Info(_)
+            | ServerMessage::Notice(_)
+            | ServerMessage::QuotaWarning(_) => return Poll::Ready(Some(msg)),
+        };
+        let len = resp.addrs.len() as u64;
+        if len <= limit {
+            if let Some(ref mut credits) = self.credits {
+                *credits -= len;
+            }
+            let msg = if is_update {
+                ServerMessage::Update(resp)
+            } else {
+                ServerMessage::Response(resp)
+            };
+            return Poll::Ready(Some(msg));
+        }
+
+        // Chunks are never empty, so only running out of credits stops us.
+        if limit == 0 {
+            // Nothing would ever wake us, and the client is gone anyway.
+            if grants_closed {
+                return Poll::Ready(None);
+            }
+            self.pending = Some(if is_update {
+                ServerMessage::Update(resp)
+            } else {
+                ServerMessage::Response(resp)
+            });
+            return Poll::Pending;
+        }
+
+        let Response { index, mut addrs, mut ttls, families, seed } = resp;
+        let split = limit as usize;
+        let rest = addrs.split_off(split);
+        // Every piece counts its own addresses, adding up to the whole.
+        let count = |addrs: &[SocketAddr]| families.map(|_| FamilyCounts::of(addrs));
+        let rest = Response {
+            index,
+            families: count(&rest),
+            addrs: rest,
+            ttls: ttls.as_mut().map(|ttls| ttls.split_off(split)),
+            seed,
+        };
+        let chunk = Response { index, families: count(&addrs), addrs, ttls, seed };
+        if let Some(ref mut credits) = self.credits {
+            *credits -= limit;
+        }
+        let (chunk, rest) = if is_update {
+            (ServerMessage::Update(chunk), ServerMessage::Update(rest))
+        } else {
+            (ServerMessage::Partial(chunk), ServerMessage::Response(rest))
+        };
+        self.pending = Some(rest);
+        Poll::Ready(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+    use futures::stream::{self, Iter};
+    use futures::FutureExt;
+
+    use addrcore::{ErrorCode, ErrorResponse};
+
+    type Messages = Iter<std::vec::IntoIter<ServerMessage>>;
+
+    fn response(index: u32, len: u16) -> Response {
+        let addrs = (0..len).map(|port| ([10, 0, 0, 1], port).into()).collect();
+        Response { index, addrs, ttls: None, families: None, seed: None }
+    }
+
+    fn error(index: u32) -> ServerMessage {
+        let code = ErrorCode::Unsatisfiable;
+        ServerMessage::Error(ErrorResponse { index, code, message: String::new() })
+    }
+
+    fn flow(messages: Vec<ServerMessage>) -> (FlowControl<Messages>, mpsc::UnboundedSender<u32>) {
+        let (grants_tx, grants_rx) = mpsc::unbounded();
+        (FlowControl::new(stream::iter(messages), grants_rx), grants_tx)
+    }
+
+    #[test]
+    fn passes_through_until_granted() {
+        let (mut flow, _grants) = flow(vec![ServerMessage::Response(response(0, 5))]);
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Response(response(0, 5))));
+        assert_eq!(block_on(flow.next()), None);
+    }
+
+    #[test]
+    fn holds_back_until_granted() {
+        let (mut flow, grants) = flow(vec![
+            ServerMessage::Response(response(0, 5)),
+            ServerMessage::Update(response(1, 2)),
+        ]);
+        grants.unbounded_send(3).unwrap();
+        let chunk = block_on(flow.next()).unwrap();
+        assert_eq!(chunk, ServerMessage::Partial(response(0, 3)));
+        assert!(flow.next().now_or_never().is_none());
+
+        grants.unbounded_send(1).unwrap();
+        let rest = match block_on(flow.next()) {
+            Some(ServerMessage::Partial(rest)) => rest,
+            msg => panic!("Expected a partial, got {:?}", msg),
+        };
+        assert_eq!(rest.addrs, response(0, 5).addrs[3..4].to_vec());
+        assert!(flow.next().now_or_never().is_none());
+
+        grants.unbounded_send(10).unwrap();
+        match block_on(flow.next()) {
+            Some(ServerMessage::Response(rest)) => assert_eq!(rest.addrs.len(), 1),
+            msg => panic!("Expected a response, got {:?}", msg),
+        }
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Update(response(1, 2))));
+    }
+
+    #[test]
+    fn splits_family_counts() {
+        let addrs: Vec<SocketAddr> = vec![
+            "10.0.0.1:1".parse().unwrap(),
+            "[2001:db8::1]:2".parse().unwrap(),
+            "10.0.0.2:3".parse().unwrap(),
+        ];
+        let families = Some(FamilyCounts::of(&addrs));
+        let resp = Response { index: 0, addrs, ttls: None, families, seed: None };
+        let (mut flow, grants) = flow(vec![ServerMessage::Response(resp)]);
+        grants.unbounded_send(2).unwrap();
+        match block_on(flow.next()) {
+            Some(ServerMessage::Partial(chunk)) => {
+                assert_eq!(chunk.families, Some(FamilyCounts { v4: 1, v6: 1 }))
+            }
+            msg => panic!("Expected a partial, got {:?}", msg),
+        }
+        grants.unbounded_send(1).unwrap();
+        match block_on(flow.next()) {
+            Some(ServerMessage::Response(rest)) => {
+                assert_eq!(rest.families, Some(FamilyCounts { v4: 1, v6: 0 }))
+            }
+            msg => panic!("Expected a response, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn errors_need_no_credits() {
+        let messages = vec![
+            ServerMessage::Response(response(0, 2)),
+            error(1),
+            ServerMessage::Info(Default::default()),
+            ServerMessage::Response(response(2, 1)),
+        ];
+        let (mut flow, grants) = flow(messages);
+        grants.unbounded_send(2).unwrap();
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Response(response(0, 2))));
+        assert_eq!(block_on(flow.next()), Some(error(1)));
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Info(Default::default())));
+        assert!(flow.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn streams_in_chunks() {
+        let (flow, _grants) = flow(vec![
+            ServerMessage::Response(response(0, 40)),
+            ServerMessage::Update(response(1, 20)),
+        ]);
+        let mut flow = flow.with_chunks(AdaptiveChunks::new(MIN_CHUNK));
+        let mut lens = Vec::new();
+        while let Some(msg) = block_on(flow.next()) {
+            match msg {
+                ServerMessage::Partial(chunk) => lens.push(("partial", chunk.addrs.len())),
+                ServerMessage::Response(rest) => lens.push(("response", rest.addrs.len())),
+                ServerMessage::Update(update) => lens.push(("update", update.addrs.len())),
+                msg => panic!("Unexpected {:?}", msg),
+            }
+        }
+        let expected = [
+            ("partial", 16),
+            ("partial", 16),
+            ("response", 8),
+            ("update", 16),
+            ("update", 4),
+        ];
+        assert_eq!(lens, expected);
+    }
+
+    #[test]
+    fn chunks_within_credits() {
+        let (flow, grants) = flow(vec![ServerMessage::Response(response(0, 40))]);
+        let mut flow = flow.with_chunks(AdaptiveChunks::new(MIN_CHUNK));
+        grants.unbounded_send(20).unwrap();
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Partial(response(0, 16))));
+        match block_on(flow.next()) {
+            Some(ServerMessage::Partial(chunk)) => assert_eq!(chunk.addrs.len(), 4),
+            msg => panic!("Expected a partial, got {:?}", msg),
+        }
+        assert!(flow.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn chunks_follow_writes() {
+        let (flow, _grants) = flow(vec![ServerMessage::Response(response(0, 100))]);
+        let mut flow = flow.with_chunks(AdaptiveChunks::new(1000));
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Partial(response(0, 16))));
+        flow.wrote(16, Duration::ZERO);
+        match block_on(flow.next()) {
+            Some(ServerMessage::Partial(chunk)) => assert_eq!(chunk.addrs.len(), 32),
+            msg => panic!("Expected a partial, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn chunks_grow_for_fast_clients() {
+        let mut chunks = AdaptiveChunks::new(4096);
+        assert_eq!(chunks.size(), MIN_CHUNK);
+        let mut sizes = Vec::new();
+        for _ in 0..10 {
+            let size = chunks.size();
+            chunks.record(size, Duration::from_micros(5));
+            sizes.push(chunks.size());
+        }
+        // Doubling at most, up to the limit.
+        assert_eq!(sizes, [32, 64, 128, 256, 512, 1024, 2048, 4096, 4096, 4096]);
+    }
+
+    #[test]
+    fn chunks_shrink_for_slow_clients() {
+        let mut chunks = AdaptiveChunks::new(4096);
+        for _ in 0..10 {
+            let size = chunks.size();
+            chunks.record(size, Duration::ZERO);
+        }
+        assert_eq!(chunks.size(), 4096);
+        // A client reading 10000 addresses a second reads 200 in 20 ms,
+        // which a single slow write gets close to.
+        chunks.record(4096, Duration::from_secs_f64(0.4096));
+        assert!(chunks.size() < 1000, "{}", chunks.size());
+        for _ in 0..20 {
+            let size = chunks.size();
+            chunks.record(size, Duration::from_secs_f64(size as f64 / 10_000.0));
+        }
+        assert_eq!(chunks.size(), 200);
+    }
+
+    #[test]
+    fn chunks_stay_within_bounds() {
+        let mut chunks = AdaptiveChunks::new(4096);
+        chunks.record(16, Duration::from_secs(10));
+        assert_eq!(chunks.size(), MIN_CHUNK);
+        chunks.record(0, Duration::ZERO);
+        assert_eq!(chunks.size(), MIN_CHUNK);
+
+        let mut chunks = AdaptiveChunks::new(4);
+        assert_eq!(chunks.size(), 4);
+        chunks.record(4, Duration::ZERO);
+        assert_eq!(chunks.size(), 4);
+    }
+
+    #[test]
+    fn ends_once_grants_close() {
+        let (mut flow, grants) = flow(vec![
+            ServerMessage::Response(response(0, 2)),
+            ServerMessage::Response(response(1, 2)),
+        ]);
+        grants.unbounded_send(3).unwrap();
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Response(response(0, 2))));
+        drop(grants);
+        // What was granted is still let through.
+        assert_eq!(block_on(flow.next()), Some(ServerMessage::Partial(response(1, 1))));
+        assert_eq!(block_on(flow.next()), None);
+    }
+}
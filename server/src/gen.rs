@@ -0,0 +1,389 @@
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use rand::prelude::*;
+
+use addrcore::{Cidr, Constraints, Family, FamilyCounts, Request, Response};
+
+/// How many times a transaction tries to generate an address it hasn't
+/// handed out yet before giving up.
+const MAX_ATTEMPTS: u32 = 100;
+
+/// Checks that addresses satisfying `constraints` can be generated, returning
+/// the reason if not.
+pub fn check_constraints(constraints: &Constraints) -> Result<(), String> {
+    if constraints.family == Some(Family::V6) {
+        return Err("Only IPv4 addresses are supported".to_string());
+    }
+    if let Some(cidr) = constraints.cidr {
+        if cidr.family() != Family::V4 {
+            return Err(format!("Only IPv4 CIDR blocks are supported, got {}", cidr));
+        }
+    }
+    check_ports(constraints)
+}
+
+pub fn check_ports(constraints: &Constraints) -> Result<(), String> {
+    if let Some((lo, hi)) = constraints.ports {
+        if lo > hi {
+            return Err(format!("Empty port range {}-{}", lo, hi));
+        }
+    }
+    Ok(())
+}
+
+/// Produces the addresses handed out to clients. Every connection gets a
+/// generator of its own, so it may keep state such as an RNG or a cursor
+/// without locking.
+pub trait AddrGenerator: Send {
+    /// Checks that addresses satisfying `constraints` can be generated,
+    /// returning the reason if not.
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        check_constraints(constraints)
+    }
+
+    /// Generates an address satisfying `constraints`, which have passed
+    /// `check`.
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr;
+
+    /// Generates `n` addresses satisfying `constraints`, which have passed
+    /// `check`.
+    fn generate(&mut self, n: usize, constraints: &Constraints) -> Vec<SocketAddr> {
+        (0..n).map(|_| self.next_addr(constraints)).collect()
+    }
+
+    /// Makes every address generated from now on depend on `seed` alone.
+    /// Generators that don't use randomness needn't do anything.
+    fn reseed(&mut self, _seed: u64) {}
+
+    /// A seed to reseed with before answering a request, drawn from the
+    /// generator's RNG if it's been seeded, so that the client may get the
+    /// same addresses again by asking for that seed. `None` for generators
+    /// that don't use randomness or haven't been seeded.
+    fn next_seed(&mut self) -> Option<u64> {
+        None
+    }
+}
+
+/// The RNG of a generator: the thread's own unless it's been seeded.
+#[derive(Clone, Debug, Default)]
+pub struct GenRng(Option<StdRng>);
+
+impl GenRng {
+    pub fn seed(&mut self, seed: u64) {
+        self.0 = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// The next number of the RNG if it's been seeded.
+    pub fn next_seed(&mut self) -> Option<u64> {
+        self.0.as_mut().map(RngCore::next_u64)
+    }
+}
+
+impl RngCore for GenRng {
+    fn next_u32(&mut self) -> u32 {
+        match self.0 {
+            Some(ref mut rng) => rng.next_u32(),
+            None => thread_rng().next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self.0 {
+            Some(ref mut rng) => rng.next_u64(),
+            None => thread_rng().next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self.0 {
+            Some(ref mut rng) => rng.fill_bytes(dest),
+            None => thread_rng().fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self.0 {
+            Some(ref mut rng) => rng.try_fill_bytes(dest),
+            None => thread_rng().try_fill_bytes(dest),
+        }
+    }
+}
+
+/// The address families a generator serves.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Families {
+    #[default]
+    V4,
+    V6,
+    /// Either, IPv6 with probability `v6_ratio`, from 0 to 1, unless a
+    /// request asks for one of them.
+    Both {
+        v6_ratio: f64,
+    },
+}
+
+impl Families {
+    fn serves(self, family: Family) -> bool {
+        !matches!((self, family), (Families::V4, Family::V6) | (Families::V6, Family::V4))
+    }
+}
+
+/// Generates uniformly random IPv4 addresses, or global unicast IPv6 ones,
+/// and ports.
+#[derive(Clone, Debug, Default)]
+pub struct RandomGenerator {
+    families: Families,
+    rng: GenRng,
+}
+
+impl RandomGenerator {
+    pub fn new(families: Families) -> Self {
+        RandomGenerator { families, rng: GenRng::default() }
+    }
+}
+
+impl AddrGenerator for RandomGenerator {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        match (constraints.family, constraints.cidr) {
+            (Some(family), Some(cidr)) if cidr.family() != family => {
+                return Err(format!("{} isn't of the requested address family", cidr));
+            }
+            (None, Some(cidr)) if !self.families.serves(cidr.family()) => {
+                return Err(format!("No addresses served lie within {}", cidr));
+            }
+            (Some(Family::V4), _) if !self.families.serves(Family::V4) => {
+                return Err("Only IPv6 addresses are served".to_string());
+            }
+            (Some(Family::V6), _) if !self.families.serves(Family::V6) => {
+                return Err("Only IPv4 addresses are served".to_string());
+            }
+            _ => (),
+        }
+        check_ports(constraints)
+    }
+
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr {
+        let rng = &mut self.rng;
+        let family = match constraints.family.or_else(|| constraints.cidr.map(|c| c.family())) {
+            Some(family) => family,
+            None => match self.families {
+                Families::V4 => Family::V4,
+                Families::V6 => Family::V6,
+                Families::Both { v6_ratio } if rng.gen_bool(v6_ratio) => Family::V6,
+                Families::Both { .. } => Family::V4,
+            },
+        };
+        let ip = match (family, constraints.cidr) {
+            (_, Some(Cidr { addr: IpAddr::V4(net), prefix_len })) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from((u32::from(net) & mask) | (rng.gen::<u32>() & !mask)))
+            }
+            (_, Some(Cidr { addr: IpAddr::V6(net), prefix_len })) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from((u128::from(net) & mask) | (rng.gen::<u128>() & !mask)))
+            }
+            (Family::V4, None) => IpAddr::V4(Ipv4Addr::from(rng.gen::<u32>())),
+            (Family::V6, None) => IpAddr::V6(global_unicast(rng)),
+        };
+        SocketAddr::new(ip, gen_port(rng, constraints))
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+    }
+
+    fn next_seed(&mut self) -> Option<u64> {
+        self.rng.next_seed()
+    }
+}
+
+/// A random address out of the global unicast range, 2000::/3, outside of
+/// the one reserved for documentation, 2001:db8::/32.
+fn global_unicast(rng: &mut GenRng) -> Ipv6Addr {
+    loop {
+        let ip = Ipv6Addr::from((rng.gen::<u128>() >> 3) | (1 << 125));
+        if ip.segments()[..2] != [0x2001, 0x0db8] {
+            return ip;
+        }
+    }
+}
+
+/// Generates random addresses within a union of IPv4 blocks, every address
+/// of it being as likely, and random ports.
+#[derive(Clone, Debug)]
+pub struct CidrGenerator {
+    /// The first address and size of every block, sorted and disjoint.
+    blocks: Vec<(u32, u64)>,
+    rng: GenRng,
+}
+
+impl CidrGenerator {
+    pub fn new(cidrs: &[Cidr]) -> Result<Self, String> {
+        let mut blocks = Vec::with_capacity(cidrs.len());
+        for cidr in cidrs {
+            match cidr.addr {
+                IpAddr::V4(net) => blocks.push(block(net, cidr.prefix_len)),
+                IpAddr::V6(_) => {
+                    return Err(format!("Only IPv4 blocks are supported, got {}", cidr))
+                }
+            }
+        }
+        if blocks.is_empty() {
+            return Err("No blocks to generate addresses from".to_string());
+        }
+        // CIDR blocks are either disjoint or nested, so the union is what's
+        // left once those within others are dropped.
+        blocks.sort_by_key(|&(start, size)| (start, Reverse(size)));
+        let mut union: Vec<(u32, u64)> = Vec::with_capacity(blocks.len());
+        for (start, size) in blocks {
+            match union.last() {
+                Some(&(last, n)) if (start as u64) < last as u64 + n => (),
+                _ => union.push((start, size)),
+            }
+        }
+        Ok(CidrGenerator { blocks: union, rng: GenRng::default() })
+    }
+
+    /// The parts of the blocks within `cidr` as well, if constrained to one.
+    fn within(&self, cidr: Option<Cidr>) -> Vec<(u32, u64)> {
+        let (net, size) = match cidr {
+            Some(Cidr { addr: IpAddr::V4(net), prefix_len }) => block(net, prefix_len),
+            _ => return self.blocks.clone(),
+        };
+        let (net_lo, net_hi) = (net as u64, net as u64 + size);
+        self.blocks
+            .iter()
+            .filter_map(|&(start, n)| {
+                let (lo, hi) = ((start as u64).max(net_lo), (start as u64 + n).min(net_hi));
+                if lo < hi {
+                    Some((lo as u32, hi - lo))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// The first address and size of a block.
+fn block(net: Ipv4Addr, prefix_len: u8) -> (u32, u64) {
+    let size = 1u64 << (32 - prefix_len as u32);
+    (u32::from(net) & !((size - 1) as u32), size)
+}
+
+/// A random address out of `blocks`, which mustn't be empty.
+fn sample(rng: &mut GenRng, blocks: &[(u32, u64)]) -> Ipv4Addr {
+    let total: u64 = blocks.iter().map(|&(_, size)| size).sum();
+    let mut offset = rng.gen_range(0, total);
+    for &(start, size) in blocks {
+        if offset < size {
+            return Ipv4Addr::from(start + offset as u32);
+        }
+        offset -= size;
+    }
+    unreachable!()
+}
+
+impl AddrGenerator for CidrGenerator {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        check_constraints(constraints)?;
+        match constraints.cidr {
+            Some(cidr) if self.within(Some(cidr)).is_empty() => {
+                Err(format!("No addresses served lie within {}", cidr))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr {
+        self.generate(1, constraints).remove(0)
+    }
+
+    fn generate(&mut self, n: usize, constraints: &Constraints) -> Vec<SocketAddr> {
+        let blocks = self.within(constraints.cidr);
+        let rng = &mut self.rng;
+        (0..n)
+            .map(|_| {
+                let ip = sample(rng, &blocks);
+                SocketAddr::new(IpAddr::V4(ip), gen_port(rng, constraints))
+            })
+            .collect()
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+    }
+
+    fn next_seed(&mut self) -> Option<u64> {
+        self.rng.next_seed()
+    }
+}
+
+fn gen_port(rng: &mut GenRng, constraints: &Constraints) -> u16 {
+    match constraints.ports {
+        Some((lo, hi)) => rng.gen_range(lo as u32, hi as u32 + 1) as u16,
+        None => rng.gen(),
+    }
+}
+
+pub fn gen_response(
+    gen: &mut dyn AddrGenerator,
+    index: u32,
+    num_addrs: u32,
+    constraints: &Constraints,
+    ttl: Option<u32>,
+) -> Response {
+    let addrs = gen.generate(num_addrs as usize, constraints);
+    let ttls = ttl.map(|ttl| vec![ttl; addrs.len()]);
+    let families = families(&addrs);
+    Response { index, addrs, ttls, families, seed: None }
+}
+
+/// The family counts of a response of `addrs`, which are only sent along
+/// with IPv6 addresses.
+fn families(addrs: &[SocketAddr]) -> Option<FamilyCounts> {
+    match addrs.iter().any(SocketAddr::is_ipv6) {
+        true => Some(FamilyCounts::of(addrs)),
+        false => None,
+    }
+}
+
+/// Generates one response per request of a transaction such that no address
+/// appears twice across all of them. Fails with the index of the first
+/// request that can't be served and the reason, in which case nothing is to
+/// be sent but the error.
+pub fn gen_transaction(
+    gen: &mut dyn AddrGenerator,
+    reqs: &[Request],
+    ttl: Option<u32>,
+) -> Result<Vec<Response>, (u32, String)> {
+    for (index, req) in reqs.iter().enumerate() {
+        gen.check(&req.constraints).map_err(|e| (index as u32, e))?;
+    }
+    let mut seen = HashSet::new();
+    let mut resps = Vec::with_capacity(reqs.len());
+    for (index, req) in reqs.iter().enumerate() {
+        let mut addrs = Vec::with_capacity(req.num_addrs as usize);
+        for _ in 0..req.num_addrs {
+            let mut attempts = 0;
+            let addr = loop {
+                let addr = gen.next_addr(&req.constraints);
+                if seen.insert(addr) {
+                    break addr;
+                }
+                attempts += 1;
+                if attempts == MAX_ATTEMPTS {
+                    let reason = "Not enough distinct addresses satisfy the constraints";
+                    return Err((index as u32, reason.to_string()));
+                }
+            };
+            addrs.push(addr);
+        }
+        let ttls = ttl.map(|ttl| vec![ttl; addrs.len()]);
+        let families = families(&addrs);
+        resps.push(Response { index: index as u32, addrs, ttls, families, seed: None });
+    }
+    Ok(resps)
+}
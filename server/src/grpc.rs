@@ -0,0 +1,238 @@
+// Tonic's `Status` is large, but its API is built around returning it.
+#![allow(clippy::result_large_err)]
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::IntervalStream;
+
+use futures::{stream, Stream, StreamExt};
+
+use tonic::transport;
+use tonic::{Request, Response, Status};
+
+use addrcore::{ClientMessage, Constraints, ErrorCode, ErrorResponse, ServerMessage};
+
+use crate::gen::gen_response;
+use crate::listen;
+use crate::{
+    answer_request, check_size, make_generator, record, throttle, AddrGenerator, Settings,
+};
+
+use self::proto::addrs_server::{Addrs, AddrsServer};
+use self::proto::{AddrsReply, AddrsRequest, StreamAddrsRequest};
+
+/// The types and service generated from `proto/addrs.proto`.
+pub mod proto {
+    tonic::include_proto!("addrs");
+}
+
+/// The generator stream of unary calls, apart from those of UDP and HTTP.
+/// Streaming calls get the streams after it, one each.
+const GRPC_STREAM: u64 = 2;
+
+struct Service {
+    settings: Arc<Settings>,
+    /// Shared by all unary calls, as they aren't tied to a connection.
+    gen: Mutex<Box<dyn AddrGenerator>>,
+    /// Streaming calls so far.
+    streams: AtomicU64,
+}
+
+/// Serves the `Addrs` service on `listener` until the server drains.
+pub async fn serve(listener: TcpListener, settings: Arc<Settings>) {
+    let gen = Mutex::new(make_generator(&settings, None, GRPC_STREAM));
+    let service = Service { settings: settings.clone(), gen, streams: AtomicU64::new(0) };
+    let result = transport::Server::builder()
+        .add_service(AddrsServer::new(service))
+        .serve_with_incoming_shutdown(incoming(listener), settings.drain_started())
+        .await;
+    if let Err(e) = result {
+        error!("gRPC server error: {}", e);
+    }
+    info!("Stopped serving gRPC");
+}
+
+/// Connections accepted on `listener` as by the other endpoints, ending
+/// with the first error that isn't skipped.
+fn incoming(listener: TcpListener) -> impl Stream<Item = io::Result<TcpStream>> {
+    stream::unfold(Some(listener), |listener| async move {
+        let listener = listener?;
+        match listen::accept(|| listener.accept()).await {
+            Ok((stream, _)) => Some((Ok(stream), Some(listener))),
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
+
+#[tonic::async_trait]
+impl Addrs for Service {
+    async fn get_addrs(
+        &self,
+        request: Request<AddrsRequest>,
+    ) -> Result<Response<AddrsReply>, Status> {
+        let peer = peer_name(&request);
+        let ip = request.remote_addr().map(|addr| addr.ip());
+        let req = request.into_inner();
+        let req = addrcore::Request { num_addrs: req.count, constraints: constraints(&req)? };
+        let reply = match throttle(&self.settings, ip, &ClientMessage::Request(req)) {
+            Some(error) => {
+                warn!("{} is over the rate limit", peer);
+                error
+            }
+            None => {
+                let max_addrs = self.settings.max_addrs();
+                answer_request(&mut **self.gen.lock().unwrap(), &req, max_addrs, &self.settings)
+            }
+        };
+        record(&self.settings, &peer, &reply);
+        match reply {
+            ServerMessage::Response(resp) => Ok(Response::new(reply_of(&resp))),
+            ServerMessage::Error(err) => Err(status(err)),
+            reply => unreachable!("{:?} answering a request", reply),
+        }
+    }
+
+    type StreamAddrsStream = Pin<Box<dyn Stream<Item = Result<AddrsReply, Status>> + Send>>;
+
+    async fn stream_addrs(
+        &self,
+        request: Request<StreamAddrsRequest>,
+    ) -> Result<Response<Self::StreamAddrsStream>, Status> {
+        let peer = peer_name(&request);
+        let StreamAddrsRequest { count, interval_ms } = request.into_inner();
+        if interval_ms == 0 {
+            return Err(Status::invalid_argument("The interval must be longer than zero"));
+        }
+        let max_addrs = self.settings.max_addrs();
+        if let Some(ServerMessage::Error(err)) = check_size(0, count, max_addrs) {
+            return Err(status(err));
+        }
+        let n = self.streams.fetch_add(1, Ordering::Relaxed);
+        let mut gen = make_generator(&self.settings, None, GRPC_STREAM + 1 + n);
+        let settings = self.settings.clone();
+        // The first reply is due one interval after the call.
+        let period = Duration::from_millis(interval_ms as u64);
+        let ticks = IntervalStream::new(time::interval_at(Instant::now() + period, period));
+        let replies = ticks.zip(stream::iter(0u32..)).map(move |(_, seq)| {
+            let update = gen_response(&mut *gen, seq, count, &Constraints::default(), settings.ttl);
+            let reply = reply_of(&update);
+            record(&settings, &peer, &ServerMessage::Update(update));
+            Ok(reply)
+        });
+        Ok(Response::new(Box::pin(replies)))
+    }
+}
+
+/// The client of a call, as it appears in the history.
+fn peer_name<T>(request: &Request<T>) -> String {
+    match request.remote_addr() {
+        Some(addr) => format!("grpc:{}", addr),
+        None => "grpc".to_string(),
+    }
+}
+
+fn constraints(req: &AddrsRequest) -> Result<Constraints, Status> {
+    let cidr = match req.cidr {
+        Some(ref cidr) => Some(cidr.parse().map_err(Status::invalid_argument)?),
+        None => None,
+    };
+    let ports = match (req.min_port, req.max_port) {
+        (None, None) => None,
+        (Some(lo), Some(hi)) if lo <= hi && hi <= u16::MAX as u32 => Some((lo as u16, hi as u16)),
+        _ => {
+            return Err(Status::invalid_argument("The ports must range from min_port to max_port"))
+        }
+    };
+    Ok(Constraints { family: None, ports, cidr, seed: None })
+}
+
+fn reply_of(resp: &addrcore::Response) -> AddrsReply {
+    AddrsReply {
+        addrs: resp.addrs.iter().map(|addr| addr.to_string()).collect(),
+        ttls: resp.ttls.clone().unwrap_or_default(),
+    }
+}
+
+fn status(err: ErrorResponse) -> Status {
+    match err.code {
+        ErrorCode::RateLimited { .. } => Status::resource_exhausted(err.message),
+        ErrorCode::TooManyAddrs { .. } => Status::invalid_argument(err.message),
+        ErrorCode::Unsatisfiable => Status::failed_precondition(err.message),
+        ErrorCode::AuthFailed => Status::unauthenticated(err.message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tonic::Code;
+
+    use crate::Server;
+
+    async fn service(builder: crate::Builder) -> Service {
+        let settings = builder.build().await.unwrap().settings;
+        let gen = Mutex::new(make_generator(&settings, None, GRPC_STREAM));
+        Service { settings, gen, streams: AtomicU64::new(0) }
+    }
+
+    fn request(count: u32, cidr: Option<&str>, ports: Option<(u32, u32)>) -> AddrsRequest {
+        AddrsRequest {
+            count,
+            cidr: cidr.map(str::to_string),
+            min_port: ports.map(|(lo, _)| lo),
+            max_port: ports.map(|(_, hi)| hi),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_constrained_addresses() {
+        let service = service(Server::bind(([127, 0, 0, 1], 0).into()).ttl(30)).await;
+        let req = Request::new(request(4, Some("10.0.0.0/8"), Some((80, 81))));
+        let reply = service.get_addrs(req).await.unwrap().into_inner();
+        assert_eq!(reply.ttls, [30; 4]);
+        for addr in reply.addrs {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(addr.ip().to_string().split('.').next(), Some("10"));
+            assert!((80..=81).contains(&addr.port()), "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_errors_to_status_codes() {
+        let service = service(Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(5)).await;
+        let code = |req| async { service.get_addrs(Request::new(req)).await.unwrap_err().code() };
+        assert_eq!(code(request(6, None, None)).await, Code::InvalidArgument);
+        assert_eq!(code(request(1, Some("10.0.0.0"), None)).await, Code::InvalidArgument);
+        assert_eq!(code(request(1, None, Some((81, 80)))).await, Code::InvalidArgument);
+        assert_eq!(code(request(1, Some("::/0"), None)).await, Code::FailedPrecondition);
+        // Calls without a peer address, as here, aren't rate limited.
+        let limited = ErrorCode::RateLimited { retry_after_ms: 1 };
+        let err = ErrorResponse { index: 0, code: limited, message: String::new() };
+        assert_eq!(status(err).code(), Code::ResourceExhausted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_replies_every_interval() {
+        let service = service(Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(5)).await;
+        let call = |count, interval_ms| {
+            service.stream_addrs(Request::new(StreamAddrsRequest { count, interval_ms }))
+        };
+        assert_eq!(call(6, 10).await.err().unwrap().code(), Code::InvalidArgument);
+        assert_eq!(call(1, 0).await.err().unwrap().code(), Code::InvalidArgument);
+        let start = Instant::now();
+        let replies: Vec<_> = call(2, 10).await.unwrap().into_inner().take(3).collect().await;
+        assert_eq!(start.elapsed(), Duration::from_millis(30));
+        assert!(replies.into_iter().all(|reply| reply.unwrap().addrs.len() == 2));
+    }
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::error;
+
+use rusqlite::{params, Connection};
+
+/// Records written in a single transaction at most.
+const MAX_BATCH: usize = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS served (
+        time_ms INTEGER NOT NULL,
+        peer TEXT NOT NULL,
+        request INTEGER NOT NULL,
+        addr TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS served_time ON served (time_ms);
+";
+
+/// An address as it was served.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Served {
+    pub time: SystemTime,
+    /// The client, as it appears in logs.
+    pub peer: String,
+    /// The index of the request, or the sequence number of a subscription
+    /// update.
+    pub request: u32,
+    pub addr: SocketAddr,
+}
+
+/// Records every served address to an SQLite database. Records are written
+/// in batches on a thread of their own, so serving never waits on the disk.
+#[derive(Clone)]
+pub struct History {
+    tx: Sender<Served>,
+}
+
+/// What to look up in a history database. The default matches everything.
+#[derive(Clone, Debug, Default)]
+pub struct HistoryQuery {
+    pub peer: Option<String>,
+    pub addr: Option<SocketAddr>,
+    /// Only records at or after this time.
+    pub since: Option<SystemTime>,
+    /// Only records before this time.
+    pub until: Option<SystemTime>,
+    /// The most recent records at most.
+    pub limit: Option<u32>,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<History> {
+        let conn = Connection::open(path).map_err(db_error)?;
+        conn.execute_batch(SCHEMA).map_err(db_error)?;
+        let (tx, rx) = mpsc::channel();
+        let path = path.to_path_buf();
+        thread::Builder::new().name("history".to_string()).spawn(move || write(conn, rx, path))?;
+        Ok(History { tx })
+    }
+
+    pub fn record(&self, peer: &str, request: u32, addrs: &[SocketAddr]) {
+        let time = SystemTime::now();
+        for &addr in addrs {
+            let record = Served { time, peer: peer.to_string(), request, addr };
+            // The writer only stops if the database failed, which it logged.
+            if self.tx.send(record).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Looks up the addresses matching `query` in the database at `path`,
+    /// oldest first.
+    pub fn query(path: &Path, query: &HistoryQuery) -> io::Result<Vec<Served>> {
+        let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(db_error)?;
+        let millis = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+        };
+        // Newest first so that the limit keeps the most recent, then reversed.
+        let mut stmt = conn
+            .prepare(
+                "SELECT time_ms, peer, request, addr FROM served
+                 WHERE (?1 IS NULL OR peer = ?1)
+                   AND (?2 IS NULL OR addr = ?2)
+                   AND (?3 IS NULL OR time_ms >= ?3)
+                   AND (?4 IS NULL OR time_ms < ?4)
+                 ORDER BY time_ms DESC, rowid DESC
+                 LIMIT ?5",
+            )
+            .map_err(db_error)?;
+        let rows = stmt
+            .query_map(
+                params![
+                    query.peer,
+                    query.addr.map(|addr| addr.to_string()),
+                    query.since.map(millis),
+                    query.until.map(millis),
+                    query.limit.map_or(-1, |limit| limit as i64),
+                ],
+                |row| {
+                    let time_ms: i64 = row.get(0)?;
+                    let addr: String = row.get(3)?;
+                    Ok((time_ms, row.get(1)?, row.get(2)?, addr))
+                },
+            )
+            .map_err(db_error)?;
+        let mut records = Vec::new();
+        for row in rows {
+            let (time_ms, peer, request, addr) = row.map_err(db_error)?;
+            let addr = addr.parse().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Invalid address {}", addr))
+            })?;
+            let time = UNIX_EPOCH + Duration::from_millis(time_ms as u64);
+            records.push(Served { time, peer, request, addr });
+        }
+        records.reverse();
+        Ok(records)
+    }
+}
+
+/// Writes records until every `History` is dropped or writing fails.
+fn write(mut conn: Connection, rx: Receiver<Served>, path: PathBuf) {
+    while let Ok(first) = rx.recv() {
+        let mut batch = vec![first];
+        batch.extend(rx.try_iter().take(MAX_BATCH - 1));
+        if let Err(e) = insert(&mut conn, &batch) {
+            error!("Could not record served addresses to {}, stopping: {}", path.display(), e);
+            return;
+        }
+    }
+}
+
+fn insert(conn: &mut Connection, batch: &[Served]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO served (time_ms, peer, request, addr) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for record in batch {
+            let time_ms = record.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            insert.execute(params![
+                time_ms as i64,
+                record.peer,
+                record.request,
+                record.addr.to_string()
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+fn db_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
@@ -0,0 +1,197 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+use tracing::{error, info, warn};
+
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+
+use serde::{Deserialize, Serialize};
+
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use addrcore::{ClientMessage, Constraints, ErrorCode, Request, ServerMessage};
+
+use crate::{answer_request, make_generator, record, throttle, AddrGenerator, Settings};
+
+/// The generator stream of HTTP, apart from that of UDP, which is also
+/// shared by all of its clients.
+const HTTP_STREAM: u64 = 1;
+
+#[derive(OpenApi)]
+#[openapi(paths(addrs), components(schemas(Addrs, HttpError)))]
+struct ApiDoc;
+
+struct Gateway {
+    settings: Arc<Settings>,
+    /// Shared by all requests, as they aren't tied to a connection.
+    gen: Mutex<Box<dyn AddrGenerator>>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AddrsQuery {
+    /// How many addresses to generate, 1 by default.
+    count: Option<u32>,
+}
+
+/// Generated addresses.
+#[derive(Serialize, ToSchema)]
+struct Addrs {
+    /// The addresses, as `ip:port`.
+    #[schema(example = json!(["93.184.216.34:443"]))]
+    addrs: Vec<String>,
+    /// How long each address should be considered valid for, in seconds, if
+    /// the server sets a TTL. Has one entry per address.
+    ttls: Option<Vec<u32>>,
+}
+
+/// Why addresses couldn't be generated.
+#[derive(Serialize, ToSchema)]
+struct HttpError {
+    error: String,
+}
+
+/// Serves `GET /addrs` and its OpenAPI description at `GET /openapi.json`
+/// on `listener` until the server drains.
+pub async fn serve(listener: TcpListener, settings: Arc<Settings>) {
+    let gen = Mutex::new(make_generator(&settings, None, HTTP_STREAM));
+    let gateway = Arc::new(Gateway { settings: settings.clone(), gen });
+    let app = Router::new()
+        .route("/addrs", get(addrs))
+        .route("/openapi.json", get(openapi))
+        .with_state(gateway);
+    let server = match axum::Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Could not serve HTTP: {}", e);
+            return;
+        }
+    };
+    let result = server
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(settings.drain_started())
+        .await;
+    if let Err(e) = result {
+        error!("HTTP server error: {}", e);
+    }
+    info!("Stopped serving HTTP");
+}
+
+/// Generates addresses, as a request of the protocol would.
+#[utoipa::path(
+    get,
+    path = "/addrs",
+    params(AddrsQuery),
+    responses(
+        (status = 200, description = "The generated addresses", body = Addrs),
+        (status = 400, description = "Too many addresses asked for", body = HttpError),
+        (status = 422, description = "The addresses can't be generated", body = HttpError),
+        (status = 429, description = "Over the rate limit, retry after a while", body = HttpError),
+    )
+)]
+async fn addrs(
+    State(gateway): State<Arc<Gateway>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(query): Query<AddrsQuery>,
+) -> Response {
+    let settings = &gateway.settings;
+    let req = Request { num_addrs: query.count.unwrap_or(1), constraints: Constraints::default() };
+    let reply = match throttle(settings, Some(addr.ip()), &ClientMessage::Request(req)) {
+        Some(error) => {
+            warn!("{} is over the rate limit", addr);
+            error
+        }
+        None => {
+            let max_addrs = settings.max_addrs();
+            answer_request(&mut **gateway.gen.lock().unwrap(), &req, max_addrs, settings)
+        }
+    };
+    record(settings, format_args!("http:{}", addr), &reply);
+    let err = match reply {
+        ServerMessage::Response(resp) => {
+            let addrs = resp.addrs.iter().map(|addr| addr.to_string()).collect();
+            return Json(Addrs { addrs, ttls: resp.ttls }).into_response();
+        }
+        ServerMessage::Error(err) => err,
+        reply => unreachable!("{:?} answering a request", reply),
+    };
+    let body = Json(HttpError { error: err.message });
+    match err.code {
+        ErrorCode::RateLimited { retry_after_ms } => {
+            // In whole seconds, rounded up so that retrying on time succeeds.
+            let retry_after = (retry_after_ms as u64).div_ceil(1000).to_string();
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after)], body)
+                .into_response()
+        }
+        ErrorCode::TooManyAddrs { .. } => (StatusCode::BAD_REQUEST, body).into_response(),
+        ErrorCode::Unsatisfiable => (StatusCode::UNPROCESSABLE_ENTITY, body).into_response(),
+        ErrorCode::AuthFailed => (StatusCode::UNAUTHORIZED, body).into_response(),
+    }
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::{Builder, Server};
+
+    /// Serves HTTP as the server built from `builder` would, returning the
+    /// address to send requests to.
+    async fn serve_http(builder: Builder) -> SocketAddr {
+        let mut server = builder.http(([127, 0, 0, 1], 0).into()).build().await.unwrap();
+        let listener = server.http_listener.take().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, server.settings.clone()));
+        addr
+    }
+
+    /// Sends `GET path`, returning the status line, headers and body.
+    async fn get(addr: SocketAddr, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        (head.to_string(), body.to_string())
+    }
+
+    #[tokio::test]
+    async fn serves_addresses() {
+        let addr = serve_http(Server::bind(([127, 0, 0, 1], 0).into()).ttl(30)).await;
+        let (head, body) = get(addr, "/addrs?count=3").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["addrs"].as_array().unwrap().len(), 3);
+        assert_eq!(body["ttls"], serde_json::json!([30, 30, 30]));
+        let (head, body) = get(addr, "/openapi.json").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        assert!(body.contains("\"/addrs\""));
+    }
+
+    #[tokio::test]
+    async fn answers_errors_with_their_status() {
+        let builder = Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(5).rate_limit(1, 2);
+        let addr = serve_http(builder).await;
+        let (head, body) = get(addr, "/addrs?count=6").await;
+        assert!(head.starts_with("HTTP/1.1 400"), "{}", head);
+        assert!(body.contains("\"error\""));
+        // Refused requests count against the limit as well.
+        let (head, _) = get(addr, "/addrs").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+        let (head, _) = get(addr, "/addrs").await;
+        assert!(head.starts_with("HTTP/1.1 429"), "{}", head);
+        assert!(head.to_lowercase().contains("retry-after: 1"), "{}", head);
+    }
+}
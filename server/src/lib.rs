@@ -0,0 +1,2133 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info, info_span, warn, Instrument, Level};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UdpSocket, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::codec::Decoder;
+
+use futures::channel::{mpsc, oneshot};
+use futures::{future, FutureExt, Sink, SinkExt, Stream, StreamExt};
+
+use tokio_rustls::TlsAcceptor;
+
+use serde_json::{json, Value};
+
+use addrcore::{
+    ClientMessage, Constraints, DecodePolicy, ErrorCode, ErrorResponse, QuotaWarning, Request,
+    Response, ServerInfo, ServerMessage, ServerToClientCodec, WireFormat, MAX_FRAME_LEN,
+    SUBSCRIPTION_INDEX,
+};
+
+mod acl;
+mod admin;
+mod admission;
+mod auth;
+mod cert;
+mod dns;
+mod exclude;
+mod flow;
+mod gen;
+mod grpc;
+mod history;
+mod http;
+mod listen;
+mod mix;
+mod norepeat;
+mod outbox;
+mod pool;
+mod ratelimit;
+mod rdns;
+mod registry;
+mod supervise;
+mod systemd;
+mod udp;
+mod usage;
+mod ws;
+
+use crate::admission::{Connections, Ticket};
+use crate::auth::Auth;
+use crate::exclude::Exclude;
+use crate::flow::{AdaptiveChunks, FlowControl};
+use crate::gen::{gen_response, gen_transaction};
+use crate::listen::ListenerStats;
+use crate::norepeat::NoRepeat;
+use crate::outbox::Outbox;
+use crate::ratelimit::RateLimiter;
+use crate::rdns::ReverseDns;
+use crate::supervise::Peer;
+use crate::systemd::Inherited;
+
+pub use crate::acl::Acl;
+pub use crate::exclude::Reserved;
+pub use crate::gen::{AddrGenerator, CidrGenerator, Families, RandomGenerator};
+pub use crate::history::{History, HistoryQuery, Served};
+pub use crate::listen::{parse_endpoint, parse_host, Endpoint, Host, SocketOptions};
+pub use crate::mix::MixGenerator;
+pub use crate::outbox::{Overflow, DEFAULT_SEND_QUEUE};
+pub use crate::pool::PoolGenerator;
+pub use crate::registry::{Connection, Registry};
+pub use crate::supervise::install_panic_hook;
+
+/// Most addresses served for a single request unless configured otherwise.
+pub const DEFAULT_MAX_ADDRS: u32 = 100_000;
+
+/// What the rate limit of a client is kept by.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum RateKey {
+    Ip(IpAddr),
+    /// The identity of a client certificate, shared by every IP using it.
+    Identity(String),
+}
+
+/// Makes the generator of a connection.
+type MakeGenerator = Arc<dyn Fn() -> Box<dyn AddrGenerator> + Send + Sync>;
+
+/// Settings shared by all connections.
+struct Settings {
+    /// Advertised to every client on connect.
+    info: ServerInfo,
+    /// Pre-shared key authenticating every frame, if set.
+    hmac_key: Option<Vec<u8>>,
+    /// What to do with frames from clients that can't be decoded.
+    policy: DecodePolicy,
+    /// How the payloads of stream connections' frames are encoded.
+    format: WireFormat,
+    /// Token authorizing debug frames, which are rejected if unset.
+    debug_token: Option<String>,
+    /// TTL in seconds attached to every generated address, if any.
+    ttl: Option<u32>,
+    /// Limits the requests of every client IP, or of every identity for
+    /// clients with a certificate, if set. May be reloaded.
+    rate_limit: RwLock<Option<RateLimiter<RateKey>>>,
+    /// Warns clients over a connection once they've used up this percentage
+    /// of a rate limit, if set.
+    quota_warning: Option<u8>,
+    /// Tokens clients must authenticate with before anything is served, if
+    /// set.
+    auth: Option<Auth>,
+    /// Which client IPs may connect, if restricted.
+    acl: Option<Acl>,
+    /// Most connections served at a time, and those queued beyond them, if
+    /// limited.
+    connections: Option<Arc<Connections>>,
+    /// Counts of the TCP, Unix domain socket and WebSocket listeners.
+    listeners: Vec<Arc<ListenerStats>>,
+    /// Most addresses served for a single request, which the admin socket
+    /// may change.
+    max_addrs: AtomicU32,
+    generator: MakeGenerator,
+    /// Seed of every generator, which use the thread's RNG otherwise.
+    seed: Option<u64>,
+    /// Records every served address, if set.
+    history: Option<History>,
+    /// Closes connections that send nothing for this long, if set. May be
+    /// reloaded.
+    idle_timeout: RwLock<Option<Duration>>,
+    /// Messages queued for every client, and what to do beyond them.
+    send_queue: usize,
+    overflow: Overflow,
+    /// Streams responses and updates in chunks of at most this many
+    /// addresses, sized to how fast every client reads, if set.
+    max_chunk: Option<usize>,
+    /// Set on the sockets of TCP and WebSocket clients once accepted.
+    socket_options: SocketOptions,
+    /// Completes the TLS handshake of new connections, if served over TLS,
+    /// and may be reloaded with a new certificate.
+    tls: Option<RwLock<TlsAcceptor>>,
+    registry: Registry,
+    /// Counts of the datagrams of every UDP client.
+    udp_peers: udp::Peers,
+    /// What the process uses, sampled while the admin socket is served.
+    usage: usage::Samples,
+    /// Set once the server stops accepting connections to let the active
+    /// ones finish.
+    draining: watch::Sender<bool>,
+}
+
+impl Settings {
+    fn max_addrs(&self) -> u32 {
+        self.max_addrs.load(Ordering::Relaxed)
+    }
+
+    fn rate_limit(&self) -> Option<RateLimiter<RateKey>> {
+        self.rate_limit.read().unwrap().clone()
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        *self.idle_timeout.read().unwrap()
+    }
+
+    /// What's advertised to a client connecting now.
+    fn info(&self) -> ServerInfo {
+        ServerInfo {
+            max_addrs: Some(self.max_addrs()),
+            max_requests_per_sec: self.rate_limit().map(|limiter| limiter.per_sec()),
+            ..self.info.clone()
+        }
+    }
+
+    fn drain(&self) {
+        self.draining.send_replace(true);
+    }
+
+    fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    /// Whether the client at `addr` may connect, logging it if not.
+    fn admits(&self, addr: SocketAddr) -> bool {
+        match self.acl {
+            Some(ref acl) if !acl.admits(addr.ip()) => {
+                info!("Rejected {}, {} rejection(s) since startup", addr, acl.rejected());
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Completes once the server starts draining.
+    async fn drain_started(&self) {
+        // The sender lives as long as the settings.
+        let _ = self.draining.subscribe().wait_for(|draining| *draining).await;
+    }
+}
+
+/// Options of a server, set before binding its listeners.
+pub struct Builder {
+    addrs: Vec<SocketAddr>,
+    unix: Vec<PathBuf>,
+    socket_activation: bool,
+    notify_systemd: bool,
+    drain_on_sigterm: bool,
+    dual_stack: bool,
+    udp: bool,
+    http: Option<SocketAddr>,
+    ws: Option<SocketAddr>,
+    grpc: Option<SocketAddr>,
+    dns: Option<SocketAddr>,
+    dns_answers: u32,
+    tls: Option<TlsAcceptor>,
+    debug_token: Option<String>,
+    reverse_dns: bool,
+    ttl: Option<u32>,
+    hmac_key: Option<Vec<u8>>,
+    policy: DecodePolicy,
+    format: WireFormat,
+    max_connections: Option<usize>,
+    accept_queue: Option<(usize, Duration)>,
+    send_queue: usize,
+    overflow: Overflow,
+    max_chunk: Option<usize>,
+    socket_options: SocketOptions,
+    rate_limit: Option<(u32, u32)>,
+    quota_warning: Option<u8>,
+    auth_file: Option<PathBuf>,
+    acl: Option<Acl>,
+    max_addrs: u32,
+    generator: MakeGenerator,
+    exclude: Vec<Reserved>,
+    no_repeat: Option<usize>,
+    seed: Option<u64>,
+    history: Option<PathBuf>,
+    idle_timeout: Option<Duration>,
+    admin_socket: Option<PathBuf>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Builder {
+            addrs: Vec::new(),
+            unix: Vec::new(),
+            socket_activation: false,
+            notify_systemd: false,
+            drain_on_sigterm: false,
+            dual_stack: false,
+            udp: false,
+            http: None,
+            ws: None,
+            grpc: None,
+            dns: None,
+            dns_answers: 1,
+            tls: None,
+            debug_token: None,
+            reverse_dns: false,
+            ttl: None,
+            hmac_key: None,
+            policy: DecodePolicy::Strict,
+            format: WireFormat::Binary,
+            max_connections: None,
+            accept_queue: None,
+            send_queue: DEFAULT_SEND_QUEUE,
+            overflow: Overflow::Block,
+            max_chunk: None,
+            socket_options: SocketOptions::default(),
+            rate_limit: None,
+            quota_warning: None,
+            auth_file: None,
+            acl: None,
+            max_addrs: DEFAULT_MAX_ADDRS,
+            generator: Arc::new(|| Box::new(RandomGenerator::default()) as Box<dyn AddrGenerator>),
+            exclude: Vec::new(),
+            no_repeat: None,
+            seed: None,
+            history: None,
+            idle_timeout: None,
+            admin_socket: None,
+        }
+    }
+
+    /// Also listens on a Unix domain socket at `path`, which TLS doesn't
+    /// apply to.
+    pub fn unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.unix.push(path.into());
+        self
+    }
+
+    /// Also listens on `endpoint`, with an accept loop of its own serving
+    /// clients the same way as the others.
+    pub fn listen(mut self, endpoint: Endpoint) -> Self {
+        match endpoint {
+            Endpoint::Tcp(addr) => self.addrs.push(addr),
+            Endpoint::Unix(path) => self.unix.push(path),
+        }
+        self
+    }
+
+    /// Also listens on the sockets systemd passes with socket activation, if
+    /// any, as in sd_listen_fds(3). Those sockets outlive the server, so
+    /// clients connecting while it restarts wait rather than being refused.
+    pub fn socket_activation(mut self, socket_activation: bool) -> Self {
+        self.socket_activation = socket_activation;
+        self
+    }
+
+    /// Tells systemd once the server is ready and when it starts draining,
+    /// as in sd_notify(3), for services of type notify.
+    pub fn notify_systemd(mut self, notify_systemd: bool) -> Self {
+        self.notify_systemd = notify_systemd;
+        self
+    }
+
+    /// Drains the server on SIGTERM, as the admin socket's `drain` does,
+    /// rather than leaving the signal to end the process at once.
+    pub fn drain_on_sigterm(mut self, drain_on_sigterm: bool) -> Self {
+        self.drain_on_sigterm = drain_on_sigterm;
+        self
+    }
+
+    /// Accepts IPv4 clients as well when bound to an IPv6 address.
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    /// Also serves requests over UDP on every TCP address, one per datagram.
+    /// Datagrams aren't authenticated.
+    pub fn udp(mut self, udp: bool) -> Self {
+        self.udp = udp;
+        self
+    }
+
+    /// Also serves addresses over HTTP on `addr`, as JSON answering
+    /// `GET /addrs?count=N`, described at `GET /openapi.json`.
+    pub fn http(mut self, addr: SocketAddr) -> Self {
+        self.http = Some(addr);
+        self
+    }
+
+    /// Also accepts WebSocket clients on `addr`, sending and receiving every
+    /// frame of the protocol as a binary message of its own.
+    pub fn ws(mut self, addr: SocketAddr) -> Self {
+        self.ws = Some(addr);
+        self
+    }
+
+    /// Also serves the gRPC service of `proto/addrs.proto` on `addr`.
+    pub fn grpc(mut self, addr: SocketAddr) -> Self {
+        self.grpc = Some(addr);
+        self
+    }
+
+    /// Also answers DNS queries over UDP on `addr`, usually port 53, with
+    /// random addresses: A records for IPv4 and AAAA for IPv6, if the
+    /// generator serves them. For testing resolvers against chaos.
+    pub fn dns(mut self, addr: SocketAddr) -> Self {
+        self.dns = Some(addr);
+        self
+    }
+
+    /// Answers every DNS query with `n` addresses, as many as fit in a
+    /// response, rather than one. Building fails if that's over the limit of
+    /// addresses served at once.
+    pub fn dns_answers(mut self, n: u32) -> Self {
+        self.dns_answers = n;
+        self
+    }
+
+    /// Serves TCP clients over TLS.
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Authorizes clients presenting `token` to change their connection's
+    /// log level.
+    pub fn debug_token(mut self, token: impl Into<String>) -> Self {
+        self.debug_token = Some(token.into());
+        self
+    }
+
+    /// Only serves connections that authenticate with a token listed in the
+    /// TOML file at `path`, each under the limits set for it. The file is
+    /// reloaded whenever it changes, so tokens can be rotated live.
+    pub fn auth_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.auth_file = Some(path.into());
+        self
+    }
+
+    /// Only accepts TCP and WebSocket clients whose IP `acl` admits, closing
+    /// the connections of the others right away.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// Logs the reverse DNS name of every TCP client.
+    pub fn reverse_dns(mut self, reverse_dns: bool) -> Self {
+        self.reverse_dns = reverse_dns;
+        self
+    }
+
+    /// Attaches a TTL in seconds to every generated address.
+    pub fn ttl(mut self, secs: u32) -> Self {
+        self.ttl = Some(secs);
+        self
+    }
+
+    /// Authenticates every frame with a key that clients must share.
+    pub fn hmac_key(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.hmac_key = Some(key.into());
+        self
+    }
+
+    /// Sets what to do with frames from clients that can't be decoded.
+    pub fn policy(mut self, policy: DecodePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets how the payloads of frames are encoded, binary by default.
+    /// Clients must use the same format. UDP datagrams are always binary.
+    pub fn wire_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Serves at most `n` connections at a time across listeners, accepting
+    /// no more until one closes.
+    pub fn max_connections(mut self, n: usize) -> Self {
+        self.max_connections = Some(n);
+        self
+    }
+
+    /// Once at the connection limit, accepts up to `len` more connections
+    /// rather than leaving them to the listen backlog, and serves them as
+    /// others close, or closes them after waiting for `deadline`. The number
+    /// waiting is in the stats of the admin socket.
+    pub fn accept_queue(mut self, len: usize, deadline: Duration) -> Self {
+        self.accept_queue = Some((len, deadline));
+        self
+    }
+
+    /// Queues at most `n` messages for every client, `DEFAULT_SEND_QUEUE`
+    /// by default, beyond which `overflow` decides what happens. Messages
+    /// held back for lack of flow control credits count, so clients must
+    /// grant credits before sending more than `n` requests ahead of them.
+    pub fn send_queue(mut self, n: usize, overflow: Overflow) -> Self {
+        self.send_queue = n;
+        self.overflow = overflow;
+        self
+    }
+
+    /// Streams responses and updates to every client in chunks of at most
+    /// `max_chunk` addresses, starting small and growing or shrinking them
+    /// with the rate the client reads at, rather than writing them whole.
+    /// Responses are sent as `Partial` chunks followed by the final
+    /// `Response`, as under flow control, which still applies.
+    pub fn stream_responses(mut self, max_chunk: usize) -> Self {
+        self.max_chunk = Some(max_chunk);
+        self
+    }
+
+    /// Sets the options of listening sockets and of the sockets of TCP and
+    /// WebSocket clients.
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Limits every client IP to `per_sec` requests, batches and
+    /// transactions a second, allowing bursts of up to `burst` after
+    /// idling. Requests over the limit are answered with a rate limited
+    /// error telling when to try again. Unix domain socket clients aren't
+    /// limited.
+    pub fn rate_limit(mut self, per_sec: u32, burst: u32) -> Self {
+        self.rate_limit = Some((per_sec, burst));
+        self
+    }
+
+    /// Warns clients connected over TCP, Unix domain sockets or WebSocket
+    /// once they've used up `percent` of the burst of a rate limit, this one
+    /// or that of their token, so that they may slow down before being
+    /// rejected.
+    pub fn quota_warning(mut self, percent: u8) -> Self {
+        self.quota_warning = Some(percent.min(100));
+        self
+    }
+
+    /// Answers requests for more than `n` addresses, batches and
+    /// transactions asking for more in all, or subscriptions to more, with
+    /// an error rather than allocating them, `DEFAULT_MAX_ADDRS` by default.
+    /// The limit is advertised to clients.
+    pub fn max_addrs(mut self, n: u32) -> Self {
+        self.max_addrs = n;
+        self
+    }
+
+    /// Generates addresses with a clone of `generator` for every connection,
+    /// and one for all of UDP, rather than a `RandomGenerator`.
+    pub fn generator<G>(mut self, generator: G) -> Self
+    where
+        G: AddrGenerator + Clone + Sync + 'static,
+    {
+        self.generator = Arc::new(move || Box::new(generator.clone()) as Box<dyn AddrGenerator>);
+        self
+    }
+
+    /// Never serves addresses in the given kinds of ranges, generating new
+    /// ones in their place.
+    pub fn exclude(mut self, reserved: &[Reserved]) -> Self {
+        self.exclude = reserved.to_vec();
+        self
+    }
+
+    /// Never serves a connection an address it has already been served,
+    /// remembering the last `capacity` of them.
+    pub fn no_repeat(mut self, capacity: usize) -> Self {
+        self.no_repeat = Some(capacity);
+        self
+    }
+
+    /// Seeds the generators so that runs are reproducible: a client gets the
+    /// same addresses for the same requests every time, as long as it
+    /// connects from the same IP, or in the same order over a Unix socket.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Records every served address, when and to whom it was served to an
+    /// SQLite database at `path`, which `History::query` looks them up in.
+    pub fn history(mut self, path: impl Into<PathBuf>) -> Self {
+        self.history = Some(path.into());
+        self
+    }
+
+    /// Closes connections that send nothing for `timeout`. Subscribers
+    /// without flow control only receive, so they're closed too.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Accepts operator commands on a Unix domain socket at `path`, only
+    /// accessible to the server's user: one command a line, such as `stats`,
+    /// `kick <peer>` or `drain`, with `help` listing them all.
+    pub fn admin_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.admin_socket = Some(path.into());
+        self
+    }
+
+    /// Binds the listeners, which are only accepted on once run.
+    pub async fn build(self) -> io::Result<Server> {
+        if self.dns.is_some() && self.dns_answers > self.max_addrs {
+            let message = format!(
+                "Can't answer DNS queries with {} addresses, over the limit of {}",
+                self.dns_answers, self.max_addrs
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        let options = self.socket_options.clone();
+        let mut tcp_listeners = Vec::new();
+        for &addr in self.addrs.iter() {
+            let listener = listen::bind_tcp(addr, self.dual_stack, &options).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not bind to {}: {}", addr, e))
+            })?;
+            let stats = ListenerStats::new(listener.local_addr()?.to_string());
+            tcp_listeners.push((listener, stats));
+        }
+        let mut unix_listeners = Vec::new();
+        for path in self.unix.iter() {
+            unix_listeners.push((bind_unix(path)?, ListenerStats::new(path.display().to_string())));
+        }
+        let inherited = match self.socket_activation {
+            true => systemd::listeners()?,
+            false => Vec::new(),
+        };
+        for listener in inherited {
+            match listener {
+                Inherited::Tcp(listener) => {
+                    let name = format!("systemd:{}", listener.local_addr()?);
+                    tcp_listeners.push((listener, ListenerStats::new(name)));
+                }
+                Inherited::Unix(listener) => {
+                    let name = match listener.local_addr()?.as_pathname() {
+                        Some(path) => format!("systemd:{}", path.display()),
+                        None => "systemd:unnamed".to_string(),
+                    };
+                    unix_listeners.push((listener, ListenerStats::new(name)));
+                }
+            }
+        }
+        if tcp_listeners.is_empty() && unix_listeners.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to listen on"));
+        }
+        let mut udp_sockets = Vec::new();
+        for &addr in self.addrs.iter().filter(|_| self.udp) {
+            udp_sockets.push(listen::bind_udp(addr, self.dual_stack, &options).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not bind to {} over UDP: {}", addr, e))
+            })?);
+        }
+        let http_listener = match self.http {
+            Some(addr) => {
+                let listener = listen::bind_tcp(addr, self.dual_stack, &options).map_err(|e| {
+                    io::Error::new(e.kind(), format!("Could not bind to {} for HTTP: {}", addr, e))
+                })?;
+                // Axum serves a std listener, which is left nonblocking.
+                Some(listener.into_std()?)
+            }
+            None => None,
+        };
+        let ws_listener = match self.ws {
+            Some(addr) => {
+                let listener = listen::bind_tcp(addr, false, &options).map_err(|e| {
+                    let msg = format!("Could not bind to {} for WebSockets: {}", addr, e);
+                    io::Error::new(e.kind(), msg)
+                })?;
+                let stats = ListenerStats::new(format!("ws://{}", listener.local_addr()?));
+                Some((listener, stats))
+            }
+            None => None,
+        };
+        let grpc_listener = match self.grpc {
+            Some(addr) => Some(listen::bind_tcp(addr, false, &options).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not bind to {} for gRPC: {}", addr, e))
+            })?),
+            None => None,
+        };
+        let dns_socket = match self.dns {
+            Some(addr) => Some(listen::bind_udp(addr, false, &options).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not bind to {} for DNS: {}", addr, e))
+            })?),
+            None => None,
+        };
+        let admin_listener = match self.admin_socket {
+            Some(ref path) => {
+                let listener = bind_unix(path)?;
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+                Some(listener)
+            }
+            None => None,
+        };
+        let history = match self.history {
+            Some(ref path) => Some(History::open(path).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not open {}: {}", path.display(), e))
+            })?),
+            None => None,
+        };
+        let auth = match self.auth_file {
+            Some(ref path) => Some(Auth::open(path).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not load {}: {}", path.display(), e))
+            })?),
+            None => None,
+        };
+        let rdns = match self.reverse_dns {
+            true => Some(ReverseDns::from_system_conf(Duration::from_secs(2))?),
+            false => None,
+        };
+
+        let stats: Vec<_> = tcp_listeners
+            .iter()
+            .map(|(_, stats)| stats)
+            .chain(unix_listeners.iter().map(|(_, stats)| stats))
+            .chain(ws_listener.iter().map(|(_, stats)| stats))
+            .cloned()
+            .collect();
+        let mut listeners: Vec<_> = stats.iter().map(|stats| stats.name.clone()).collect();
+        if let Some(ref socket) = dns_socket {
+            listeners.push(format!("dns://{}", socket.local_addr()?));
+        }
+        if let Some(ref listener) = grpc_listener {
+            listeners.push(format!("grpc://{}", listener.local_addr()?));
+        }
+        if let Some(ref listener) = http_listener {
+            listeners.push(format!("http://{}", listener.local_addr()?));
+        }
+        let mut features = vec![
+            "batch",
+            "subscribe",
+            "flow-control",
+            "constraints",
+            "transactions",
+        ];
+        if self.tls.is_some() {
+            features.push("tls");
+        }
+        if self.debug_token.is_some() {
+            features.push("debug");
+        }
+        if self.ttl.is_some() {
+            features.push("ttl");
+        }
+        if self.hmac_key.is_some() {
+            features.push("hmac");
+        }
+        if !udp_sockets.is_empty() {
+            features.push("udp");
+        }
+        if auth.is_some() {
+            features.push("auth");
+        }
+        if self.max_chunk.is_some() {
+            features.push("streaming");
+        }
+        let info = ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: features.into_iter().map(String::from).collect(),
+            max_frame_len: MAX_FRAME_LEN as u32,
+            max_addrs: Some(self.max_addrs),
+            max_requests_per_sec: None,
+            session: None,
+        };
+        let accept_queue = self.accept_queue;
+        let settings = Settings {
+            info,
+            hmac_key: self.hmac_key,
+            policy: self.policy,
+            format: self.format,
+            debug_token: self.debug_token,
+            ttl: self.ttl,
+            rate_limit: RwLock::new(
+                self.rate_limit.map(|(per_sec, burst)| RateLimiter::new(per_sec, burst)),
+            ),
+            quota_warning: self.quota_warning,
+            auth,
+            acl: self.acl,
+            connections: self.max_connections.map(|n| Connections::new(n, accept_queue)),
+            listeners: stats,
+            max_addrs: AtomicU32::new(self.max_addrs),
+            generator: layer(self.generator, self.exclude, self.no_repeat),
+            seed: self.seed,
+            history,
+            idle_timeout: RwLock::new(self.idle_timeout),
+            send_queue: self.send_queue,
+            overflow: self.overflow,
+            max_chunk: self.max_chunk,
+            socket_options: options,
+            tls: self.tls.map(RwLock::new),
+            registry: Registry::default(),
+            udp_peers: udp::Peers::default(),
+            usage: usage::Samples::default(),
+            draining: watch::channel(false).0,
+        };
+        Ok(Server {
+            tcp_listeners,
+            unix_listeners,
+            udp_sockets,
+            http_listener,
+            ws_listener,
+            grpc_listener,
+            dns_socket,
+            dns_answers: self.dns_answers,
+            admin_listener,
+            rdns,
+            notify_systemd: self.notify_systemd,
+            drain_on_sigterm: self.drain_on_sigterm,
+            settings: Arc::new(settings),
+            listeners,
+        })
+    }
+
+    /// Binds the listeners and serves clients until accepting fails.
+    pub async fn run(self) -> io::Result<()> {
+        self.build().await?.run().await;
+        Ok(())
+    }
+}
+
+/// Wraps the generators made by `make` to exclude the given ranges, if any,
+/// and then to not repeat addresses, if asked to.
+fn layer(make: MakeGenerator, reserved: Vec<Reserved>, no_repeat: Option<usize>) -> MakeGenerator {
+    let make = match reserved.is_empty() {
+        true => make,
+        false => {
+            Arc::new(move || Box::new(Exclude::new(make(), &reserved)) as Box<dyn AddrGenerator>)
+        }
+    };
+    match no_repeat {
+        Some(capacity) => {
+            Arc::new(move || Box::new(NoRepeat::new(make(), capacity)) as Box<dyn AddrGenerator>)
+        }
+        None => make,
+    }
+}
+
+/// Serves random socket addresses over TCP, optionally with TLS, over Unix
+/// domain sockets and over UDP.
+pub struct Server {
+    tcp_listeners: Vec<(TcpListener, Arc<ListenerStats>)>,
+    unix_listeners: Vec<(UnixListener, Arc<ListenerStats>)>,
+    udp_sockets: Vec<UdpSocket>,
+    http_listener: Option<std::net::TcpListener>,
+    ws_listener: Option<(TcpListener, Arc<ListenerStats>)>,
+    grpc_listener: Option<TcpListener>,
+    dns_socket: Option<UdpSocket>,
+    dns_answers: u32,
+    admin_listener: Option<UnixListener>,
+    rdns: Option<ReverseDns>,
+    notify_systemd: bool,
+    drain_on_sigterm: bool,
+    settings: Arc<Settings>,
+    /// Where the server listens, for the startup report.
+    listeners: Vec<String>,
+}
+
+impl Server {
+    /// Starts building a server listening on `addr`, which may have port 0
+    /// to pick any free port.
+    pub fn bind(addr: SocketAddr) -> Builder {
+        Builder::new().listen(Endpoint::Tcp(addr))
+    }
+
+    /// Starts building a server listening on a Unix domain socket only.
+    pub fn bind_unix(path: impl Into<PathBuf>) -> Builder {
+        Builder::new().listen(Endpoint::Unix(path.into()))
+    }
+
+    /// Starts building a server listening on `endpoint`, which more can be
+    /// added to with `Builder::listen`.
+    pub fn listen(endpoint: Endpoint) -> Builder {
+        Builder::new().listen(endpoint)
+    }
+
+    /// Starts building a server listening on nothing yet, which must be
+    /// given endpoints or inherit sockets from systemd.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// The address TCP clients connect to first, if listening on any.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.tcp_listeners.first().and_then(|(listener, _)| listener.local_addr().ok())
+    }
+
+    /// The connections of the server once it runs, which messages can be
+    /// broadcast to.
+    pub fn registry(&self) -> Registry {
+        self.settings.registry.clone()
+    }
+
+    /// Changes settings of the server once it runs, such as when its config
+    /// file is reloaded.
+    pub fn reloader(&self) -> Reloader {
+        Reloader { settings: self.settings.clone() }
+    }
+
+    /// Serves clients until accepting fails, or until the server is drained
+    /// and its last connection closed.
+    pub async fn run(self) {
+        let Server {
+            tcp_listeners,
+            unix_listeners,
+            udp_sockets,
+            http_listener,
+            ws_listener,
+            grpc_listener,
+            dns_socket,
+            dns_answers,
+            admin_listener,
+            rdns,
+            notify_systemd,
+            drain_on_sigterm,
+            settings,
+            listeners,
+        } = self;
+        let connections = settings.connections.clone();
+        log_startup_report(&settings, &listeners, rdns.is_some());
+        for socket in udp_sockets {
+            tokio::spawn(udp::serve(socket, settings.clone()).instrument(info_span!("udp")));
+        }
+        if let Some(listener) = http_listener {
+            tokio::spawn(http::serve(listener, settings.clone()).instrument(info_span!("http")));
+        }
+        if let Some(listener) = grpc_listener {
+            tokio::spawn(grpc::serve(listener, settings.clone()).instrument(info_span!("grpc")));
+        }
+        if let Some(socket) = dns_socket {
+            let dns = dns::serve(socket, dns_answers, settings.clone());
+            tokio::spawn(dns.instrument(info_span!("dns")));
+        }
+        if let Some(listener) = admin_listener {
+            tokio::spawn(admin::serve(listener, settings.clone()).instrument(info_span!("admin")));
+            tokio::spawn(usage::sample(settings.clone()));
+        }
+        if drain_on_sigterm {
+            tokio::spawn(drain_on_term(settings.clone()));
+        }
+        // One accept loop per listener, all serving clients alike.
+        let tcp = tcp_listeners.into_iter().map(|(listener, stats)| {
+            let rdns = rdns.clone();
+            accept_tcp(listener, stats, rdns, settings.clone(), connections.clone()).boxed()
+        });
+        let unix = unix_listeners.into_iter().map(|(listener, stats)| {
+            accept_unix(listener, stats, settings.clone(), connections.clone()).boxed()
+        });
+        let ws = ws_listener.into_iter().map(|(listener, stats)| {
+            ws::accept(listener, stats, settings.clone(), connections.clone()).boxed()
+        });
+        if notify_systemd {
+            systemd::notify("READY=1");
+        }
+        future::join_all(tcp.chain(unix).chain(ws)).await;
+        if settings.is_draining() {
+            // The accept loops end as soon as draining starts.
+            if notify_systemd {
+                systemd::notify("STOPPING=1");
+            }
+            info!("Draining {} connection(s)", settings.registry.connections().len());
+            settings.registry.wait_empty().await;
+            info!("Drained");
+        }
+    }
+}
+
+/// Changes the settings of a running server that can be changed without
+/// dropping connections. Those already open are served under the new
+/// limits from their next request, but keep their TLS certificate.
+#[derive(Clone)]
+pub struct Reloader {
+    settings: Arc<Settings>,
+}
+
+impl Reloader {
+    /// As `Builder::max_addrs`.
+    pub fn set_max_addrs(&self, max_addrs: u32) {
+        if self.settings.max_addrs.swap(max_addrs, Ordering::Relaxed) != max_addrs {
+            info!("Setting max-addrs to {}", max_addrs);
+        }
+    }
+
+    /// As `Builder::rate_limit`, or lifting the limit if `None`. Clients
+    /// start over with a full burst if the limit changes.
+    pub fn set_rate_limit(&self, limit: Option<(u32, u32)>) {
+        let mut rate_limit = self.settings.rate_limit.write().unwrap();
+        if rate_limit.as_ref().map(|limiter| (limiter.per_sec(), limiter.burst())) == limit {
+            return;
+        }
+        match limit {
+            Some((per_sec, burst)) => {
+                info!("Setting the rate limit to {}/s, bursts of {}", per_sec, burst)
+            }
+            None => info!("Lifting the rate limit"),
+        }
+        *rate_limit = limit.map(|(per_sec, burst)| RateLimiter::new(per_sec, burst));
+    }
+
+    /// As `Builder::idle_timeout`, or never closing idle connections if
+    /// `None`.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        let mut idle_timeout = self.settings.idle_timeout.write().unwrap();
+        if *idle_timeout != timeout {
+            info!("Setting the idle timeout to {:?}", timeout);
+            *idle_timeout = timeout;
+        }
+    }
+
+    /// Completes the TLS handshake of new connections with `acceptor`, such
+    /// as one with a renewed certificate. Returns false, changing nothing,
+    /// if the server doesn't serve TLS.
+    pub fn set_tls(&self, acceptor: TlsAcceptor) -> bool {
+        match self.settings.tls {
+            Some(ref tls) => {
+                info!("Reloaded the TLS certificate");
+                *tls.write().unwrap() = acceptor;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Drains the server once it's sent SIGTERM.
+async fn drain_on_term(settings: Arc<Settings>) {
+    match signal(SignalKind::terminate()) {
+        Ok(mut signals) => {
+            signals.recv().await;
+            info!("Draining on SIGTERM");
+            settings.drain();
+        }
+        Err(e) => warn!("Could not handle SIGTERM: {}", e),
+    }
+}
+
+/// Returns the soft and hard limits on open file descriptors, if available.
+fn nofile_rlimit() -> Option<(u64, u64)> {
+    let mut rlim = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } == 0 {
+        // `rlim_t` is only `u64` on some platforms.
+        #[allow(clippy::unnecessary_cast)]
+        Some((rlim.rlim_cur as u64, rlim.rlim_max as u64))
+    } else {
+        None
+    }
+}
+
+/// Logs everything needed to make sense of the server's logs in a single JSON
+/// object, so that logs attached to bug reports are self-contained.
+fn log_startup_report(settings: &Settings, listeners: &[String], reverse_dns: bool) {
+    info!("Startup report: {}", startup_report(settings, listeners, reverse_dns));
+}
+
+/// The report logged on startup: what the server was built with, where it
+/// listens and what it runs on.
+fn startup_report(settings: &Settings, listeners: &[String], reverse_dns: bool) -> Value {
+    let rlimit = nofile_rlimit().map(|(soft, hard)| json!({ "soft": soft, "hard": hard }));
+    let runtime = tokio::runtime::Handle::current();
+    let rate_limit = settings
+        .rate_limit()
+        .map(|limiter| json!({ "per_sec": limiter.per_sec(), "burst": limiter.burst() }));
+    let connections = settings.connections.as_deref();
+    let accept_queue = connections.and_then(Connections::queue).map(|(len, deadline)| {
+        json!({ "len": len, "deadline_ms": deadline.as_millis() as u64 })
+    });
+    let feature = |name| settings.info.has_feature(name);
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": {
+            "tls": feature("tls"),
+            "debug_frames": feature("debug"),
+            "reverse_dns": reverse_dns,
+            "udp": feature("udp"),
+            "hmac": feature("hmac"),
+            "ttl": settings.ttl,
+            "auth": feature("auth"),
+        },
+        "limits": {
+            "max_frame_len": MAX_FRAME_LEN,
+            "max_addrs": settings.max_addrs(),
+            "rate_limit": rate_limit,
+            "max_connections": connections.map(Connections::max),
+            "accept_queue": accept_queue,
+            "idle_timeout_ms": settings.idle_timeout().map(|timeout| timeout.as_millis() as u64),
+        },
+        "listeners": listeners,
+        "rlimits": {
+            "nofile": rlimit,
+        },
+        "runtime_threads": runtime.metrics().num_workers(),
+        "runtime_flavor": format!("{:?}", runtime.runtime_flavor()),
+        "rng": match settings.seed {
+            Some(seed) => json!({ "seed": seed }),
+            None => json!("thread_rng"),
+        },
+    })
+}
+
+/// Logger for a single connection whose verbosity can be changed at runtime
+/// by an authorized debug frame without affecting other connections. Its
+/// records are in the span of the connection, which tells the client.
+struct ConnLog {
+    level: LevelFilter,
+}
+
+impl ConnLog {
+    fn log(&self, level: Level, args: fmt::Arguments) {
+        if level > self.level {
+            return;
+        }
+        // The global subscriber filters at --log-level, info by default, so
+        // more verbose records are logged at info level, tagged with their
+        // own level.
+        if level == Level::ERROR {
+            error!("{}", args);
+        } else if level == Level::WARN {
+            warn!("{}", args);
+        } else if level == Level::INFO {
+            info!("{}", args);
+        } else {
+            info!("[{}] {}", level, args);
+        }
+    }
+}
+
+/// The level of a debug frame, which the protocol takes from `log`.
+fn level_filter(level: log::LevelFilter) -> LevelFilter {
+    match level {
+        log::LevelFilter::Off => LevelFilter::OFF,
+        log::LevelFilter::Error => LevelFilter::ERROR,
+        log::LevelFilter::Warn => LevelFilter::WARN,
+        log::LevelFilter::Info => LevelFilter::INFO,
+        log::LevelFilter::Debug => LevelFilter::DEBUG,
+        log::LevelFilter::Trace => LevelFilter::TRACE,
+    }
+}
+
+/// Pushes `count` fresh addresses into `outbox` every `interval_ms` until
+/// the returned sender is dropped or the connection to `addr` goes away.
+/// No addresses are generated while waiting for room in the queue.
+fn subscribe(
+    addr: Peer,
+    outbox: Outbox,
+    mut gen: Box<dyn AddrGenerator>,
+    count: u32,
+    interval_ms: u32,
+    settings: Arc<Settings>,
+) -> oneshot::Sender<()> {
+    let (cancel_tx, mut cancel_rx) = oneshot::channel::<()>();
+    let period = Duration::from_millis(interval_ms as u64);
+    supervise::spawn(addr, async move {
+        // The first update is due one interval after subscribing.
+        let mut interval = time::interval_at(Instant::now() + period, period);
+        for seq in 0u32.. {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = &mut cancel_rx => break,
+            }
+            let update = gen_response(&mut *gen, seq, count, &Constraints::default(), settings.ttl);
+            let update = ServerMessage::Update(update);
+            // Only recorded once queued, as dropped updates aren't served.
+            let recorded = update.clone();
+            let sent = tokio::select! {
+                sent = outbox.send(update) => sent,
+                _ = &mut cancel_rx => break,
+            };
+            match sent {
+                Ok(true) => record(&settings, addr, &recorded),
+                // Dropped for the queue being full.
+                Ok(false) => (),
+                Err(_) => break,
+            }
+        }
+    });
+    cancel_tx
+}
+
+/// Records the addresses `msg` serves `peer` to the history, if it's kept.
+fn record(settings: &Settings, peer: impl fmt::Display, msg: &ServerMessage) {
+    let history = match settings.history {
+        Some(ref history) => history,
+        None => return,
+    };
+    match msg {
+        ServerMessage::Response(resp) | ServerMessage::Update(resp) => {
+            history.record(&peer.to_string(), resp.index, &resp.addrs)
+        }
+        _ => (),
+    }
+}
+
+/// The error answering a request for `n` addresses at `index` if that's
+/// more than `max`, what the client is served at once.
+fn check_size(index: u32, n: u32, max: u32) -> Option<ServerMessage> {
+    if n <= max {
+        return None;
+    }
+    Some(ServerMessage::Error(ErrorResponse {
+        index,
+        code: ErrorCode::TooManyAddrs { max },
+        message: format!("Asked for {} addresses, over the limit of {}", n, max),
+    }))
+}
+
+/// The error answering the request at `index` of a batch or transaction if
+/// the addresses asked for by all of them, `total`, are more than `max`, so
+/// a batch of many requests under the limit can't allocate far more than it.
+fn check_total(index: u32, total: u64, max: u32) -> Option<ServerMessage> {
+    if total <= max as u64 {
+        return None;
+    }
+    Some(ServerMessage::Error(ErrorResponse {
+        index,
+        code: ErrorCode::TooManyAddrs { max },
+        message: format!("Asked for {} addresses in all, over the limit of {}", total, max),
+    }))
+}
+
+fn answer_request(
+    gen: &mut dyn AddrGenerator,
+    req: &Request,
+    max_addrs: u32,
+    settings: &Settings,
+) -> ServerMessage {
+    if let Some(error) = check_size(0, req.num_addrs, max_addrs) {
+        return error;
+    }
+    match gen.check(&req.constraints) {
+        Ok(()) => {
+            let (seed, mut resp) = with_seed(gen, req.constraints.seed, settings, |gen| {
+                gen_response(gen, 0, req.num_addrs, &req.constraints, settings.ttl)
+            });
+            resp.seed = seed;
+            ServerMessage::Response(resp)
+        }
+        Err(message) => ServerMessage::Error(ErrorResponse {
+            index: 0,
+            code: ErrorCode::Unsatisfiable,
+            message,
+        }),
+    }
+}
+
+/// The error answering `msg` if it's a request, batch or transaction from a
+/// client IP over its rate limit.
+fn throttle(settings: &Settings, ip: Option<IpAddr>, msg: &ClientMessage) -> Option<ServerMessage> {
+    throttle_key(settings, ip.map(RateKey::Ip), msg)
+}
+
+/// As `throttle`, for a client told apart by `key`.
+fn throttle_key(
+    settings: &Settings,
+    key: Option<RateKey>,
+    msg: &ClientMessage,
+) -> Option<ServerMessage> {
+    let limiter = settings.rate_limit()?;
+    let key = key?;
+    over_limit(msg, limiter.per_sec(), || limiter.check(key))
+}
+
+/// Whether `msg` counts against rate limits, which only requests, batches
+/// and transactions do.
+fn is_limited(msg: &ClientMessage) -> bool {
+    matches!(
+        msg,
+        ClientMessage::Request(_) | ClientMessage::Batch(_) | ClientMessage::Transaction(_)
+    )
+}
+
+/// The least of `quotas` left, if at least `percent` of it is used up.
+fn quota_warning(
+    percent: u8,
+    quotas: impl IntoIterator<Item = QuotaWarning>,
+) -> Option<QuotaWarning> {
+    let used_up = |quota: &QuotaWarning| {
+        let used = u64::from(quota.burst.saturating_sub(quota.remaining));
+        used * 100 >= u64::from(percent) * u64::from(quota.burst)
+    };
+    quotas.into_iter().filter(used_up).min_by_key(|quota| quota.remaining)
+}
+
+/// The error answering `msg` if it's a request, batch or transaction that
+/// `check` finds over a limit of `per_sec`. A throttled batch is answered
+/// with this one error in all, as it's a single request to the limiter.
+fn over_limit(
+    msg: &ClientMessage,
+    per_sec: u32,
+    check: impl FnOnce() -> Result<(), Duration>,
+) -> Option<ServerMessage> {
+    if !is_limited(msg) {
+        return None;
+    }
+    let retry_after = check().err()?;
+    // Rounded up so that retrying right on time succeeds.
+    let retry_after_ms = (retry_after.as_micros() as u64).div_ceil(1000).min(u32::MAX as u64);
+    Some(ServerMessage::Error(ErrorResponse {
+        index: 0,
+        code: ErrorCode::RateLimited { retry_after_ms: retry_after_ms as u32 },
+        message: format!("Over the limit of {} requests per second", per_sec),
+    }))
+}
+
+/// Answers every count on its own, so that only the counts that are too
+/// large fail. Every count fails if they're too large in all, as the client
+/// expects an answer to each.
+fn answer_batch(
+    gen: &mut dyn AddrGenerator,
+    counts: &[u32],
+    max_addrs: u32,
+    settings: &Settings,
+) -> Vec<ServerMessage> {
+    let total = counts.iter().map(|&n| n as u64).sum();
+    counts
+        .iter()
+        .enumerate()
+        .map(|(index, &n)| {
+            let index = index as u32;
+            let error = check_total(index, total, max_addrs);
+            error.or_else(|| check_size(index, n, max_addrs)).unwrap_or_else(|| {
+                let (seed, mut resp) = with_seed(gen, None, settings, |gen| {
+                    gen_response(gen, index, n, &Constraints::default(), settings.ttl)
+                });
+                resp.seed = seed;
+                ServerMessage::Response(resp)
+            })
+        })
+        .collect()
+}
+
+/// Answers all requests or, if any of them is too large, none.
+fn answer_transaction(
+    gen: &mut dyn AddrGenerator,
+    reqs: &[Request],
+    max_addrs: u32,
+    settings: &Settings,
+) -> Vec<ServerMessage> {
+    for (index, req) in reqs.iter().enumerate() {
+        if let Some(error) = check_size(index as u32, req.num_addrs, max_addrs) {
+            return vec![error];
+        }
+    }
+    let total = reqs.iter().map(|req| req.num_addrs as u64).sum();
+    if let Some(error) = check_total(0, total, max_addrs) {
+        return vec![error];
+    }
+    let seed = reqs.first().and_then(|req| req.constraints.seed);
+    match with_seed(gen, seed, settings, |gen| gen_transaction(gen, reqs, settings.ttl)) {
+        (seed, Ok(resps)) => {
+            let seeded = |resp| ServerMessage::Response(Response { seed, ..resp });
+            resps.into_iter().map(seeded).collect()
+        }
+        (_, Err((index, message))) => vec![ServerMessage::Error(ErrorResponse {
+            index,
+            code: ErrorCode::Unsatisfiable,
+            message,
+        })],
+    }
+}
+
+/// Runs `f` with the generator to answer a request with, returning the seed
+/// the client may ask for to get the same addresses again, if any: a fresh
+/// generator seeded with `seed` if the client asked for one, so that it
+/// depends on nothing else, or else `gen`, reseeded from its own RNG if the
+/// server is seeded.
+fn with_seed<R>(
+    gen: &mut dyn AddrGenerator,
+    seed: Option<u64>,
+    settings: &Settings,
+    f: impl FnOnce(&mut dyn AddrGenerator) -> R,
+) -> (Option<u64>, R) {
+    let mut fresh = seed.map(|_| (settings.generator)());
+    let seed = seed.or_else(|| gen.next_seed());
+    let gen = match fresh {
+        Some(ref mut fresh) => &mut **fresh,
+        None => gen,
+    };
+    if let Some(seed) = seed {
+        gen.reseed(seed);
+    }
+    (seed, f(gen))
+}
+
+/// Makes a generator for `peer`, or for all of UDP, HTTP, gRPC or DNS. If
+/// the server is seeded, so is the generator, from the seed, the peer and
+/// `stream`, which tells the generators of a connection, or of the other
+/// transports, apart.
+fn make_generator(settings: &Settings, peer: Option<Peer>, stream: u64) -> Box<dyn AddrGenerator> {
+    let mut gen = (settings.generator)();
+    if let Some(seed) = settings.seed {
+        // TCP clients are told apart by IP, as their ports are ephemeral.
+        let (kind, id) = match peer {
+            Some(Peer::Tcp(addr)) | Some(Peer::Ws(addr)) => match addr.ip() {
+                IpAddr::V4(ip) => (1, u32::from(ip) as u128),
+                IpAddr::V6(ip) => (2, u128::from(ip)),
+            },
+            Some(Peer::Unix(n)) => (3, n as u128),
+            None => (0, 0),
+        };
+        let parts = [kind, (id >> 64) as u64, id as u64, stream];
+        gen.reseed(parts.iter().fold(seed, |seed, &part| splitmix64(seed ^ part)));
+    }
+    gen
+}
+
+/// The SplitMix64 mixing function, spreading every bit of `x` over all of
+/// the result.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The codec of a connection, which keeps the state of its authentication.
+fn codec(settings: &Settings) -> ServerToClientCodec {
+    let codec = match settings.hmac_key {
+        Some(ref key) => ServerToClientCodec::with_key(key),
+        None => ServerToClientCodec::new(),
+    };
+    codec.with_policy(settings.policy).with_format(settings.format)
+}
+
+/// Serves a single client over any byte stream, be it a plain `TcpStream`,
+/// a TLS stream wrapping one or a `UnixStream`.
+async fn serve<S>(
+    stream: S,
+    addr: Peer,
+    id: u64,
+    identity: Option<String>,
+    settings: Arc<Settings>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (writer, reader) = codec(&settings).framed(stream).split();
+    serve_messages(reader, writer, addr, id, identity, settings).await
+}
+
+/// Writes `messages` to `writer`, in chunks of at most `max_chunk`
+/// addresses sized to how fast the client reads if set.
+async fn write_messages<S, W>(
+    messages: FlowControl<S>,
+    mut writer: W,
+    max_chunk: Option<usize>,
+) -> io::Result<()>
+where
+    S: Stream<Item = ServerMessage> + Unpin,
+    W: Sink<ServerMessage, Error = io::Error> + Unpin,
+{
+    let mut messages = match max_chunk {
+        Some(max) => messages.with_chunks(AdaptiveChunks::new(max)),
+        None => return messages.map(Ok).forward(writer).await,
+    };
+    while let Some(msg) = messages.next().await {
+        let entries = match msg {
+            ServerMessage::Response(ref resp)
+            | ServerMessage::Update(ref resp)
+            | ServerMessage::Partial(ref resp) => resp.addrs.len(),
+            _ => 0,
+        };
+        // Flushed one at a time, so that how long it takes tells how fast
+        // the client reads once the socket buffer is full.
+        let start = Instant::now();
+        writer.send(msg).await?;
+        messages.wrote(entries, start.elapsed());
+    }
+    writer.close().await
+}
+
+/// Serves a single client over any transport of messages, reading them from
+/// `reader` and writing them to `writer`, such as a WebSocket. Clients with
+/// the `identity` of a certificate are rate limited by it rather than IP.
+async fn serve_messages<R, W>(
+    mut reader: R,
+    writer: W,
+    addr: Peer,
+    id: u64,
+    identity: Option<String>,
+    settings: Arc<Settings>,
+) -> io::Result<()>
+where
+    R: Stream<Item = io::Result<ClientMessage>> + Unpin,
+    W: Sink<ServerMessage, Error = io::Error> + Send + Unpin + 'static,
+{
+    // Responses and subscription updates are all funneled through this
+    // queue into the socket, subject to the credits granted by the client.
+    // It fills up once the socket can't take more, which suspends reading
+    // requests and generating updates, unless the policy says otherwise.
+    let (outbox, queued) = Outbox::new(settings.send_queue, settings.overflow);
+    let (grants_tx, grants_rx) = mpsc::unbounded();
+    let overflowed = outbox.overflowed();
+    let max_chunk = settings.max_chunk;
+    supervise::spawn(addr, async move {
+        let messages = FlowControl::new(ReceiverStream::new(queued), grants_rx);
+        tokio::select! {
+            written = write_messages(messages, writer, max_chunk) => {
+                if let Err(e) = written {
+                    error!("Write error for {}: {}", addr, e);
+                }
+            }
+            // The queue is dropped along with the connection.
+            _ = overflowed => warn!("Disconnecting {}, which reads too slowly", addr),
+        }
+    });
+    // Can't fail as the writer was just spawned.
+    let _ = outbox.send(ServerMessage::Info(settings.info())).await;
+    let mut registration = settings.registry.register(id, addr, outbox.clone());
+
+    let mut log = ConnLog { level: LevelFilter::INFO };
+    // Looked up again for every message, so that a token removed from the
+    // auth file stops working right away.
+    let mut token: Option<String> = None;
+    let rate_key = match identity {
+        Some(identity) => Some(RateKey::Identity(identity)),
+        None => addr.ip().map(RateKey::Ip),
+    };
+    let mut gen = make_generator(&settings, Some(addr), 0);
+    // Subscriptions get generators of their own, each a stream of its own.
+    let mut subscriptions = 0;
+    // Dropping the sender cancels the subscription.
+    let mut subscription: Option<oneshot::Sender<()>> = None;
+    for n in 0u64.. {
+        // The timer starts over with every message.
+        let read = async {
+            match settings.idle_timeout() {
+                Some(timeout) => time::timeout(timeout, reader.next()).await.map_err(|_| timeout),
+                None => Ok(reader.next().await),
+            }
+        };
+        let next = tokio::select! {
+            next = read => match next {
+                Ok(next) => next,
+                Err(timeout) => {
+                    info!("Closing {} after {:?} idle", addr, timeout);
+                    break;
+                }
+            },
+            _ = registration.kicked() => {
+                info!("Kicked {}", addr);
+                break;
+            }
+        };
+        let msg = match next {
+            Some(msg) => msg?,
+            None => break,
+        };
+        // Instrumented rather than entered, as handling a message awaits
+        // room in the queue, and records of other connections served by the
+        // same thread meanwhile mustn't end up in its span. Returns whether
+        // to read on.
+        let handled = async {
+            log.log(Level::INFO, format_args!("Received {:?}", msg));
+            let identity = match settings.auth {
+                Some(ref auth) => {
+                    if let ClientMessage::Auth { token: ref presented } = msg {
+                        token = Some(presented.clone());
+                    }
+                    match token.as_deref().and_then(|token| auth.lookup(token)) {
+                        Some(identity) => Some(identity),
+                        // Saying goodbye needs no token.
+                        None if msg == ClientMessage::Goodbye => None,
+                        None => {
+                            warn!("{} failed to authenticate", addr);
+                            let message = match token {
+                                Some(_) => "Invalid token",
+                                None => "Authenticate before anything else",
+                            };
+                            let error = ServerMessage::Error(ErrorResponse {
+                                index: 0,
+                                code: ErrorCode::AuthFailed,
+                                message: message.to_string(),
+                            });
+                            // The connection is closed either way.
+                            let _ = outbox.send(error).await;
+                            return Ok(false);
+                        }
+                    }
+                }
+                None => None,
+            };
+            let max_addrs = match identity.as_ref().and_then(|identity| identity.max_addrs) {
+                Some(max) => max.min(settings.max_addrs()),
+                None => settings.max_addrs(),
+            };
+            let throttled = throttle_key(&settings, rate_key.clone(), &msg).or_else(|| {
+                let limiter = identity.as_ref()?.rate_limit.as_ref()?;
+                over_limit(&msg, limiter.per_sec(), || limiter.check(()))
+            });
+            if let Some(error) = throttled {
+                warn!("{} is over the rate limit", addr);
+                outbox.send(error).await?;
+                return Ok(true);
+            }
+            if let (Some(percent), true) = (settings.quota_warning, is_limited(&msg)) {
+                let limiter = settings.rate_limit();
+                let quota = limiter.as_ref().zip(rate_key.as_ref()).map(|(l, key)| l.quota(key));
+                let own = identity.as_ref().and_then(|identity| identity.rate_limit.as_ref());
+                let quotas = quota.into_iter().chain(own.map(|limiter| limiter.quota(&())));
+                if let Some(warning) = quota_warning(percent, quotas) {
+                    log.log(Level::DEBUG, format_args!("Close to the rate limit: {:?}", warning));
+                    outbox.send(ServerMessage::QuotaWarning(warning)).await?;
+                }
+            }
+            if let ClientMessage::Request(_)
+            | ClientMessage::Batch(_)
+            | ClientMessage::Transaction(_) = msg
+            {
+                registration.request();
+            }
+            let replies = match msg {
+                ClientMessage::Request(req) => {
+                    vec![answer_request(&mut *gen, &req, max_addrs, &settings)]
+                }
+                ClientMessage::Batch(counts) => {
+                    answer_batch(&mut *gen, &counts, max_addrs, &settings)
+                }
+                ClientMessage::Transaction(reqs) => {
+                    answer_transaction(&mut *gen, &reqs, max_addrs, &settings)
+                }
+                ClientMessage::Debug { token, level } => {
+                    match settings.debug_token {
+                        Some(ref expected) if *expected == token => {
+                            info!("Setting log level of {} to {}", addr, level);
+                            log.level = level_filter(level);
+                        }
+                        _ => warn!("Unauthorized debug frame from {}", addr),
+                    }
+                    Vec::new()
+                }
+                ClientMessage::Subscribe { count, interval_ms } => {
+                    if interval_ms == 0 {
+                        vec![ServerMessage::Error(ErrorResponse {
+                            index: SUBSCRIPTION_INDEX,
+                            code: ErrorCode::Unsatisfiable,
+                            message: "The interval must be longer than zero".to_string(),
+                        })]
+                    } else if let Some(error) = check_size(SUBSCRIPTION_INDEX, count, max_addrs) {
+                        vec![error]
+                    } else {
+                        subscriptions += 1;
+                        let gen = make_generator(&settings, Some(addr), subscriptions);
+                        let cancel = subscribe(
+                            addr,
+                            outbox.clone(),
+                            gen,
+                            count,
+                            interval_ms,
+                            settings.clone(),
+                        );
+                        subscription = Some(cancel);
+                        Vec::new()
+                    }
+                }
+                ClientMessage::Unsubscribe => {
+                    subscription.take();
+                    Vec::new()
+                }
+                ClientMessage::Credits(n) => {
+                    // The writer is only gone if the connection is too.
+                    let _ = grants_tx.unbounded_send(n);
+                    Vec::new()
+                }
+                ClientMessage::Auth { .. } => {
+                    match identity {
+                        Some(identity) => info!("{} authenticated as {}", addr, identity.name),
+                        None => warn!("Ignoring auth from {}, as none is required", addr),
+                    }
+                    Vec::new()
+                }
+                // Nothing is read after this.
+                ClientMessage::Goodbye => {
+                    info!("{} said goodbye", addr);
+                    return Ok(false);
+                }
+            };
+            for reply in replies {
+                if let ServerMessage::Response(ref resp) = reply {
+                    log.log(Level::DEBUG, format_args!("Generated addrs: {:?}", resp.addrs));
+                }
+                log.log(Level::TRACE, format_args!("Sending {:?}", reply));
+                // Only recorded once queued, as dropped replies aren't served.
+                let recorded = reply.clone();
+                if outbox.send(reply).await? {
+                    record(&settings, addr, &recorded);
+                }
+            }
+            Ok::<_, io::Error>(true)
+        };
+        if !handled.instrument(info_span!("msg", n)).await? {
+            break;
+        }
+    }
+    info!("{} disconnected", addr);
+    Ok(())
+}
+
+/// Binds a Unix domain socket at `path`, replacing a socket left behind by
+/// a previous run but nothing else.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path).map_err(|e| {
+                io::Error::new(e.kind(), format!("Could not remove {}: {}", path.display(), e))
+            })?;
+        }
+    }
+    UnixListener::bind(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("Could not bind to {}: {}", path.display(), e))
+    })
+}
+
+/// Waits for room for another connection, if they're limited.
+async fn admit(connections: &Option<Arc<Connections>>) -> Ticket {
+    match connections {
+        Some(connections) => connections.ticket().await,
+        None => Ticket::Unlimited,
+    }
+}
+
+async fn accept_tcp(
+    listener: TcpListener,
+    stats: Arc<ListenerStats>,
+    rdns: Option<ReverseDns>,
+    settings: Arc<Settings>,
+    connections: Option<Arc<Connections>>,
+) {
+    loop {
+        let accept =
+            async { (admit(&connections).await, listen::accept(|| listener.accept()).await) };
+        let (ticket, accepted) = tokio::select! {
+            accepted = accept => accepted,
+            _ = settings.drain_started() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Server error: {}", e);
+                return;
+            }
+        };
+        if !settings.admits(addr) {
+            continue;
+        }
+        if let Err(e) = settings.socket_options.apply(&stream) {
+            warn!("Could not set the socket options of {}: {}", addr, e);
+        }
+        let active = stats.accept();
+        let peer = Peer::Tcp(addr);
+        let id = supervise::next_conn_id();
+        let span = supervise::conn_span(peer, id);
+        span.in_scope(|| info!("Connected to {:?}", stream));
+
+        if let Some(ref rdns) = rdns {
+            let rdns = rdns.clone();
+            let lookup = async move {
+                match rdns.lookup(addr.ip()).await {
+                    Some(name) => info!("{} is {}", addr, name),
+                    None => info!("{} has no reverse DNS name", addr),
+                }
+            };
+            supervise::spawn(peer, lookup.instrument(span.clone()));
+        }
+        // Taken as the connection is accepted, so that reloading the
+        // certificate only applies to new ones.
+        let acceptor = settings.tls.as_ref().map(|tls| tls.read().unwrap().clone());
+        let settings = settings.clone();
+        let task = async move {
+            let permit = match ticket.admit().await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    info!("Closing the connection, queued past the deadline");
+                    return;
+                }
+            };
+            // Held until the connection closes.
+            let (_permit, _active) = (permit, active);
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => match cert::client_identity(&stream) {
+                        Some(identity) => {
+                            info!("{} presented a certificate of {}", addr, identity);
+                            let span = info_span!("cert", %identity);
+                            serve(stream, peer, id, Some(identity), settings).instrument(span).await
+                        }
+                        None => serve(stream, peer, id, None, settings).await,
+                    },
+                    Err(e) => {
+                        error!("TLS handshake with {} failed: {}", addr, e);
+                        return;
+                    }
+                },
+                None => serve(stream, peer, id, None, settings).await,
+            };
+            if let Err(e) = result {
+                error!("Client error: {}", e);
+            }
+        };
+        supervise::spawn(peer, task.instrument(span));
+    }
+}
+
+async fn accept_unix(
+    listener: UnixListener,
+    stats: Arc<ListenerStats>,
+    settings: Arc<Settings>,
+    connections: Option<Arc<Connections>>,
+) {
+    loop {
+        let accept =
+            async { (admit(&connections).await, listen::accept(|| listener.accept()).await) };
+        let (ticket, accepted) = tokio::select! {
+            accepted = accept => accepted,
+            _ = settings.drain_started() => return,
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Server error: {}", e);
+                return;
+            }
+        };
+        let active = stats.accept();
+        let peer = Peer::Unix(supervise::next_unix_client());
+        let id = supervise::next_conn_id();
+        let span = supervise::conn_span(peer, id);
+        span.in_scope(|| info!("Connected to {}", peer));
+        let settings = settings.clone();
+        let task = async move {
+            let permit = match ticket.admit().await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    info!("Closing the connection, queued past the deadline");
+                    return;
+                }
+            };
+            let (_permit, _active) = (permit, active);
+            if let Err(e) = serve(stream, peer, id, None, settings).await {
+                error!("Client error: {}", e);
+            }
+        };
+        supervise::spawn(peer, task.instrument(span));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use addrcore::ClientToServerCodec;
+    use futures::SinkExt;
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    async fn settings(max_addrs: u32) -> Arc<Settings> {
+        let server =
+            Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(max_addrs).build().await.unwrap();
+        server.settings
+    }
+
+    fn request(num_addrs: u32) -> Request {
+        Request { num_addrs, constraints: Constraints::default() }
+    }
+
+    fn too_many_addrs(msgs: &[ServerMessage], max: u32) -> bool {
+        match msgs {
+            [ServerMessage::Error(err)] => err.code == ErrorCode::TooManyAddrs { max },
+            _ => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn caps_batches_in_all() {
+        let settings = settings(10).await;
+        let mut gen = make_generator(&settings, None, 0);
+        let answers = answer_batch(&mut *gen, &[4, 6], 10, &settings);
+        assert_eq!(answers.len(), 2);
+        assert!(answers.iter().all(|msg| matches!(msg, ServerMessage::Response(_))));
+        // Every count is answered with an error of its own.
+        let answers = answer_batch(&mut *gen, &[4, 6, 1], 10, &settings);
+        assert_eq!(answers.len(), 3);
+        for (index, answer) in answers.iter().enumerate() {
+            match answer {
+                ServerMessage::Error(err) => assert_eq!(err.index, index as u32),
+                other => panic!("Unexpected {:?}", other),
+            }
+            assert!(too_many_addrs(std::slice::from_ref(answer), 10), "{:?}", answer);
+        }
+        // Summed without overflowing.
+        let answers = answer_batch(&mut *gen, &[u32::MAX, u32::MAX], u32::MAX, &settings);
+        assert!(too_many_addrs(&answers[..1], u32::MAX), "{:?}", answers);
+    }
+
+    #[tokio::test]
+    async fn reports_limits_and_features() {
+        let server = Server::bind(([127, 0, 0, 1], 0).into())
+            .udp(true)
+            .hmac_key(&b"key"[..])
+            .ttl(60)
+            .max_addrs(10)
+            .rate_limit(5, 20)
+            .max_connections(3)
+            .accept_queue(8, Duration::from_millis(500))
+            .idle_timeout(Duration::from_secs(30))
+            .seed(7)
+            .build()
+            .await
+            .unwrap();
+        let report = startup_report(&server.settings, &server.listeners, false);
+        let features = &report["features"];
+        assert_eq!(features["udp"], true);
+        assert_eq!(features["hmac"], true);
+        assert_eq!(features["ttl"], 60);
+        assert_eq!(features["auth"], false);
+        assert_eq!(features["tls"], false);
+        let limits = &report["limits"];
+        assert_eq!(limits["max_addrs"], 10);
+        assert_eq!(limits["rate_limit"], json!({ "per_sec": 5, "burst": 20 }));
+        assert_eq!(limits["max_connections"], 3);
+        assert_eq!(limits["accept_queue"], json!({ "len": 8, "deadline_ms": 500 }));
+        assert_eq!(limits["idle_timeout_ms"], 30_000);
+        assert_eq!(report["rng"]["seed"], 7);
+        assert_eq!(report["listeners"].as_array().unwrap().len(), server.listeners.len());
+
+        let server = Server::bind(([127, 0, 0, 1], 0).into()).build().await.unwrap();
+        let limits = &startup_report(&server.settings, &server.listeners, false)["limits"];
+        assert!(limits["rate_limit"].is_null() && limits["max_connections"].is_null());
+        assert!(limits["accept_queue"].is_null());
+    }
+
+    #[test]
+    fn throttles_batches_with_one_error() {
+        let batch = ClientMessage::Batch(vec![1, 2, 3]);
+        match over_limit(&batch, 10, || Err(Duration::from_micros(1500))) {
+            Some(ServerMessage::Error(err)) => {
+                assert_eq!(err.code, ErrorCode::RateLimited { retry_after_ms: 2 })
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(over_limit(&batch, 10, || Ok(())).is_none());
+        assert!(over_limit(&ClientMessage::Unsubscribe, 10, || Err(Duration::ZERO)).is_none());
+    }
+
+    #[tokio::test]
+    async fn regenerates_responses_from_their_seed() {
+        let server = Server::bind(([127, 0, 0, 1], 0).into()).seed(7).build().await.unwrap();
+        let settings = server.settings;
+        let response = |msg| match msg {
+            ServerMessage::Response(resp) => resp,
+            other => panic!("Unexpected {:?}", other),
+        };
+        let mut gen = make_generator(&settings, None, 0);
+        let first = response(answer_request(&mut *gen, &request(5), 10, &settings));
+        let second = response(answer_request(&mut *gen, &request(5), 10, &settings));
+        assert!(first.seed.is_some() && first.seed != second.seed);
+
+        // On another generator, as from another client.
+        let mut other = make_generator(&settings, None, 1);
+        let constraints = Constraints { seed: second.seed, ..Default::default() };
+        let req = Request { num_addrs: 5, constraints };
+        let again = response(answer_request(&mut *other, &req, 10, &settings));
+        assert_eq!((again.addrs, again.seed), (second.addrs, second.seed));
+        let batch = answer_batch(&mut *gen, &[1, 1], 10, &settings);
+        assert!(batch.into_iter().map(response).all(|resp| resp.seed.is_some()));
+    }
+
+    #[tokio::test]
+    async fn seeds_only_on_request_if_unseeded() {
+        let settings = settings(10).await;
+        let mut gen = make_generator(&settings, None, 0);
+        let constraints = Constraints { seed: Some(1), ..Default::default() };
+        let seeded = Request { num_addrs: 3, constraints };
+        let answers = answer_transaction(&mut *gen, &[seeded, request(2)], 10, &settings);
+        let again = answer_transaction(&mut *gen, &[seeded, request(2)], 10, &settings);
+        assert_eq!(answers, again);
+        match answer_request(&mut *gen, &request(1), 10, &settings) {
+            ServerMessage::Response(resp) => assert_eq!(resp.seed, None),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn generates_the_same_addresses_from_the_same_seed() {
+        let seeded = |seed| async move {
+            Server::bind(([127, 0, 0, 1], 0).into()).seed(seed).build().await.unwrap().settings
+        };
+        let (settings, again, other) = (seeded(7).await, seeded(7).await, seeded(8).await);
+        let addrs = |settings: &Settings, port, stream| {
+            let peer = Peer::Tcp(([10, 0, 0, 1], port).into());
+            make_generator(settings, Some(peer), stream).generate(20, &Constraints::default())
+        };
+        let first = addrs(&settings, 40000, 0);
+        assert_eq!(first, addrs(&again, 40000, 0));
+        // Reconnecting from another ephemeral port changes nothing.
+        assert_eq!(first, addrs(&again, 40001, 0));
+        assert_ne!(first, addrs(&settings, 40000, 1));
+        assert_ne!(first, addrs(&other, 40000, 0));
+    }
+
+    #[tokio::test]
+    async fn caps_transactions_in_all() {
+        let settings = settings(10).await;
+        let mut gen = make_generator(&settings, None, 0);
+        let answers = answer_transaction(&mut *gen, &[request(4), request(6)], 10, &settings);
+        assert_eq!(answers.len(), 2);
+        let answers =
+            answer_transaction(&mut *gen, &[request(4), request(6), request(1)], 10, &settings);
+        assert!(too_many_addrs(&answers, 10), "{:?}", answers);
+    }
+
+    /// Runs the server built by `builder` and connects to it, past its info.
+    async fn connect(builder: Builder) -> Framed<TcpStream, ClientToServerCodec> {
+        let server = builder.build().await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = Framed::new(stream, ClientToServerCodec::new());
+        match conn.next().await {
+            Some(Ok(ServerMessage::Info(_))) => {}
+            other => panic!("Unexpected {:?}", other),
+        }
+        conn
+    }
+
+    #[tokio::test]
+    async fn reloads_limits_for_open_connections() {
+        let server = Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(10).build().await.unwrap();
+        let (addr, reloader) = (server.local_addr().unwrap(), server.reloader());
+        let settings = server.settings.clone();
+        tokio::spawn(server.run());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut conn = Framed::new(stream, ClientToServerCodec::new());
+        assert!(matches!(conn.next().await, Some(Ok(ServerMessage::Info(_)))));
+
+        reloader.set_max_addrs(3);
+        conn.send(ClientMessage::Request(request(5))).await.unwrap();
+        let msg = conn.next().await.unwrap().unwrap();
+        assert!(too_many_addrs(std::slice::from_ref(&msg), 3), "{:?}", msg);
+
+        reloader.set_rate_limit(Some((1, 1)));
+        conn.send(ClientMessage::Request(request(1))).await.unwrap();
+        assert!(matches!(conn.next().await, Some(Ok(ServerMessage::Response(_)))));
+        conn.send(ClientMessage::Request(request(1))).await.unwrap();
+        match conn.next().await {
+            Some(Ok(ServerMessage::Error(err))) => {
+                assert!(matches!(err.code, ErrorCode::RateLimited { .. }), "{:?}", err)
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        reloader.set_rate_limit(None);
+        conn.send(ClientMessage::Request(request(1))).await.unwrap();
+        assert!(matches!(conn.next().await, Some(Ok(ServerMessage::Response(_)))));
+
+        reloader.set_idle_timeout(Some(Duration::from_secs(9)));
+        assert_eq!(settings.idle_timeout(), Some(Duration::from_secs(9)));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_subscriptions() {
+        let mut conn = connect(Server::bind(([127, 0, 0, 1], 0).into()).max_addrs(10)).await;
+
+        conn.send(ClientMessage::Subscribe { count: 11, interval_ms: 10 }).await.unwrap();
+        let msg = conn.next().await.unwrap().unwrap();
+        assert!(too_many_addrs(std::slice::from_ref(&msg), 10), "{:?}", msg);
+
+        conn.send(ClientMessage::Subscribe { count: 1, interval_ms: 0 }).await.unwrap();
+        match conn.next().await {
+            Some(Ok(ServerMessage::Error(err))) => {
+                assert_eq!((err.index, err.code), (SUBSCRIPTION_INDEX, ErrorCode::Unsatisfiable))
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn warns_of_the_least_quota_used_up() {
+        let quota = |remaining| QuotaWarning { remaining, burst: 10, per_sec: 5 };
+        assert_eq!(quota_warning(80, vec![quota(3)]), None);
+        assert_eq!(quota_warning(80, vec![quota(2)]), Some(quota(2)));
+        assert_eq!(quota_warning(80, vec![quota(3), quota(0)]), Some(quota(0)));
+        assert_eq!(quota_warning(80, vec![]), None);
+    }
+
+    #[tokio::test]
+    async fn warns_before_throttling() {
+        let builder =
+            Server::bind(([127, 0, 0, 1], 0).into()).rate_limit(1, 4).quota_warning(50);
+        let mut conn = connect(builder).await;
+        conn.send(ClientMessage::Request(request(1))).await.unwrap();
+        assert!(matches!(conn.next().await, Some(Ok(ServerMessage::Response(_)))));
+        conn.send(ClientMessage::Request(request(1))).await.unwrap();
+        match conn.next().await {
+            Some(Ok(ServerMessage::QuotaWarning(warning))) => {
+                assert_eq!((warning.remaining, warning.burst, warning.per_sec), (2, 4, 1))
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(matches!(conn.next().await, Some(Ok(ServerMessage::Response(_)))));
+    }
+
+    #[tokio::test]
+    async fn authenticates_before_serving() {
+        let path = std::env::temp_dir().join(format!("tokens-{}-serve", std::process::id()));
+        let tokens = "[[tokens]]\nname = \"ci\"\ntoken = \"secret\"\nmax_addrs = 5\n";
+        fs::write(&path, tokens).unwrap();
+        let builder = || Server::bind(([127, 0, 0, 1], 0).into()).auth_file(&path);
+        let auth_failed = |msg| match msg {
+            Some(Ok(ServerMessage::Error(err))) => assert_eq!(err.code, ErrorCode::AuthFailed),
+            other => panic!("Unexpected {:?}", other),
+        };
+
+        let mut conn = connect(builder()).await;
+        conn.send(ClientMessage::Request(request(1))).await.unwrap();
+        auth_failed(conn.next().await);
+        assert!(conn.next().await.is_none());
+
+        let mut conn = connect(builder()).await;
+        conn.send(ClientMessage::Auth { token: "guess".to_string() }).await.unwrap();
+        auth_failed(conn.next().await);
+
+        // Served under the token's limit.
+        let mut conn = connect(builder()).await;
+        conn.send(ClientMessage::Auth { token: "secret".to_string() }).await.unwrap();
+        conn.send(ClientMessage::Request(request(5))).await.unwrap();
+        match conn.next().await {
+            Some(Ok(ServerMessage::Response(resp))) => assert_eq!(resp.addrs.len(), 5),
+            other => panic!("Unexpected {:?}", other),
+        }
+        conn.send(ClientMessage::Request(request(6))).await.unwrap();
+        let msg = conn.next().await.unwrap().unwrap();
+        assert!(too_many_addrs(std::slice::from_ref(&msg), 5), "{:?}", msg);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_json_frames() {
+        let server = Server::bind(([127, 0, 0, 1], 0).into())
+            .wire_format(WireFormat::Json)
+            .build()
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let codec = ClientToServerCodec::new().with_format(WireFormat::Json);
+        let mut conn = Framed::new(stream, codec);
+        assert!(matches!(conn.next().await, Some(Ok(ServerMessage::Info(_)))));
+        conn.send(ClientMessage::Request(request(3))).await.unwrap();
+        match conn.next().await {
+            Some(Ok(ServerMessage::Response(resp))) => assert_eq!(resp.addrs.len(), 3),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_responses_in_chunks() {
+        let builder = Server::bind(([127, 0, 0, 1], 0).into()).stream_responses(16);
+        let mut conn = connect(builder).await;
+        conn.send(ClientMessage::Request(request(40))).await.unwrap();
+        let mut chunks = Vec::new();
+        loop {
+            match conn.next().await {
+                Some(Ok(ServerMessage::Partial(chunk))) => chunks.push(chunk.addrs.len()),
+                Some(Ok(ServerMessage::Response(rest))) => {
+                    chunks.push(rest.addrs.len());
+                    break;
+                }
+                other => panic!("Unexpected {:?}", other),
+            }
+        }
+        assert_eq!(chunks, [16, 16, 8]);
+    }
+}
@@ -0,0 +1,345 @@
+use std::ffi::CString;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::warn;
+
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time;
+
+/// Connections waiting to be accepted at most.
+const BACKLOG: i32 = 1024;
+
+/// How long to stop accepting after running out of file descriptors or
+/// memory, which accepting again at once would only fail for.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// An IP to listen on, with the zone of a scoped IPv6 address, e.g. the
+/// interface of a link-local one.
+#[derive(Copy, Clone, Debug)]
+pub struct Host {
+    ip: IpAddr,
+    scope_id: u32,
+}
+
+impl Host {
+    pub fn is_ipv6(&self) -> bool {
+        self.ip.is_ipv6()
+    }
+
+    pub fn addr(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V4(_) => SocketAddr::new(self.ip, port),
+            IpAddr::V6(ip) => SocketAddrV6::new(ip, port, 0, self.scope_id).into(),
+        }
+    }
+}
+
+/// Parses an IP, optionally in brackets, where an IPv6 one may be followed
+/// by a zone: an interface name or index after a `%`, as in `fe80::1%eth0`.
+pub fn parse_host(s: &str) -> Result<Host, String> {
+    let s = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+    let (ip, zone) = match s.split_once('%') {
+        Some((ip, zone)) => (ip, Some(zone)),
+        None => (s, None),
+    };
+    let ip: IpAddr = ip.parse().map_err(|_| format!("invalid IP {}", ip))?;
+    let scope_id = match zone {
+        Some(_) if ip.is_ipv4() => return Err("only IPv6 addresses have zones".to_string()),
+        Some(zone) => match zone.parse() {
+            Ok(index) => index,
+            Err(_) => interface_index(zone).ok_or_else(|| format!("no interface {}", zone))?,
+        },
+        None => 0,
+    };
+    Ok(Host { ip, scope_id })
+}
+
+/// Something to serve connections on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+/// Parses a TCP address such as `0.0.0.0:7000` or `[::]:7000`, or else the
+/// path of a Unix domain socket, which is told apart by having a `/`.
+pub fn parse_endpoint(s: &str) -> Result<Endpoint, String> {
+    if s.contains('/') {
+        return Ok(Endpoint::Unix(s.into()));
+    }
+    s.parse().map(Endpoint::Tcp).map_err(|_| format!("invalid address {}, or path without a /", s))
+}
+
+/// Options of the sockets of TCP and WebSocket clients, set as they're
+/// accepted, and of listening sockets, set before they're bound.
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    /// Sets TCP_NODELAY, sending small writes at once rather than
+    /// coalescing them.
+    pub nodelay: bool,
+    /// Probes connections idle for this long with TCP keepalives, if set.
+    pub keepalive: Option<Duration>,
+    /// Time between keepalive probes, the system's default if unset.
+    pub keepalive_interval: Option<Duration>,
+    /// SO_SNDBUF, in bytes, the system's default if unset.
+    pub send_buffer: Option<usize>,
+    /// SO_RCVBUF, in bytes, the system's default if unset.
+    pub recv_buffer: Option<usize>,
+    /// Sets SO_REUSEADDR on TCP listeners, so that restarting doesn't wait
+    /// for old connections, as std does. Set by default.
+    pub reuse_address: bool,
+    /// Sets SO_REUSEPORT on listening sockets, so that several servers can
+    /// share an address, with the system spreading clients between them.
+    pub reuse_port: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: false,
+            keepalive: None,
+            keepalive_interval: None,
+            send_buffer: None,
+            recv_buffer: None,
+            reuse_address: true,
+            reuse_port: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Sets the options of an accepted connection.
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            let keepalive = match self.keepalive_interval {
+                Some(interval) => keepalive.with_interval(interval),
+                None => keepalive,
+            };
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts of the connections of a single listener.
+#[derive(Debug)]
+pub struct ListenerStats {
+    /// Where it listens, as in the startup report.
+    pub name: String,
+    accepted: AtomicU64,
+    active: AtomicU64,
+}
+
+/// A connection counted as active by its listener until dropped.
+pub struct Active(Arc<ListenerStats>);
+
+impl ListenerStats {
+    pub fn new(name: String) -> Arc<Self> {
+        Arc::new(ListenerStats { name, accepted: AtomicU64::new(0), active: AtomicU64::new(0) })
+    }
+
+    /// Counts a connection as accepted, and as active until the returned
+    /// guard is dropped.
+    pub fn accept(self: &Arc<Self>) -> Active {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Active(self.clone())
+    }
+
+    /// Connections accepted since startup.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Connections open now.
+    pub fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// Binds a TCP listener to `addr`. An IPv6 wildcard only accepts IPv4
+/// clients too, as IPv4-mapped addresses, if `dual_stack` is set.
+pub fn bind_tcp(
+    addr: SocketAddr,
+    dual_stack: bool,
+    options: &SocketOptions,
+) -> io::Result<TcpListener> {
+    let socket = bind(addr, Type::STREAM, dual_stack, options)?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Binds a UDP socket to `addr`, dual-stack as for `bind_tcp`.
+pub fn bind_udp(
+    addr: SocketAddr,
+    dual_stack: bool,
+    options: &SocketOptions,
+) -> io::Result<UdpSocket> {
+    UdpSocket::from_std(bind(addr, Type::DGRAM, dual_stack, options)?.into())
+}
+
+fn bind(
+    addr: SocketAddr,
+    ty: Type,
+    dual_stack: bool,
+    options: &SocketOptions,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, None)?;
+    // Set explicitly as the system default varies.
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    if ty == Type::STREAM {
+        socket.set_reuse_address(options.reuse_address)?;
+    }
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+/// Accepts a connection with `accept`, logging errors that only concern the
+/// connection being accepted or a passing shortage of resources and
+/// accepting again, after a pause for the latter. Fails with any other
+/// error, after which the listener is of no use.
+pub(crate) async fn accept<F, Fut, T>(mut accept: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    loop {
+        let e = match accept().await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => e,
+        };
+        match e.raw_os_error() {
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => {
+                let delay = humantime::format_duration(ACCEPT_ERROR_DELAY);
+                warn!("Could not accept a connection, pausing for {}: {}", delay, e);
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+            _ if is_connection_error(&e) => warn!("Could not accept a connection: {}", e),
+            _ => return Err(e),
+        }
+    }
+}
+
+/// Whether accepting failed due to the connection alone, e.g. as the client
+/// closed it before it was accepted, or as for the network errors accept(2)
+/// says to retry after.
+fn is_connection_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted => true,
+        _ => matches!(
+            e.raw_os_error(),
+            Some(libc::EPROTO)
+                | Some(libc::EPERM)
+                | Some(libc::ENETDOWN)
+                | Some(libc::ENETUNREACH)
+                | Some(libc::EHOSTDOWN)
+                | Some(libc::EHOSTUNREACH)
+                | Some(libc::ENOPROTOOPT)
+                | Some(libc::EOPNOTSUPP)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use tokio::time::Instant;
+
+    /// Stands in for a listener, accepting with each of its results in turn.
+    struct MockListener {
+        results: Mutex<VecDeque<io::Result<u32>>>,
+    }
+
+    impl MockListener {
+        fn new(results: Vec<io::Result<u32>>) -> Self {
+            MockListener { results: Mutex::new(results.into()) }
+        }
+
+        async fn accept(&self) -> io::Result<u32> {
+            self.results.lock().unwrap().pop_front().expect("Accepted too often")
+        }
+
+        fn remaining(&self) -> usize {
+            self.results.lock().unwrap().len()
+        }
+    }
+
+    fn os_error(code: i32) -> io::Result<u32> {
+        Err(io::Error::from_raw_os_error(code))
+    }
+
+    #[tokio::test]
+    async fn skips_connection_errors() {
+        let listener = MockListener::new(vec![
+            os_error(libc::ECONNABORTED),
+            Err(io::ErrorKind::ConnectionReset.into()),
+            os_error(libc::EPROTO),
+            Ok(1),
+        ]);
+        assert_eq!(accept(|| listener.accept()).await.unwrap(), 1);
+        assert_eq!(listener.remaining(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pauses_when_out_of_resources() {
+        let listener =
+            MockListener::new(vec![os_error(libc::EMFILE), os_error(libc::ENFILE), Ok(2)]);
+        let start = Instant::now();
+        assert_eq!(accept(|| listener.accept()).await.unwrap(), 2);
+        assert_eq!(start.elapsed(), 2 * ACCEPT_ERROR_DELAY);
+    }
+
+    #[tokio::test]
+    async fn fails_on_other_errors() {
+        let listener = MockListener::new(vec![os_error(libc::EINVAL), Ok(3)]);
+        let e = accept(|| listener.accept()).await.unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+        assert_eq!(listener.remaining(), 1);
+    }
+}
@@ -1,75 +1,973 @@
 use std::fs::File;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::io::{self, BufReader, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use log::*;
-use simplelog::*;
+use tracing::level_filters::LevelFilter;
+use tracing::{info, warn};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, Layer, Registry};
 
-use rand::prelude::*;
+use tokio::runtime::{self, Runtime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 
-use tokio::prelude::*;
-use tokio::net::TcpListener;
-use tokio::codec::Decoder;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
 
-use core::{Response, ServerToClientCodec};
+use rustls_pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
 
-fn gen_sock_addr() -> SocketAddr {
-    let ip = IpAddr::V4(Ipv4Addr::new(
-        rand::random::<u8>(),
-        rand::random::<u8>(),
-        rand::random::<u8>(),
-        rand::random::<u8>(),
-    ));
-    let port = rand::random::<u16>();
-    SocketAddr::new(ip, port)
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+
+use chrono::Utc;
+
+use serde::Deserialize;
+
+use addrcore::{Cidr, DecodePolicy, WireFormat};
+
+use server::{
+    Acl, Builder, CidrGenerator, Endpoint, Families, History, HistoryQuery, Host, Overflow,
+    PoolGenerator, RandomGenerator, Reloader, Reserved, Server, SocketOptions,
+};
+
+mod config_file;
+mod daemon;
+
+use crate::config_file::{ConfigFile, Limits};
+use crate::daemon::{Detached, Pidfile};
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    let certs = certs(&mut BufReader::new(file))
+        .map_err(|e| format!("Invalid certificate file {}: {}", path, e))?;
+    Ok(certs.into_iter().map(Certificate).collect())
 }
 
-fn main() {
-    CombinedLogger::init(
-        vec![
-            TermLogger::new(LevelFilter::Info, Config::default()).unwrap(),
-            WriteLogger::new(
-                LevelFilter::Info,
-                Config::default(),
-                File::create("/tmp/maidsafe-test-server.log").unwrap()),
-        ]
-    ).unwrap();
-
-    let mut args = std::env::args();
-    let program = args.next().unwrap();
-    let (host, port) = match (args.next(), args.next()) {
-        (Some(host), Some(port)) => (host, port),
-        _ => return println!("Usage: {} <host> <port>", program),
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    let open = || File::open(path).map_err(|e| format!("Could not open {}: {}", path, e));
+    let invalid = |e| format!("Invalid key file {}: {}", path, e);
+    // Try PKCS8 first and fall back to RSA keys.
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(open()?)).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(open()?)).map_err(invalid)?;
+    }
+    let key = keys.pop().ok_or_else(|| format!("No private key found in {}", path))?;
+    Ok(PrivateKey(key))
+}
+
+/// Makes an acceptor serving the certificate at `cert_path`, and requiring
+/// clients to present one signed by a CA at `client_ca_path`, if given.
+fn tls_acceptor(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<TlsAcceptor, String> {
+    let config = ServerConfig::builder().with_safe_defaults();
+    let config = match client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| format!("Invalid CA certificate in {}: {}", path, e))?;
+            }
+            config.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => config.with_no_client_auth(),
+    };
+    let config = config
+        .with_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Serves random socket addresses to clients.
+#[derive(Parser)]
+#[command(version, subcommand_negates_reqs = true, args_conflicts_with_subcommands = true)]
+struct Args {
+    /// Address to listen on, e.g. 127.0.0.1, :: or [::1]. A link-local IPv6
+    /// address needs the zone of its interface, as in fe80::1%eth0.
+    #[arg(long, default_value = "127.0.0.1", value_parser = server::parse_host)]
+    host: Host,
+    /// Accept IPv4 clients as well when listening on an IPv6 address such as
+    /// ::, rather than IPv6 clients only.
+    #[arg(long)]
+    dual_stack: bool,
+    /// Port to listen on.
+    #[arg(long, required_unless_present_any = ["unix", "listen", "systemd", "config"])]
+    port: Option<u16>,
+    /// Unix domain socket to listen on, in addition to the port if given.
+    /// TLS only applies to the port.
+    #[arg(long, value_name = "PATH")]
+    unix: Option<PathBuf>,
+    /// Also listen on this TCP address, such as [::]:7000, or Unix domain
+    /// socket, told apart by a `/` in its path. May be repeated, and every
+    /// listener is counted on its own by the admin socket's stats.
+    #[arg(long, value_name = "ADDR|PATH", value_parser = server::parse_endpoint)]
+    listen: Vec<Endpoint>,
+    /// Also listen on the sockets systemd passes with socket activation, so
+    /// that clients wait rather than being refused while the server
+    /// restarts, and it can be started on demand.
+    #[arg(long)]
+    systemd: bool,
+    /// Tell systemd once the server is ready and when it starts draining,
+    /// for services of Type=notify.
+    #[arg(long)]
+    systemd_notify: bool,
+    /// Detach from the terminal once the server has started, logging to
+    /// --log-file only. Failing to start is still reported on the terminal.
+    #[arg(long, requires = "pidfile", conflicts_with = "log_stderr")]
+    daemonize: bool,
+    /// Write the pid of the server to FILE, removing it on exit, for `stop`
+    /// and `status`. SIGTERM drains the server rather than ending it at once.
+    #[arg(long, value_name = "FILE")]
+    pidfile: Option<PathBuf>,
+    /// Serve over TLS, which requires --cert and --key.
+    #[arg(long, requires_all = ["cert", "key"])]
+    tls: bool,
+    /// PEM certificate chain to serve over TLS.
+    #[arg(long, value_name = "FILE")]
+    cert: Option<String>,
+    /// PEM private key of the certificate, PKCS8 or RSA.
+    #[arg(long, value_name = "FILE")]
+    key: Option<String>,
+    /// Only accept TLS clients presenting a certificate signed by a CA of
+    /// --client-ca, rejecting the others during the handshake. Clients are
+    /// rate limited and logged by the common name of their certificate.
+    #[arg(long, requires_all = ["tls", "client_ca"])]
+    require_client_cert: bool,
+    /// PEM file of the CA certificates to verify clients with.
+    #[arg(long, value_name = "FILE", requires = "require_client_cert")]
+    client_ca: Option<String>,
+    /// Token authorizing clients to change their connection's log level.
+    #[arg(long, value_name = "TOKEN")]
+    debug_token: Option<String>,
+    /// Log the reverse DNS name of every client.
+    #[arg(long)]
+    reverse_dns: bool,
+    /// TTL attached to every generated address.
+    #[arg(long, value_name = "SECS")]
+    ttl: Option<u32>,
+    /// Pre-shared key authenticating every frame, which clients must share.
+    #[arg(long, value_name = "SECRET")]
+    hmac_key: Option<String>,
+    /// Skip frames that can't be decoded instead of dropping the client, and
+    /// precede every frame with a sync marker to recover from corruption.
+    #[arg(long)]
+    lenient: bool,
+    /// Encoding of the frames of TCP, TLS, Unix socket and WebSocket clients,
+    /// which must use the same: compact binary, or a JSON object a frame.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "binary")]
+    wire_format: WireFormatArg,
+    /// Also serve requests over UDP on every TCP address, one per datagram.
+    /// Datagrams aren't authenticated.
+    #[arg(long, conflicts_with = "hmac_key")]
+    udp: bool,
+    /// Serve at most this many connections at a time, accepting no more
+    /// until one closes.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: Option<u64>,
+    /// Once at --max-connections, accept up to this many more connections
+    /// and serve them as others close, rather than leaving them unaccepted.
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "max_connections",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    accept_queue: Option<u64>,
+    /// How long a connection waits in the --accept-queue before it's closed.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "1s",
+        value_parser = humantime::parse_duration
+    )]
+    accept_deadline: Duration,
+    /// Messages to queue for a client that reads them slower than they're
+    /// made, beyond which --overflow applies. Clients granting credits must
+    /// grant them before sending more requests than this ahead of them.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = server::DEFAULT_SEND_QUEUE as u64,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    send_queue: u64,
+    /// What to do once a client's queue is full: block, to stop reading its
+    /// requests and generating its updates until there's room, drop, to
+    /// drop the updates and notices that don't fit, or disconnect.
+    #[arg(long, value_name = "POLICY", default_value = "block")]
+    overflow: Overflow,
+    /// Stream responses and updates in chunks of at most N addresses rather
+    /// than whole, starting at 16 and growing or shrinking them with how
+    /// fast each client reads.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_chunk: Option<u64>,
+    /// Set TCP_NODELAY on client connections, sending small writes at once
+    /// rather than coalescing them.
+    #[arg(long)]
+    nodelay: bool,
+    /// Probe client connections idle for this long with TCP keepalives.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    keepalive: Option<Duration>,
+    /// Time between keepalive probes, the system's default if not given.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        requires = "keepalive",
+        value_parser = humantime::parse_duration
+    )]
+    keepalive_interval: Option<Duration>,
+    /// Size of the send buffer of client connections, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    send_buffer: Option<u64>,
+    /// Size of the receive buffer of client connections, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..))]
+    recv_buffer: Option<u64>,
+    /// Set SO_REUSEPORT on listening sockets, so that several servers can
+    /// listen on the same address, with the system spreading clients
+    /// between them.
+    #[arg(long)]
+    reuse_port: bool,
+    /// Don't set SO_REUSEADDR on TCP listeners, so that binding fails while
+    /// connections of a previous server linger.
+    #[arg(long)]
+    no_reuse_addr: bool,
+    /// Only accept TCP and WebSocket clients within these CIDR blocks. The
+    /// most specific --allow or --deny block containing a client decides.
+    #[arg(long, value_name = "CIDR,...", value_delimiter = ',')]
+    allow: Vec<Cidr>,
+    /// Reject TCP and WebSocket clients within these CIDR blocks, closing
+    /// their connections as soon as they're accepted.
+    #[arg(long, value_name = "CIDR,...", value_delimiter = ',')]
+    deny: Vec<Cidr>,
+    /// Whether to accept clients in no --allow or --deny block. Deny if any
+    /// --allow block is given, and allow otherwise.
+    #[arg(long, value_name = "POLICY", value_enum)]
+    acl_default: Option<AclDefault>,
+    /// Requests, batches and transactions every client IP may send per
+    /// second, answering the rest with an error telling when to retry. The
+    /// limit is advertised to clients.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+    /// Requests a client may send at once after idling, --rate-limit by
+    /// default.
+    #[arg(long, value_name = "N", requires = "rate_limit")]
+    rate_burst: Option<u32>,
+    /// Warn clients connected over a stream once they've used up this
+    /// percentage of a rate limit, so that they may slow down.
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    quota_warning: Option<u8>,
+    /// Most addresses to serve for a single request, answering larger ones
+    /// with an error instead.
+    #[arg(long, value_name = "N", default_value_t = server::DEFAULT_MAX_ADDRS)]
+    max_addrs: u32,
+    /// Only serve IPv4 addresses within these CIDR blocks, every address of
+    /// them being as likely. Requests for a block outside them all fail.
+    #[arg(long, value_name = "CIDR,...", value_delimiter = ',')]
+    generate_from: Vec<Cidr>,
+    /// Only serve addresses sampled from this file, one `ip:port` a line,
+    /// reloading it whenever it changes.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["generate_from", "families"])]
+    pool_file: Option<PathBuf>,
+    /// Only serve a client an address from --pool-file again once it's been
+    /// served all others on the same connection.
+    #[arg(long, requires = "pool_file")]
+    without_replacement: bool,
+    /// Read settings from this TOML file: listeners, limits, generator, log
+    /// and TLS settings, and those too involved for options, such as
+    /// weighted pools of addresses and limits that apply during time windows.
+    /// Options given here take precedence.
+    /// SIGHUP reloads the limits but --max-connections, the log level and
+    /// the TLS certificate without dropping connections.
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Address families to serve. IPv6 addresses are global unicast ones,
+    /// outside of the documentation range.
+    #[arg(
+        long,
+        value_name = "FAMILIES",
+        value_enum,
+        default_value = "v4",
+        conflicts_with = "generate_from"
+    )]
+    families: FamiliesArg,
+    /// Share of IPv6 addresses with --families both, from 0 to 1.
+    #[arg(long, value_name = "RATIO", default_value_t = 0.5, value_parser = parse_ratio)]
+    v6_ratio: f64,
+    /// Never serve addresses in these kinds of ranges: loopback, multicast,
+    /// link-local, broadcast or private (RFC 1918). All of them if none are
+    /// given.
+    #[arg(long, value_name = "KIND,...", value_delimiter = ',', num_args = 0..)]
+    exclude_reserved: Option<Vec<Reserved>>,
+    /// Never serve a connection an address it has already been served,
+    /// remembering the last N of them.
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "1000000",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    no_repeat: Option<u64>,
+    /// Record every served address, when and to whom it was served to this
+    /// SQLite database, to look them up with `history`.
+    #[arg(long, value_name = "FILE")]
+    history: Option<PathBuf>,
+    /// Seed the generators so that runs are reproducible: a client sending
+    /// the same requests from the same IP gets the same addresses.
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+    /// Close connections that send nothing for this long, such as 60s.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+    /// Accept operator commands, such as stats, kick or drain, on a Unix
+    /// domain socket at PATH. Send help for the full list.
+    #[arg(long, value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+    /// Also serve addresses over HTTP on ADDR, answering GET /addrs?count=N
+    /// with JSON. The API is described at GET /openapi.json.
+    #[arg(long, value_name = "ADDR")]
+    http_addr: Option<SocketAddr>,
+    /// Also accept WebSocket clients on ADDR, such as browsers, with every
+    /// frame of the protocol sent as a binary message.
+    #[arg(long, value_name = "ADDR")]
+    ws_addr: Option<SocketAddr>,
+    /// Also serve the gRPC service of proto/addrs.proto on ADDR.
+    #[arg(long, value_name = "ADDR")]
+    grpc_addr: Option<SocketAddr>,
+    /// Also answer DNS queries over UDP with random addresses, on ADDR or
+    /// on 0.0.0.0:53 if not given, to test resolvers against chaos.
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "0.0.0.0:53")]
+    dns: Option<SocketAddr>,
+    /// Addresses in every DNS answer, as many as fit in 512 bytes.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "1",
+        requires = "dns",
+        value_parser = clap::value_parser!(u32).range(1..=100)
+    )]
+    dns_answers: u32,
+    /// Only serve connections that authenticate with a token listed in this
+    /// TOML file, under the limits set for it, reloading it whenever it
+    /// changes. Transports without connections can't authenticate.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["udp", "http_addr", "grpc_addr", "dns"]
+    )]
+    auth_file: Option<PathBuf>,
+    /// File to write the log to, in addition to the terminal unless
+    /// daemonized. Skipped if it can't be created.
+    #[arg(long, value_name = "FILE", default_value = "/tmp/maidsafe-test-server.log")]
+    log_file: PathBuf,
+    /// Maximum level of log records, from off to trace. Defaults to RUST_LOG
+    /// if it's set to a level, and info otherwise.
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+    /// Write the log to stderr only, rather than to stderr and --log-file.
+    #[arg(long, conflicts_with = "log_file")]
+    log_stderr: bool,
+    /// Format of log records: text, or JSON objects one a line with the
+    /// fields of their spans, such as the client of a connection.
+    #[arg(long, value_name = "FORMAT", value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// Worker threads serving clients, one per core by default.
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with = "single_thread",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    workers: Option<u64>,
+    /// Serve clients on a single thread, with tokio's current thread
+    /// runtime, rather than on a pool of workers.
+    #[arg(long)]
+    single_thread: bool,
+    /// Most threads to run blocking work on, such as resolving host names,
+    /// 512 by default. They're only started as needed.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    blocking_threads: Option<u64>,
+    /// Serve task instrumentation to tokio-console, on 127.0.0.1:6669 unless
+    /// TOKIO_CONSOLE_BIND says otherwise. The server must be built with the
+    /// console feature and RUSTFLAGS="--cfg tokio_unstable".
+    #[arg(long)]
+    console: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the addresses recorded with --history, oldest first, one a
+    /// line: when, to which client, for which request and the address.
+    History(HistoryArgs),
+    /// Drain the server of a pidfile with SIGTERM and wait for it to exit,
+    /// killing it if it takes too long.
+    Stop(StopArgs),
+    /// Tell whether the server of a pidfile is running, with the exit code
+    /// of LSB init scripts: 0 if it is, 3 if it isn't.
+    Status(StatusArgs),
+}
+
+#[derive(clap::Args)]
+struct StopArgs {
+    /// Pidfile written with --pidfile.
+    #[arg(long, value_name = "FILE")]
+    pidfile: PathBuf,
+    /// How long to let the server drain before killing it.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        default_value = "30s",
+        value_parser = humantime::parse_duration
+    )]
+    timeout: Duration,
+}
+
+#[derive(clap::Args)]
+struct StatusArgs {
+    /// Pidfile written with --pidfile.
+    #[arg(long, value_name = "FILE")]
+    pidfile: PathBuf,
+}
+
+#[derive(clap::Args)]
+struct HistoryArgs {
+    /// Database written with --history.
+    #[arg(long, value_name = "FILE")]
+    db: PathBuf,
+    /// Only addresses served to this client, as it appears in logs, e.g.
+    /// 127.0.0.1:50000, unix#3 or udp:127.0.0.1:50000.
+    #[arg(long)]
+    peer: Option<String>,
+    /// Only this address.
+    #[arg(long)]
+    addr: Option<SocketAddr>,
+    /// Only addresses served within this long, e.g. 1h or 30m.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    since: Option<Duration>,
+    /// Only the N most recent addresses.
+    #[arg(long, value_name = "N")]
+    limit: Option<u32>,
+}
+
+/// Prints the history as `history` is asked to.
+fn print_history(args: HistoryArgs) -> io::Result<()> {
+    let query = HistoryQuery {
+        peer: args.peer,
+        addr: args.addr,
+        since: args.since.map(|since| SystemTime::now() - since),
+        until: None,
+        limit: args.limit,
     };
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    for served in History::query(&args.db, &query)? {
+        let time = humantime::format_rfc3339_millis(served.time);
+        writeln!(out, "{} {} {} {}", time, served.peer, served.request, served.addr)?;
+    }
+    out.flush()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum AclDefault {
+    Allow,
+    Deny,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum WireFormatArg {
+    Binary,
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, ValueEnum)]
+enum FamiliesArg {
+    V4,
+    V6,
+    Both,
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("{} isn't a number from 0 to 1", s)),
+    }
+}
 
-    let addr = format!("{}:{}", host, port).parse().unwrap();
-    let listener = TcpListener::bind(&addr)
-        .expect(&format!("Could not bind to {}", addr));
-
-    let server = listener
-        .incoming()
-        .map_err(|e| error!("Server error: {}", e))
-        .for_each(move |stream| {
-            info!("Connected to {:?}", stream);
-
-            let addr = stream.peer_addr().unwrap();
-            let (writer, reader) = ServerToClientCodec.framed(stream).split();
-            let client = reader
-                .map(move |req| {
-                    info!("Received request {:?} from {}", req, addr);
-                    let mut addrs = Vec::with_capacity(req.num_addrs as usize);
-                    for _ in 0..req.num_addrs {
-                        addrs.push(gen_sock_addr());
-                    }
-                    info!("Generated addrs: {:?}", addrs);
-                    Response { addrs }
-                })
-                .forward(writer)
-                .map_err(|e| error!("Client error: {}", e))
-                .and_then(|(_reader, _writer)| Ok(()));
-
-            tokio::spawn(client)
+/// Logs to stderr and --log-file, or only to one of them if asked to or
+/// daemonized, as text or JSON. Records of dependencies logging with `log`
+/// are included. With --console, tasks are also reported to tokio-console,
+/// whatever the level. Returns a handle to change the level with.
+fn init_logging(args: &Args) -> LevelHandle {
+    let level = args.log_level.or_else(env_log_level).unwrap_or(LevelFilter::INFO);
+    let json = args.log_format == LogFormat::Json;
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = Vec::new();
+    // A daemon's stderr is soon detached.
+    if !args.daemonize {
+        layers.push(match json {
+            true => fmt::layer().json().with_writer(io::stderr).boxed(),
+            false => {
+                let ansi = io::stderr().is_terminal();
+                fmt::layer().with_ansi(ansi).with_writer(io::stderr).boxed()
+            }
         });
+    }
+    let mut error = None;
+    if !args.log_stderr {
+        match File::create(&args.log_file) {
+            Ok(file) => layers.push(match json {
+                true => fmt::layer().json().with_writer(Mutex::new(file)).boxed(),
+                false => fmt::layer().with_ansi(false).with_writer(Mutex::new(file)).boxed(),
+            }),
+            Err(e) => error = Some(e),
+        }
+    }
+    // The level only filters the logs, as the console needs the runtime's
+    // trace level spans.
+    let (level, handle) = reload::Layer::new(level);
+    let layers = vec![layers.with_filter(level).boxed()];
+    #[cfg(feature = "console")]
+    let layers = {
+        let mut layers = layers;
+        if args.console {
+            layers.push(console_subscriber::spawn().boxed());
+        }
+        layers
+    };
+    tracing_subscriber::registry().with(layers).init();
+    if let Some(e) = error {
+        warn!("Could not create log file {}: {}", args.log_file.display(), e);
+    }
+    handle
+}
+
+type LevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// The level RUST_LOG is set to. Per module directives aren't supported.
+fn env_log_level() -> Option<LevelFilter> {
+    let value = std::env::var("RUST_LOG").ok()?;
+    match value.parse() {
+        Ok(level) => Some(level),
+        Err(_) => {
+            eprintln!("Ignoring RUST_LOG={}, which isn't a log level", value);
+            None
+        }
+    }
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match args.command.take() {
+        Some(Command::History(history)) => {
+            if let Err(e) = print_history(history) {
+                eprintln!("Could not read history: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Stop(stop)) => std::process::exit(daemon::stop(&stop.pidfile, stop.timeout)),
+        Some(Command::Status(status)) => std::process::exit(daemon::status(&status.pidfile)),
+        None => (),
+    }
+    let config = match args.config {
+        Some(ref path) => match config_file::load(path) {
+            Ok(config) => config,
+            Err(e) => Args::command().error(ErrorKind::Io, e).exit(),
+        },
+        None => ConfigFile::default(),
+    };
+    let generated = args.pool_file.is_some() || !args.generate_from.is_empty();
+    if !config.pools.is_empty() && (generated || given(&matches, "families")) {
+        let msg = "Pools of --config conflict with --pool-file, --generate-from and --families";
+        Args::command().error(ErrorKind::ArgumentConflict, msg).exit();
+    }
+    merge_config(&mut args, &config, &matches);
+    // Forking only keeps the calling thread, so it must happen before the
+    // runtime spawns its own.
+    let detached = match args.daemonize {
+        true => match daemon::daemonize(&args.log_file) {
+            Ok(detached) => Some(detached),
+            Err(e) => {
+                let msg = format!("Could not daemonize: {}", e);
+                Args::command().error(ErrorKind::Io, msg).exit()
+            }
+        },
+        false => None,
+    };
+    let runtime = build_runtime(&args).unwrap_or_else(|e| {
+        let msg = format!("Could not start the runtime: {}", e);
+        Args::command().error(ErrorKind::Io, msg).exit()
+    });
+    runtime.block_on(serve(args, matches, config, detached));
+}
+
+/// Builds the runtime as --workers, --single-thread and --blocking-threads
+/// say, so that configurations can be compared.
+fn build_runtime(args: &Args) -> io::Result<Runtime> {
+    let mut builder = match args.single_thread {
+        true => runtime::Builder::new_current_thread(),
+        false => runtime::Builder::new_multi_thread(),
+    };
+    if let Some(n) = args.workers {
+        builder.worker_threads(n as usize);
+    }
+    if let Some(n) = args.blocking_threads {
+        builder.max_blocking_threads(n as usize);
+    }
+    builder.enable_all().build()
+}
+
+/// Whether the option `id` was given on the command line, rather than left
+/// to its default.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+/// Fills in the options not given on the command line from `config`.
+fn merge_config(args: &mut Args, config: &ConfigFile, matches: &ArgMatches) {
+    args.listen.extend(config.listen.iter().cloned());
+    let limits = &config.limits;
+    args.max_connections = args.max_connections.or(limits.max_connections);
+    if let (false, Some(n)) = (given(matches, "max_addrs"), limits.max_addrs) {
+        args.max_addrs = n;
+    }
+    if args.rate_limit.is_none() {
+        args.rate_limit = limits.rate_limit;
+        args.rate_burst = limits.rate_burst;
+    }
+    args.idle_timeout = args.idle_timeout.or(limits.idle_timeout);
+    args.seed = args.seed.or(config.generator.seed);
+    args.no_repeat = args.no_repeat.or(config.generator.no_repeat);
+    args.log_level = args.log_level.or(config.log.level);
+    if let (false, Some(file)) = (given(matches, "log_file"), &config.log.file) {
+        args.log_file = file.clone();
+    }
+    if let (false, Some(format)) = (given(matches, "log_format"), config.log.format) {
+        args.log_format = format;
+    }
+    if let (None, Some(tls)) = (&args.cert, &config.tls) {
+        args.tls = true;
+        args.cert = Some(tls.cert.clone());
+        args.key = Some(tls.key.clone());
+        if tls.client_ca.is_some() {
+            args.require_client_cert = true;
+            args.client_ca = tls.client_ca.clone();
+        }
+    }
+}
 
-    tokio::run(server);
+/// Applies the reloadable settings of the config file whenever it's
+/// reloaded, and the limits of its schedules as they change, unless given
+/// on the command line.
+struct Reload {
+    matches: ArgMatches,
+    reloader: Reloader,
+    log_level: LevelHandle,
 }
 
+impl Reload {
+    /// Applies the limits in force, as `config_file::limits_at` says.
+    fn apply_limits(&self, limits: &Limits) {
+        if !given(&self.matches, "max_addrs") {
+            let max_addrs = limits.max_addrs.unwrap_or(server::DEFAULT_MAX_ADDRS);
+            self.reloader.set_max_addrs(max_addrs);
+        }
+        if !given(&self.matches, "rate_limit") {
+            let burst = |per_sec| limits.rate_burst.unwrap_or(per_sec);
+            self.reloader.set_rate_limit(limits.rate_limit.map(|n| (n, burst(n))));
+        }
+        if !given(&self.matches, "idle_timeout") {
+            self.reloader.set_idle_timeout(limits.idle_timeout);
+        }
+    }
+
+    /// Applies the settings of `config` other than its limits.
+    fn apply(&self, config: &ConfigFile) {
+        if !given(&self.matches, "log_level") {
+            let level = config.log.level.or_else(env_log_level).unwrap_or(LevelFilter::INFO);
+            match self.log_level.modify(|filter| *filter = level) {
+                Ok(()) => info!("Logging at level {}", level),
+                Err(e) => warn!("Could not change the log level: {}", e),
+            }
+        }
+        // Read again even if the paths are the same, for renewed certificates.
+        if let (false, Some(tls)) = (given(&self.matches, "cert"), &config.tls) {
+            match tls_acceptor(&tls.cert, &tls.key, tls.client_ca.as_deref()) {
+                // Without TLS to begin with, it's reported as needing a restart.
+                Ok(acceptor) => {
+                    self.reloader.set_tls(acceptor);
+                }
+                Err(e) => warn!("Could not reload the TLS certificate, keeping it: {}", e),
+            }
+        }
+    }
+}
+
+/// Reloads the config file at `path` on every SIGHUP, applying the settings
+/// that can be changed without dropping connections and handing it to
+/// `configs` for its limits, and warning about those that need a restart.
+/// An invalid file is skipped altogether.
+async fn reload_on_sighup(
+    path: PathBuf,
+    running: ConfigFile,
+    reload: Arc<Reload>,
+    configs: watch::Sender<ConfigFile>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Could not handle SIGHUP, so {} can't be reloaded: {}", path.display(), e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let config = match config_file::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Could not reload, keeping the settings: {}", e);
+                continue;
+            }
+        };
+        info!("Reloading {}", path.display());
+        reload.apply(&config);
+        for what in config_file::restart_needed(&running, &config) {
+            warn!("{} changed in {}, which only applies on restart", what, path.display());
+        }
+        // Nothing's left to apply the limits if it failed.
+        if configs.send(config).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs the server as `args` and `config` say, telling the terminal once
+/// it's started if `detached` from it.
+async fn serve(
+    mut args: Args,
+    matches: ArgMatches,
+    config: ConfigFile,
+    detached: Option<Detached>,
+) {
+    if args.console && !cfg!(feature = "console") {
+        let msg = "--console requires building with --features console and \
+                   RUSTFLAGS=\"--cfg tokio_unstable\"";
+        Args::command().error(ErrorKind::InvalidValue, msg).exit();
+    }
+    // clap ensures --port, --unix, --listen, --systemd or --config is given,
+    // and building the server that there's something to listen on.
+    let mut endpoints = Vec::new();
+    endpoints.extend(args.port.map(|port| Endpoint::Tcp(args.host.addr(port))));
+    endpoints.extend(args.unix.take().map(Endpoint::Unix));
+    endpoints.append(&mut args.listen);
+    let tcp_addrs: Vec<_> = endpoints
+        .iter()
+        .filter_map(|endpoint| match endpoint {
+            Endpoint::Tcp(addr) => Some(addr),
+            Endpoint::Unix(_) => None,
+        })
+        .collect();
+    if args.dual_stack && !tcp_addrs.iter().any(|addr| addr.is_ipv6()) {
+        let msg = "--dual-stack requires an IPv6 --host or --listen address";
+        Args::command().error(ErrorKind::ArgumentConflict, msg).exit();
+    }
+    if args.udp && tcp_addrs.is_empty() {
+        let msg = "--udp requires --port or a TCP --listen address";
+        Args::command().error(ErrorKind::MissingRequiredArgument, msg).exit();
+    }
+    let families = match args.families {
+        FamiliesArg::V4 => Families::V4,
+        FamiliesArg::V6 => Families::V6,
+        FamiliesArg::Both => Families::Both { v6_ratio: args.v6_ratio },
+    };
+    let generator = match args.generate_from.as_slice() {
+        [] => None,
+        cidrs => match CidrGenerator::new(cidrs) {
+            Ok(generator) => Some(generator),
+            Err(e) => Args::command().error(ErrorKind::InvalidValue, e).exit(),
+        },
+    };
+
+    let log_level = init_logging(&args);
+    server::install_panic_hook();
+
+    let mut builder = endpoints.into_iter().fold(Server::builder(), Builder::listen);
+    builder = builder
+        .socket_activation(args.systemd)
+        .notify_systemd(args.systemd_notify)
+        .drain_on_sigterm(args.pidfile.is_some())
+        .dual_stack(args.dual_stack)
+        .udp(args.udp)
+        .reverse_dns(args.reverse_dns)
+        .max_addrs(args.max_addrs)
+        .policy(if args.lenient { DecodePolicy::Lenient } else { DecodePolicy::Strict })
+        .wire_format(match args.wire_format {
+            WireFormatArg::Binary => WireFormat::Binary,
+            WireFormatArg::Json => WireFormat::Json,
+        });
+    if let (true, Some(cert), Some(key)) = (args.tls, args.cert, args.key) {
+        let acceptor = tls_acceptor(&cert, &key, args.client_ca.as_deref())
+            .unwrap_or_else(|e| Args::command().error(ErrorKind::Io, e).exit());
+        builder = builder.tls(acceptor);
+    }
+    if let Some(token) = args.debug_token {
+        builder = builder.debug_token(token);
+    }
+    if let Some(secs) = args.ttl {
+        builder = builder.ttl(secs);
+    }
+    if let Some(key) = args.hmac_key {
+        builder = builder.hmac_key(key);
+    }
+    if let Some(seed) = args.seed {
+        builder = builder.seed(seed);
+    }
+    if let Some(addr) = args.dns {
+        builder = builder.dns(addr).dns_answers(args.dns_answers);
+    }
+    if let Some(addr) = args.grpc_addr {
+        builder = builder.grpc(addr);
+    }
+    if let Some(addr) = args.ws_addr {
+        builder = builder.ws(addr);
+    }
+    if let Some(addr) = args.http_addr {
+        builder = builder.http(addr);
+    }
+    if let Some(path) = args.admin_socket {
+        builder = builder.admin_socket(path);
+    }
+    if let Some(path) = args.auth_file {
+        builder = builder.auth_file(path);
+    }
+    if let Some(timeout) = args.idle_timeout {
+        if timeout == Duration::ZERO {
+            let msg = "--idle-timeout must be longer than zero";
+            Args::command().error(ErrorKind::InvalidValue, msg).exit();
+        }
+        builder = builder.idle_timeout(timeout);
+    }
+    if let Some(n) = args.max_connections {
+        builder = builder.max_connections(n as usize);
+    }
+    if let Some(n) = args.accept_queue {
+        builder = builder.accept_queue(n as usize, args.accept_deadline);
+    }
+    builder = builder.send_queue(args.send_queue as usize, args.overflow);
+    if let Some(n) = args.max_chunk {
+        builder = builder.stream_responses(n as usize);
+    }
+    if args.keepalive == Some(Duration::ZERO) || args.keepalive_interval == Some(Duration::ZERO) {
+        let msg = "--keepalive and --keepalive-interval must be longer than zero";
+        Args::command().error(ErrorKind::InvalidValue, msg).exit();
+    }
+    builder = builder.socket_options(SocketOptions {
+        nodelay: args.nodelay,
+        keepalive: args.keepalive,
+        keepalive_interval: args.keepalive_interval,
+        send_buffer: args.send_buffer.map(|n| n as usize),
+        recv_buffer: args.recv_buffer.map(|n| n as usize),
+        reuse_address: !args.no_reuse_addr,
+        reuse_port: args.reuse_port,
+    });
+    if !args.allow.is_empty() || !args.deny.is_empty() || args.acl_default.is_some() {
+        let allow_by_default = match args.acl_default {
+            Some(default) => default == AclDefault::Allow,
+            None => args.allow.is_empty(),
+        };
+        let acl = if allow_by_default { Acl::allow_by_default() } else { Acl::deny_by_default() };
+        let acl = args.allow.into_iter().fold(acl, Acl::allow);
+        builder = builder.acl(args.deny.into_iter().fold(acl, Acl::deny));
+    }
+    if let Some(per_sec) = args.rate_limit {
+        builder = builder.rate_limit(per_sec, args.rate_burst.unwrap_or(per_sec));
+    }
+    if let Some(percent) = args.quota_warning {
+        builder = builder.quota_warning(percent);
+    }
+    match (args.pool_file, generator) {
+        _ if !config.pools.is_empty() => {
+            let mix = config_file::mix(&config.pools)
+                .unwrap_or_else(|e| Args::command().error(ErrorKind::ValueValidation, e).exit());
+            builder = builder.generator(mix);
+        }
+        (Some(path), _) => {
+            let pool = PoolGenerator::open(&path, !args.without_replacement).unwrap_or_else(|e| {
+                let msg = format!("Could not load pool {}: {}", path.display(), e);
+                Args::command().error(ErrorKind::Io, msg).exit()
+            });
+            builder = builder.generator(pool);
+        }
+        (None, Some(generator)) => builder = builder.generator(generator),
+        (None, None) => builder = builder.generator(RandomGenerator::new(families)),
+    }
+    match args.exclude_reserved {
+        Some(ref kinds) if kinds.is_empty() => builder = builder.exclude(&Reserved::ALL),
+        Some(ref kinds) => builder = builder.exclude(kinds),
+        None => (),
+    }
+    if let Some(path) = args.history {
+        builder = builder.history(path);
+    }
+    if let Some(n) = args.no_repeat {
+        builder = builder.no_repeat(n as usize);
+    }
+    let _pidfile = args.pidfile.map(|path| {
+        Pidfile::create(path).unwrap_or_else(|e| Args::command().error(ErrorKind::Io, e).exit())
+    });
+    let server = builder.build().await.unwrap_or_else(|e| {
+        let msg = format!("Could not start server: {}", e);
+        Args::command().error(ErrorKind::Io, msg).exit()
+    });
+    if let Some(detached) = detached {
+        if let Err(e) = detached.ready() {
+            warn!("Could not detach from the terminal: {}", e);
+        }
+    }
+    if let Some(path) = args.config {
+        let reload = Arc::new(Reload { matches, reloader: server.reloader(), log_level });
+        let (configs_tx, configs_rx) = watch::channel(config.clone());
+        let scheduled = reload.clone();
+        let apply = move |limits: &Limits| scheduled.apply_limits(limits);
+        tokio::spawn(config_file::follow_schedules(configs_rx, Utc::now, apply));
+        tokio::spawn(reload_on_sighup(path, config, reload, configs_tx));
+    }
+    server.run().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn loads_certificates_and_keys() {
+        let (cert, key, ca) = (testdata("server.pem"), testdata("server.key"), testdata("ca.pem"));
+        assert!(tls_acceptor(&cert, &key, None).is_ok());
+        assert!(tls_acceptor(&cert, &key, Some(&ca)).is_ok());
+        let error = |cert: &str, key: &str| tls_acceptor(cert, key, None).err().unwrap();
+        let missing = testdata("missing.pem");
+        assert!(error(&missing, &key).starts_with("Could not open"));
+        assert!(error(&cert, &cert).starts_with("No private key found"));
+        assert!(tls_acceptor(&cert, &key, Some(&missing)).is_err());
+    }
+}
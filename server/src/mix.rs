@@ -0,0 +1,213 @@
+use std::net::SocketAddr;
+
+use rand::prelude::*;
+
+use addrcore::Constraints;
+
+use crate::gen::{AddrGenerator, GenRng};
+
+/// A generator that can be cloned as a trait object.
+trait Part: AddrGenerator + Sync {
+    fn clone_part(&self) -> Box<dyn Part>;
+}
+
+impl<G> Part for G
+where
+    G: AddrGenerator + Clone + Sync + 'static,
+{
+    fn clone_part(&self) -> Box<dyn Part> {
+        Box::new(self.clone())
+    }
+}
+
+/// Mixes the addresses of several generators, each generating a share of
+/// them in proportion to its weight. Requests that only some of them can
+/// serve, such as for a CIDR block outside the others, are served by those
+/// alone.
+pub struct MixGenerator {
+    parts: Vec<(u32, Box<dyn Part>)>,
+    rng: GenRng,
+}
+
+impl MixGenerator {
+    pub fn new() -> Self {
+        MixGenerator { parts: Vec::new(), rng: GenRng::default() }
+    }
+
+    /// Adds `generator`, which generates `weight` addresses out of the total
+    /// weight of all of them.
+    pub fn add<G>(mut self, weight: u32, generator: G) -> Self
+    where
+        G: AddrGenerator + Clone + Sync + 'static,
+    {
+        self.parts.push((weight, Box::new(generator)));
+        self
+    }
+
+    /// The indices and weights of the parts that can serve `constraints`.
+    fn eligible(&self, constraints: &Constraints) -> Vec<(usize, u32)> {
+        self.parts
+            .iter()
+            .enumerate()
+            .filter(|(_, (weight, part))| *weight > 0 && part.check(constraints).is_ok())
+            .map(|(i, &(weight, _))| (i, weight))
+            .collect()
+    }
+
+    /// Picks the part to generate an address, out of `eligible`, which
+    /// mustn't be empty.
+    fn pick(&mut self, eligible: &[(usize, u32)]) -> usize {
+        let total: u64 = eligible.iter().map(|&(_, weight)| weight as u64).sum();
+        let mut n = self.rng.gen_range(0, total);
+        for &(i, weight) in eligible {
+            if n < weight as u64 {
+                return i;
+            }
+            n -= weight as u64;
+        }
+        unreachable!()
+    }
+}
+
+impl Default for MixGenerator {
+    fn default() -> Self {
+        MixGenerator::new()
+    }
+}
+
+impl Clone for MixGenerator {
+    fn clone(&self) -> Self {
+        let parts = self.parts.iter().map(|(weight, part)| (*weight, part.clone_part())).collect();
+        MixGenerator { parts, rng: self.rng.clone() }
+    }
+}
+
+impl AddrGenerator for MixGenerator {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        if !self.eligible(constraints).is_empty() {
+            return Ok(());
+        }
+        // Tell why the first part with a weight can't serve it.
+        match self.parts.iter().find(|(weight, _)| *weight > 0) {
+            Some((_, part)) => part.check(constraints),
+            None => Err("No generator has a weight".to_string()),
+        }
+    }
+
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr {
+        let eligible = self.eligible(constraints);
+        let i = self.pick(&eligible);
+        self.parts[i].1.next_addr(constraints)
+    }
+
+    fn generate(&mut self, n: usize, constraints: &Constraints) -> Vec<SocketAddr> {
+        // Every part generates its share in one go, as it may do it faster.
+        let eligible = self.eligible(constraints);
+        let mut counts = vec![0; self.parts.len()];
+        for _ in 0..n {
+            counts[self.pick(&eligible)] += 1;
+        }
+        let mut addrs = Vec::with_capacity(n);
+        for (count, (_, part)) in counts.into_iter().zip(self.parts.iter_mut()) {
+            if count > 0 {
+                addrs.extend(part.generate(count, constraints));
+            }
+        }
+        addrs.shuffle(&mut self.rng);
+        addrs
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+        for (_, part) in self.parts.iter_mut() {
+            part.reseed(self.rng.next_u64());
+        }
+    }
+
+    fn next_seed(&mut self) -> Option<u64> {
+        self.rng.next_seed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use addrcore::Cidr;
+
+    use super::*;
+    use crate::gen::CidrGenerator;
+
+    /// The 0.1% critical value of the chi-squared distribution with 1
+    /// degree of freedom.
+    const CHI2_1DF_P001: f64 = 10.828;
+
+    fn cidrs(cidr: &str) -> CidrGenerator {
+        CidrGenerator::new(&[cidr.parse().unwrap()]).unwrap()
+    }
+
+    fn mix() -> MixGenerator {
+        let mut gen =
+            MixGenerator::new().add(80, cidrs("10.0.0.0/8")).add(20, cidrs("192.168.0.0/16"));
+        gen.reseed(42);
+        gen
+    }
+
+    /// The chi-squared statistic of how many of `addrs` are in `cidr`
+    /// against the expected share.
+    fn chi2(addrs: &[SocketAddr], cidr: &str, share: f64) -> f64 {
+        let cidr: Cidr = cidr.parse().unwrap();
+        let n = addrs.len() as f64;
+        let inside = addrs.iter().filter(|addr| cidr.contains(addr.ip())).count() as f64;
+        let (expected_in, expected_out) = (n * share, n * (1.0 - share));
+        (inside - expected_in).powi(2) / expected_in
+            + (n - inside - expected_out).powi(2) / expected_out
+    }
+
+    #[test]
+    fn shares_follow_weights() {
+        let addrs = mix().generate(10_000, &Constraints::default());
+        assert_eq!(addrs.len(), 10_000);
+        assert!(chi2(&addrs, "10.0.0.0/8", 0.8) < CHI2_1DF_P001);
+    }
+
+    #[test]
+    fn shares_follow_weights_one_at_a_time() {
+        let mut gen = mix();
+        let addrs: Vec<_> = (0..10_000).map(|_| gen.next_addr(&Constraints::default())).collect();
+        assert!(chi2(&addrs, "10.0.0.0/8", 0.8) < CHI2_1DF_P001);
+    }
+
+    #[test]
+    fn uniform_within_part() {
+        // The halves of 10.0.0.0/8 should each get half of its share.
+        let addrs = mix().generate(10_000, &Constraints::default());
+        let part: Vec<_> = addrs
+            .into_iter()
+            .filter(|addr| matches!(addr.ip(), IpAddr::V4(ip) if ip.octets()[0] == 10))
+            .collect();
+        assert!(chi2(&part, "10.0.0.0/9", 0.5) < CHI2_1DF_P001);
+    }
+
+    #[test]
+    fn constraints_narrow_parts() {
+        let mut gen = mix();
+        let constraints =
+            Constraints { cidr: Some("192.168.1.0/24".parse().unwrap()), ..Default::default() };
+        assert!(gen.check(&constraints).is_ok());
+        let block: Cidr = "192.168.1.0/24".parse().unwrap();
+        for addr in gen.generate(1000, &constraints) {
+            assert!(block.contains(addr.ip()), "{} outside {}", addr, block);
+        }
+
+        let outside =
+            Constraints { cidr: Some("172.16.0.0/12".parse().unwrap()), ..Default::default() };
+        assert!(gen.check(&outside).is_err());
+    }
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let constraints = Constraints::default();
+        assert_eq!(mix().generate(100, &constraints), mix().generate(100, &constraints));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+
+use tracing::warn;
+
+use addrcore::Constraints;
+
+use crate::gen::AddrGenerator;
+
+/// How many times a repeated address is replaced before giving up, which
+/// only happens if nearly all addresses that can be generated were served.
+const MAX_RESAMPLES: u32 = 1000;
+
+/// Wraps the generator of a connection, replacing the addresses it has
+/// already served with new ones. Only the last `capacity` addresses are
+/// remembered, the oldest being forgotten first, so memory stays bounded.
+pub struct NoRepeat {
+    inner: Box<dyn AddrGenerator>,
+    capacity: usize,
+    seen: HashSet<SocketAddr>,
+    /// The addresses in `seen`, oldest first.
+    order: VecDeque<SocketAddr>,
+}
+
+impl NoRepeat {
+    pub fn new(inner: Box<dyn AddrGenerator>, capacity: usize) -> Self {
+        NoRepeat { inner, capacity, seen: HashSet::new(), order: VecDeque::new() }
+    }
+
+    fn remember(&mut self, addr: SocketAddr) {
+        if self.capacity == 0 || !self.seen.insert(addr) {
+            return;
+        }
+        self.order.push_back(addr);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl AddrGenerator for NoRepeat {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        self.inner.check(constraints)
+    }
+
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr {
+        let mut addr = self.inner.next_addr(constraints);
+        for _ in 0..MAX_RESAMPLES {
+            if !self.seen.contains(&addr) {
+                break;
+            }
+            addr = self.inner.next_addr(constraints);
+        }
+        if self.seen.contains(&addr) {
+            warn!("Serving {} again after {} resamples", addr, MAX_RESAMPLES);
+        }
+        self.remember(addr);
+        addr
+    }
+
+    fn generate(&mut self, n: usize, constraints: &Constraints) -> Vec<SocketAddr> {
+        // Generated in one go as the inner generator may do it faster.
+        let mut addrs = self.inner.generate(n, constraints);
+        for addr in addrs.iter_mut() {
+            if self.seen.contains(addr) {
+                *addr = self.next_addr(constraints);
+            } else {
+                self.remember(*addr);
+            }
+        }
+        addrs
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.inner.reseed(seed);
+    }
+
+    fn next_seed(&mut self) -> Option<u64> {
+        self.inner.next_seed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Generates 10.0.0.1 with the given ports, over and over.
+    struct Ports {
+        ports: Vec<u16>,
+        next: usize,
+    }
+
+    impl AddrGenerator for Ports {
+        fn next_addr(&mut self, _constraints: &Constraints) -> SocketAddr {
+            let port = self.ports[self.next];
+            self.next = (self.next + 1) % self.ports.len();
+            ([10, 0, 0, 1], port).into()
+        }
+    }
+
+    fn no_repeat(ports: &[u16], capacity: usize) -> NoRepeat {
+        NoRepeat::new(Box::new(Ports { ports: ports.to_vec(), next: 0 }), capacity)
+    }
+
+    fn ports(addrs: Vec<SocketAddr>) -> Vec<u16> {
+        addrs.iter().map(SocketAddr::port).collect()
+    }
+
+    #[test]
+    fn skips_served() {
+        let mut gen = no_repeat(&[0, 1, 0, 2, 1, 3, 4], 10);
+        let constraints = Constraints::default();
+        // The second 0 is replaced by the next port not served, skipping 1.
+        assert_eq!(ports(gen.generate(4, &constraints)), [0, 1, 3, 2]);
+        assert_eq!(gen.next_addr(&constraints).port(), 4);
+    }
+
+    #[test]
+    fn forgets_oldest() {
+        let mut gen = no_repeat(&[0, 1, 2], 2);
+        assert_eq!(ports(gen.generate(6, &Constraints::default())), [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn gives_up_when_all_served() {
+        let mut gen = no_repeat(&[0, 1], 2);
+        let ports = ports(gen.generate(3, &Constraints::default()));
+        assert_eq!(ports.len(), 3);
+        assert_eq!(ports[..2], [0, 1]);
+    }
+
+    #[test]
+    fn zero_capacity_repeats() {
+        let mut gen = no_repeat(&[0], 0);
+        assert_eq!(ports(gen.generate(3, &Constraints::default())), [0, 0, 0]);
+    }
+}
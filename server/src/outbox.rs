@@ -0,0 +1,180 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use tracing::debug;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+use addrcore::ServerMessage;
+
+/// Messages queued for a client by default, beyond which its policy applies.
+pub const DEFAULT_SEND_QUEUE: usize = 256;
+
+/// What to do when a client reads its messages slower than they're made,
+/// filling its queue.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overflow {
+    /// Stop reading its requests and generating its updates until there's
+    /// room again.
+    Block,
+    /// Drop the updates, notices and quota warnings that don't fit, blocking
+    /// for responses, which the client waits for.
+    Drop,
+    /// Close the connection, dropping whatever is queued.
+    Disconnect,
+}
+
+impl Overflow {
+    const ALL: [Overflow; 3] = [Overflow::Block, Overflow::Drop, Overflow::Disconnect];
+
+    fn name(self) -> &'static str {
+        match self {
+            Overflow::Block => "block",
+            Overflow::Drop => "drop",
+            Overflow::Disconnect => "disconnect",
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        Overflow::ALL.iter().copied().find(|policy| policy.name() == s).ok_or_else(|| {
+            let names: Vec<_> = Overflow::ALL.iter().map(|policy| policy.name()).collect();
+            format!("unknown policy {}, expected one of {}", s, names.join(", "))
+        })
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The bounded queue of messages written to a client, subject to its flow
+/// control, so that a slow reader can only make the server hold so much.
+/// Cloning it queues to the same client.
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    tx: mpsc::Sender<ServerMessage>,
+    overflow: Overflow,
+    /// Notified once the client is disconnected for overflowing.
+    overflowed: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Makes a queue of `len` messages, which are read from the receiver.
+    pub fn new(len: usize, overflow: Overflow) -> (Self, mpsc::Receiver<ServerMessage>) {
+        let (tx, rx) = mpsc::channel(len.max(1));
+        (Outbox { tx, overflow, overflowed: Arc::new(Notify::new()) }, rx)
+    }
+
+    /// Queues `msg`, waiting for room unless the policy says otherwise.
+    /// Returns whether it was queued rather than dropped, and fails if the
+    /// client is gone or was just disconnected.
+    pub async fn send(&self, msg: ServerMessage) -> io::Result<bool> {
+        let droppable = matches!(
+            msg,
+            ServerMessage::Update(_) | ServerMessage::Notice(_) | ServerMessage::QuotaWarning(_)
+        );
+        match self.overflow {
+            Overflow::Block => (),
+            Overflow::Drop if !droppable => (),
+            _ => return self.try_send(msg),
+        }
+        self.tx.send(msg).await.map(|()| true).map_err(|_| closed())
+    }
+
+    /// Queues `msg` without waiting, for senders that can't, dropping it if
+    /// the queue is full whatever the policy, unless it disconnects.
+    pub fn try_send(&self, msg: ServerMessage) -> io::Result<bool> {
+        match self.tx.try_send(msg) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) if self.overflow == Overflow::Disconnect => {
+                // Stored for the writer even if it isn't waiting yet.
+                self.overflowed.notify_one();
+                Err(io::Error::other("Send queue full"))
+            }
+            Err(TrySendError::Full(msg)) => {
+                debug!("Dropping {:?}, as the send queue is full", msg);
+                Ok(false)
+            }
+            Err(TrySendError::Closed(_)) => Err(closed()),
+        }
+    }
+
+    /// Completes once the client is disconnected for overflowing. Doesn't
+    /// keep the queue open, unlike a clone.
+    pub fn overflowed(&self) -> impl Future<Output = ()> {
+        let overflowed = self.overflowed.clone();
+        async move { overflowed.notified().await }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Writer closed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::executor::block_on;
+    use futures::FutureExt;
+
+    use addrcore::{ErrorCode, ErrorResponse, Response};
+
+    fn update() -> ServerMessage {
+        let resp = Response { index: 0, addrs: Vec::new(), ttls: None, families: None, seed: None };
+        ServerMessage::Update(resp)
+    }
+
+    fn error() -> ServerMessage {
+        let code = ErrorCode::Unsatisfiable;
+        ServerMessage::Error(ErrorResponse { index: 0, code, message: String::new() })
+    }
+
+    #[test]
+    fn drop_policy() {
+        let (outbox, mut rx) = Outbox::new(1, Overflow::Drop);
+        assert!(block_on(outbox.send(update())).unwrap());
+        assert!(!block_on(outbox.send(update())).unwrap());
+        // Responses and errors wait for room instead.
+        assert!(outbox.send(error()).now_or_never().is_none());
+        assert_eq!(rx.try_recv().unwrap(), update());
+        assert!(block_on(outbox.send(error())).unwrap());
+    }
+
+    #[test]
+    fn disconnect_policy() {
+        let (outbox, _rx) = Outbox::new(1, Overflow::Disconnect);
+        let overflowed = outbox.overflowed();
+        assert!(block_on(outbox.send(error())).unwrap());
+        assert!(block_on(outbox.send(error())).is_err());
+        assert!(overflowed.now_or_never().is_some());
+    }
+
+    #[test]
+    fn block_policy() {
+        let (outbox, mut rx) = Outbox::new(1, Overflow::Block);
+        assert!(block_on(outbox.send(update())).unwrap());
+        assert!(outbox.send(update()).now_or_never().is_none());
+        assert!(!outbox.try_send(update()).unwrap());
+        rx.try_recv().unwrap();
+        assert!(block_on(outbox.send(update())).unwrap());
+        drop(rx);
+        assert!(block_on(outbox.send(update())).is_err());
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!("drop".parse::<Overflow>(), Ok(Overflow::Drop));
+        assert!("queue".parse::<Overflow>().is_err());
+    }
+}
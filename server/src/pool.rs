@@ -0,0 +1,304 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use rand::prelude::*;
+
+use tokio::time;
+
+use addrcore::{Constraints, Family};
+
+use crate::gen::{check_ports, AddrGenerator, GenRng};
+
+/// How often the pool file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The addresses of the pool, swapped out whole when the file changes.
+type Pool = RwLock<Arc<Vec<SocketAddr>>>;
+
+/// Serves addresses sampled from a list read from a file, one `ip:port` a
+/// line, with blank lines and those starting with `#` skipped. The file is
+/// reloaded whenever it changes, keeping the previous list if it's invalid.
+///
+/// Sampled without replacement, a connection is only served an address
+/// again once it's been served every other one.
+#[derive(Clone, Debug)]
+pub struct PoolGenerator {
+    pool: Arc<Pool>,
+    replacement: bool,
+    /// What's left of the pool to sample from without replacement.
+    deck: Vec<SocketAddr>,
+    /// The pool the deck was dealt from, which is redealt if it's reloaded.
+    dealt_from: Arc<Vec<SocketAddr>>,
+    rng: GenRng,
+}
+
+impl PoolGenerator {
+    /// Reads the pool at `path` and watches it in the background, until all
+    /// clones of the generator are dropped.
+    pub fn open(path: impl Into<PathBuf>, replacement: bool) -> io::Result<Self> {
+        let path = path.into();
+        let modified = fs::metadata(&path)?.modified()?;
+        let addrs = read_pool(&path)?;
+        info!("Serving {} address(es) from {}", addrs.len(), path.display());
+        let pool = Arc::new(RwLock::new(Arc::new(addrs)));
+        tokio::spawn(watch(path, modified, Arc::downgrade(&pool)));
+        Ok(PoolGenerator {
+            pool,
+            replacement,
+            deck: Vec::new(),
+            dealt_from: Arc::new(Vec::new()),
+            rng: GenRng::default(),
+        })
+    }
+
+    fn current(&self) -> Arc<Vec<SocketAddr>> {
+        self.pool.read().unwrap().clone()
+    }
+}
+
+impl AddrGenerator for PoolGenerator {
+    fn check(&self, constraints: &Constraints) -> Result<(), String> {
+        check_ports(constraints)?;
+        if !self.current().iter().any(|&addr| satisfies(addr, constraints)) {
+            return Err("No address in the pool satisfies the constraints".to_string());
+        }
+        Ok(())
+    }
+
+    fn next_addr(&mut self, constraints: &Constraints) -> SocketAddr {
+        match self.generate(1, constraints).pop() {
+            Some(addr) => addr,
+            // Only if the pool was reloaded since the constraints were
+            // checked, which is better than failing the whole request.
+            None => *self.current().choose(&mut self.rng).unwrap(),
+        }
+    }
+
+    fn generate(&mut self, n: usize, constraints: &Constraints) -> Vec<SocketAddr> {
+        let pool = self.current();
+        if self.replacement {
+            let candidates: Vec<_> =
+                pool.iter().copied().filter(|&addr| satisfies(addr, constraints)).collect();
+            // The pool may have been reloaded since the constraints were
+            // checked, leaving none.
+            if candidates.is_empty() {
+                warn!("No address in the reloaded pool satisfies {:?}", constraints);
+                return Vec::new();
+            }
+            return (0..n).map(|_| *candidates.choose(&mut self.rng).unwrap()).collect();
+        }
+        if !Arc::ptr_eq(&pool, &self.dealt_from) {
+            self.deck.clear();
+            self.dealt_from = pool.clone();
+        }
+        let mut addrs = Vec::with_capacity(n);
+        // Whether the deck was dealt since an address was last taken from it.
+        let mut fresh = false;
+        while addrs.len() < n {
+            // Searched from the end, so that it's a pop without constraints.
+            match self.deck.iter().rposition(|&addr| satisfies(addr, constraints)) {
+                Some(i) => {
+                    addrs.push(self.deck.swap_remove(i));
+                    fresh = false;
+                }
+                None if fresh => {
+                    warn!("No address in the reloaded pool satisfies {:?}", constraints);
+                    break;
+                }
+                // Addresses left in the deck that don't satisfy these
+                // constraints are put back in, so all are as likely next.
+                None => {
+                    self.deck = pool.to_vec();
+                    self.deck.shuffle(&mut self.rng);
+                    fresh = true;
+                }
+            }
+        }
+        addrs
+    }
+
+    fn reseed(&mut self, seed: u64) {
+        self.rng.seed(seed);
+        self.deck.clear();
+    }
+
+    fn next_seed(&mut self) -> Option<u64> {
+        self.rng.next_seed()
+    }
+}
+
+/// Whether `addr` may be served given `constraints`.
+fn satisfies(addr: SocketAddr, constraints: &Constraints) -> bool {
+    let family = match addr {
+        SocketAddr::V4(_) => Family::V4,
+        SocketAddr::V6(_) => Family::V6,
+    };
+    let family = constraints.family.is_none_or(|wanted| wanted == family);
+    let cidr = constraints.cidr.is_none_or(|cidr| cidr.contains(addr.ip()));
+    let ports = constraints.ports.is_none_or(|(lo, hi)| lo <= addr.port() && addr.port() <= hi);
+    family && cidr && ports
+}
+
+fn read_pool(path: &Path) -> io::Result<Vec<SocketAddr>> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut addrs = Vec::new();
+    for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let addr: SocketAddr = line
+            .parse()
+            .map_err(|_| invalid(format!("Line {}: invalid address {}", i + 1, line)))?;
+        addrs.push(addr);
+    }
+    if addrs.is_empty() {
+        return Err(invalid(format!("No addresses in {}", path.display())));
+    }
+    Ok(addrs)
+}
+
+/// Reloads the pool at `path` whenever it's modified, until it's dropped.
+async fn watch(path: PathBuf, mut modified: SystemTime, pool: Weak<Pool>) {
+    let mut interval = time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        match fs::metadata(&path).and_then(|meta| meta.modified()) {
+            Ok(time) if time != modified => modified = time,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Could not check pool file {}: {}", path.display(), e);
+                continue;
+            }
+        }
+        match read_pool(&path) {
+            Ok(addrs) => {
+                info!("Reloaded {} address(es) from {}", addrs.len(), path.display());
+                *pool.write().unwrap() = Arc::new(addrs);
+            }
+            Err(e) => warn!("Could not reload {}, keeping the pool: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::process;
+
+    use super::*;
+
+    /// Writes `contents` to a pool file named after the test.
+    fn pool_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pool-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Rewrites the pool file, marking it modified later than before.
+    fn rewrite(path: &Path, contents: &str) {
+        let modified = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified + Duration::from_secs(10))
+            .unwrap();
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    fn sorted(mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        addrs.sort();
+        addrs
+    }
+
+    #[test]
+    fn read_lines() {
+        let path = pool_file("read", "# Pool\n10.0.0.1:80\n\n  [::1]:443  \n");
+        assert_eq!(read_pool(&path).unwrap(), addrs(&["10.0.0.1:80", "[::1]:443"]));
+
+        fs::write(&path, "10.0.0.1:80\n10.0.0.2\n").unwrap();
+        let err = read_pool(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Line 2:"), "{}", err);
+
+        fs::write(&path, "# Nothing yet\n").unwrap();
+        assert!(read_pool(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_all_before_repeating() {
+        let pool = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"];
+        let path = pool_file("deal", &pool.join("\n"));
+        let mut gen = PoolGenerator::open(&path, false).unwrap();
+        gen.reseed(42);
+        let constraints = Constraints::default();
+        for _ in 0..3 {
+            assert_eq!(sorted(gen.generate(3, &constraints)), addrs(&pool));
+        }
+        let mut addrs = gen.generate(2, &constraints);
+        addrs.push(gen.next_addr(&constraints));
+        assert_eq!(sorted(addrs), self::addrs(&pool));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_with_replacement() {
+        let path = pool_file("replace", "10.0.0.1:80\n10.0.0.2:80\n");
+        let mut gen = PoolGenerator::open(&path, true).unwrap();
+        let served = gen.generate(100, &Constraints::default());
+        assert_eq!(served.len(), 100);
+        let pool = addrs(&["10.0.0.1:80", "10.0.0.2:80"]);
+        assert!(served.iter().all(|addr| pool.contains(addr)), "{:?}", served);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn constraints() {
+        let path = pool_file("constraints", "10.0.0.1:80\n10.0.0.2:443\n[::1]:80\n");
+        let mut gen = PoolGenerator::open(&path, false).unwrap();
+        let v6 = Constraints { family: Some(Family::V6), ..Default::default() };
+        assert_eq!(gen.check(&v6), Ok(()));
+        assert_eq!(gen.generate(3, &v6), addrs(&["[::1]:80", "[::1]:80", "[::1]:80"]));
+
+        let https = Constraints { ports: Some((443, 443)), ..Default::default() };
+        assert_eq!(gen.generate(2, &https), addrs(&["10.0.0.2:443", "10.0.0.2:443"]));
+
+        let within = Constraints { cidr: Some("192.168.0.0/16".parse().unwrap()), ..https };
+        assert!(gen.check(&within).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_when_modified() {
+        let path = pool_file("reload", "10.0.0.1:80\n");
+        let mut gen = PoolGenerator::open(&path, false).unwrap();
+        let constraints = Constraints::default();
+        assert_eq!(gen.generate(1, &constraints), addrs(&["10.0.0.1:80"]));
+
+        rewrite(&path, "10.0.0.2:80\n");
+        time::sleep(RELOAD_INTERVAL * 2).await;
+        assert_eq!(gen.generate(2, &constraints), addrs(&["10.0.0.2:80", "10.0.0.2:80"]));
+
+        // An invalid pool is ignored.
+        rewrite(&path, "10.0.0.3\n");
+        time::sleep(RELOAD_INTERVAL * 2).await;
+        assert_eq!(gen.generate(1, &constraints), addrs(&["10.0.0.2:80"]));
+        fs::remove_file(&path).unwrap();
+    }
+}
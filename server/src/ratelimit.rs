@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::debug;
+
+use tokio::time::{self, Instant};
+
+use addrcore::QuotaWarning;
+
+/// How often buckets of idle clients are dropped.
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Limits the requests, batches and transactions of every client IP, or
+/// whatever else tells clients apart, to a rate, allowing bursts of up to
+/// `burst` after idling. Clients share their bucket across connections, so
+/// reconnecting doesn't reset it.
+#[derive(Clone)]
+pub struct RateLimiter<K = IpAddr> {
+    per_sec: u32,
+    burst: u32,
+    buckets: Arc<Mutex<HashMap<K, Bucket>>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl<K: Eq + Hash + Send + 'static> RateLimiter<K> {
+    /// Creates a limiter dropping idle buckets in the background, until
+    /// it's dropped.
+    pub fn new(per_sec: u32, burst: u32) -> Self {
+        let limiter = RateLimiter {
+            per_sec: per_sec.max(1),
+            burst: burst.max(1),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let buckets = Arc::downgrade(&limiter.buckets);
+        // A bucket that's been idle long enough to be full is the same as
+        // none at all.
+        let full_after = Duration::from_secs_f64(limiter.burst as f64 / limiter.per_sec as f64);
+        tokio::spawn(async move {
+            let mut interval = time::interval(EVICT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let buckets = match buckets.upgrade() {
+                    Some(buckets) => buckets,
+                    None => return,
+                };
+                let mut buckets = buckets.lock().unwrap();
+                let before = buckets.len();
+                buckets.retain(|_, bucket| bucket.last_refill.elapsed() < full_after);
+                debug!("Evicted {} idle rate limit bucket(s)", before - buckets.len());
+            }
+        });
+        limiter
+    }
+
+    pub fn per_sec(&self) -> u32 {
+        self.per_sec
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// Accounts for a request from `key`, or returns how long until it may
+    /// send one if it's over the limit.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let (per_sec, burst) = (self.per_sec as f64, self.burst as f64);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: burst, last_refill: now });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+        bucket.last_refill = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// How much of the limit `key` has left now.
+    pub fn quota(&self, key: &K) -> QuotaWarning {
+        let buckets = self.buckets.lock().unwrap();
+        let tokens = match buckets.get(key) {
+            Some(bucket) => {
+                let elapsed = bucket.last_refill.elapsed().as_secs_f64();
+                (bucket.tokens + elapsed * self.per_sec as f64).min(self.burst as f64)
+            }
+            None => self.burst as f64,
+        };
+        QuotaWarning { remaining: tokens as u32, burst: self.burst, per_sec: self.per_sec }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    #[tokio::test(start_paused = true)]
+    async fn allows_bursts_then_rate() {
+        let limiter = RateLimiter::new(10, 3);
+        for _ in 0..3 {
+            assert_eq!(limiter.check(A), Ok(()));
+        }
+        assert_eq!(limiter.check(A), Err(Duration::from_millis(100)));
+
+        time::advance(Duration::from_millis(100)).await;
+        assert_eq!(limiter.check(A), Ok(()));
+        assert!(limiter.check(A).is_err());
+
+        // Idling refills no more than the burst.
+        time::advance(Duration::from_secs(10)).await;
+        for _ in 0..3 {
+            assert_eq!(limiter.check(A), Ok(()));
+        }
+        assert!(limiter.check(A).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_keys_apart() {
+        let limiter = RateLimiter::new(1, 1);
+        assert_eq!(limiter.check(A), Ok(()));
+        assert!(limiter.check(A).is_err());
+        assert_eq!(limiter.check(B), Ok(()));
+        // Clones share buckets, as connections of a client do.
+        assert!(limiter.clone().check(B).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tells_the_quota_left() {
+        let limiter = RateLimiter::new(10, 3);
+        assert_eq!(limiter.quota(&A).remaining, 3);
+        limiter.check(A).unwrap();
+        limiter.check(A).unwrap();
+        assert_eq!(limiter.quota(&A), QuotaWarning { remaining: 1, burst: 3, per_sec: 10 });
+        time::advance(Duration::from_millis(100)).await;
+        assert_eq!(limiter.quota(&A).remaining, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_idle_buckets() {
+        let limiter = RateLimiter::new(1, 1);
+        limiter.check(A).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+        time::sleep(EVICT_INTERVAL * 2).await;
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time;
+
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Resolved names are cached, including negative results, but the cache is
+/// simply cleared once it grows beyond this many entries.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+/// Asynchronous reverse DNS lookups of peer addresses, used purely to
+/// annotate logs. Lookups never block serving the peer.
+#[derive(Clone)]
+pub struct ReverseDns {
+    resolver: TokioAsyncResolver,
+    cache: Arc<Mutex<HashMap<IpAddr, Option<String>>>>,
+    timeout: Duration,
+}
+
+impl ReverseDns {
+    /// Creates a resolver from the system configuration.
+    pub fn from_system_conf(timeout: Duration) -> io::Result<Self> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+        Ok(ReverseDns {
+            resolver,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        })
+    }
+
+    /// Resolves `ip` to a host name, or `None` if it has no PTR record or
+    /// the lookup failed or timed out.
+    pub async fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let cached = self.cache.lock().unwrap().get(&ip).cloned();
+        if let Some(name) = cached {
+            return name;
+        }
+        let name = match time::timeout(self.timeout, self.resolver.reverse_lookup(ip)).await {
+            Ok(Ok(names)) => names
+                .iter()
+                .next()
+                .map(|name| name.to_string().trim_end_matches('.').to_string()),
+            Ok(Err(_)) => None,
+            // Don't cache timeouts, the next lookup may well succeed.
+            Err(_elapsed) => return None,
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.clear();
+        }
+        cache.insert(ip, name.clone());
+        name
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use tokio::sync::Notify;
+
+use futures::channel::oneshot;
+
+use addrcore::ServerMessage;
+
+use crate::outbox::Outbox;
+use crate::supervise::Peer;
+
+/// An active connection, as listed by `Registry::connections`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Connection {
+    /// Tells the connection apart from any other since startup, as its log
+    /// records do.
+    pub id: u64,
+    /// The client, as it appears in logs.
+    pub peer: String,
+    pub connected: SystemTime,
+    /// Requests, batches and transactions received so far.
+    pub requests: u64,
+}
+
+struct Entry {
+    peer: Peer,
+    connected: SystemTime,
+    requests: Arc<AtomicU64>,
+    /// Writes to the connection, subject to its flow control.
+    outbox: Outbox,
+    /// Closes the connection, unless it already was.
+    kick: Option<oneshot::Sender<()>>,
+}
+
+/// The active connections of a server, which messages can be broadcast to.
+/// Cloning it shares the registry.
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Arc<Mutex<HashMap<u64, Entry>>>,
+    /// Notified whenever the last connection is unregistered.
+    empty: Arc<Notify>,
+}
+
+impl Registry {
+    /// Registers connection `id` with `peer`, writing to it through
+    /// `outbox`, until the returned registration is dropped.
+    pub(crate) fn register(&self, id: u64, peer: Peer, outbox: Outbox) -> Registration {
+        let requests = Arc::new(AtomicU64::new(0));
+        let (kick, kicked) = oneshot::channel();
+        let entry = Entry {
+            peer,
+            connected: SystemTime::now(),
+            requests: requests.clone(),
+            outbox,
+            kick: Some(kick),
+        };
+        self.entries.lock().unwrap().insert(id, entry);
+        Registration { registry: self.clone(), id, requests, kicked }
+    }
+
+    /// The active connections, oldest first.
+    pub fn connections(&self) -> Vec<Connection> {
+        let mut conns: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, entry)| Connection {
+                id,
+                peer: entry.peer.to_string(),
+                connected: entry.connected,
+                requests: entry.requests.load(Ordering::Relaxed),
+            })
+            .collect();
+        conns.sort_by_key(|conn| conn.id);
+        conns
+    }
+
+    /// Sends `msg` to every active connection, after whatever was already
+    /// queued for it. Connections whose queue is full miss it, or are
+    /// disconnected if that's the policy. Returns how many it was sent to.
+    pub fn broadcast(&self, msg: &ServerMessage) -> usize {
+        let entries = self.entries.lock().unwrap();
+        // Connections whose writer is gone are about to be unregistered.
+        let queued = |entry: &&Entry| matches!(entry.outbox.try_send(msg.clone()), Ok(true));
+        entries.values().filter(queued).count()
+    }
+
+    /// Closes the connections of `peer`, as it appears in logs, or with ID
+    /// `peer`. Returns how many there were.
+    pub fn kick(&self, peer: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let mut kicked = 0;
+        for (id, entry) in entries.iter_mut() {
+            if entry.peer.to_string() != peer && id.to_string() != peer {
+                continue;
+            }
+            if let Some(kick) = entry.kick.take() {
+                let _ = kick.send(());
+                kicked += 1;
+            }
+        }
+        kicked
+    }
+
+    /// Waits until no connection is active.
+    pub async fn wait_empty(&self) {
+        loop {
+            // Created before checking so that it's woken if the last one
+            // goes away in between.
+            let empty = self.empty.notified();
+            if self.entries.lock().unwrap().is_empty() {
+                return;
+            }
+            empty.await;
+        }
+    }
+}
+
+/// Keeps a connection registered until dropped.
+pub(crate) struct Registration {
+    registry: Registry,
+    id: u64,
+    requests: Arc<AtomicU64>,
+    kicked: oneshot::Receiver<()>,
+}
+
+impl Registration {
+    /// Counts a request received on the connection.
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Completes once the connection is kicked. Mustn't be polled again
+    /// after it completed.
+    pub fn kicked(&mut self) -> &mut oneshot::Receiver<()> {
+        &mut self.kicked
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut entries = self.registry.entries.lock().unwrap();
+        entries.remove(&self.id);
+        if entries.is_empty() {
+            self.registry.empty.notify_waiters();
+        }
+    }
+}
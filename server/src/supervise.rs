@@ -0,0 +1,127 @@
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tracing::{error, info_span, Instrument, Span};
+
+use futures::FutureExt;
+
+/// A client, as it appears in logs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A client connected over a WebSocket.
+    Ws(SocketAddr),
+    /// Clients of a Unix domain socket are unnamed, so they're numbered in
+    /// the order they connected.
+    Unix(u64),
+}
+
+impl Peer {
+    /// The IP of a TCP or WebSocket client.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) | Peer::Ws(addr) => Some(addr.ip()),
+            Peer::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Ws(addr) => write!(f, "ws:{}", addr),
+            Peer::Unix(n) => write!(f, "unix#{}", n),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The client served by the current task, if it's a connection task.
+    static PEER: Peer;
+}
+
+/// Panics caught in connection tasks since startup.
+static PANICS: AtomicU64 = AtomicU64::new(0);
+
+/// Connections accepted since startup.
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Clients of Unix domain sockets accepted since startup.
+static UNIX_CLIENTS: AtomicU64 = AtomicU64::new(0);
+
+/// Connections accepted since startup.
+pub fn accepted() -> u64 {
+    CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Panics caught in connection tasks since startup.
+pub fn panics() -> u64 {
+    PANICS.load(Ordering::Relaxed)
+}
+
+/// An ID telling a new connection apart from any other since startup.
+pub fn next_conn_id() -> u64 {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The number of a new client of a Unix domain socket, in the order they
+/// connected to any of them.
+pub fn next_unix_client() -> u64 {
+    UNIX_CLIENTS.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A span for the tasks serving `peer` on connection `id`, tagging their
+/// records with both.
+pub fn conn_span(peer: Peer, id: u64) -> Span {
+    info_span!("conn", %peer, id)
+}
+
+/// Logs panics instead of printing them to stderr, along with the client
+/// served by the task that panicked, if any.
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let location = match info.location() {
+            Some(location) => location.to_string(),
+            None => "unknown location".to_string(),
+        };
+        let msg = payload_message(info.payload());
+        match PEER.try_with(|peer| *peer) {
+            Ok(peer) => error!("Panic serving {} at {}: {}", peer, location, msg),
+            Err(_) => error!("Panic at {}: {}", location, msg),
+        }
+    }));
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(msg) => msg,
+        None => match payload.downcast_ref::<String>() {
+            Some(msg) => msg,
+            None => "non-string payload",
+        },
+    }
+}
+
+/// Spawns a task serving `peer`, in the current span so that tasks spawned
+/// for a connection are in its span. If it panics, the panic is logged with
+/// the peer and counted, and only this task is torn down, leaving the accept
+/// loop and other connections running.
+pub fn spawn<F>(peer: Peer, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let task = async move {
+        // Whatever state the task shared is only ever observed by tasks of
+        // the same connection, which are torn down along with it.
+        if AssertUnwindSafe(task).catch_unwind().await.is_err() {
+            let panics = PANICS.fetch_add(1, Ordering::Relaxed) + 1;
+            error!("Task serving {} panicked, {} panic(s) since startup", peer, panics);
+        }
+    };
+    tokio::spawn(PEER.scope(peer, task).instrument(Span::current()));
+}
@@ -0,0 +1,78 @@
+use std::env;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use tracing::{debug, warn};
+
+use tokio::net::{TcpListener, UnixListener};
+
+/// The first socket passed by systemd, as in sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket inherited from systemd.
+pub enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Takes the listening sockets systemd passed to the process with socket
+/// activation, if any, and unsets the variables announcing them so that
+/// they aren't passed on. Sockets other than stream ones are skipped.
+pub fn listeners() -> io::Result<Vec<Inherited>> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<RawFd>().ok());
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let n = match (pid, fds) {
+        // Meant for this process, rather than its parent.
+        (Some(pid), Some(n)) if pid == std::process::id() => n,
+        _ => return Ok(Vec::new()),
+    };
+    let mut listeners = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + n {
+        // Not inherited by processes spawned from here on.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Ours from here on, as systemd passed it for this process only.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if socket.r#type()? != Type::STREAM {
+            warn!("Skipping socket {} from systemd, which isn't a stream socket", fd);
+            continue;
+        }
+        socket.set_nonblocking(true)?;
+        // Only IP sockets have a socket address.
+        let listener = match socket.local_addr()?.as_socket() {
+            Some(_) => Inherited::Tcp(TcpListener::from_std(socket.into())?),
+            None => Inherited::Unix(UnixListener::from_std(socket.into())?),
+        };
+        debug!("Inherited socket {} from systemd", fd);
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Tells systemd about the state of the server, as in sd_notify(3), if it
+/// asked to be told. Failing to is only logged, as it's just a courtesy.
+pub fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    // Abstract socket names start with a @, which stands for a zero byte.
+    let mut path = path.as_bytes().to_vec();
+    if path.first() == Some(&b'@') {
+        path[0] = 0;
+    }
+    let result = SockAddr::unix(std::ffi::OsStr::from_bytes(&path)).and_then(|addr| {
+        let socket = Socket::new(Domain::UNIX, Type::DGRAM, None)?;
+        socket.send_to(state.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        warn!("Could not notify systemd of {:?}: {}", state, e);
+    }
+}
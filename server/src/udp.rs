@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tracing::{debug, error, warn};
+
+use tokio::net::UdpSocket;
+use tokio_util::udp::UdpFramed;
+
+use futures::{SinkExt, StreamExt};
+
+use addrcore::datagram::{self, ServerDatagramCodec};
+use addrcore::ClientMessage;
+
+use crate::{
+    answer_batch, answer_request, answer_transaction, make_generator, record, throttle, Settings,
+};
+
+/// Most UDP clients whose datagrams are counted, beyond which the one heard
+/// from least recently is forgotten.
+const MAX_PEERS: usize = 4096;
+
+/// How far behind the highest ID seen a datagram's ID may be to still tell
+/// whether it was seen before.
+const WINDOW: u32 = 64;
+
+/// What the IDs of the datagrams from a UDP client tell of the path from it,
+/// given that clients number their requests one after another and reuse the
+/// ID when retrying.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PathCounts {
+    pub received: u64,
+    /// Datagrams with an ID seen before, such as retries.
+    pub duplicate: u64,
+    /// Datagrams arriving after one with a higher ID.
+    pub reordered: u64,
+    /// IDs skipped and not seen since.
+    pub lost: u64,
+}
+
+#[derive(Default)]
+struct Sequence {
+    counts: PathCounts,
+    highest: Option<u32>,
+    /// Bit `i` is set if `highest - i` was seen.
+    seen: u64,
+}
+
+impl Sequence {
+    fn receive(&mut self, id: u32) {
+        self.counts.received += 1;
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(id);
+                self.seen = 1;
+                return;
+            }
+        };
+        if id > highest {
+            let ahead = id - highest;
+            self.counts.lost += u64::from(ahead - 1);
+            self.seen = if ahead < WINDOW { self.seen << ahead | 1 } else { 1 };
+            self.highest = Some(id);
+            return;
+        }
+        let behind = highest - id;
+        if behind < WINDOW && self.seen & 1 << behind != 0 {
+            self.counts.duplicate += 1;
+            return;
+        }
+        // Counted as lost when skipped, unless too old to tell.
+        if behind < WINDOW {
+            self.seen |= 1 << behind;
+            self.counts.lost = self.counts.lost.saturating_sub(1);
+        }
+        self.counts.reordered += 1;
+    }
+}
+
+/// Counts of the datagrams from every UDP client.
+#[derive(Default)]
+pub struct Peers(Mutex<HashMap<SocketAddr, (Sequence, Instant)>>);
+
+impl Peers {
+    fn receive(&self, addr: SocketAddr, id: u32) {
+        let mut peers = self.0.lock().unwrap();
+        if peers.len() >= MAX_PEERS && !peers.contains_key(&addr) {
+            let oldest = peers.iter().min_by_key(|(_, (_, heard))| *heard).map(|(&addr, _)| addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        let now = Instant::now();
+        let (sequence, heard) = peers.entry(addr).or_insert_with(|| (Sequence::default(), now));
+        sequence.receive(id);
+        *heard = now;
+    }
+
+    /// The counts of every client heard from, by address.
+    pub fn counts(&self) -> Vec<(SocketAddr, PathCounts)> {
+        let peers = self.0.lock().unwrap();
+        let mut counts: Vec<_> =
+            peers.iter().map(|(&addr, (sequence, _))| (addr, sequence.counts)).collect();
+        counts.sort_by_key(|&(addr, _)| addr);
+        counts
+    }
+}
+
+/// Answers requests, batches and transactions sent over UDP, one per
+/// datagram. Everything else needs a connection and is ignored, as are
+/// datagrams that can't be decoded. The IDs of the datagrams of every client
+/// are counted in `settings.udp_peers`.
+pub async fn serve(socket: UdpSocket, settings: Arc<Settings>) {
+    let (mut writer, mut reader) = UdpFramed::new(socket, ServerDatagramCodec).split();
+    let mut gen = make_generator(&settings, None, 0);
+    while let Some(result) = reader.next().await {
+        let ((id, msg), addr) = match result {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("Invalid datagram: {}", e);
+                continue;
+            }
+        };
+        debug!("Received {:?} from {} over UDP", msg, addr);
+        settings.udp_peers.receive(addr, id);
+        let replies = match throttle(&settings, Some(addr.ip()), &msg) {
+            Some(error) => {
+                warn!("{} is over the rate limit", addr);
+                vec![error]
+            }
+            None => match msg {
+                ClientMessage::Request(req) => {
+                    vec![answer_request(&mut *gen, &req, settings.max_addrs(), &settings)]
+                }
+                ClientMessage::Batch(counts) => {
+                    answer_batch(&mut *gen, &counts, settings.max_addrs(), &settings)
+                }
+                ClientMessage::Transaction(reqs) => {
+                    answer_transaction(&mut *gen, &reqs, settings.max_addrs(), &settings)
+                }
+                msg => {
+                    warn!("Ignoring {:?} from {} over UDP", msg, addr);
+                    continue;
+                }
+            },
+        };
+        for reply in replies.iter() {
+            record(&settings, format_args!("udp:{}", addr), reply);
+        }
+        let chunks = match datagram::split(id, &replies) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                error!("Could not answer {} over UDP: {}", addr, e);
+                continue;
+            }
+        };
+        for chunk in chunks {
+            // A client that's gone away only fails its own answer.
+            if let Err(e) = writer.send((chunk, addr)).await {
+                warn!("Could not send to {} over UDP: {}", addr, e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(ids: &[u32]) -> PathCounts {
+        let mut sequence = Sequence::default();
+        for &id in ids {
+            sequence.receive(id);
+        }
+        sequence.counts
+    }
+
+    #[test]
+    fn counts_in_order_datagrams() {
+        assert_eq!(counts(&[0, 1, 2]), PathCounts { received: 3, ..PathCounts::default() });
+    }
+
+    #[test]
+    fn counts_duplicates() {
+        let expected = PathCounts { received: 4, duplicate: 2, ..PathCounts::default() };
+        assert_eq!(counts(&[0, 1, 1, 0]), expected);
+    }
+
+    #[test]
+    fn counts_late_datagrams_as_reordered_not_lost() {
+        let expected = PathCounts { received: 4, reordered: 1, lost: 1, ..PathCounts::default() };
+        assert_eq!(counts(&[0, 2, 1, 4]), expected);
+    }
+
+    #[test]
+    fn counts_skipped_ids_as_lost() {
+        let expected = PathCounts { received: 2, lost: 99, ..PathCounts::default() };
+        assert_eq!(counts(&[0, 100]), expected);
+        // Too old to tell whether it's a duplicate.
+        let expected = PathCounts { received: 3, reordered: 1, lost: 99, ..PathCounts::default() };
+        assert_eq!(counts(&[0, 100, 0]), expected);
+    }
+}
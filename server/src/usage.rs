@@ -0,0 +1,146 @@
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::Settings;
+
+/// How often the process is sampled.
+const SAMPLE_EVERY: Duration = Duration::from_secs(5);
+
+/// What the server process uses, as last sampled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    /// Resident memory in bytes.
+    pub rss: u64,
+    /// CPU time used since the sample before, as a percentage of one core.
+    pub cpu: f64,
+    /// Open file descriptors, sockets included.
+    pub fds: usize,
+    /// Tasks alive on the runtime.
+    pub tasks: usize,
+}
+
+/// The last sample of the process, and the CPU time it was taken at.
+#[derive(Default)]
+pub struct Samples(Mutex<Option<(Usage, Times)>>);
+
+/// CPU time used by the process by the time it was read.
+#[derive(Clone, Copy, Debug)]
+struct Times {
+    cpu: Duration,
+    at: Instant,
+}
+
+impl Samples {
+    /// The last sample taken, if any.
+    pub fn last(&self) -> Option<Usage> {
+        self.0.lock().unwrap().map(|(usage, _)| usage)
+    }
+
+    /// Samples the process now, working out its CPU use since the sample
+    /// before.
+    fn sample(&self) -> io::Result<Usage> {
+        let (rss, cpu) = read_stat()?;
+        let times = Times { cpu, at: Instant::now() };
+        let fds = fs::read_dir("/proc/self/fd")?.count();
+        let tasks = tokio::runtime::Handle::current().metrics().num_alive_tasks();
+        let mut last = self.0.lock().unwrap();
+        let cpu = last.map_or(0.0, |(_, before)| cpu_percent(before, times));
+        let usage = Usage { rss, cpu, fds, tasks };
+        *last = Some((usage, times));
+        Ok(usage)
+    }
+}
+
+/// Samples the process every few seconds into `settings.usage` until it
+/// can't be read, as on systems without `/proc`.
+pub async fn sample(settings: Arc<Settings>) {
+    let mut every = tokio::time::interval(SAMPLE_EVERY);
+    loop {
+        every.tick().await;
+        if let Err(e) = settings.usage.sample() {
+            warn!("Could not sample process usage, giving up: {}", e);
+            return;
+        }
+    }
+}
+
+/// Reads the resident memory and CPU time used from `/proc/self/stat`.
+fn read_stat() -> io::Result<(u64, Duration)> {
+    let stat = fs::read_to_string("/proc/self/stat")?;
+    parse_stat(&stat, page_size(), clock_ticks())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed /proc/self/stat"))
+}
+
+/// Parses the resident pages and the user and system clock ticks out of a
+/// `/proc/<pid>/stat` line.
+fn parse_stat(stat: &str, page_size: u64, ticks_per_sec: u64) -> Option<(u64, Duration)> {
+    // The command name may hold spaces and parentheses, so fields are
+    // counted from the last parenthesis on, starting with the state, the
+    // third field.
+    let fields: Vec<_> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+    let (utime, stime, rss) = (field(14)?, field(15)?, field(24)?);
+    let ticks = utime + stime;
+    let cpu = Duration::from_millis(ticks * 1000 / ticks_per_sec.max(1));
+    Some((rss * page_size, cpu))
+}
+
+fn cpu_percent(before: Times, now: Times) -> f64 {
+    let wall = now.at.duration_since(before.at).as_secs_f64();
+    if wall == 0.0 {
+        return 0.0;
+    }
+    now.cpu.saturating_sub(before.cpu).as_secs_f64() / wall * 100.0
+}
+
+fn page_size() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        n if n > 0 => n as u64,
+        _ => 4096,
+    }
+}
+
+fn clock_ticks() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        n if n > 0 => n as u64,
+        _ => 100,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stat() {
+        let stat = "4242 (a (b) c) S 1 4242 4242 0 -1 4194560 2048 0 0 0 \
+                    150 50 0 0 20 0 9 0 12345 104857600 300 18446744073709551615";
+        let (rss, cpu) = parse_stat(stat, 4096, 100).unwrap();
+        assert_eq!(rss, 300 * 4096);
+        assert_eq!(cpu, Duration::from_secs(2));
+        assert_eq!(parse_stat("4242 (short) S 1", 4096, 100), None);
+    }
+
+    #[test]
+    fn works_out_cpu_use() {
+        let at = Instant::now();
+        let before = Times { cpu: Duration::from_millis(100), at };
+        let now = Times { cpu: Duration::from_millis(600), at: at + Duration::from_secs(2) };
+        assert_eq!(cpu_percent(before, now), 25.0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn samples_the_process() {
+        let samples = Samples::default();
+        assert_eq!(samples.last(), None);
+        let usage = samples.sample().unwrap();
+        assert!(usage.rss > 0);
+        assert!(usage.fds > 0);
+        assert_eq!(samples.last(), Some(usage));
+    }
+}
@@ -0,0 +1,208 @@
+use std::io;
+use std::sync::Arc;
+
+use tracing::{error, info, warn, Instrument};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{Decoder, Encoder};
+
+use futures::{future, SinkExt, StreamExt};
+
+use bytes::BytesMut;
+
+use addrcore::{ClientMessage, ServerToClientCodec};
+
+use crate::listen::{self, ListenerStats};
+use crate::supervise::{self, Peer};
+use crate::admission::Connections;
+use crate::{admit, codec, serve_messages, Settings};
+
+/// Accepts WebSocket clients on `listener` until accepting fails or the
+/// server drains. Every binary message is a single frame of the protocol,
+/// length prefix included, both ways.
+pub async fn accept(
+    listener: TcpListener,
+    stats: Arc<ListenerStats>,
+    settings: Arc<Settings>,
+    connections: Option<Arc<Connections>>,
+) {
+    loop {
+        let accept =
+            async { (admit(&connections).await, listen::accept(|| listener.accept()).await) };
+        let (ticket, accepted) = tokio::select! {
+            accepted = accept => accepted,
+            _ = settings.drain_started() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("WebSocket server error: {}", e);
+                return;
+            }
+        };
+        if !settings.admits(addr) {
+            continue;
+        }
+        if let Err(e) = settings.socket_options.apply(&stream) {
+            warn!("Could not set the socket options of {}: {}", addr, e);
+        }
+        let active = stats.accept();
+        let peer = Peer::Ws(addr);
+        let id = supervise::next_conn_id();
+        let span = supervise::conn_span(peer, id);
+        span.in_scope(|| info!("Connected to {:?}", stream));
+        let settings = settings.clone();
+        let task = async move {
+            let permit = match ticket.admit().await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    info!("Closing the connection, queued past the deadline");
+                    return;
+                }
+            };
+            // Held until the connection closes.
+            let (_permit, _active) = (permit, active);
+            let ws = match tokio_tungstenite::accept_async(stream).await {
+                Ok(ws) => ws,
+                Err(e) => {
+                    error!("WebSocket handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+            if let Err(e) = serve(ws, peer, id, settings).await {
+                error!("Client error: {}", e);
+            }
+        };
+        supervise::spawn(peer, task.instrument(span));
+    }
+}
+
+async fn serve(
+    ws: WebSocketStream<TcpStream>,
+    peer: Peer,
+    id: u64,
+    settings: Arc<Settings>,
+) -> io::Result<()> {
+    let (writer, reader) = ws.split();
+    let mut encoder = codec(&settings);
+    // Shares the session of authenticated frames.
+    let mut decoder = encoder.clone();
+    let writer = writer.sink_map_err(ws_error).with(move |msg| {
+        let mut frame = BytesMut::new();
+        let result = encoder.encode(msg, &mut frame).map(|()| Message::Binary(frame.to_vec()));
+        future::ready(result)
+    });
+    let reader = reader.filter_map(move |msg| {
+        future::ready(match msg {
+            Ok(Message::Binary(data)) => decode(&mut decoder, &data).transpose(),
+            Ok(Message::Text(_)) => Some(Err(invalid("Frames must be sent as binary messages"))),
+            // Pings are answered by tungstenite, and the stream ends once
+            // the client closes it.
+            Ok(_) => None,
+            Err(e) => Some(Err(ws_error(e))),
+        })
+    });
+    serve_messages(reader, writer, peer, id, None, settings).await
+}
+
+/// Decodes the frame of a message, which must hold exactly one. A frame
+/// skipped by a lenient decode policy decodes to nothing.
+fn decode(decoder: &mut ServerToClientCodec, data: &[u8]) -> io::Result<Option<ClientMessage>> {
+    let mut buf = BytesMut::from(data);
+    let msg = decoder.decode(&mut buf)?;
+    if !buf.is_empty() {
+        return Err(invalid("A message must hold exactly one frame"));
+    }
+    Ok(msg)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use addrcore::{ClientToServerCodec, Constraints, Request, ServerMessage};
+
+    use crate::{Builder, Server};
+
+    type Client = WebSocketStream<TcpStream>;
+
+    /// Accepts WebSocket clients as the server built from `builder` would,
+    /// and connects to it.
+    async fn connect(builder: Builder) -> Client {
+        let mut server = builder.ws(([127, 0, 0, 1], 0).into()).build().await.unwrap();
+        let (listener, stats) = server.ws_listener.take().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept(listener, stats, server.settings.clone(), None));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let url = format!("ws://{}/", addr);
+        tokio_tungstenite::client_async(url, stream).await.unwrap().0
+    }
+
+    fn frame(msg: ClientMessage) -> Message {
+        let mut frame = BytesMut::new();
+        ClientToServerCodec::new().encode(msg, &mut frame).unwrap();
+        Message::Binary(frame.to_vec())
+    }
+
+    async fn recv(client: &mut Client) -> Option<ServerMessage> {
+        match client.next().await? {
+            Ok(Message::Binary(data)) => {
+                let mut buf = BytesMut::from(&data[..]);
+                ClientToServerCodec::new().decode(&mut buf).unwrap()
+            }
+            Ok(Message::Close(_)) | Err(_) => None,
+            Ok(other) => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    fn request(num_addrs: u32) -> ClientMessage {
+        ClientMessage::Request(Request { num_addrs, constraints: Constraints::default() })
+    }
+
+    #[tokio::test]
+    async fn serves_a_frame_per_message() {
+        let mut client = connect(Server::bind(([127, 0, 0, 1], 0).into())).await;
+        assert!(matches!(recv(&mut client).await, Some(ServerMessage::Info(_))));
+        client.send(frame(request(3))).await.unwrap();
+        match recv(&mut client).await {
+            Some(ServerMessage::Response(resp)) => assert_eq!(resp.addrs.len(), 3),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_on_text_messages() {
+        let mut client = connect(Server::bind(([127, 0, 0, 1], 0).into())).await;
+        assert!(matches!(recv(&mut client).await, Some(ServerMessage::Info(_))));
+        client.send(Message::Text("hello".to_string())).await.unwrap();
+        assert!(recv(&mut client).await.is_none());
+    }
+
+    #[test]
+    fn decodes_exactly_one_frame() {
+        let data = |msgs: &[ClientMessage]| {
+            let mut data = Vec::new();
+            for msg in msgs {
+                match frame(msg.clone()) {
+                    Message::Binary(frame) => data.extend(frame),
+                    _ => unreachable!(),
+                }
+            }
+            data
+        };
+        let mut decoder = ServerToClientCodec::new();
+        let msg = decode(&mut decoder, &data(&[request(1)])).unwrap();
+        assert_eq!(msg, Some(request(1)));
+        assert!(decode(&mut decoder, &data(&[request(1), request(2)])).is_err());
+    }
+}